@@ -0,0 +1,234 @@
+use crate::storage::StorageResult;
+use crate::utils::normalize_queue_name;
+use crate::{AppConfig, QueueItem, Storage};
+use chrono::Utc;
+use futures_util::StreamExt;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
+    ConfirmSelectOptions, QueueDeclareOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+use log::{debug, error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Pause between claims while a published queue has no due item.
+const PUBLISH_IDLE: Duration = Duration::from_millis(250);
+/// Pause before reconnecting once the broker connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Pause after a consumed message could not be stored, before the next is taken.
+const STORE_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Unacknowledged messages the broker hands each consumer at once.
+const CONSUME_PREFETCH: u16 = 64;
+
+/// Runs a storage call on the blocking pool, since SQLite calls block the thread,
+/// describing why it failed if it did.
+async fn blocking<T: Send + 'static>(
+    storage: &Arc<dyn Storage>,
+    call: impl FnOnce(&dyn Storage) -> StorageResult<T> + Send + 'static,
+) -> Result<T, String> {
+    let storage = storage.clone();
+    match tokio::task::spawn_blocking(move || call(storage.as_ref())).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Runs every `amqp_bindings` entry until the task is dropped, reconnecting to the broker
+/// whenever the connection fails. Does nothing without bindings, or on a read-only server.
+pub async fn run_bridges(storage: Arc<dyn Storage>, config: Arc<AppConfig>) {
+    if config.amqp_bindings.is_empty() || config.read_only {
+        return;
+    }
+    loop {
+        // The URL may carry credentials, so it is not logged
+        match bridge(&storage, &config).await {
+            Ok(()) => warn!("AMQP bridge stopped, reconnecting"),
+            Err(e) => error!("AMQP bridge failed, reconnecting: {e}"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects once and serves every binding over its own channel, until one of them fails.
+async fn bridge(storage: &Arc<dyn Storage>, config: &AppConfig) -> lapin::Result<()> {
+    let connection = Connection::connect(&config.amqp_url, ConnectionProperties::default()).await?;
+    info!(
+        "Connected to the AMQP broker, bridging {} queues",
+        config.amqp_bindings.len()
+    );
+    // Dropping the set aborts the other bindings once one of them returns
+    let mut bindings = JoinSet::new();
+    for binding in &config.amqp_bindings {
+        let queue = normalize_queue_name(&binding.queue);
+        if !storage.queue_exists(&queue) {
+            error!("AMQP binding names queue {queue}, which does not exist");
+            continue;
+        }
+        if let Some(exchange) = &binding.publish_exchange {
+            let channel = connection.create_channel().await?;
+            let routing_key = binding.routing_key.clone().unwrap_or_else(|| queue.clone());
+            bindings.spawn(publish(
+                storage.clone(),
+                channel,
+                queue.clone(),
+                exchange.clone(),
+                routing_key,
+            ));
+        }
+        if let Some(consume_queue) = &binding.consume_queue {
+            let channel = connection.create_channel().await?;
+            bindings.spawn(consume(
+                storage.clone(),
+                channel,
+                queue,
+                consume_queue.clone(),
+            ));
+        }
+    }
+    match bindings.join_next().await {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => {
+            error!("AMQP binding task failed: {e}");
+            Ok(())
+        }
+        // No binding names an existing queue; wait for one to be created
+        None => Ok(()),
+    }
+}
+
+/// Claims the queue's due items one at a time and publishes each to `exchange`, waiting
+/// for the broker's confirmation. An item that is not confirmed is put back.
+async fn publish(
+    storage: Arc<dyn Storage>,
+    channel: Channel,
+    queue: String,
+    exchange: String,
+    routing_key: String,
+) -> lapin::Result<()> {
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    let properties = BasicProperties::default()
+        .with_content_type("application/json".into())
+        // Persistent, so a confirmed item survives a broker restart
+        .with_delivery_mode(2);
+    loop {
+        let claimed = {
+            let queue = queue.clone();
+            blocking(&storage, move |storage| {
+                storage.claim_any(std::slice::from_ref(&queue), Utc::now(), true, 0)
+            })
+            .await
+        };
+        let item = match claimed {
+            Ok(Some((_, item))) => item,
+            Ok(None) => {
+                tokio::time::sleep(PUBLISH_IDLE).await;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to claim from '{queue}' for AMQP: {e}");
+                tokio::time::sleep(PUBLISH_IDLE).await;
+                continue;
+            }
+        };
+        let body = item.to_json_string().expect("items serialize to JSON");
+        let confirmation = match channel
+            .basic_publish(
+                &exchange,
+                &routing_key,
+                BasicPublishOptions::default(),
+                body.as_bytes(),
+                properties.clone(),
+            )
+            .await
+        {
+            Ok(confirm) => confirm.await,
+            Err(e) => Err(e),
+        };
+        match confirmation {
+            Ok(confirmation) if confirmation.is_ack() => {
+                debug!("Published {item:?} from queue {queue} to AMQP exchange '{exchange}'");
+            }
+            outcome => {
+                warn!("AMQP broker did not confirm {item:?} from queue {queue}, putting it back");
+                let (back, put_back) = (queue.clone(), item.clone());
+                if let Err(e) =
+                    blocking(&storage, move |storage| storage.put_item(&back, put_back)).await
+                {
+                    error!("Failed to put {item:?} back into '{queue}', it is lost: {e}");
+                }
+                outcome?;
+            }
+        }
+    }
+}
+
+/// Stores every message of the AMQP queue `consume_queue` in `queue`, acknowledging it
+/// once stored. Messages that are not JSON items are rejected without requeueing.
+async fn consume(
+    storage: Arc<dyn Storage>,
+    channel: Channel,
+    queue: String,
+    consume_queue: String,
+) -> lapin::Result<()> {
+    channel
+        .basic_qos(CONSUME_PREFETCH, BasicQosOptions::default())
+        .await?;
+    channel
+        .queue_declare(
+            &consume_queue,
+            QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            FieldTable::default(),
+        )
+        .await?;
+    let mut consumer = channel
+        .basic_consume(
+            &consume_queue,
+            &format!("dtqueue-{queue}"),
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await?;
+    while let Some(delivery) = consumer.next().await {
+        let delivery = delivery?;
+        let item = match serde_json::from_slice::<QueueItem>(&delivery.data) {
+            Ok(item) => item,
+            Err(e) => {
+                warn!("Rejecting a message of AMQP queue '{consume_queue}', not an item: {e}");
+                delivery
+                    .nack(BasicNackOptions {
+                        requeue: false,
+                        ..Default::default()
+                    })
+                    .await?;
+                continue;
+            }
+        };
+        let target = queue.clone();
+        match blocking(&storage, move |storage| storage.put_item(&target, item)).await {
+            Ok(_) => {
+                debug!("Stored a message of AMQP queue '{consume_queue}' in queue {queue}");
+                delivery.ack(BasicAckOptions::default()).await?;
+            }
+            Err(e) => {
+                error!("Failed to store a message of AMQP queue '{consume_queue}': {e}");
+                // Requeued, so it is delivered again once the storage recovers
+                delivery
+                    .nack(BasicNackOptions {
+                        requeue: true,
+                        ..Default::default()
+                    })
+                    .await?;
+                tokio::time::sleep(STORE_RETRY_DELAY).await;
+            }
+        }
+    }
+    Ok(())
+}
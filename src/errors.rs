@@ -0,0 +1,210 @@
+use axum::http::StatusCode;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The `code` of every JSON error response. Clients match on these names, so a variant
+/// must never be renamed; `GET /_errors` lists them all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidParameter,
+    InvalidQueueName,
+    QueueNotFound,
+    QueueExists,
+    TableConflict,
+    QueueLimitReached,
+    QueueCreateRateLimited,
+    ItemNotFound,
+    HeadChanged,
+    MessageMismatch,
+    StaleFenceToken,
+    OutOfOrder,
+    MissingMessage,
+    MissingId,
+    SecondaryPolicyViolation,
+    NotAnInteger,
+    Unauthorized,
+    ReadOnly,
+    Forbidden,
+    ServicePaused,
+    NotReady,
+    TooManySubscribers,
+    InternalError,
+}
+
+impl ErrorCode {
+    /// Every code, in the order the catalog lists them.
+    pub const ALL: [ErrorCode; 24] = [
+        ErrorCode::BadRequest,
+        ErrorCode::InvalidParameter,
+        ErrorCode::InvalidQueueName,
+        ErrorCode::QueueNotFound,
+        ErrorCode::QueueExists,
+        ErrorCode::TableConflict,
+        ErrorCode::QueueLimitReached,
+        ErrorCode::QueueCreateRateLimited,
+        ErrorCode::ItemNotFound,
+        ErrorCode::HeadChanged,
+        ErrorCode::MessageMismatch,
+        ErrorCode::StaleFenceToken,
+        ErrorCode::OutOfOrder,
+        ErrorCode::MissingMessage,
+        ErrorCode::MissingId,
+        ErrorCode::SecondaryPolicyViolation,
+        ErrorCode::NotAnInteger,
+        ErrorCode::Unauthorized,
+        ErrorCode::ReadOnly,
+        ErrorCode::Forbidden,
+        ErrorCode::ServicePaused,
+        ErrorCode::NotReady,
+        ErrorCode::TooManySubscribers,
+        ErrorCode::InternalError,
+    ];
+
+    /// The status every response carrying the code is sent with.
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidParameter
+            | ErrorCode::MissingMessage
+            | ErrorCode::MissingId
+            | ErrorCode::SecondaryPolicyViolation => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::InvalidQueueName
+            | ErrorCode::QueueLimitReached
+            | ErrorCode::ReadOnly
+            | ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::QueueNotFound | ErrorCode::ItemNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::QueueExists
+            | ErrorCode::TableConflict
+            | ErrorCode::HeadChanged
+            | ErrorCode::StaleFenceToken
+            | ErrorCode::OutOfOrder
+            | ErrorCode::NotAnInteger => StatusCode::CONFLICT,
+            ErrorCode::MessageMismatch => StatusCode::PRECONDITION_FAILED,
+            ErrorCode::QueueCreateRateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ServicePaused | ErrorCode::NotReady | ErrorCode::TooManySubscribers => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "Malformed JSON or invalid datetime format",
+            ErrorCode::InvalidParameter => {
+                "A query parameter is missing, malformed or out of range; the message names it"
+            }
+            ErrorCode::InvalidQueueName => "Attempted to access a non-existent or invalid queue",
+            ErrorCode::QueueNotFound => {
+                "A valid queue name that has not been created yet (with auto_create_queues)"
+            }
+            ErrorCode::QueueExists => {
+                "PUT /_queues/{queue} without if_not_exists=true named an existing queue"
+            }
+            ErrorCode::TableConflict => {
+                "Creating the queue would take a SQLite table of an existing sharded or partitioned queue"
+            }
+            ErrorCode::QueueLimitReached => "Creating the queue would exceed max_queues",
+            ErrorCode::QueueCreateRateLimited => {
+                "More than queue_create_rate_per_min queues were created during the last minute"
+            }
+            ErrorCode::ItemNotFound => "The item addressed by a key does not exist",
+            ErrorCode::HeadChanged => "The head no longer matches the key given to cas-delete",
+            ErrorCode::MessageMismatch => {
+                "The item under the key does not hold the message given as if_message"
+            }
+            ErrorCode::StaleFenceToken => {
+                "The X-Fence-Token is lower than the highest one the queue has seen"
+            }
+            ErrorCode::OutOfOrder => {
+                "The item's datetime is not after the previous one from its X-Producer-Id"
+            }
+            ErrorCode::MissingMessage => "The item has no message while require_message is set",
+            ErrorCode::MissingId => "The item has no id but the queue is keyed by id",
+            ErrorCode::SecondaryPolicyViolation => {
+                "The item's datetime_secondary violates the queue's datetime_secondary policy"
+            }
+            ErrorCode::NotAnInteger => "The message to increment is not an integer",
+            ErrorCode::Unauthorized => "Missing or invalid API key on an administrative endpoint",
+            ErrorCode::ReadOnly => "A mutating request reached a server running with read_only",
+            ErrorCode::Forbidden => "The client address is refused by allowed_ips / denied_ips",
+            ErrorCode::ServicePaused => "The server is paused for maintenance by POST /_pause",
+            ErrorCode::NotReady => "The server is still opening its storage at startup",
+            ErrorCode::TooManySubscribers => {
+                "The queue already has max_subscribers_per_queue connections open"
+            }
+            ErrorCode::InternalError => "Server or database error",
+        }
+    }
+}
+
+/// Error responses by code, indexed like `ErrorCode::ALL`.
+#[derive(Default)]
+pub struct ErrorCounts([AtomicU64; ErrorCode::ALL.len()]);
+
+impl ErrorCounts {
+    pub fn record(&self, code: ErrorCode) {
+        if let Some(index) = ErrorCode::ALL.iter().position(|known| *known == code) {
+            self.0[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets every count back to zero.
+    pub fn reset(&self) {
+        for count in &self.0 {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// The codes sent at least once, with their counts.
+    pub fn snapshot(&self) -> BTreeMap<ErrorCode, u64> {
+        ErrorCode::ALL
+            .into_iter()
+            .zip(&self.0)
+            .map(|(code, count)| (code, count.load(Ordering::Relaxed)))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+}
+
+/// One entry of the `GET /_errors` catalog.
+#[derive(Debug, Serialize)]
+pub struct ErrorCodeInfo {
+    pub code: ErrorCode,
+    pub status: u16,
+    pub description: &'static str,
+}
+
+/// Describes every error code.
+pub fn catalog() -> Vec<ErrorCodeInfo> {
+    ErrorCode::ALL
+        .into_iter()
+        .map(|code| ErrorCodeInfo {
+            code,
+            status: code.status().as_u16(),
+            description: code.description(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_lists_each_code_once() {
+        let names: Vec<String> = catalog()
+            .iter()
+            .map(|info| serde_json::to_value(info.code).unwrap())
+            .map(|code| code.as_str().unwrap().to_string())
+            .collect();
+        let mut unique = names.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), names.len());
+        assert!(names.contains(&"InvalidQueueName".to_string()));
+    }
+}
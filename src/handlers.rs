@@ -1,218 +1,7334 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    Router,
+    body::{Body, Bytes},
+    extract::{
+        ConnectInfo, FromRequestParts, Path, Query, Request, State,
+        rejection::{PathRejection, QueryRejection},
+    },
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header, request::Parts},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, post, put},
 };
-use dtqueue::{QueueItem, Storage, utils};
-use log::{error, info, warn};
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use dtqueue::errors::{self, ErrorCode, ErrorCounts};
+use dtqueue::maintenance::{self, Activity, DepthHistory};
+use dtqueue::webhooks::Webhooks;
+use dtqueue::{
+    AppConfig, CasResult, DecodeErrorPolicy, KeyBy, QueueItem, QueueKey, QueueOptions, Storage,
+    StorageError, TxOp, ValidityCounts, utils,
+};
+use futures_util::future::{BoxFuture, Shared};
+use futures_util::{FutureExt, StreamExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tower::ServiceExt;
+use tower_http::compression::CompressionLayer;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+
+/// Shared state handed to every handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Arc<dyn Storage>,
+    pub config: Arc<AppConfig>,
+    pub webhooks: Webhooks,
+    /// Rotates which queue wins ties in `claim_any`.
+    pub claim_rotation: Arc<AtomicUsize>,
+    /// When queues were auto-created during the last minute, for
+    /// `queue_create_rate_per_min`.
+    pub queue_creations: Arc<Mutex<VecDeque<Instant>>>,
+    /// When each queue last served a request, for `idle_compact_secs`.
+    pub activity: Arc<Activity>,
+    /// Depth samples served by `GET /{queue}/history`.
+    pub depth_history: Arc<DepthHistory>,
+    /// Hands items accepted by PUT to `run_write_buffer` when `async_write_ack` is set.
+    pub write_buffer: Option<mpsc::Sender<(String, QueueItem)>>,
+    /// Set by `POST /_pause` and cleared by `POST /_resume`.
+    pub paused: Arc<AtomicBool>,
+    /// Connections held open per queue, for `max_subscribers_per_queue`.
+    pub subscribers: Arc<Subscribers>,
+    /// The latest datetime each producer put to each queue, for `monotonic_producers`.
+    pub producers: Arc<ProducerClocks>,
+    /// Requests and errors counted since startup or the last `POST /_stats/reset`,
+    /// served by `GET /_stats`.
+    pub operations: Arc<OperationCounts>,
+    /// Head reads shared by concurrent GETs, for `get_coalesce_window_ms`.
+    pub head_reads: Arc<HeadReads>,
+}
+
+/// A head read that concurrent GETs of one queue wait for together.
+type SharedRead = Shared<BoxFuture<'static, Result<Option<QueueItem>, String>>>;
+
+/// Coalesces the head reads of concurrent GETs on each queue into one storage read.
+#[derive(Default)]
+pub struct HeadReads {
+    pending: Mutex<HashMap<String, SharedRead>>,
+    /// Storage reads issued so far.
+    reads: AtomicU64,
+}
+
+impl HeadReads {
+    /// Reads the head of `queue` once `window` has passed, answering every other call for
+    /// the same queue made in the meantime with the same result.
+    async fn get(
+        self: &Arc<Self>,
+        storage: &Arc<dyn Storage>,
+        queue: &str,
+        window: Duration,
+    ) -> Result<Option<QueueItem>, String> {
+        let read = {
+            let mut pending = self.pending.lock().unwrap();
+            let read = pending.entry(queue.to_string()).or_insert_with(|| {
+                let head_reads = self.clone();
+                let storage = storage.clone();
+                let queue = queue.to_string();
+                async move {
+                    tokio::time::sleep(window).await;
+                    // Later calls wait for the next read, so each is answered by a read
+                    // that began after it was made and sees every write before it
+                    head_reads.pending.lock().unwrap().remove(&queue);
+                    head_reads.reads.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::spawn_blocking(move || storage.get_item(&queue))
+                        .await
+                        .map_err(|e| e.to_string())?
+                        .map_err(|e| e.to_string())
+                }
+                .boxed()
+                .shared()
+            });
+            read.clone()
+        };
+        read.await
+    }
+}
+
+/// The datetime of the last accepted PUT of every producer to every queue.
+#[derive(Default)]
+pub struct ProducerClocks {
+    last: Mutex<HashMap<(String, String), DateTime<Utc>>>,
+}
+
+impl ProducerClocks {
+    /// Returns the datetime of `producer`'s last accepted PUT to `queue`.
+    pub fn last(&self, queue: &str, producer: &str) -> Option<DateTime<Utc>> {
+        let last = self.last.lock().unwrap();
+        last.get(&(queue.to_string(), producer.to_string()))
+            .copied()
+    }
+
+    /// Records an accepted PUT of `datetime` by `producer` to `queue`.
+    pub fn record(&self, queue: &str, producer: &str, datetime: DateTime<Utc>) {
+        let mut last = self.last.lock().unwrap();
+        let previous = last
+            .entry((queue.to_string(), producer.to_string()))
+            .or_insert(datetime);
+        // Concurrent PUTs of one producer may finish in either order
+        *previous = (*previous).max(datetime);
+    }
+}
+
+/// Request counts since startup or the last reset, per queue and per error code,
+/// counted by `count_operations` and served by `GET /_stats`.
+pub struct OperationCounts {
+    /// When counting began; the queue counts are only reset under its lock.
+    started: Mutex<DateTime<Utc>>,
+    queues: Mutex<HashMap<String, Arc<QueueOperations>>>,
+    /// Every error response, whether or not it concerned a queue.
+    errors: ErrorCounts,
+}
+
+impl Default for OperationCounts {
+    fn default() -> Self {
+        OperationCounts {
+            started: Mutex::new(Utc::now()),
+            queues: Default::default(),
+            errors: Default::default(),
+        }
+    }
+}
+
+/// The requests answered for one queue.
+#[derive(Default)]
+struct QueueOperations {
+    puts: AtomicU64,
+    gets: AtomicU64,
+    deletes: AtomicU64,
+    errors: ErrorCounts,
+}
+
+/// The operation of a request on `/{queue}` itself that `GET /_stats` counts.
+#[derive(Clone, Copy)]
+enum QueueOperation {
+    Put,
+    Get,
+    Delete,
+}
+
+impl OperationCounts {
+    /// Counts a request concerning `queue`: `operation` if it succeeded, and `error`.
+    fn record(
+        &self,
+        queue: Option<&str>,
+        operation: Option<QueueOperation>,
+        succeeded: bool,
+        error: Option<ErrorCode>,
+    ) {
+        if let Some(code) = error {
+            self.errors.record(code);
+        }
+        let Some(queue) = queue else {
+            return;
+        };
+        let counts = self
+            .queues
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_default()
+            .clone();
+        if let Some(code) = error {
+            counts.errors.record(code);
+        }
+        let counter = match operation {
+            Some(QueueOperation::Put) => &counts.puts,
+            Some(QueueOperation::Get) => &counts.gets,
+            Some(QueueOperation::Delete) => &counts.deletes,
+            None => return,
+        };
+        if succeeded {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Zeroes every count and restarts counting now. A request still being counted
+    /// may add itself to either window.
+    pub fn reset(&self) {
+        let mut started = self.started.lock().unwrap();
+        self.queues.lock().unwrap().clear();
+        self.errors.reset();
+        *started = Utc::now();
+    }
+
+    pub fn snapshot(&self) -> OperationStats {
+        let started = self.started.lock().unwrap();
+        let queues = self.queues.lock().unwrap();
+        OperationStats {
+            since: *started,
+            errors: self.errors.snapshot(),
+            queues: queues
+                .iter()
+                .map(|(queue, counts)| {
+                    let stats = QueueOperationStats {
+                        puts: counts.puts.load(Ordering::Relaxed),
+                        gets: counts.gets.load(Ordering::Relaxed),
+                        deletes: counts.deletes.load(Ordering::Relaxed),
+                        errors: counts.errors.snapshot(),
+                    };
+                    (queue.clone(), stats)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OperationStats {
+    pub since: DateTime<Utc>,
+    pub errors: BTreeMap<ErrorCode, u64>,
+    pub queues: BTreeMap<String, QueueOperationStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueOperationStats {
+    pub puts: u64,
+    pub gets: u64,
+    pub deletes: u64,
+    pub errors: BTreeMap<ErrorCode, u64>,
+}
+
+/// Counts the long-lived connections open on each queue.
+#[derive(Default)]
+pub struct Subscribers {
+    counts: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl Subscribers {
+    /// Registers a connection to `queue`, unless `limit` are already open. The
+    /// connection counts until the returned guard is dropped.
+    pub fn subscribe(&self, queue: &str, limit: Option<usize>) -> Option<Subscription> {
+        let count = self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_default()
+            .clone();
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| match limit {
+                Some(limit) if open >= limit => None,
+                _ => Some(open + 1),
+            })
+            .ok()?;
+        Some(Subscription { count })
+    }
+}
+
+/// An open connection counted by `Subscribers`; dropping it, e.g. when the client
+/// disconnects and axum drops the handler, releases the slot.
+pub struct Subscription {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The answer to a connection refused by `max_subscribers_per_queue`.
+fn too_many_subscribers(queue: &str) -> Response {
+    warn!("Connection to queue {queue} refused, too many are open");
+    utils::json_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::TooManySubscribers,
+        &format!("Queue {queue} already has the maximum number of connections open"),
+    )
+}
+
+/// Builds the application router with all queue endpoints.
+pub fn router(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/_dump", get(dump))
+        .route("/_restore", post(restore))
+        .route("/_prefix/{prefix}/peek", get(peek_prefix))
+        .route("/_snapshot", get(snapshot))
+        .route("/_errors", get(error_catalog))
+        .route("/_stats", get(operation_stats))
+        .route("/_stats/reset", post(reset_operation_stats))
+        .route("/_config", get(effective_config))
+        .route("/_purge-deleted", post(purge_deleted))
+        .route("/_health", get(health))
+        .route("/_ready", get(ready))
+        .route("/_pause", post(pause))
+        .route("/_resume", post(resume))
+        .route("/_metrics/stream", get(metrics_stream))
+        .route("/_queues/{queue}", put(create_queue))
+        .route("/_dispatch", post(dispatch))
+        .route("/_tx", post(transact))
+        .route("/claim-any", post(claim_any))
+        .route(
+            "/{queue}",
+            get(get_item)
+                .head(head_queue)
+                .put(put_item)
+                .delete(delete_item),
+        )
+        .route("/{queue}/peek", get(peek_items))
+        .route("/{queue}/tail", get(tail_item))
+        .route("/{queue}/overdue", get(overdue_items))
+        .route("/{queue}/export", get(export_items))
+        .route("/{queue}/history", get(depth_history))
+        .route("/{queue}/eta", get(drain_eta))
+        .route("/{queue}/counts", get(count_by_validity))
+        .route("/{queue}/stats", get(queue_stats))
+        .route("/{queue}/stream", put(put_stream))
+        .route("/{queue}/cas-delete", post(compare_and_delete))
+        .route("/{queue}/claim-if-due", post(claim_if_due))
+        .route("/{queue}/swap", post(swap_items))
+        .route("/{queue}/hide", post(hide_item))
+        .route("/{queue}/extend", post(extend_hidden))
+        .route("/{queue}/incr", post(increment_item))
+        .route("/{queue}/shift", post(shift_items))
+        .route("/{queue}/rank", get(rank_of_key))
+        .route("/{queue}/exists", post(items_exist))
+        .fallback(invalid_queue)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            refuse_while_paused,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), filter_ip))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            count_operations,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), debug_sample));
+    let router = match state.config.compression_min_bytes {
+        Some(min_bytes) => router.layer(compression(min_bytes)),
+        None => router,
+    };
+    router.with_state(state)
+}
+
+/// Gzips responses of at least `min_bytes`. Bodies of unknown size, such as streams,
+/// count as large; event streams are never compressed, so each event arrives at once.
+fn compression(min_bytes: u16) -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(min_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+    CompressionLayer::new().compress_when(predicate)
+}
+
+/// Bodies up to this size are buffered to be logged by `debug_sample`; larger or
+/// unbounded ones, such as streams, pass through uncaptured.
+const DEBUG_SAMPLE_CAPTURE_LIMIT: u64 = 1024 * 1024;
+
+/// Bytes of a captured body that `debug_sample` writes to the log.
+const DEBUG_SAMPLE_LOGGED_BYTES: usize = 4096;
+
+/// Headers whose values `debug_sample` never logs.
+const REDACTED_HEADERS: [header::HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// Counts every request for `GET /_stats`: successful GET, PUT and DELETE requests on
+/// `/{queue}`, and every error response by its code. Only existing queues are counted
+/// by name, so requests to made-up names cannot grow the counts without bound.
+async fn count_operations(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    let segment = path
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    // Server-wide endpoints start with `_`
+    let queue = (!segment.is_empty() && !segment.starts_with('_') && segment != "claim-any")
+        .then(|| utils::normalize_queue_name(segment));
+    let operation = match request.method().as_str() {
+        _ if path.len() != segment.len() + 1 => None,
+        "PUT" => Some(QueueOperation::Put),
+        "GET" => Some(QueueOperation::Get),
+        "DELETE" => Some(QueueOperation::Delete),
+        _ => None,
+    };
+
+    let response = next.run(request).await;
+    // Checked afterwards, since a PUT may create the queue
+    let queue = queue.filter(|queue| state.storage.queue_exists(queue));
+    state.operations.record(
+        queue.as_deref(),
+        operation,
+        response.status().is_success(),
+        response.extensions().get::<ErrorCode>().copied(),
+    );
+    response
+}
+
+/// Logs the method, path, headers and body of a `debug_sample_rate` fraction of
+/// requests at debug level, together with the status and body of their responses.
+async fn debug_sample(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if !log::log_enabled!(log::Level::Debug) || !sampled(state.config.debug_sample_rate) {
+        return next.run(request).await;
+    }
+    let (parts, body) = request.into_parts();
+    let (body, request_body) = capture_body(body).await;
+    let request_line = format!(
+        "{} {} headers [{}] body {request_body}",
+        parts.method,
+        parts.uri,
+        redacted_headers(&parts.headers)
+    );
+
+    let response = next.run(Request::from_parts(parts, body)).await;
+    let (parts, body) = response.into_parts();
+    let (body, response_body) = capture_body(body).await;
+    debug!(
+        "sampled request {request_line}; response {} body {response_body}",
+        parts.status
+    );
+    Response::from_parts(parts, body)
+}
+
+/// Whether to sample a request at `rate`, drawing from the randomly keyed std hasher.
+fn sampled(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    let draw = std::hash::RandomState::new().build_hasher().finish();
+    ((draw >> 11) as f64 / (1u64 << 53) as f64) < rate
+}
+
+/// Buffers `body` if it is small enough, returning a body with the same content and
+/// the text to log for it.
+async fn capture_body(body: Body) -> (Body, String) {
+    use axum::body::HttpBody;
+    match body.size_hint().upper() {
+        Some(size) if size <= DEBUG_SAMPLE_CAPTURE_LIMIT => {}
+        _ => return (body, "<not captured>".to_string()),
+    }
+    match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => {
+            let shown = &bytes[..bytes.len().min(DEBUG_SAMPLE_LOGGED_BYTES)];
+            let mut text = format!("{:?}", String::from_utf8_lossy(shown));
+            if shown.len() < bytes.len() {
+                text.push_str(&format!(" ({} bytes in total)", bytes.len()));
+            }
+            (Body::from(bytes), text)
+        }
+        // The client went away mid-body; the handler sees the same empty body
+        Err(e) => (Body::empty(), format!("<unreadable: {e}>")),
+    }
+}
+
+/// Formats headers as `name: value` pairs, replacing credentials with `<redacted>`.
+fn redacted_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            if REDACTED_HEADERS.contains(name) {
+                format!("{name}: <redacted>")
+            } else {
+                format!("{name}: {}", String::from_utf8_lossy(value.as_bytes()))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Refuses clients outside `allowed_ips` or inside `denied_ips` before routing.
+/// Requires the server to be started with connect info so the peer address is known.
+async fn filter_ip(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = &state.config;
+    if config.allowed_ips.is_empty() && config.denied_ips.is_empty() {
+        return next.run(request).await;
+    }
+    let (mut parts, body) = request.into_parts();
+    let path = parts.uri.path().to_string();
+    if config.ip_filter_exempt_paths.contains(&path) {
+        return next.run(Request::from_parts(parts, body)).await;
+    }
+    // The extractor also falls back to the MockConnectInfo layer used by tests
+    let peer = ConnectInfo::<SocketAddr>::from_request_parts(&mut parts, &state)
+        .await
+        .ok()
+        .map(|ConnectInfo(addr)| addr.ip());
+    match peer {
+        Some(ip) if config.ip_allowed(ip) => next.run(Request::from_parts(parts, body)).await,
+        _ => {
+            let peer = peer.map_or("an unknown address".to_string(), |ip| ip.to_string());
+            warn!("Refused request to {path} from {peer}");
+            utils::json_error(
+                StatusCode::FORBIDDEN,
+                ErrorCode::Forbidden,
+                "Requests from this address are not allowed",
+            )
+        }
+    }
+}
+
+/// Paths served while paused with `pause_blocks_reads`, so the server can be inspected
+/// and resumed.
+const PAUSE_EXEMPT_PATHS: [&str; 4] = ["/_health", "/_ready", "/_pause", "/_resume"];
+
+/// With `pause_blocks_reads`, refuses every request but the exempt ones while the server
+/// is paused. Writes are refused by `check_writable` either way.
+async fn refuse_while_paused(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.config.pause_blocks_reads
+        && state.paused.load(Ordering::Relaxed)
+        && !PAUSE_EXEMPT_PATHS.contains(&request.uri().path())
+    {
+        return paused_error();
+    }
+    next.run(request).await
+}
+
+fn paused_error() -> Response {
+    warn!("Rejected a request while the server is paused");
+    utils::json_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::ServicePaused,
+        "The server is paused for maintenance; retry after it is resumed",
+    )
+}
+
+/// Reports that the server is up, and whether it is paused.
+pub async fn health(State(state): State<AppState>) -> Response {
+    let paused = state.paused.load(Ordering::Relaxed);
+    (
+        StatusCode::OK,
+        axum::Json(serde_json::json!({"status": "ok", "paused": paused})),
+    )
+        .into_response()
+}
+
+/// Reports that startup has finished; before then `startup_router` answers instead.
+pub async fn ready() -> Response {
+    (
+        StatusCode::OK,
+        axum::Json(serde_json::json!({"status": "ready"})),
+    )
+        .into_response()
+}
+
+/// The application router once startup has finished. The listener is bound before the
+/// storage is opened, so that readiness probes get an answer while it is still opening.
+#[derive(Clone, Default)]
+pub struct Startup(Arc<OnceLock<Router>>);
+
+impl Startup {
+    /// Marks the server ready, handing every further request to `app`.
+    pub fn finish(&self, app: Router) {
+        if self.0.set(app).is_err() {
+            warn!("startup finished twice, keeping the first router");
+        }
+    }
+}
+
+/// Serves the router handed to `Startup::finish`. Until then `/_ready` answers `503`
+/// with `{"status": "starting"}`, and every other request `503 NotReady`.
+pub fn startup_router(startup: Startup) -> Router {
+    Router::new()
+        .fallback(forward_when_ready)
+        .with_state(startup)
+}
+
+async fn forward_when_ready(State(startup): State<Startup>, request: Request) -> Response {
+    if let Some(app) = startup.0.get() {
+        return match app.clone().oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+    }
+    if request.uri().path() == "/_ready" {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            axum::Json(serde_json::json!({"status": "starting"})),
+        )
+            .into_response();
+    }
+    utils::json_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::NotReady,
+        "The server is starting; retry once GET /_ready answers 200",
+    )
+}
+
+/// Stops accepting writes, and with `pause_blocks_reads` every other request, until
+/// `POST /_resume`.
+pub async fn pause(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    if !state.paused.swap(true, Ordering::Relaxed) {
+        warn!("server paused");
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Undoes `POST /_pause`.
+pub async fn resume(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    if state.paused.swap(false, Ordering::Relaxed) {
+        info!("server resumed");
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Checks the bearer token of a request to an administrative endpoint,
+/// returning the error response if the request is not authorized.
+fn authorize(config: &AppConfig, headers: &HeaderMap) -> Option<Response> {
+    if config.api_keys.is_empty() {
+        return None;
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if config.api_keys.iter().any(|key| key == token) => None,
+        _ => {
+            warn!("Unauthorized request to an administrative endpoint");
+            Some(utils::json_error(
+                StatusCode::UNAUTHORIZED,
+                ErrorCode::Unauthorized,
+                "A valid API key is required for this endpoint",
+            ))
+        }
+    }
+}
+
+/// The `{queue}` path segment in its canonical form (see `utils::normalize_queue_name`),
+/// so a queue answers to its name in any case.
+pub struct QueueName(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for QueueName {
+    type Rejection = PathRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(queue) = Path::<String>::from_request_parts(parts, state).await?;
+        Ok(QueueName(utils::normalize_queue_name(&queue)))
+    }
+}
+
+/// Rejects requests to queues that are not registered, returning the error response.
+/// With `auto_create_queues`, a valid name that has not been written yet is reported
+/// as not found rather than invalid.
+fn check_queue(state: &AppState, queue: &str) -> Option<Response> {
+    if state.storage.queue_exists(queue) {
+        state.activity.touch(queue);
+        return None;
+    }
+    if state.config.auto_create_queues && utils::sanitize_queue_name(queue).is_some() {
+        info!("Queue not created yet: {queue}");
+        return Some(utils::json_error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::QueueNotFound,
+            &format!("Queue not found: {queue}"),
+        ));
+    }
+    // The path is percent-decoded exactly once, so a `%` left over means double encoding
+    if queue.contains('%') {
+        warn!("Percent-encoded queue name attempted: {queue}");
+        return Some(utils::json_error(
+            StatusCode::FORBIDDEN,
+            ErrorCode::InvalidQueueName,
+            &format!(
+                "Invalid queue name attempted: {queue} is still percent-encoded after decoding the path once"
+            ),
+        ));
+    }
+    warn!("Invalid queue name attempted: {queue}");
+    Some(utils::json_error(
+        StatusCode::FORBIDDEN,
+        ErrorCode::InvalidQueueName,
+        &format!("Invalid queue name attempted: {queue}"),
+    ))
+}
+
+/// Millisecond timestamps accepted in query parameters span the years 0000 through 9999,
+/// the range an RFC 3339 datetime can express.
+const MIN_TIMESTAMP_MILLIS: i64 = -62_167_219_200_000;
+const MAX_TIMESTAMP_MILLIS: i64 = 253_402_300_799_999;
+
+/// Converts the millisecond timestamp given as query parameter `name`, describing why
+/// it is rejected if it is out of range.
+fn millis_param(name: &str, millis: i64) -> Result<DateTime<Utc>, String> {
+    (MIN_TIMESTAMP_MILLIS..=MAX_TIMESTAMP_MILLIS)
+        .contains(&millis)
+        .then(|| DateTime::from_timestamp_millis(millis))
+        .flatten()
+        .ok_or_else(|| {
+            format!(
+                "{name}: {millis} is outside {MIN_TIMESTAMP_MILLIS}..={MAX_TIMESTAMP_MILLIS} milliseconds since the epoch"
+            )
+        })
+}
+
+/// Builds an item key from millisecond timestamps given as query parameters.
+fn key_from_millis(at: i64, secondary: Option<i64>) -> Result<QueueKey, String> {
+    Ok(QueueKey {
+        datetime: millis_param("at", at)?,
+        datetime_secondary: secondary
+            .map(|secondary| millis_param("secondary", secondary))
+            .transpose()?,
+    })
+}
+
+/// Builds the response for a query parameter that is malformed or out of range.
+fn invalid_parameter(message: &str) -> Response {
+    warn!("Invalid query parameter: {message}");
+    utils::json_error(
+        StatusCode::BAD_REQUEST,
+        ErrorCode::InvalidParameter,
+        &format!("Invalid query parameter {message}"),
+    )
+}
+
+/// Builds the response for a failed queue creation.
+fn create_queue_error(queue: &str, e: StorageError) -> Response {
+    match e {
+        StorageError::InvalidQueueName(_) => {
+            warn!("Invalid queue name attempted: {queue}");
+            utils::json_error(
+                StatusCode::FORBIDDEN,
+                ErrorCode::InvalidQueueName,
+                &format!("Invalid queue name attempted: {queue}"),
+            )
+        }
+        StorageError::TableConflict { .. } => {
+            warn!("Refused to create queue {queue}: {e}");
+            utils::json_error(
+                StatusCode::CONFLICT,
+                ErrorCode::TableConflict,
+                &format!("Failed to create queue {queue}: {e}"),
+            )
+        }
+        StorageError::QueueLimitReached(_) => {
+            warn!("Refused to create queue {queue}: {e}");
+            utils::json_error(
+                StatusCode::FORBIDDEN,
+                ErrorCode::QueueLimitReached,
+                &format!("Failed to create queue {queue}: {e}"),
+            )
+        }
+        e => {
+            error!("Failed to create queue '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to create queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Lists every error code with its usual status and a description.
+pub async fn error_catalog() -> Response {
+    (StatusCode::OK, axum::Json(errors::catalog())).into_response()
+}
+
+/// Returns the requests counted since startup, per queue and per error code.
+pub async fn operation_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    (StatusCode::OK, axum::Json(state.operations.snapshot())).into_response()
+}
+
+/// Zeroes the counts of `GET /_stats`, so they cover a window such as a load test.
+pub async fn reset_operation_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    state.operations.reset();
+    info!("operation counts reset");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Returns the configuration the server runs with, secrets redacted.
+pub async fn effective_config(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    (StatusCode::OK, axum::Json(state.config.redacted())).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PurgeDeletedParams {
+    /// How long ago a deleted item must have been last written, such as `1h`; 0 by default.
+    pub older_than: Option<String>,
+}
+
+/// Physically deletes the consumed items SQLite keeps, in every queue, answering with
+/// the number removed per queue.
+pub async fn purge_deleted(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    params: Result<Query<PurgeDeletedParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let older_than = match params.older_than.as_deref().map(utils::parse_duration) {
+        None => chrono::Duration::zero(),
+        Some(Some(older_than)) => older_than,
+        Some(None) => {
+            return invalid_parameter("older_than: expected a duration such as 90s, 5m, 2h or 1d");
+        }
+    };
+
+    let mut purged = BTreeMap::new();
+    for queue in state.storage.queue_names() {
+        match state.storage.purge_deleted(&queue, older_than) {
+            Ok(count) => {
+                purged.insert(queue, count);
+            }
+            Err(e) => {
+                error!("Failed to purge deleted items of '{queue}': {e}");
+                return utils::json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    &format!("Failed to purge deleted items of queue {queue}: {e}"),
+                );
+            }
+        }
+    }
+    info!("purged deleted items: {purged:?}");
+    (StatusCode::OK, axum::Json(purged)).into_response()
+}
+
+/// Sends the item counts of every queue as a `metrics` server-sent event every
+/// `metrics_stream_interval_secs`. The timer lives in the stream, so it stops when the
+/// client disconnects and axum drops the response.
+pub async fn metrics_stream(State(state): State<AppState>) -> Response {
+    let period = Duration::from_secs(state.config.metrics_stream_interval_secs.max(1));
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    info!("metrics stream opened");
+
+    let frames =
+        futures_util::stream::unfold((state, interval), |(state, mut interval)| async move {
+            interval.tick().await;
+            let counting = state.clone();
+            // Counting queries the database, so keep it off the async workers
+            let snapshot = tokio::task::spawn_blocking(move || {
+                maintenance::collect_metrics(
+                    counting.storage.as_ref(),
+                    &counting.config,
+                    &counting.activity,
+                )
+            })
+            .await
+            .inspect_err(|e| error!("Metrics collection failed: {e}"))
+            .ok()?;
+            let event = Event::default()
+                .event("metrics")
+                .json_data(&snapshot)
+                .ok()?;
+            Some((Ok::<_, std::convert::Infallible>(event), (state, interval)))
+        });
+    Sse::new(frames)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Any path not matched by a route is treated as a queue name, which cannot be valid
+/// since queue names never contain '/'.
+async fn invalid_queue(uri: Uri) -> Response {
+    let queue = uri.path().trim_start_matches('/');
+    warn!("Invalid queue name attempted: {queue}");
+    utils::json_error(
+        StatusCode::FORBIDDEN,
+        ErrorCode::InvalidQueueName,
+        &format!("Invalid queue name attempted: {queue}"),
+    )
+}
+
+/// Rejects a mutating request while the server runs with `read_only` or is paused.
+fn check_writable(state: &AppState) -> Option<Response> {
+    if state.paused.load(Ordering::Relaxed) {
+        return Some(paused_error());
+    }
+    if !state.config.read_only {
+        return None;
+    }
+    warn!("Rejected a write while the server is read-only");
+    Some(utils::json_error(
+        StatusCode::FORBIDDEN,
+        ErrorCode::ReadOnly,
+        "The server is running in read-only mode",
+    ))
+}
+
+/// Reserves a queue creation under `queue_create_rate_per_min`, refusing it once that
+/// many queues were created during the last minute with the seconds until a slot frees
+/// up. A creation that does not happen hands its reservation back with
+/// `release_creation`.
+fn reserve_creation(state: &AppState) -> Result<Option<Instant>, u64> {
+    let Some(limit) = state.config.queue_create_rate_per_min else {
+        return Ok(None);
+    };
+    let now = Instant::now();
+    let mut creations = state.queue_creations.lock().unwrap();
+    while creations
+        .front()
+        .is_some_and(|created| now.duration_since(*created) >= Duration::from_secs(60))
+    {
+        creations.pop_front();
+    }
+    if creations.len() < limit as usize {
+        creations.push_back(now);
+        return Ok(Some(now));
+    }
+    // Retry once the oldest creation leaves the window
+    Err(creations.front().map_or(60, |oldest| {
+        60 - now.duration_since(*oldest).as_secs().min(59)
+    }))
+}
+
+/// The 429 refusing to create `queue` under `queue_create_rate_per_min`.
+fn create_rate_limited(state: &AppState, queue: &str, retry_after: u64) -> Response {
+    let limit = state.config.queue_create_rate_per_min.unwrap_or_default();
+    warn!("Refused to create queue {queue}: more than {limit} queues created per minute");
+    let mut response = utils::json_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::QueueCreateRateLimited,
+        &format!(
+            "Failed to create queue {queue}: at most {limit} queues may be created per minute"
+        ),
+    );
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after.into());
+    response
+}
+
+/// Frees a slot taken by `reserve_creation` for a queue that was not created after all.
+fn release_creation(state: &AppState, reserved: Instant) {
+    let mut creations = state.queue_creations.lock().unwrap();
+    if let Some(index) = creations.iter().position(|created| *created == reserved) {
+        creations.remove(index);
+    }
+}
+
+/// Whether a write to `queue` would create it under `auto_create_queues`: a valid name
+/// that is not registered yet.
+fn auto_creates(state: &AppState, queue: &str) -> bool {
+    state.config.auto_create_queues
+        && !state.storage.queue_exists(queue)
+        && utils::sanitize_queue_name(queue).is_some()
+}
+
+/// Like `check_queue` for writes, except that with `auto_create_queues` a valid queue
+/// name that is not registered yet passes. `create_write_queue` creates it once the
+/// write itself was checked, so a rejected write creates nothing.
+fn check_write_queue(state: &AppState, queue: &str) -> Option<Response> {
+    if let Some(response) = check_writable(state) {
+        return Some(response);
+    }
+    if auto_creates(state, queue) {
+        return None;
+    }
+    check_queue(state, queue)
+}
+
+/// Creates the queue of a checked write that `check_write_queue` let through
+/// unregistered, right before the write is stored.
+fn create_write_queue(state: &AppState, queue: &str) -> Option<Response> {
+    if !auto_creates(state, queue) {
+        return None;
+    }
+    let reserved = match reserve_creation(state) {
+        Ok(reserved) => reserved,
+        Err(retry_after) => return Some(create_rate_limited(state, queue, retry_after)),
+    };
+    let created = state.storage.create_queue(queue);
+    // Only queues this request created count against the rate, not ones that failed or
+    // that a concurrent write created first
+    if !matches!(created, Ok(true))
+        && let Some(reserved) = reserved
+    {
+        release_creation(state, reserved);
+    }
+    match created {
+        Ok(_) => info!("created queue {queue} on first write"),
+        Err(e) => return Some(create_queue_error(queue, e)),
+    }
+    None
+}
+
+#[derive(Deserialize)]
+pub struct PutParams {
+    /// Only replace the item under the key if its current message equals this.
+    pub if_message: Option<String>,
+}
 
 pub async fn put_item(
-    State(storage): State<Arc<dyn Storage>>,
-    Path(queue): Path<String>,
-    body: String,
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<PutParams>, QueryRejection>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Response {
-    if !storage.queue_exists(&queue) {
-        warn!("Invalid queue name attempted: {queue}");
+    if let Some(response) = check_write_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    // parse item from the body
+    let item = match parse_item(&headers, &body) {
+        Ok(body) => body,
+        Err(message) => {
+            warn!("Failed to parse request body: {message}");
+            return utils::json_error(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, &message);
+        }
+    };
+    if state.config.require_message && item.message.is_empty() {
+        warn!("Rejected an item without a message for queue {queue}");
         return utils::json_error(
-            StatusCode::FORBIDDEN,
-            "InvalidQueueName",
-            &format!("Invalid queue name attempted: {queue}"),
+            StatusCode::BAD_REQUEST,
+            ErrorCode::MissingMessage,
+            "The item must have a non-empty message",
+        );
+    }
+    let options = state.config.queue_options(&queue);
+    if let Err((code, message)) = check_item_fields(&options, &item) {
+        warn!("Rejected an item for queue {queue}: {message}");
+        return utils::json_error(StatusCode::BAD_REQUEST, code, message);
+    }
+
+    let fence = match fence_token(&headers) {
+        Ok(fence) => fence,
+        Err(message) => {
+            warn!("Rejected a put to queue {queue}: {message}");
+            return utils::json_error(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, message);
+        }
+    };
+    if params.if_message.is_some() && fence.is_some() {
+        return utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            "if_message cannot be combined with X-Fence-Token",
+        );
+    }
+
+    // Puts without a producer id are not ordered
+    let producer = headers
+        .get("X-Producer-Id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| options.monotonic_producers)
+        .map(str::to_string);
+    if let Some(producer) = &producer
+        && let Some(previous) = state.producers.last(&queue, producer)
+        && item.datetime <= previous
+    {
+        warn!("Refused an out-of-order put from producer {producer} to queue {queue}");
+        return utils::json_error(
+            StatusCode::CONFLICT,
+            ErrorCode::OutOfOrder,
+            &format!(
+                "The datetime {} is not after {previous}, the previous one from producer {producer}",
+                item.datetime
+            ),
         );
     }
 
-    // parse item from the body
-    let item = match QueueItem::from_json_string(&body) {
-        Ok(body) => body,
-        Err(e) => {
-            warn!("Failed to parse request body: {e}");
-            return utils::json_error(
-                StatusCode::BAD_REQUEST,
-                "BadRequest",
-                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
-            );
-        }
-    };
+    if let Some(response) = create_write_queue(&state, &queue) {
+        return response;
+    }
+    let datetime = item.datetime;
+    let response = store_put(&state, &queue, &params, item, fence).await;
+    if let Some(producer) = producer
+        && response.status().is_success()
+    {
+        state.producers.record(&queue, &producer, datetime);
+    }
+    response
+}
+
+/// Stores the item of a checked `PUT /{queue}`, or replaces the stored one with
+/// `if_message`.
+async fn store_put(
+    state: &AppState,
+    queue: &str,
+    params: &PutParams,
+    item: QueueItem,
+    fence: Option<i64>,
+) -> Response {
+    if let Some(expected) = &params.if_message {
+        return replace_if_message(state, queue, item, expected);
+    }
+
+    // A fenced put is never buffered, since it may be refused
+    if let Some(write_buffer) = state.write_buffer.as_ref().filter(|_| fence.is_none()) {
+        let key = item.key();
+        if write_buffer.send((queue.to_string(), item)).await.is_err() {
+            error!("The write buffer is closed, dropping an item for '{queue}'");
+            return utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to buffer item for queue {queue}"),
+            );
+        }
+        info!("buffered an item for queue {queue}, the key is {key:?}");
+        // The key is the receipt a producer can later look up with /exists
+        return (StatusCode::ACCEPTED, axum::Json(key)).into_response();
+    }
+
+    let stored = match fence {
+        Some(token) => state.storage.put_item_fenced(queue, item.clone(), token),
+        None => state.storage.put_item(queue, item.clone()),
+    };
+    match stored {
+        Ok(created) => {
+            info!("append to queue {queue} successful, the item is {item:?}");
+            notify_enqueued(state, queue, std::slice::from_ref(&item));
+            if created && state.config.rest_semantics {
+                StatusCode::CREATED.into_response()
+            } else {
+                StatusCode::OK.into_response()
+            }
+        }
+        Err(e @ StorageError::StaleFenceToken { .. }) => {
+            warn!("Refused a fenced put to queue {queue}: {e}");
+            utils::json_error(
+                StatusCode::CONFLICT,
+                ErrorCode::StaleFenceToken,
+                &e.to_string(),
+            )
+        }
+        Err(e) => {
+            error!("Failed to append {item:?} to '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to append item to queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Reads the optional `X-Fence-Token` header of a PUT, an integer.
+fn fence_token(headers: &HeaderMap) -> Result<Option<i64>, &'static str> {
+    let Some(value) = headers.get("X-Fence-Token") else {
+        return Ok(None);
+    };
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok());
+    token.map(Some).ok_or("X-Fence-Token must be an integer")
+}
+
+/// The conditional `PUT /{queue}?if_message=`, never buffered since its outcome depends
+/// on the stored message.
+fn replace_if_message(state: &AppState, queue: &str, item: QueueItem, expected: &str) -> Response {
+    match state
+        .storage
+        .replace_if_message(queue, item.clone(), expected)
+    {
+        Ok(true) => {
+            info!("conditional replace in queue {queue} successful, the item is {item:?}");
+            notify_enqueued(state, queue, std::slice::from_ref(&item));
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => {
+            info!(
+                "conditional replace in queue {queue} refused, the message under {:?} is not {expected:?}",
+                item.key()
+            );
+            utils::json_error(
+                StatusCode::PRECONDITION_FAILED,
+                ErrorCode::MessageMismatch,
+                &format!("No item under the key in queue {queue} holds the expected message"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to conditionally replace {item:?} in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to replace item in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Items accepted by `async_write_ack` PUTs that may wait for the writer before
+/// further PUTs wait for room.
+pub const WRITE_BUFFER_CAPACITY: usize = 10_000;
+
+/// Stores the items accepted by `async_write_ack` PUTs. Whatever piled up while the
+/// previous batch was committed is stored next, one transaction per queue of at most
+/// `STREAM_BATCH_SIZE` items. Enqueue webhooks fire once the items are stored.
+pub async fn run_write_buffer(state: AppState, mut receiver: mpsc::Receiver<(String, QueueItem)>) {
+    // Holding no sender itself, the buffer drains and ends once the router is dropped
+    let state = AppState {
+        write_buffer: None,
+        ..state
+    };
+    let mut pending = Vec::with_capacity(STREAM_BATCH_SIZE);
+    while receiver.recv_many(&mut pending, STREAM_BATCH_SIZE).await > 0 {
+        let mut batches: BTreeMap<String, Vec<QueueItem>> = BTreeMap::new();
+        for (queue, item) in pending.drain(..) {
+            batches.entry(queue).or_default().push(item);
+        }
+        let state = state.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            for (queue, items) in batches {
+                match state.storage.put_items(&queue, items.clone()) {
+                    Ok(()) => {
+                        info!("stored {} buffered items in queue {queue}", items.len());
+                        notify_enqueued(&state, &queue, &items);
+                    }
+                    // The items were already acknowledged, so they can only be logged
+                    Err(e) => error!(
+                        "Failed to store {} buffered items in '{queue}': {e}",
+                        items.len()
+                    ),
+                }
+            }
+        });
+        if let Err(e) = written.await {
+            error!("Buffered write failed: {e}");
+        }
+    }
+}
+
+/// Checks `item` against the queue's `datetime_secondary` and `key_by` settings, and
+/// that a recurring item moves on when it recurs.
+fn check_item_fields(
+    options: &QueueOptions,
+    item: &QueueItem,
+) -> Result<(), (ErrorCode, &'static str)> {
+    options
+        .datetime_secondary
+        .check(item.datetime_secondary.is_some())
+        .map_err(|message| (ErrorCode::SecondaryPolicyViolation, message))?;
+    if options.key_by == KeyBy::Id && item.id.is_none() {
+        return Err((
+            ErrorCode::MissingId,
+            "The queue is keyed by id, so the item must have an id",
+        ));
+    }
+    // Keys outside this range could not be addressed by the query parameters, and
+    // partitioned queues name tables after a four-digit year
+    let in_range = |datetime: &DateTime<Utc>| {
+        (MIN_TIMESTAMP_MILLIS..=MAX_TIMESTAMP_MILLIS).contains(&datetime.timestamp_millis())
+    };
+    if ![Some(item.datetime), item.datetime_secondary]
+        .iter()
+        .flatten()
+        .all(in_range)
+    {
+        return Err((
+            ErrorCode::BadRequest,
+            "Datetimes must lie in the years 0000 through 9999",
+        ));
+    }
+    if item.recurrence_interval_secs == Some(0) {
+        return Err((
+            ErrorCode::BadRequest,
+            "recurrence_interval_secs must be at least 1",
+        ));
+    }
+    let sub_millisecond =
+        |datetime: &DateTime<Utc>| !datetime.timestamp_subsec_nanos().is_multiple_of(1_000_000);
+    if options.strict_datetime_precision
+        && [
+            Some(item.datetime),
+            item.datetime_secondary,
+            item.recurrence_until,
+        ]
+        .iter()
+        .flatten()
+        .any(sub_millisecond)
+    {
+        return Err((
+            ErrorCode::BadRequest,
+            "Datetimes are stored in milliseconds, so they must not be more precise",
+        ));
+    }
+    Ok(())
+}
+
+/// Fires the enqueue and depth webhooks of `queue` after `items` were stored.
+fn notify_enqueued(state: &AppState, queue: &str, items: &[QueueItem]) {
+    for item in items {
+        state.webhooks.enqueued(queue, item);
+    }
+    if state.webhooks.wants_depth(queue) {
+        match state.storage.count_items(queue) {
+            Ok(depth) => state.webhooks.depth_changed(queue, depth),
+            Err(e) => warn!("Failed to count items of '{queue}' for webhooks: {e}"),
+        }
+    }
+}
+
+/// Number of streamed items stored per transaction.
+const STREAM_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize)]
+pub struct StreamError {
+    pub line: usize,
+    pub error: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct StreamSummary {
+    pub inserted: usize,
+    pub failed: usize,
+    pub errors: Vec<StreamError>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub aborted: bool,
+}
+
+/// Stores newline-delimited JSON items as they arrive, committing them in batches.
+/// Malformed lines are reported by line number and skipped, or end the request
+/// with `stream_abort_on_error`; batches stored before that are kept.
+pub async fn put_stream(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    body: Body,
+) -> Response {
+    if let Some(response) = check_write_queue(&state, &queue) {
+        return response;
+    }
+    let limit = state.config.max_subscribers_per_queue;
+    let Some(_subscription) = state.subscribers.subscribe(&queue, limit) else {
+        return too_many_subscribers(&queue);
+    };
+
+    let options = state.config.queue_options(&queue);
+    let mut summary = StreamSummary::default();
+    let mut batch = Vec::with_capacity(STREAM_BATCH_SIZE);
+    let mut pending: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    let mut chunks = body.into_data_stream();
+    let mut finished = false;
+
+    while !finished {
+        match chunks.next().await {
+            Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                warn!("Stream to queue {queue} interrupted: {e}");
+                summary.aborted = true;
+                break;
+            }
+            // A final line without a trailing newline is still an item
+            None => {
+                finished = true;
+                if !pending.is_empty() {
+                    pending.push(b'\n');
+                }
+            }
+        }
+
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            line_number += 1;
+            let parsed = std::str::from_utf8(&line)
+                .map_err(|e| e.to_string())
+                .and_then(|line| match line.trim() {
+                    "" => Ok(None),
+                    line => match QueueItem::from_json_string(line) {
+                        Ok(item) if state.config.require_message && item.message.is_empty() => {
+                            Err("The item must have a non-empty message".to_string())
+                        }
+                        Ok(item) => check_item_fields(&options, &item)
+                            .map(|()| Some(item))
+                            .map_err(|(_, message)| message.to_string()),
+                        Err(e) => Err(e.to_string()),
+                    },
+                });
+            match parsed {
+                Ok(Some(item)) => batch.push(item),
+                Ok(None) => {}
+                Err(error) => {
+                    warn!("Failed to parse line {line_number} streamed to queue {queue}: {error}");
+                    summary.failed += 1;
+                    summary.errors.push(StreamError {
+                        line: line_number,
+                        error,
+                    });
+                    if state.config.stream_abort_on_error {
+                        summary.aborted = true;
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+
+            if batch.len() >= STREAM_BATCH_SIZE
+                && let Some(response) = flush_stream_batch(&state, &queue, &mut batch, &mut summary)
+            {
+                return response;
+            }
+        }
+    }
+    if let Some(response) = flush_stream_batch(&state, &queue, &mut batch, &mut summary) {
+        return response;
+    }
+
+    info!(
+        "stream to queue {queue} finished, inserted {} items, {} lines failed",
+        summary.inserted, summary.failed
+    );
+    let status = if summary.aborted {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    (status, axum::Json(summary)).into_response()
+}
+
+/// Stores a batch of streamed items, returning the error response if that fails.
+fn flush_stream_batch(
+    state: &AppState,
+    queue: &str,
+    batch: &mut Vec<QueueItem>,
+    summary: &mut StreamSummary,
+) -> Option<Response> {
+    if batch.is_empty() {
+        return None;
+    }
+    if let Some(response) = create_write_queue(state, queue) {
+        return Some(response);
+    }
+    let items = std::mem::take(batch);
+    let count = items.len();
+    match state.storage.put_items(queue, items.clone()) {
+        Ok(()) => {
+            summary.inserted += count;
+            notify_enqueued(state, queue, &items);
+            None
+        }
+        Err(e) => {
+            error!("Failed to append {count} streamed items to '{queue}': {e}");
+            Some(utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!(
+                    "Failed to append items to queue {queue} after {} were stored: {e}",
+                    summary.inserted
+                ),
+            ))
+        }
+    }
+}
+
+/// Query parameters of the single-item GET and DELETE.
+#[derive(Deserialize)]
+pub struct ReadParams {
+    /// Return only the message as the body, with the queue's content type, and the key
+    /// in `X-Item-Datetime` / `X-Item-Datetime-Secondary` headers.
+    #[serde(default)]
+    pub raw: bool,
+    /// Indent the JSON item for reading by humans.
+    #[serde(default)]
+    pub pretty: bool,
+    /// Serve the named entry of the item's `payloads` in place of its message. Items
+    /// without that payload are served unchanged.
+    pub payload: Option<String>,
+}
+
+/// Which head GET and DELETE on a queue select.
+#[derive(Deserialize)]
+pub struct HeadParams {
+    /// Only consider items tagged with this tenant.
+    pub tenant: Option<String>,
+    /// `desc` selects the newest visible item instead of the earliest, for this request
+    /// only; needs `allow_order_override`.
+    #[serde(default)]
+    pub order: HeadOrder,
+    /// Serve only an item whose datetime has come, as on a `due_only` queue, for this
+    /// request only. It cannot turn the queue's `due_only` off.
+    #[serde(default)]
+    pub due_only: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Checks that the head selection of a GET or DELETE is allowed, returning the problem
+/// with the query otherwise.
+fn check_head_params(config: &AppConfig, queue: &str, head: &HeadParams) -> Option<&'static str> {
+    if serves_due_only(config, queue, head) && head.order == HeadOrder::Desc {
+        return Some("order: desc cannot be used with due_only");
+    }
+    if head.order == HeadOrder::Asc {
+        return None;
+    }
+    if !config.allow_order_override {
+        return Some("order: desc needs allow_order_override to be enabled");
+    }
+    head.tenant
+        .is_some()
+        .then_some("order: desc cannot be combined with tenant")
+}
+
+/// Whether a GET or DELETE serves only a due item, by the queue's setting or the query.
+fn serves_due_only(config: &AppConfig, queue: &str, head: &HeadParams) -> bool {
+    head.due_only || config.queue_options(queue).due_only
+}
+
+/// Returns the head a due-only GET or DELETE that found nothing due waits for: the
+/// tenant's if the request names one, the queue's otherwise.
+fn waiting_head(state: &AppState, queue: &str, head: &HeadParams) -> Option<QueueItem> {
+    match &head.tenant {
+        Some(tenant) => state.storage.get_tenant_item(queue, tenant, None),
+        None => state.storage.get_item(queue),
+    }
+    .ok()
+    .flatten()
+}
+
+/// Answers a due-only GET or DELETE whose head is not due yet with 204, and a
+/// `Retry-After` of the seconds until it is, rounded up.
+fn not_due_yet(queue: &str, head: &QueueItem) -> Response {
+    info!(
+        "no item of queue {queue} is due, the head is due at {}",
+        head.datetime
+    );
+    let wait_ms = (head.datetime - Utc::now()).num_milliseconds().max(0);
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        (wait_ms as u64).div_ceil(1000).max(1).into(),
+    );
+    response
+}
+
+/// Builds the response for malformed query parameters.
+fn query_error(e: QueryRejection) -> Response {
+    // Names the offending parameter, e.g. "limit: number too large to fit in target type"
+    let message = e.body_text();
+    invalid_parameter(
+        message
+            .strip_prefix("Failed to deserialize query string: ")
+            .unwrap_or(&message),
+    )
+}
+
+/// Media type of the protobuf encoding of items, see `proto/queue_item.proto`.
+const PROTOBUF: &str = "application/x-protobuf";
+
+/// Whether a header such as `Accept` or `Content-Type` names the protobuf encoding.
+fn names_protobuf(headers: &HeaderMap, name: header::HeaderName) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|part| part.trim().starts_with(PROTOBUF))
+        })
+}
+
+/// Parses a PUT body as JSON or, with `Content-Type: application/x-protobuf`, protobuf.
+/// The error is the message for the client.
+fn parse_item(headers: &HeaderMap, body: &Bytes) -> Result<QueueItem, String> {
+    if names_protobuf(headers, header::CONTENT_TYPE) {
+        return QueueItem::from_protobuf(body)
+            .map_err(|e| format!("Failed to parse request body due to: {e}"));
+    }
+    let body = String::from_utf8_lossy(body);
+    QueueItem::from_json_string(&body)
+        .map_err(|e| format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"))
+}
+
+/// Builds the 200 response carrying a retrieved item, either as the JSON item or,
+/// in raw mode, as the bare message labelled with the queue's content type.
+fn item_response(
+    state: &AppState,
+    queue: &str,
+    item: &QueueItem,
+    params: &ReadParams,
+    headers: &HeaderMap,
+) -> Response {
+    let mut content_type = state.config.queue_options(queue).content_type;
+    // Derived from the stored message, whichever payload is served
+    let content_id = state.config.content_ids.then(|| item.content_id());
+    let selected = params
+        .payload
+        .as_ref()
+        .and_then(|name| Some((name, item.payloads.as_ref()?.get(name)?)));
+    let selected_item;
+    let item = match selected {
+        Some((_, payload)) => {
+            // The queue's content type describes the message, not its alternatives
+            content_type = None;
+            selected_item = QueueItem {
+                message: payload.clone(),
+                payloads: None,
+                ..item.clone()
+            };
+            &selected_item
+        }
+        None => item,
+    };
+    let payload_name = selected.map(|(name, _)| name.as_str());
+
+    if params.raw {
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(
+                "Content-Type",
+                content_type
+                    .as_deref()
+                    .unwrap_or("text/plain; charset=utf-8"),
+            )
+            .header("X-Item-Datetime", item.datetime.to_rfc3339());
+        if let Some(secondary) = item.datetime_secondary {
+            response = response.header("X-Item-Datetime-Secondary", secondary.to_rfc3339());
+        }
+        if let Some(name) = payload_name {
+            response = response.header("X-Payload", name);
+        }
+        if let Some(content_id) = &content_id {
+            response = response.header("X-Item-Id", content_id);
+        }
+        return response.body(item.message.clone().into()).unwrap();
+    }
+
+    let (body, media_type) = if names_protobuf(headers, header::ACCEPT) {
+        (item.to_protobuf(), PROTOBUF)
+    } else {
+        let body = match &content_id {
+            Some(content_id) => item_json(&IdentifiedItem { item, content_id }, params.pretty),
+            None => item_json(item, params.pretty),
+        };
+        (body, "application/json")
+    };
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", media_type);
+    if let Some(content_type) = content_type {
+        response = response.header("X-Message-Content-Type", content_type);
+    }
+    if let Some(name) = payload_name {
+        response = response.header("X-Payload", name);
+    }
+    if let Some(content_id) = &content_id {
+        response = response.header("X-Item-Id", content_id);
+    }
+    response.body(body.into()).unwrap()
+}
+
+/// Adds the queue's depth after the request as `X-Queue-Remaining`, under
+/// `queue_remaining_header`. Counted separately, so concurrent requests may already have
+/// changed it.
+fn with_remaining(state: &AppState, queue: &str, mut response: Response) -> Response {
+    if state.config.queue_remaining_header {
+        match state.storage.count_items(queue) {
+            Ok(depth) => {
+                response
+                    .headers_mut()
+                    .insert("X-Queue-Remaining", HeaderValue::from(depth));
+            }
+            Err(e) => warn!("Failed to count the items left in '{queue}': {e}"),
+        }
+    }
+    response
+}
+
+/// An item served together with its `QueueItem::content_id`, under `content_ids`.
+#[derive(Serialize)]
+struct IdentifiedItem<'a> {
+    #[serde(flatten)]
+    item: &'a QueueItem,
+    content_id: &'a str,
+}
+
+/// Serializes an item response body, indented if `pretty` is set.
+fn item_json<T: Serialize>(value: &T, pretty: bool) -> Vec<u8> {
+    let json = if pretty {
+        serde_json::to_vec_pretty(value)
+    } else {
+        serde_json::to_vec(value)
+    };
+    json.expect("items serialize")
+}
+
+pub async fn get_item(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    headers: HeaderMap,
+    params: Result<Query<ReadParams>, QueryRejection>,
+    head: Result<Query<HeadParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let (params, head) = match (params, head) {
+        (Ok(Query(params)), Ok(Query(head))) => (params, head),
+        (Err(e), _) | (_, Err(e)) => return query_error(e),
+    };
+    if let Some(message) = check_head_params(&state.config, &queue, &head) {
+        return invalid_parameter(message);
+    }
+
+    let due_by = serves_due_only(&state.config, &queue, &head).then(Utc::now);
+    let item = if let Some(window_ms) = state.config.get_coalesce_window_ms
+        && head.tenant.is_none()
+        && head.order == HeadOrder::Asc
+    {
+        let window = Duration::from_millis(window_ms);
+        state.head_reads.get(&state.storage, &queue, window).await
+    } else {
+        match (&head.tenant, head.order) {
+            (Some(tenant), _) => state.storage.get_tenant_item(&queue, tenant, due_by),
+            (None, HeadOrder::Desc) => state.storage.get_newest_item(&queue),
+            (None, HeadOrder::Asc) => state.storage.get_item(&queue),
+        }
+        .map_err(|e| e.to_string())
+    };
+    match item {
+        // Items are ordered by datetime, so once the head is not due no later item is either
+        Ok(Some(item))
+            if due_by
+                .is_some_and(|now| item.datetime.timestamp_millis() > now.timestamp_millis()) =>
+        {
+            not_due_yet(&queue, &item)
+        }
+        Ok(Some(item)) => {
+            info!("retrieve from queue {queue}, got {item:?}");
+            let response = item_response(&state, &queue, &item, &params, &headers);
+            with_remaining(&state, &queue, response)
+        }
+        Ok(None) => {
+            // Nothing of the tenant was due, but a future item may wait at its head
+            if due_by.is_some()
+                && let Some(head) = waiting_head(&state, &queue, &head)
+            {
+                return not_due_yet(&queue, &head);
+            }
+            info!("retrieve from queue {queue}, the queue is empty");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to get item from '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to get item from queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Returns the item with the latest key, the far end of the queue, without removing it.
+pub async fn tail_item(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    headers: HeaderMap,
+    params: Result<Query<ReadParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    match state.storage.tail_item(&queue) {
+        Ok(Some(item)) => {
+            info!("tail of queue {queue} is {item:?}");
+            item_response(&state, &queue, &item, &params, &headers)
+        }
+        Ok(None) => {
+            info!("tail of queue {queue}, the queue is empty");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to get the tail of '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to get the tail of queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    /// Number of most recent samples to return; all that are kept by default.
+    pub points: Option<usize>,
+}
+
+/// Returns the recent depth samples of a queue, oldest first, as `[{t, depth}]`.
+/// Empty unless `depth_history_interval_secs` is configured.
+pub async fn depth_history(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<HistoryParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    let samples = state
+        .depth_history
+        .recent(&queue, params.points.unwrap_or(usize::MAX));
+    info!(
+        "depth history of queue {queue}, got {} points",
+        samples.len()
+    );
+    (StatusCode::OK, axum::Json(samples)).into_response()
+}
+
+/// Projects when a queue empties from its recent depth samples, as
+/// `{rate_per_sec, depth, eta_secs, growing, samples}`.
+pub async fn drain_eta(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<HistoryParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    let samples = state
+        .depth_history
+        .recent(&queue, params.points.unwrap_or(usize::MAX));
+    let estimate = maintenance::estimate_drain(&samples);
+    info!("drain eta of queue {queue}, got {estimate:?}");
+    (StatusCode::OK, axum::Json(estimate)).into_response()
+}
+
+/// Returns how many pending and how many deleted items a queue stores, as
+/// `{"valid": n, "invalid": m}`. Deleted items wait for compaction on SQLite.
+pub async fn count_by_validity(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    match state.storage.count_by_validity(&queue) {
+        Ok(counts) => {
+            info!("counts of queue {queue}, got {counts:?}");
+            (StatusCode::OK, axum::Json(counts)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to count items of '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to count items of queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// The order a queue hands out items in, so clients need not assume it.
+#[derive(Debug, Serialize)]
+pub struct QueueOrdering {
+    /// Items are retrieved by ascending key; no queue retrieves in descending order.
+    pub direction: &'static str,
+    /// Where items without a `datetime_secondary` go among items with the same
+    /// `datetime`: before those with one.
+    pub secondary_nulls: &'static str,
+    /// What identifies an item for replacement, the queue's `key_by`.
+    pub key_by: KeyBy,
+}
+
+/// The `GET /{queue}/stats` response.
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    #[serde(flatten)]
+    pub counts: ValidityCounts,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Whether the queue went `stale_after_secs` without a write; only for queues with
+    /// that option.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<bool>,
+    pub ordering: QueueOrdering,
+}
+
+/// Returns a queue's item counts and last modification time, together with the
+/// ordering it applies.
+pub async fn queue_stats(State(state): State<AppState>, QueueName(queue): QueueName) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let stats = state.storage.count_by_validity(&queue).and_then(|counts| {
+        let stale = maintenance::staleness(
+            state.storage.as_ref(),
+            &state.config,
+            &state.activity,
+            &queue,
+        )?;
+        Ok(QueueStats {
+            counts,
+            last_modified: state.storage.last_modified(&queue)?,
+            stale: stale.map(|(stale, _)| stale),
+            ordering: QueueOrdering {
+                direction: "asc",
+                secondary_nulls: "first",
+                key_by: state.config.queue_options(&queue).key_by,
+            },
+        })
+    });
+    match stats {
+        Ok(stats) => {
+            info!("stats of queue {queue}, got {stats:?}");
+            (StatusCode::OK, axum::Json(stats)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to read the stats of '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to read the stats of queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Reports a queue's depth and last modification time in headers, without a body.
+pub async fn head_queue(State(state): State<AppState>, QueueName(queue): QueueName) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        // HEAD responses carry no body
+        return response.status().into_response();
+    }
+
+    let info = state.storage.count_items(&queue).and_then(|depth| {
+        let last_modified = state.storage.last_modified(&queue)?;
+        Ok((depth, last_modified))
+    });
+    match info {
+        Ok((depth, last_modified)) => {
+            info!("head of queue {queue}, depth is {depth}");
+            let mut response = Response::builder()
+                .status(StatusCode::OK)
+                .header("X-Queue-Depth", depth.to_string());
+            if let Some(last_modified) = last_modified {
+                response = response.header(
+                    header::LAST_MODIFIED,
+                    last_modified
+                        .format("%a, %d %b %Y %H:%M:%S GMT")
+                        .to_string(),
+                );
+            }
+            response.body(Body::empty()).unwrap()
+        }
+        Err(e) => {
+            error!("Failed to inspect '{queue}': {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn delete_item(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    headers: HeaderMap,
+    params: Result<Query<ReadParams>, QueryRejection>,
+    head: Result<Query<HeadParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let (params, head) = match (params, head) {
+        (Ok(Query(params)), Ok(Query(head))) => (params, head),
+        (Err(e), _) | (_, Err(e)) => return query_error(e),
+    };
+    if let Some(message) = check_head_params(&state.config, &queue, &head) {
+        return invalid_parameter(message);
+    }
+
+    let due_by = serves_due_only(&state.config, &queue, &head).then(Utc::now);
+    let popped = match (&head.tenant, head.order) {
+        (None, HeadOrder::Asc) if let Some(now) = due_by => state
+            .storage
+            .claim_any(std::slice::from_ref(&queue), now, true, 0)
+            .map(|claimed| claimed.map(|(_, item)| item)),
+        (Some(tenant), _) => state.storage.delete_tenant_item(&queue, tenant, due_by),
+        (None, HeadOrder::Desc) => state.storage.delete_newest_item(&queue),
+        (None, HeadOrder::Asc) => state.storage.delete_item(&queue),
+    };
+    match popped {
+        Ok(Some(item)) => {
+            info!("pop from queue {queue}, got {item:?}");
+            let response = item_response(&state, &queue, &item, &params, &headers);
+            with_remaining(&state, &queue, response)
+        }
+        Ok(None) => {
+            // Nothing was due, but a future item may wait at the head
+            if due_by.is_some()
+                && let Some(head) = waiting_head(&state, &queue, &head)
+            {
+                return not_due_yet(&queue, &head);
+            }
+            info!("pop from queue {queue}, the queue is empty");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to delete item from '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to delete item from queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Pops the head like DELETE, but only if its key matches the key in the body,
+/// answering 409 if the head changed since the client read it.
+pub async fn compare_and_delete(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    headers: HeaderMap,
+    params: Result<Query<ReadParams>, QueryRejection>,
+    body: String,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let expected: QueueKey = match serde_json::from_str(&body) {
+        Ok(expected) => expected,
+        Err(e) => {
+            warn!("Failed to parse cas-delete request body: {e}");
+            return utils::json_error(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::BadRequest,
+                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
+            );
+        }
+    };
+
+    match state.storage.compare_and_delete(&queue, &expected) {
+        Ok(CasResult::Deleted(item)) => {
+            info!("compare-and-delete from queue {queue}, got {item:?}");
+            item_response(&state, &queue, &item, &params, &headers)
+        }
+        Ok(CasResult::Mismatch(head)) => {
+            info!("compare-and-delete from queue {queue} expected {expected:?}, head is {head:?}");
+            let message = match head {
+                Some(head) => format!(
+                    "The head of queue {queue} is now {}, not the expected key",
+                    head.datetime.to_rfc3339()
+                ),
+                None => format!("Queue {queue} is empty"),
+            };
+            utils::json_error(StatusCode::CONFLICT, ErrorCode::HeadChanged, &message)
+        }
+        Err(e) => {
+            error!("Failed to compare-and-delete from '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to delete item from queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Pops the head like DELETE, but only if its datetime has passed. Otherwise answers
+/// 204, with `X-Next-Due-Ms` holding the milliseconds until the head is due unless the
+/// queue is empty, so a worker can sleep exactly that long before retrying.
+pub async fn claim_if_due(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    headers: HeaderMap,
+    params: Result<Query<ReadParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    let now = Utc::now();
+    // A single-queue claim-any checks the head and deletes it in one atomic step
+    let claimed = state
+        .storage
+        .claim_any(std::slice::from_ref(&queue), now, true, 0)
+        .and_then(|claimed| match claimed {
+            Some((_, item)) => Ok(Ok(item)),
+            None => state.storage.get_item(&queue).map(Err),
+        });
+    match claimed {
+        Ok(Ok(item)) => {
+            info!("claim from queue {queue}, got {item:?}");
+            item_response(&state, &queue, &item, &params, &headers)
+        }
+        Ok(Err(Some(head))) => {
+            let wait = (head.datetime - now).num_milliseconds().max(0);
+            info!("claim from queue {queue}, the head is due in {wait} ms");
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header("X-Next-Due-Ms", wait.to_string())
+                .body(Body::empty())
+                .unwrap()
+        }
+        Ok(Err(None)) => {
+            info!("claim from queue {queue}, the queue is empty");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to claim from '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to claim an item from queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Largest number of queues a single claim-any considers.
+const MAX_CLAIM_QUEUES: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ClaimAnyParams {
+    /// Comma-separated queue names.
+    pub queues: String,
+}
+
+/// Collects the distinct queues of a comma-separated list into `queues`, or answers
+/// why the list is refused: a name is invalid, or there are none or too many.
+fn parse_queue_list(state: &AppState, list: &str, queues: &mut Vec<String>) -> Option<Response> {
+    for queue in list.split(',').map(str::trim) {
+        let queue = utils::normalize_queue_name(queue);
+        if queue.is_empty() || queues.contains(&queue) {
+            continue;
+        }
+        if let Some(response) = check_queue(state, &queue) {
+            return Some(response);
+        }
+        queues.push(queue);
+    }
+    if queues.is_empty() || queues.len() > MAX_CLAIM_QUEUES {
+        warn!("Claim across {} queues rejected", queues.len());
+        return Some(utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            &format!("Between 1 and {MAX_CLAIM_QUEUES} queues must be given"),
+        ));
+    }
+    None
+}
+
+#[derive(Serialize)]
+pub struct ClaimedItem {
+    pub queue: String,
+    #[serde(flatten)]
+    pub item: QueueItem,
+}
+
+/// Pops the earliest due head across several queues in one atomic step, sharing ties
+/// between the queues round-robin, so one consumer can drain them fairly.
+pub async fn claim_any(
+    State(state): State<AppState>,
+    params: Result<Query<ClaimAnyParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let mut queues = Vec::new();
+    if let Some(response) = parse_queue_list(&state, &params.queues, &mut queues) {
+        return response;
+    }
+
+    let start = state.claim_rotation.fetch_add(1, Ordering::Relaxed);
+    match state.storage.claim_any(&queues, Utc::now(), true, start) {
+        Ok(Some((queue, item))) => {
+            info!("claim across {queues:?}, got {item:?} from queue {queue}");
+            (StatusCode::OK, axum::Json(ClaimedItem { queue, item })).into_response()
+        }
+        Ok(None) => {
+            info!("claim across {queues:?}, no item is due");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to claim across {queues:?}: {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to claim an item: {e}"),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DispatchParams {
+    /// Comma-separated queue names; every registered queue if absent.
+    pub queues: Option<String>,
+    /// Only hand out items whose datetime has come, the default.
+    pub due_only: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct DispatchedItem {
+    pub queue: String,
+    pub item: QueueItem,
+}
+
+/// Pops the globally earliest head across every queue, or the listed ones, for a single
+/// dispatcher process. Like `claim_any` all heads are compared and the winner deleted
+/// in one transaction, so concurrent dispatchers never receive the same item.
+pub async fn dispatch(
+    State(state): State<AppState>,
+    params: Result<Query<DispatchParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let queues = match &params.queues {
+        Some(list) => {
+            let mut queues = Vec::new();
+            if let Some(response) = parse_queue_list(&state, list, &mut queues) {
+                return response;
+            }
+            queues
+        }
+        None => state.storage.queue_names(),
+    };
+    let due_only = params.due_only.unwrap_or(true);
+
+    let start = state.claim_rotation.fetch_add(1, Ordering::Relaxed);
+    match state
+        .storage
+        .claim_any(&queues, Utc::now(), due_only, start)
+    {
+        Ok(Some((queue, item))) => {
+            info!("dispatch, got {item:?} from queue {queue}");
+            (StatusCode::OK, axum::Json(DispatchedItem { queue, item })).into_response()
+        }
+        Ok(None) => {
+            info!(
+                "dispatch across {} queues, nothing to hand out",
+                queues.len()
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            error!("Failed to dispatch: {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to dispatch an item: {e}"),
+            )
+        }
+    }
+}
+
+/// One operation of a `POST /_tx` body.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TxRequest {
+    Put { queue: String, item: QueueItem },
+    Pop { queue: String },
+    Delete { queue: String, key: QueueKey },
+}
+
+/// Applies the operations in the body, possibly on several queues, atomically: if one
+/// fails, none is applied. Answers with the item each pop consumed, and null for the
+/// other operations.
+pub async fn transact(State(state): State<AppState>, body: String) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    let requests: Vec<TxRequest> = match serde_json::from_str(&body) {
+        Ok(requests) => requests,
+        Err(e) => {
+            warn!("Failed to parse transaction body: {e}");
+            return utils::json_error(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::BadRequest,
+                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
+            );
+        }
+    };
+
+    let mut ops = Vec::with_capacity(requests.len());
+    for (index, request) in requests.into_iter().enumerate() {
+        let op = match request {
+            TxRequest::Put { queue, item } => TxOp::Put {
+                queue: utils::normalize_queue_name(&queue),
+                item,
+            },
+            TxRequest::Pop { queue } => {
+                let queue = utils::normalize_queue_name(&queue);
+                TxOp::Pop {
+                    due_only: state.config.queue_options(&queue).due_only,
+                    queue,
+                }
+            }
+            TxRequest::Delete { queue, key } => TxOp::Delete {
+                queue: utils::normalize_queue_name(&queue),
+                key,
+            },
+        };
+        if let Some(response) = check_queue(&state, op.queue()) {
+            return response;
+        }
+        if let TxOp::Put { queue, item } = &op {
+            if state.config.require_message && item.message.is_empty() {
+                warn!("Rejected a transaction putting an item without a message");
+                return utils::json_error(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::MissingMessage,
+                    &format!("Operation {index}: the item must have a non-empty message"),
+                );
+            }
+            if let Err((code, message)) =
+                check_item_fields(&state.config.queue_options(queue), item)
+            {
+                warn!("Rejected a transaction putting an item to queue {queue}: {message}");
+                return utils::json_error(
+                    StatusCode::BAD_REQUEST,
+                    code,
+                    &format!("Operation {index}: {message}"),
+                );
+            }
+        }
+        ops.push(op);
+    }
+
+    match state.storage.transact(&ops) {
+        Ok(results) => {
+            info!("transaction of {} operations applied", ops.len());
+            for op in &ops {
+                if let TxOp::Put { queue, item } = op {
+                    notify_enqueued(&state, queue, std::slice::from_ref(item));
+                }
+            }
+            (StatusCode::OK, axum::Json(results)).into_response()
+        }
+        Err(e @ StorageError::TransactionAborted { .. }) => {
+            info!("transaction rolled back: {e}");
+            utils::json_error(
+                StatusCode::NOT_FOUND,
+                ErrorCode::ItemNotFound,
+                &format!("{e}; no operation was applied"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to apply a transaction: {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to apply the transaction, no operation was applied: {e}"),
+            )
+        }
+    }
+}
+
+/// Number of items returned by peek when no limit is given.
+const DEFAULT_PEEK_LIMIT: usize = 10;
+/// Largest number of items a single peek returns.
+const MAX_PEEK_LIMIT: usize = 1000;
+
+/// Writes the key of the last item of a page as `X-Next-Cursor`: its datetime and, if
+/// set, `,` and its secondary datetime, as milliseconds since the Unix epoch.
+fn encode_cursor(key: &QueueKey) -> String {
+    let at = key.datetime.timestamp_millis();
+    match key.datetime_secondary {
+        Some(secondary) => format!("{at},{}", secondary.timestamp_millis()),
+        None => at.to_string(),
+    }
+}
+
+/// Reads the `after` parameter back into the key to continue after.
+fn parse_cursor(cursor: &str) -> Result<QueueKey, String> {
+    let millis = |text: &str| {
+        text.parse()
+            .map_err(|_| format!("after: {cursor} is not a cursor"))
+    };
+    match cursor.split_once(',') {
+        Some((at, secondary)) => key_from_millis(millis(at)?, Some(millis(secondary)?)),
+        None => key_from_millis(millis(cursor)?, None),
+    }
+}
+
+/// Encodes leading `items` until the next would take the body past `max_bytes`, where
+/// each item adds `separator` bytes beside its encoding and the body `framing` bytes
+/// in all. The first item is always kept, so paging moves on even past a huge one.
+fn encode_within<E>(
+    items: &[QueueItem],
+    max_bytes: Option<usize>,
+    framing: usize,
+    separator: usize,
+    mut encode: impl FnMut(&QueueItem) -> Result<Vec<u8>, E>,
+) -> Result<Vec<Vec<u8>>, E> {
+    let mut encoded = Vec::new();
+    let mut size = framing;
+    for item in items {
+        let bytes = encode(item)?;
+        size += bytes.len() + separator;
+        if max_bytes.is_some_and(|max_bytes| size > max_bytes) && !encoded.is_empty() {
+            break;
+        }
+        encoded.push(bytes);
+    }
+    Ok(encoded)
+}
+
+/// Encodes an item as it appears inside a pretty-printed list, indented one level.
+fn pretty_list_entry(item: &QueueItem) -> Result<Vec<u8>, serde_json::Error> {
+    let mut entry = b"  ".to_vec();
+    for byte in serde_json::to_vec_pretty(item)? {
+        entry.push(byte);
+        if byte == b'\n' {
+            entry.extend(b"  ");
+        }
+    }
+    Ok(entry)
+}
+
+/// Adds `X-Next-Cursor` pointing after `last` to a page that does not reach the end.
+fn with_next_cursor(mut response: Response, more: bool, last: Option<&QueueItem>) -> Response {
+    if let Some(last) = last.filter(|_| more)
+        && let Ok(cursor) = HeaderValue::from_str(&encode_cursor(&last.key()))
+    {
+        response.headers_mut().insert("X-Next-Cursor", cursor);
+    }
+    response
+}
+
+#[derive(Deserialize)]
+pub struct PeekParams {
+    pub limit: Option<usize>,
+    /// An `X-Next-Cursor` of an earlier peek, to continue after its last item.
+    pub after: Option<String>,
+    /// Indent the JSON list for reading by humans.
+    #[serde(default)]
+    pub pretty: bool,
+}
+
+pub async fn peek_items(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<PeekParams>, QueryRejection>,
+) -> Response {
+    if !state.storage.queue_exists(&queue) && state.config.empty_list_for_unknown_queues {
+        info!("peek into unknown queue {queue}, returning an empty list");
+        return (StatusCode::OK, axum::Json(Vec::<QueueItem>::new())).into_response();
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let after = match params.after.as_deref().map(parse_cursor).transpose() {
+        Ok(after) => after,
+        Err(message) => return invalid_parameter(&message),
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PEEK_LIMIT)
+        .min(MAX_PEEK_LIMIT);
+
+    // One item beyond the limit tells whether the page reaches the end
+    match state.storage.peek_items(&queue, after.as_ref(), limit + 1) {
+        Ok(mut items) => {
+            let mut more = items.len() > limit;
+            items.truncate(limit);
+            // Sized as the list is written: compact, the brackets and a comma after all
+            // but the last; pretty, also a line break after each and one around the items
+            let max_bytes = state.config.max_response_bytes;
+            let encoded = if params.pretty {
+                encode_within(&items, max_bytes, 2, 2, pretty_list_entry)
+            } else {
+                encode_within(&items, max_bytes, 1, 1, serde_json::to_vec)
+            };
+            let fitting = match encoded {
+                Ok(encoded) => encoded.len(),
+                Err(e) => {
+                    error!("Failed to encode a peek into '{queue}': {e}");
+                    return utils::json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        ErrorCode::InternalError,
+                        &format!("Failed to peek into queue {queue}: {e}"),
+                    );
+                }
+            };
+            more |= fitting < items.len();
+            items.truncate(fitting);
+            info!("peek into queue {queue}, got {} items", items.len());
+            let response = utils::json_response(StatusCode::OK, &items, params.pretty);
+            with_next_cursor(response, more, items.last())
+        }
+        Err(e) => {
+            error!("Failed to peek into '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to peek into queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// The formats `GET /{queue}/export` can write.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON item per line, as `GET /{queue}` returns them.
+    #[default]
+    Ndjson,
+    /// A `datetime,datetime_secondary,message` table with a header row, for spreadsheets.
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// An `X-Next-Cursor` of an earlier export, to continue after its last item.
+    pub after: Option<String>,
+}
+
+/// Writes an item as one NDJSON line.
+fn item_ndjson(item: &QueueItem) -> Result<Vec<u8>, serde_json::Error> {
+    let mut line = serde_json::to_vec(item)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Items an export reads from storage at a time.
+const EXPORT_BATCH: usize = 500;
+
+/// The header row heading every CSV export.
+const CSV_HEADER: &[u8] = b"datetime,datetime_secondary,message\n";
+
+/// Writes an item as one CSV row. Datetimes are RFC 3339 as in JSON, and a missing
+/// secondary is an empty cell.
+fn item_csv(item: &QueueItem) -> Result<Vec<u8>, csv::Error> {
+    let rfc3339 =
+        |datetime: DateTime<Utc>| datetime.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true);
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        rfc3339(item.datetime),
+        item.datetime_secondary.map(rfc3339).unwrap_or_default(),
+        item.message.clone(),
+    ])?;
+    writer
+        .into_inner()
+        .map_err(|e| csv::Error::from(e.into_error()))
+}
+
+/// Returns every valid item of a queue in retrieval order, hidden ones included, as
+/// NDJSON or CSV, up to `max_response_bytes`.
+pub async fn export_items(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<ExportParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let after = match params.after.as_deref().map(parse_cursor).transpose() {
+        Ok(after) => after,
+        Err(message) => return invalid_parameter(&message),
+    };
+
+    let encode = |item: &QueueItem| match params.format {
+        ExportFormat::Ndjson => item_ndjson(item).map_err(|e| e.to_string()),
+        ExportFormat::Csv => item_csv(item).map_err(|e| e.to_string()),
+    };
+    let (content_type, header_row) = match params.format {
+        ExportFormat::Ndjson => ("application/x-ndjson", &b""[..]),
+        ExportFormat::Csv => ("text/csv", CSV_HEADER),
+    };
+    let max_bytes = state.config.max_response_bytes;
+    let mut body = header_row.to_vec();
+    let mut exported = 0;
+    let mut last: Option<QueueItem> = None;
+    let mut more = false;
+    // Reads the queue a batch at a time until the body is full, keeping the first item
+    // of a page even past `max_response_bytes` so paging moves on
+    'pages: loop {
+        let cursor = last.as_ref().map(QueueItem::key).or(after);
+        let batch = match state
+            .storage
+            .export_page(&queue, cursor.as_ref(), EXPORT_BATCH)
+        {
+            Ok(batch) => batch,
+            Err(e) => {
+                error!("Failed to export '{queue}': {e}");
+                return utils::json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    &format!("Failed to export queue {queue}: {e}"),
+                );
+            }
+        };
+        let exhausted = batch.len() < EXPORT_BATCH;
+        for item in batch {
+            let bytes = match encode(&item) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    error!("Failed to encode the export of '{queue}': {e}");
+                    return utils::json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        ErrorCode::InternalError,
+                        &format!("Failed to export queue {queue}: {e}"),
+                    );
+                }
+            };
+            if max_bytes.is_some_and(|max_bytes| body.len() + bytes.len() > max_bytes)
+                && last.is_some()
+            {
+                more = true;
+                break 'pages;
+            }
+            body.extend(bytes);
+            exported += 1;
+            last = Some(item);
+        }
+        if exhausted {
+            break;
+        }
+    }
+    info!(
+        "export of queue {queue} as {:?}, {exported} items{}",
+        params.format,
+        if more { ", more to follow" } else { "" }
+    );
+    let response = (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response();
+    with_next_cursor(response, more, last.as_ref())
+}
+
+#[derive(Deserialize)]
+pub struct OverdueParams {
+    /// How far past its datetime an item must be, such as `5m`; 0 by default.
+    pub by: Option<String>,
+    #[serde(default)]
+    pub pretty: bool,
+}
+
+/// Returns every valid item whose datetime is more than `by` in the past, in retrieval
+/// order, hidden ones included. A consumer that keeps up leaves this empty.
+pub async fn overdue_items(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<OverdueParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let by = match params.by.as_deref().map(utils::parse_duration) {
+        None => chrono::Duration::zero(),
+        Some(Some(by)) => by,
+        Some(None) => {
+            return invalid_parameter("by: expected a duration such as 90s, 5m, 2h or 1d");
+        }
+    };
+    let Some(before) = Utc::now().checked_sub_signed(by) else {
+        return invalid_parameter("by is out of range");
+    };
+
+    match state.storage.overdue_items(&queue, before) {
+        Ok(items) => {
+            info!(
+                "overdue items of queue {queue}, got {} before {before}",
+                items.len()
+            );
+            utils::json_response(StatusCode::OK, &items, params.pretty)
+        }
+        Err(e) => {
+            error!("Failed to list the overdue items of '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to list the overdue items of queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Largest number of queues a prefix peek considers.
+const MAX_PREFIX_QUEUES: usize = 100;
+
+/// Returns the head item of every queue named `{prefix}_*`, keyed by queue name,
+/// with `null` for empty queues.
+pub async fn peek_prefix(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(prefix): Path<String>,
+) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    let prefix = utils::normalize_queue_name(&prefix);
+    if utils::sanitize_queue_name(&prefix).is_none() {
+        warn!("Invalid queue prefix attempted: {prefix}");
+        return utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            &format!("Invalid queue prefix attempted: {prefix}"),
+        );
+    }
+
+    let prefix = format!("{prefix}_");
+    let mut queues: Vec<String> = state
+        .storage
+        .queue_names()
+        .into_iter()
+        .filter(|queue| queue.starts_with(&prefix))
+        .collect();
+    queues.sort();
+    if queues.len() > MAX_PREFIX_QUEUES {
+        warn!(
+            "{} queues match prefix {prefix}, peeking into the first {MAX_PREFIX_QUEUES}",
+            queues.len()
+        );
+        queues.truncate(MAX_PREFIX_QUEUES);
+    }
+
+    let mut heads = BTreeMap::new();
+    for queue in queues {
+        match state.storage.get_item(&queue) {
+            Ok(item) => {
+                heads.insert(queue, item);
+            }
+            Err(e) => {
+                error!("Failed to get item from '{queue}': {e}");
+                return utils::json_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    &format!("Failed to get item from queue {queue}: {e}"),
+                );
+            }
+        }
+    }
+    info!("peek across prefix {prefix}, got {} queues", heads.len());
+    (StatusCode::OK, axum::Json(heads)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CreateQueueParams {
+    /// Answer `200 OK` instead of `409 Conflict` when the queue already exists.
+    #[serde(default)]
+    pub if_not_exists: bool,
+}
+
+/// Creates a queue ahead of its first write: `201 Created` when it is new, `409
+/// QueueExists` when it already exists, or `200 OK` with `if_not_exists=true`.
+/// Like queues created on first write, it is forgotten on restart unless configured.
+pub async fn create_queue(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    QueueName(queue): QueueName,
+    params: Result<Query<CreateQueueParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+
+    match state.storage.create_queue(&queue) {
+        Ok(true) => {
+            info!("created queue {queue}");
+            StatusCode::CREATED.into_response()
+        }
+        Ok(false) if params.if_not_exists => StatusCode::OK.into_response(),
+        Ok(false) => utils::json_error(
+            StatusCode::CONFLICT,
+            ErrorCode::QueueExists,
+            &format!("Queue {queue} already exists"),
+        ),
+        Err(e) => create_queue_error(&queue, e),
+    }
+}
+
+/// Largest number of queues a single snapshot reads.
+const MAX_SNAPSHOT_QUEUES: usize = 100;
+
+#[derive(Deserialize)]
+pub struct SnapshotParams {
+    /// Comma-separated queue names.
+    pub queues: String,
+}
+
+/// Returns the head item of each requested queue, keyed by queue name with `null` for
+/// empty queues, all read at the same instant.
+pub async fn snapshot(
+    State(state): State<AppState>,
+    params: Result<Query<SnapshotParams>, QueryRejection>,
+) -> Response {
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let mut queues: Vec<String> = Vec::new();
+    for queue in params.queues.split(',').map(str::trim) {
+        let queue = utils::normalize_queue_name(queue);
+        if queue.is_empty() || queues.contains(&queue) {
+            continue;
+        }
+        if let Some(response) = check_queue(&state, &queue) {
+            return response;
+        }
+        queues.push(queue);
+    }
+    if queues.is_empty() || queues.len() > MAX_SNAPSHOT_QUEUES {
+        warn!("snapshot of {} queues rejected", queues.len());
+        return utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            &format!("queues must name between 1 and {MAX_SNAPSHOT_QUEUES} queues"),
+        );
+    }
+
+    match state.storage.snapshot_heads(&queues) {
+        Ok(heads) => {
+            info!("snapshot of {} queues", queues.len());
+            let heads: BTreeMap<String, Option<QueueItem>> =
+                queues.into_iter().zip(heads).collect();
+            (StatusCode::OK, axum::Json(heads)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to snapshot queues {queues:?}: {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to snapshot queues: {e}"),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SwapRequest {
+    pub a: QueueKey,
+    pub b: QueueKey,
+}
+
+pub async fn swap_items(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    body: String,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let request: SwapRequest = match serde_json::from_str(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Failed to parse swap request body: {e}");
+            return utils::json_error(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::BadRequest,
+                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
+            );
+        }
+    };
+
+    match state.storage.swap_items(&queue, &request.a, &request.b) {
+        Ok(true) => {
+            info!(
+                "swap in queue {queue} successful, swapped {:?} and {:?}",
+                request.a, request.b
+            );
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => {
+            info!(
+                "swap in queue {queue} failed, {:?} or {:?} not found",
+                request.a, request.b
+            );
+            utils::json_error(
+                StatusCode::NOT_FOUND,
+                ErrorCode::ItemNotFound,
+                &format!("Item to swap not found in queue {queue}"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to swap items in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to swap items in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Query parameters of a hide request; all timestamps are milliseconds since the epoch.
+#[derive(Deserialize)]
+pub struct HideParams {
+    pub at: i64,
+    pub secondary: Option<i64>,
+    pub until: i64,
+}
+
+pub async fn hide_item(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<HideParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let (key, until) = match (
+        key_from_millis(params.at, params.secondary),
+        millis_param("until", params.until),
+    ) {
+        (Ok(key), Ok(until)) => (key, until),
+        (Err(message), _) | (_, Err(message)) => return invalid_parameter(&message),
+    };
+
+    match state.storage.hide_item(&queue, &key, until) {
+        Ok(true) => {
+            info!("hide in queue {queue} successful, hid {key:?} until {until}");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => {
+            info!("hide in queue {queue} failed, {key:?} not found");
+            utils::json_error(
+                StatusCode::NOT_FOUND,
+                ErrorCode::ItemNotFound,
+                &format!("Item to hide not found in queue {queue}"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to hide item in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to hide item in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Largest number of keys a single extension accepts.
+const MAX_EXTEND_KEYS: usize = 1000;
+
+/// Query parameters of an extend request.
+#[derive(Deserialize)]
+pub struct ExtendParams {
+    /// How long from now the items stay hidden.
+    pub secs: u64,
+}
+
+/// The outcome of an extension: the keys still held until `until`, and those whose
+/// hiding had already expired or that are gone.
+#[derive(Serialize)]
+struct ExtendResult {
+    until: i64,
+    held: Vec<QueueKey>,
+    lost: Vec<QueueKey>,
+}
+
+/// Extends the hiding of a JSON array of keys a worker is holding, as a heartbeat.
+pub async fn extend_hidden(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<ExtendParams>, QueryRejection>,
+    body: String,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let Some(until) = i64::try_from(params.secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|secs| Utc::now().checked_add_signed(secs))
+    else {
+        return invalid_parameter("secs is out of range");
+    };
+
+    let keys: Vec<QueueKey> = match serde_json::from_str(&body) {
+        Ok(keys) => keys,
+        Err(e) => {
+            warn!("Failed to parse extend request body: {e}");
+            return utils::json_error(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::BadRequest,
+                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
+            );
+        }
+    };
+    if keys.len() > MAX_EXTEND_KEYS {
+        warn!("extend request with {} keys rejected", keys.len());
+        return utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            &format!("At most {MAX_EXTEND_KEYS} keys can be extended at once"),
+        );
+    }
+
+    match state.storage.extend_hidden(&queue, &keys, until) {
+        Ok(held) => {
+            let (held, lost): (Vec<_>, Vec<_>) = keys.iter().zip(held).partition(|(_, held)| *held);
+            let result = ExtendResult {
+                until: until.timestamp_millis(),
+                held: held.into_iter().map(|(key, _)| *key).collect(),
+                lost: lost.into_iter().map(|(key, _)| *key).collect(),
+            };
+            info!(
+                "extend in queue {queue}, held {} keys until {until}, lost {}",
+                result.held.len(),
+                result.lost.len()
+            );
+            (StatusCode::OK, axum::Json(result)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to extend items in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to extend items in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Query parameters of an increment request; timestamps are milliseconds since the epoch.
+#[derive(Deserialize)]
+pub struct IncrementParams {
+    pub at: i64,
+    pub secondary: Option<i64>,
+    pub by: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct IncrementResponse {
+    pub value: i64,
+}
+
+/// Adds `by` (default 1) to the integer message under a key, creating it at 0 if absent.
+pub async fn increment_item(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<IncrementParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let key = match key_from_millis(params.at, params.secondary) {
+        Ok(key) => key,
+        Err(message) => return invalid_parameter(&message),
+    };
+    let by = params.by.unwrap_or(1);
+
+    match state.storage.increment_item(&queue, &key, by) {
+        Ok(value) => {
+            info!("increment in queue {queue} by {by}, {key:?} is now {value}");
+            (StatusCode::OK, axum::Json(IncrementResponse { value })).into_response()
+        }
+        Err(StorageError::NotAnInteger(e)) => {
+            warn!("Failed to increment {key:?} in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::CONFLICT,
+                ErrorCode::NotAnInteger,
+                &format!("Item in queue {queue} cannot be incremented: {e}"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to increment item in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to increment item in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Query parameters of a shift request; `by_secs` may be negative to move items earlier.
+#[derive(Deserialize)]
+pub struct ShiftParams {
+    pub by_secs: i64,
+    #[serde(default)]
+    pub secondary: bool,
+}
+
+#[derive(Serialize)]
+pub struct ShiftResponse {
+    pub shifted: usize,
+}
+
+/// Reschedules every item of a queue by `by_secs`, their secondary datetimes too with
+/// `secondary=true`, in one transaction.
+pub async fn shift_items(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<ShiftParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let Some(by) = chrono::Duration::try_seconds(params.by_secs) else {
+        return invalid_parameter("by_secs is out of range");
+    };
+
+    match state.storage.shift_items(&queue, by, params.secondary) {
+        Ok(shifted) => {
+            info!("shift in queue {queue} by {by}, moved {shifted} items");
+            (StatusCode::OK, axum::Json(ShiftResponse { shifted })).into_response()
+        }
+        Err(StorageError::DatetimeOutOfRange(e)) => {
+            warn!("Refused to shift '{queue}' by {by}: {e}");
+            invalid_parameter(&format!("by_secs moves an item out of range: {e}"))
+        }
+        Err(e) => {
+            error!("Failed to shift items in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to shift items in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// Query parameters naming an item by its timestamps in milliseconds since the epoch.
+#[derive(Deserialize)]
+pub struct KeyParams {
+    pub at: i64,
+    pub secondary: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RankResponse {
+    pub rank: usize,
+}
+
+pub async fn rank_of_key(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    params: Result<Query<KeyParams>, QueryRejection>,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let params = match params {
+        Ok(Query(params)) => params,
+        Err(e) => return query_error(e),
+    };
+    let key = match key_from_millis(params.at, params.secondary) {
+        Ok(key) => key,
+        Err(message) => return invalid_parameter(&message),
+    };
+
+    match state.storage.rank_of_key(&queue, &key) {
+        Ok(Some(rank)) => {
+            info!("rank in queue {queue} of {key:?} is {rank}");
+            (StatusCode::OK, axum::Json(RankResponse { rank })).into_response()
+        }
+        Ok(None) => {
+            info!("rank in queue {queue} failed, {key:?} not found");
+            utils::json_error(
+                StatusCode::NOT_FOUND,
+                ErrorCode::ItemNotFound,
+                &format!("Item not found in queue {queue}"),
+            )
+        }
+        Err(e) => {
+            error!("Failed to rank item in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to rank item in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+/// One line of a server dump: an item together with the queue holding it.
+#[derive(Serialize, Deserialize)]
+pub struct DumpRecord {
+    pub queue: String,
+    #[serde(flatten)]
+    pub item: QueueItem,
+    /// False for an item that was already consumed, which is restored as soft-deleted.
+    /// Dumps only contain valid items, so it is only written when false.
+    #[serde(default = "default_valid", skip_serializing_if = "Clone::clone")]
+    pub valid: bool,
+}
+
+fn default_valid() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// How many of the restored items were stored as already consumed.
+    pub consumed: usize,
+    pub created_queues: Vec<String>,
+}
+
+pub async fn dump(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+
+    info!("dumping all queues");
+    // Items are loaded and written out one queue at a time
+    let storage = state.storage.clone();
+    let policy = state.config.stream_on_decode_error;
+    let chunks = futures_util::stream::iter(storage.queue_names()).map(move |queue| {
+        let mut chunk = String::new();
+        for row in storage.export_rows(&queue)? {
+            let line = match row {
+                Ok(item) => serde_json::to_string(&DumpRecord {
+                    queue: queue.clone(),
+                    item,
+                    valid: true,
+                }),
+                Err(row) => {
+                    error!(
+                        "Failed to decode the row of queue {queue} with key ({}, {}): {}",
+                        row.datetime, row.datetime_secondary, row.error
+                    );
+                    if policy == DecodeErrorPolicy::Abort {
+                        return Err(format!("corrupt row in queue {queue}: {}", row.error).into());
+                    }
+                    serde_json::to_string(&serde_json::json!({
+                        "error": "CorruptRow",
+                        "queue": queue,
+                        "datetime_ms": row.datetime,
+                        "datetime_secondary_ms": row.datetime_secondary,
+                        "message": row.error,
+                    }))
+                }
+            };
+            chunk.push_str(&line.map_err(std::io::Error::other)?);
+            chunk.push('\n');
+        }
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(Bytes::from(chunk))
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::from_stream(chunks))
+        .unwrap()
+}
+
+pub async fn restore(State(state): State<AppState>, headers: HeaderMap, body: String) -> Response {
+    if let Some(response) = check_writable(&state) {
+        return response;
+    }
+    if let Some(response) = authorize(&state.config, &headers) {
+        return response;
+    }
+
+    // Parse the whole dump first so a malformed line leaves the server untouched
+    let mut records = Vec::new();
+    for (index, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<DumpRecord>(line) {
+            Ok(mut record) => {
+                record.queue = utils::normalize_queue_name(&record.queue);
+                records.push(record);
+            }
+            Err(e) => {
+                warn!("Failed to parse restore line {}: {e}", index + 1);
+                return utils::json_error(
+                    StatusCode::BAD_REQUEST,
+                    ErrorCode::BadRequest,
+                    &format!("Failed to parse line {} due to: {e}", index + 1),
+                );
+            }
+        }
+    }
+
+    let mut summary = RestoreSummary {
+        restored: 0,
+        consumed: 0,
+        created_queues: Vec::new(),
+    };
+    for record in records {
+        if !state.storage.queue_exists(&record.queue) {
+            match state.storage.create_queue(&record.queue) {
+                Ok(true) => summary.created_queues.push(record.queue.clone()),
+                Ok(false) => {}
+                Err(e) => return create_queue_error(&record.queue, e),
+            }
+        }
+        let stored = if record.valid {
+            state
+                .storage
+                .put_item(&record.queue, record.item)
+                .map(|_| ())
+        } else {
+            summary.consumed += 1;
+            state
+                .storage
+                .put_consumed_items(&record.queue, vec![record.item])
+        };
+        if let Err(e) = stored {
+            error!("Failed to restore item into '{}': {e}", record.queue);
+            return utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to restore item into queue {}: {e}", record.queue),
+            );
+        }
+        summary.restored += 1;
+    }
+
+    info!(
+        "restored {} items ({} consumed), created queues {:?}",
+        summary.restored, summary.consumed, summary.created_queues
+    );
+    (StatusCode::OK, axum::Json(summary)).into_response()
+}
+
+/// Largest number of keys a single existence check accepts.
+const MAX_EXISTS_KEYS: usize = 1000;
+
+/// Answers a JSON array of keys with a parallel array telling which are still stored.
+pub async fn items_exist(
+    State(state): State<AppState>,
+    QueueName(queue): QueueName,
+    body: String,
+) -> Response {
+    if let Some(response) = check_queue(&state, &queue) {
+        return response;
+    }
+
+    let keys: Vec<QueueKey> = match serde_json::from_str(&body) {
+        Ok(keys) => keys,
+        Err(e) => {
+            warn!("Failed to parse exists request body: {e}");
+            return utils::json_error(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::BadRequest,
+                &format!("Failed to parse request body due to: {e}\nRequest body:\n{body}"),
+            );
+        }
+    };
+    if keys.len() > MAX_EXISTS_KEYS {
+        warn!("exists request with {} keys rejected", keys.len());
+        return utils::json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            &format!("At most {MAX_EXISTS_KEYS} keys can be checked at once"),
+        );
+    }
+
+    match state.storage.items_exist(&queue, &keys) {
+        Ok(exists) => {
+            info!("exists in queue {queue}, checked {} keys", keys.len());
+            (StatusCode::OK, axum::Json(exists)).into_response()
+        }
+        Err(e) => {
+            error!("Failed to check keys in '{queue}': {e}");
+            utils::json_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                &format!("Failed to check keys in queue {queue}: {e}"),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::Request;
+    use chrono::{Duration, Utc};
+    use tower::{Layer, ServiceExt};
+
+    fn setup_test_app() -> (Router, Arc<dyn Storage>) {
+        setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        })
+    }
+
+    fn setup_test_app_with(config: AppConfig) -> (Router, Arc<dyn Storage>) {
+        let storage: Arc<dyn Storage> = Arc::new(dtqueue::InMemoryStorage::new(&config));
+        let config = Arc::new(config);
+        let state = AppState {
+            storage: storage.clone(),
+            webhooks: Webhooks::new(config.clone()),
+            depth_history: Arc::new(DepthHistory::new(config.depth_history_points)),
+            config,
+            claim_rotation: Default::default(),
+            queue_creations: Default::default(),
+            activity: Default::default(),
+            write_buffer: None,
+            paused: Default::default(),
+            subscribers: Default::default(),
+            producers: Default::default(),
+            operations: Default::default(),
+            head_reads: Default::default(),
+        };
+        let state = if state.config.async_write_ack {
+            let (sender, receiver) = mpsc::channel(WRITE_BUFFER_CAPACITY);
+            let state = AppState {
+                write_buffer: Some(sender),
+                ..state
+            };
+            tokio::spawn(run_write_buffer(state.clone(), receiver));
+            state
+        } else {
+            state
+        };
+
+        (router(state), storage)
+    }
+
+    async fn body_string(response: Response) -> String {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_put_item_handler() {
+        let (app, _) = setup_test_app();
+
+        let now = Utc::now();
+        let item = QueueItem {
+            datetime: now,
+            datetime_secondary: None,
+            message: "test message".to_string(),
+            ..Default::default()
+        };
+
+        let json = item.to_json_string().unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .header("Content-Type", "application/json")
+                    .header("Content-Length", json.len().to_string())
+                    .body(Body::from(json))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Debug records logged by every test of this binary; each test finds its own by a
+    // marker only it uses
+    static DEBUG_RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.level() == log::Level::Debug {
+                DEBUG_RECORDS
+                    .lock()
+                    .unwrap()
+                    .push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_debug_logs() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Debug);
+        });
+    }
+
+    fn debug_records_with(marker: &str) -> Vec<String> {
+        let records = DEBUG_RECORDS.lock().unwrap();
+        records
+            .iter()
+            .filter(|record| record.contains(marker))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_debug_sampling() {
+        capture_debug_logs();
+        for (rate, marker) in [(1.0, "marker-sampled"), (0.0, "marker-unsampled")] {
+            let (app, _) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                debug_sample_rate: rate,
+                ..Default::default()
+            });
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue")
+                        .header("Content-Type", "application/json")
+                        .header("Authorization", "Bearer hunter2")
+                        .body(Body::from(format!(
+                            r#"{{"datetime": "2024-06-01T12:00:00Z", "message": "{marker}"}}"#
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/queue")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            // The handler's body still reaches the client in full
+            assert!(body_string(response).await.contains(marker));
+
+            let records = debug_records_with(marker);
+            if rate == 0.0 {
+                assert!(records.is_empty(), "{records:?}");
+                continue;
+            }
+            let [put, get] = records.as_slice() else {
+                panic!("expected a record per request, got {records:?}");
+            };
+            assert!(put.starts_with("sampled request PUT /queue headers ["));
+            assert!(put.contains("authorization: <redacted>"));
+            assert!(!put.contains("hunter2"));
+            assert!(put.contains("response 200 OK"));
+            assert!(get.contains("sampled request GET /queue"));
+            assert!(get.contains(r#"response 200 OK body "{\"datetime\""#));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_with_fence_token() {
+        let (app, storage) = setup_test_app();
+
+        for (i, (token, status)) in [
+            ("3", StatusCode::OK),
+            ("2", StatusCode::CONFLICT),
+            ("3", StatusCode::OK),
+            ("4", StatusCode::OK),
+            ("3", StatusCode::CONFLICT),
+            ("three", StatusCode::BAD_REQUEST),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue")
+                        .header("Content-Type", "application/json")
+                        .header("X-Fence-Token", token)
+                        .body(Body::from(format!(
+                            r#"{{"datetime": "2024-06-01T12:00:0{i}Z", "message": "write {i}"}}"#
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "token {token}");
+            if status == StatusCode::CONFLICT {
+                assert!(body_string(response).await.contains("StaleFenceToken"));
+            }
+        }
+        // Only the accepted writes were stored
+        let messages: Vec<String> = storage
+            .peek_items("queue", None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.message)
+            .collect();
+        assert_eq!(messages, ["write 0", "write 2", "write 3"]);
+    }
+
+    #[tokio::test]
+    async fn test_due_only_queue() {
+        let mut config = AppConfig {
+            queues: vec!["scheduled".to_string(), "queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "scheduled".to_string(),
+            QueueOptions {
+                due_only: true,
+                ..Default::default()
+            },
+        );
+        let (app, storage) = setup_test_app_with(config);
+        // Stored datetimes keep milliseconds
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let item = |datetime, message: &str| QueueItem {
+            datetime,
+            message: message.to_string(),
+            ..Default::default()
+        };
+        let soon = item(now + Duration::milliseconds(1500), "soon");
+        let later = item(now + Duration::seconds(60), "later");
+        for queue in ["scheduled", "queue"] {
+            storage.put_item(queue, soon.clone()).unwrap();
+            storage.put_item(queue, later.clone()).unwrap();
+        }
+        let send = |method: &str, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let retry_after = |response: &Response| -> u64 {
+            let value = response.headers()[header::RETRY_AFTER].to_str().unwrap();
+            value.parse().unwrap()
+        };
+        // The query cannot turn the queue's due_only off, but can turn it on elsewhere
+        for (method, uri) in [
+            ("GET", "/scheduled"),
+            ("DELETE", "/scheduled"),
+            ("GET", "/scheduled?due_only=false"),
+            ("GET", "/queue?due_only=true"),
+            ("DELETE", "/queue?due_only=true"),
+        ] {
+            let response = send(method, uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{method} {uri}");
+            // The head comes due in 1.5s
+            assert!((1..=2).contains(&retry_after(&response)), "{method} {uri}");
+        }
+        // Without it, other queues still hand out future items
+        let response = send("GET", "/queue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            QueueItem::from_json_string(&body_string(response).await).unwrap(),
+            soon
+        );
+        let response = send("GET", "/scheduled?order=desc").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        tokio::time::sleep(std::time::Duration::from_millis(1600)).await;
+        let response = send("GET", "/scheduled").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("DELETE", "/queue?due_only=true").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("DELETE", "/scheduled").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            QueueItem::from_json_string(&body_string(response).await).unwrap(),
+            soon
+        );
+        let response = send("DELETE", "/scheduled").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!((59..=60).contains(&retry_after(&response)));
+
+        // A tenant is served only its own due items, and waits for its own head
+        let tagged = |datetime, tenant: &str| QueueItem {
+            tenant: Some(tenant.to_string()),
+            ..item(datetime, tenant)
+        };
+        let acme = tagged(now + Duration::seconds(90), "acme");
+        let globex = tagged(now, "globex");
+        storage.put_item("scheduled", acme.clone()).unwrap();
+        storage.put_item("scheduled", globex.clone()).unwrap();
+        for method in ["GET", "DELETE"] {
+            let response = send(method, "/scheduled?tenant=acme").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{method}");
+            assert!((88..=90).contains(&retry_after(&response)), "{method}");
+        }
+        let response = send("DELETE", "/scheduled?tenant=globex").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            QueueItem::from_json_string(&body_string(response).await).unwrap(),
+            globex
+        );
+        let response = send("GET", "/scheduled?tenant=globex").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!response.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(
+            storage.export_items("scheduled").unwrap(),
+            vec![later, acme]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_remaining_header() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            queue_remaining_header: true,
+            ..Default::default()
+        });
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        for i in 0..3 {
+            let item = QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: format!("job {i}"),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        let remaining = |method: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri("/queue")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response.headers()["X-Queue-Remaining"]
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            }
+        };
+
+        // A peek leaves the item counted, a pop does not
+        assert_eq!(remaining("GET").await, "3");
+        assert_eq!(remaining("DELETE").await, "2");
+        assert_eq!(remaining("DELETE").await, "1");
+        assert_eq!(remaining("GET").await, "1");
+        assert_eq!(remaining("DELETE").await, "0");
+
+        // Off by default
+        let (app, storage) = setup_test_app();
+        storage
+            .put_item(
+                "queue",
+                QueueItem {
+                    datetime: base,
+                    message: "uncounted".to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key("X-Queue-Remaining"));
+    }
+
+    #[tokio::test]
+    async fn test_order_override() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            allow_order_override: true,
+            ..Default::default()
+        });
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let items: Vec<QueueItem> = (0..4)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: format!("job {i}"),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items.clone()).unwrap();
+        let read = |method: &str, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                QueueItem::from_json_string(&body_string(response).await).unwrap()
+            }
+        };
+
+        // Newest-first claims and ordinary ones interleave on the same queue
+        assert_eq!(read("DELETE", "/queue?order=desc").await, items[3]);
+        assert_eq!(read("DELETE", "/queue").await, items[0]);
+        assert_eq!(read("GET", "/queue?order=desc").await, items[2]);
+        assert_eq!(read("DELETE", "/queue?order=desc").await, items[2]);
+        assert_eq!(read("GET", "/queue?order=asc").await, items[1]);
+        assert_eq!(read("DELETE", "/queue").await, items[1]);
+
+        storage.put_items("queue", items.clone()).unwrap();
+        for uri in ["/queue?order=sideways", "/queue?order=desc&tenant=acme"] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+
+        // Without the option the override is refused
+        let (app, _storage) = setup_test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/queue?order=desc")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("allow_order_override"));
+    }
+
+    #[tokio::test]
+    async fn test_compression_min_bytes() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            compression_min_bytes: Some(1024),
+            ..Default::default()
+        });
+        storage
+            .put_item(
+                "queue",
+                QueueItem::from_json_string(
+                    r#"{"datetime": "2024-06-01T12:00:00Z", "message": "small"}"#,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .header("Accept-Encoding", "gzip")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+            }
+        };
+
+        let response = get("/queue").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("content-encoding").is_none());
+        assert!(body_string(response).await.contains("small"));
+
+        let large = "x".repeat(4096);
+        storage
+            .put_item(
+                "queue",
+                QueueItem::from_json_string(&format!(
+                    r#"{{"datetime": "2024-06-01T12:00:01Z", "message": "{large}"}}"#
+                ))
+                .unwrap(),
+            )
+            .unwrap();
+        let response = get("/queue/peek").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        // Gzip magic number, and a repetitive body shrinks a lot
+        assert_eq!(&body[..2], [0x1f, 0x8b]);
+        assert!(body.len() < 1024, "{}", body.len());
+    }
+
+    #[tokio::test]
+    async fn test_monotonic_producers() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            queue_options: std::collections::HashMap::from([(
+                "queue".to_string(),
+                dtqueue::QueueOptions {
+                    monotonic_producers: true,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+
+        for (producer, second, status) in [
+            (Some("a"), 1, StatusCode::OK),
+            (Some("a"), 3, StatusCode::OK),
+            // Another producer keeps its own order
+            (Some("b"), 2, StatusCode::OK),
+            (Some("a"), 3, StatusCode::CONFLICT),
+            (Some("a"), 0, StatusCode::CONFLICT),
+            // Puts without a producer id are not ordered
+            (None, 0, StatusCode::OK),
+            (Some("a"), 4, StatusCode::OK),
+        ] {
+            let mut request = Request::builder()
+                .method("PUT")
+                .uri("/queue")
+                .header("Content-Type", "application/json");
+            if let Some(producer) = producer {
+                request = request.header("X-Producer-Id", producer);
+            }
+            let response = app
+                .clone()
+                .oneshot(
+                    request
+                        .body(Body::from(format!(
+                            r#"{{"datetime": "2024-06-01T12:00:0{second}Z", "message": "{} {second}"}}"#,
+                            producer.unwrap_or("none")
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{producer:?} at {second}");
+            if status == StatusCode::CONFLICT {
+                assert!(body_string(response).await.contains("OutOfOrder"));
+            }
+        }
+        let messages: Vec<String> = storage
+            .peek_items("queue", None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.message)
+            .collect();
+        assert_eq!(messages, ["none 0", "a 1", "b 2", "a 3", "a 4"]);
+    }
+
+    #[tokio::test]
+    async fn test_put_if_message() {
+        let (app, storage) = setup_test_app();
+        let datetime = "2024-06-01T12:00:00Z";
+        storage
+            .put_item(
+                "queue",
+                QueueItem::from_json_string(&format!(
+                    r#"{{"datetime": "{datetime}", "message": "v1"}}"#
+                ))
+                .unwrap(),
+            )
+            .unwrap();
+
+        for (expected, status, message) in [
+            ("v0", StatusCode::PRECONDITION_FAILED, "v1"),
+            ("v1", StatusCode::OK, "v2"),
+            // v1 is no longer stored, so a second writer that read it loses
+            ("v1", StatusCode::PRECONDITION_FAILED, "v2"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri(format!("/queue?if_message={expected}"))
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(format!(
+                            r#"{{"datetime": "{datetime}", "message": "v2"}}"#
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+            if status == StatusCode::PRECONDITION_FAILED {
+                assert!(body_string(response).await.contains("MessageMismatch"));
+            }
+            assert_eq!(storage.get_item("queue").unwrap().unwrap().message, message);
+        }
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_put_invalid_queue() {
+        let (app, _) = setup_test_app();
+
+        let now = Utc::now();
+        let item = QueueItem {
+            datetime: now,
+            datetime_secondary: None,
+            message: "test message".to_string(),
+            ..Default::default()
+        };
+
+        let json = item.to_json_string().unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue/invalid_queue")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_swap_items_handler() {
+        let (app, storage) = setup_test_app();
+
+        let now = Utc::now();
+        let first = QueueItem {
+            datetime: now,
+            message: "first".to_string(),
+            ..Default::default()
+        };
+        let second = QueueItem {
+            datetime: now + Duration::seconds(10),
+            message: "second".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", first.clone()).unwrap();
+        storage.put_item("queue", second.clone()).unwrap();
+
+        let body = serde_json::json!({ "a": first.key(), "b": second.key() }).to_string();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/swap")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            storage.get_item("queue").unwrap().unwrap().message,
+            "second"
+        );
+
+        let missing = QueueKey {
+            datetime: now + Duration::seconds(20),
+            datetime_secondary: None,
+        };
+        let body = serde_json::json!({ "a": first.key(), "b": missing }).to_string();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/swap")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_dump_with_corrupt_row() {
+        for policy in [DecodeErrorPolicy::Skip, DecodeErrorPolicy::Abort] {
+            let dir = tempfile::tempdir().unwrap();
+            let db_path = dir.path().join("queue.sqlite");
+            let config = AppConfig {
+                queues: vec!["queue".to_string()],
+                database_path: db_path.to_str().unwrap().to_string(),
+                stream_on_decode_error: policy,
+                ..Default::default()
+            };
+            let storage: Arc<dyn Storage> = Arc::new(dtqueue::SqliteStorage::new(&config).unwrap());
+            let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+            for (offset, message) in ["first", "corrupt", "last"].into_iter().enumerate() {
+                let item = QueueItem {
+                    datetime: base + Duration::seconds(offset as i64),
+                    message: message.to_string(),
+                    ..Default::default()
+                };
+                storage.put_item("queue", item).unwrap();
+            }
+            rusqlite::Connection::open(&db_path)
+                .unwrap()
+                .execute(
+                    "UPDATE queue_queue SET payloads = '{broken' WHERE message = 'corrupt'",
+                    [],
+                )
+                .unwrap();
+
+            let config = Arc::new(config);
+            let app = router(AppState {
+                storage,
+                webhooks: Webhooks::new(config.clone()),
+                depth_history: Arc::new(DepthHistory::new(config.depth_history_points)),
+                config,
+                claim_rotation: Default::default(),
+                queue_creations: Default::default(),
+                activity: Default::default(),
+                write_buffer: None,
+                paused: Default::default(),
+                subscribers: Default::default(),
+                producers: Default::default(),
+                operations: Default::default(),
+                head_reads: Default::default(),
+            });
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/_dump")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await;
+            if policy == DecodeErrorPolicy::Abort {
+                assert!(body.is_err());
+                continue;
+            }
+
+            let lines: Vec<serde_json::Value> = String::from_utf8(body.unwrap().to_vec())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(lines.len(), 3);
+            assert_eq!(lines[0]["message"], "first");
+            assert_eq!(lines[1]["error"], "CorruptRow");
+            assert_eq!(lines[1]["datetime_ms"], 1_700_000_001_000i64);
+            assert_eq!(lines[1]["datetime_secondary_ms"], i64::MIN);
+            assert_eq!(lines[2]["message"], "last");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_restore() {
+        let (source, source_storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string(), "other".to_string()],
+            api_keys: vec!["secret".to_string()],
+            ..Default::default()
+        });
+        let now = Utc::now();
+        for (queue, offset) in [("queue", 0), ("queue", 5), ("other", 1)] {
+            let item = QueueItem {
+                datetime: now + Duration::seconds(offset),
+                datetime_secondary: Some(now),
+                message: format!("{queue} {offset}"),
+                ..Default::default()
+            };
+            source_storage.put_item(queue, item).unwrap();
+        }
+
+        let response = source
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/_dump")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = source
+            .oneshot(
+                Request::builder()
+                    .uri("/_dump")
+                    .header("Authorization", "Bearer secret")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let dump = body_string(response).await;
+        assert_eq!(dump.lines().count(), 3);
+
+        // The fresh server only knows "queue"; "other" is created by the restore
+        let (target, target_storage) = setup_test_app();
+        let response = target
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/_restore")
+                    .body(Body::from(dump))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(summary["restored"], 3);
+        assert_eq!(summary["created_queues"], serde_json::json!(["other"]));
+
+        for queue in ["queue", "other"] {
+            assert_eq!(
+                target_storage.export_items(queue).unwrap(),
+                source_storage.export_items(queue).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_create_queue_on_put() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            auto_create_queues: true,
+            max_queues: Some(2),
+            ..Default::default()
+        });
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "first write".to_string(),
+            ..Default::default()
+        };
+
+        // Reading a valid but never-written queue does not create it
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/fresh")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!storage.queue_exists("fresh"));
+
+        // Nor does a write that is rejected
+        for (uri, body, status) in [
+            ("/fresh", "not json", StatusCode::BAD_REQUEST),
+            ("/fresh/stream", "not json\n", StatusCode::OK),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri(uri)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{uri}");
+            assert!(!storage.queue_exists("fresh"), "{uri}");
+        }
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/fresh")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/fresh")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let retrieved = QueueItem::from_json_string(&body_string(response).await).unwrap();
+        assert_eq!(retrieved.message, "first write");
+
+        // A third queue would exceed max_queues
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/another")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!storage.queue_exists("another"));
+    }
+
+    #[tokio::test]
+    async fn test_queue_creation_is_rate_limited() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            auto_create_queues: true,
+            queue_create_rate_per_min: Some(3),
+            ..Default::default()
+        });
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "hello".to_string(),
+            ..Default::default()
+        };
+        let put = |uri: String| {
+            Request::builder()
+                .method("PUT")
+                .uri(uri)
+                .body(Body::from(item.to_json_string().unwrap()))
+                .unwrap()
+        };
+
+        // Rejected writes to new names use up no creations
+        for n in 0..3 {
+            let request = Request::builder()
+                .method("PUT")
+                .uri(format!("/bad{n}"))
+                .body(Body::from("not json"))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        for n in 0..3 {
+            let response = app.clone().oneshot(put(format!("/q{n}"))).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = app.clone().oneshot(put("/q3".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        assert!(
+            body_string(response)
+                .await
+                .contains("QueueCreateRateLimited")
+        );
+        assert!(!storage.queue_exists("q3"));
+
+        // Writes to queues that already exist are not throttled
+        let response = app.oneshot(put("/q0".to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_peek_items_handler() {
+        let (app, storage) = setup_test_app();
+        let now = Utc::now();
+        for offset in (0..3).rev() {
+            let item = QueueItem {
+                datetime: now + Duration::seconds(offset),
+                message: format!("item {offset}"),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/peek?limit=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let items: Vec<QueueItem> = serde_json::from_str(&body_string(response).await).unwrap();
+        let messages: Vec<&str> = items.iter().map(|item| item.message.as_str()).collect();
+        assert_eq!(messages, ["item 0", "item 1"]);
+        // Peeking does not consume
+        assert_eq!(storage.export_items("queue").unwrap().len(), 3);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/peek?limit=many")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            max_response_bytes: Some(3500),
+            ..Default::default()
+        });
+        // Items sharing a datetime must neither be skipped nor repeated
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        for i in 0..10 {
+            let item = QueueItem {
+                datetime: base + Duration::milliseconds(i / 2),
+                datetime_secondary: (i % 2 == 1).then(|| base + Duration::milliseconds(i)),
+                message: format!("{i}{}", "x".repeat(1000)),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        let expected: Vec<String> = (0..10)
+            .map(|i| format!("{i}{}", "x".repeat(1000)))
+            .collect();
+
+        for (path, parse) in [
+            ("/queue/peek?limit=100", "json"),
+            ("/queue/peek?limit=100&pretty=true", "json"),
+            ("/queue/export", "ndjson"),
+            ("/queue/export?format=csv", "csv"),
+        ] {
+            let mut messages = Vec::new();
+            let mut pages = 0;
+            let mut uri = path.to_string();
+            loop {
+                let response = app
+                    .clone()
+                    .oneshot(Request::builder().uri(&uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let cursor = response
+                    .headers()
+                    .get("X-Next-Cursor")
+                    .map(|cursor| cursor.to_str().unwrap().to_string());
+                let body = body_string(response).await;
+                assert!(body.len() <= 3500, "{path}: {} bytes", body.len());
+                match parse {
+                    "json" => {
+                        let items: Vec<QueueItem> = serde_json::from_str(&body).unwrap();
+                        messages.extend(items.into_iter().map(|item| item.message));
+                    }
+                    "ndjson" => messages.extend(
+                        body.lines()
+                            .map(|line| serde_json::from_str::<QueueItem>(line).unwrap().message),
+                    ),
+                    _ => {
+                        let mut lines = body.lines();
+                        assert_eq!(lines.next(), Some("datetime,datetime_secondary,message"));
+                        messages
+                            .extend(lines.map(|line| line.rsplit(',').next().unwrap().to_string()));
+                    }
+                }
+                pages += 1;
+                match cursor {
+                    Some(cursor) => {
+                        let separator = if path.contains('?') { '&' } else { '?' };
+                        uri = format!("{path}{separator}after={cursor}");
+                    }
+                    None => break,
+                }
+            }
+            assert!(messages == expected, "{path}: {} items", messages.len());
+            assert!(pages >= 4, "{path}: {pages} pages");
+        }
+
+        // A limit smaller than the queue also hands out a cursor
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/peek?limit=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response.headers().contains_key("X-Next-Cursor"));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/peek?after=yesterday")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_numeric_parameters() {
+        let (app, _) = setup_test_app();
+        for (method, uri, parameter) in [
+            ("GET", "/queue/peek?limit=99999999999999999999", "limit"),
+            ("GET", "/queue/peek?limit=-1", "limit"),
+            ("GET", "/queue/peek?limit=ten", "limit"),
+            ("GET", "/queue/rank?at=99999999999999999999", "at"),
+            ("GET", "/queue/rank?at=1.5", "at"),
+            ("GET", "/queue/rank?at=9223372036854775807", "at"),
+            (
+                "GET",
+                "/queue/rank?at=0&secondary=-9223372036854775808",
+                "secondary",
+            ),
+            ("POST", "/queue/hide?at=0&until=253402300800000", "until"),
+            ("POST", "/queue/incr?at=0&by=9223372036854775808", "by"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+            let body = body_string(response).await;
+            assert!(body.contains("InvalidParameter"), "{uri}: {body}");
+            assert!(body.contains(&format!("{parameter}: ")), "{uri}: {body}");
+        }
+
+        // The bounds themselves are accepted
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/rank?at=253402300799999&secondary=-62167219200000")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_tail_item_handler() {
+        let (app, storage) = setup_test_app();
+        let tail = |app: Router| async move {
+            app.oneshot(
+                Request::builder()
+                    .uri("/queue/tail")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+        };
+        assert_eq!(tail(app.clone()).await.status(), StatusCode::NO_CONTENT);
+
+        let now = Utc::now();
+        for (hours, message) in [(48, "latest"), (-1, "due"), (3, "later")] {
+            let item = QueueItem {
+                datetime: now + chrono::Duration::hours(hours),
+                message: message.to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        let response = tail(app).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let item = QueueItem::from_json_string(&body_string(response).await).unwrap();
+        assert_eq!(item.message, "latest");
+        // The tail is only inspected
+        assert_eq!(storage.count_items("queue").unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_one_read() {
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            get_coalesce_window_ms: Some(50),
+            ..Default::default()
+        };
+        let storage: Arc<dyn Storage> = Arc::new(dtqueue::InMemoryStorage::new(&config));
+        let config = Arc::new(config);
+        let head_reads = Arc::new(HeadReads::default());
+        let app = router(AppState {
+            storage: storage.clone(),
+            webhooks: Webhooks::new(config.clone()),
+            depth_history: Arc::new(DepthHistory::new(config.depth_history_points)),
+            config,
+            claim_rotation: Default::default(),
+            queue_creations: Default::default(),
+            activity: Default::default(),
+            write_buffer: None,
+            paused: Default::default(),
+            subscribers: Default::default(),
+            producers: Default::default(),
+            operations: Default::default(),
+            head_reads: head_reads.clone(),
+        });
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        for (offset, message) in [(0, "head"), (1, "next")] {
+            let item = QueueItem {
+                datetime: base + Duration::seconds(offset),
+                message: message.to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        let get = || async {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/queue")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            QueueItem::from_json_string(&body_string(response).await)
+                .unwrap()
+                .message
+        };
+
+        let messages = futures_util::future::join_all((0..50).map(|_| get())).await;
+        assert!(messages.iter().all(|message| message == "head"));
+        assert_eq!(head_reads.reads.load(Ordering::Relaxed), 1);
+
+        // A GET after a write reads afresh and sees it
+        storage.delete_item("queue").unwrap();
+        assert_eq!(get().await, "next");
+        assert_eq!(head_reads.reads.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_depth_history_handler() {
+        let storage: Arc<dyn Storage> = Arc::new(dtqueue::InMemoryStorage::new(&AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        }));
+        let config = Arc::new(AppConfig::default());
+        let history = Arc::new(DepthHistory::new(10));
+        let app = router(AppState {
+            storage: storage.clone(),
+            webhooks: Webhooks::new(config.clone()),
+            config,
+            claim_rotation: Default::default(),
+            queue_creations: Default::default(),
+            activity: Default::default(),
+            depth_history: history.clone(),
+            write_buffer: None,
+            paused: Default::default(),
+            subscribers: Default::default(),
+            producers: Default::default(),
+            operations: Default::default(),
+            head_reads: Default::default(),
+        });
+
+        // The queue fills up over three samples and drains over the next two
+        let now = Utc::now();
+        for i in 0..3 {
+            let item = QueueItem {
+                datetime: now + chrono::Duration::milliseconds(i),
+                message: "job".to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+            dtqueue::maintenance::sample_depths(storage.as_ref(), &history);
+        }
+        for _ in 0..2 {
+            storage.delete_item("queue").unwrap();
+            dtqueue::maintenance::sample_depths(storage.as_ref(), &history);
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/history?points=4")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let samples: Vec<serde_json::Value> =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        let depths: Vec<u64> = samples
+            .iter()
+            .map(|sample| sample["depth"].as_u64().unwrap())
+            .collect();
+        assert_eq!(depths, [2, 3, 2, 1]);
+        assert!(samples.iter().all(|sample| sample["t"].is_string()));
+    }
+
+    #[tokio::test]
+    async fn test_drain_eta_handler() {
+        let storage: Arc<dyn Storage> = Arc::new(dtqueue::InMemoryStorage::new(&AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        }));
+        let config = Arc::new(AppConfig::default());
+        let history = Arc::new(DepthHistory::new(10));
+        let app = router(AppState {
+            storage,
+            webhooks: Webhooks::new(config.clone()),
+            config,
+            claim_rotation: Default::default(),
+            queue_creations: Default::default(),
+            activity: Default::default(),
+            depth_history: history.clone(),
+            write_buffer: None,
+            paused: Default::default(),
+            subscribers: Default::default(),
+            producers: Default::default(),
+            operations: Default::default(),
+            head_reads: Default::default(),
+        });
+
+        // Drains 5 items per 10 seconds, so the last 15 take 30 seconds
+        let t = Utc::now();
+        for i in 0..4 {
+            let sample = maintenance::DepthSample {
+                t: t + chrono::Duration::seconds(10 * i),
+                depth: 30 - 5 * i as usize,
+            };
+            history.record("queue", sample);
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/eta")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let estimate: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(estimate["depth"], 15);
+        assert_eq!(estimate["samples"], 4);
+        assert_eq!(estimate["growing"], false);
+        assert!((estimate["rate_per_sec"].as_f64().unwrap() - 0.5).abs() < 1e-9);
+        assert!((estimate["eta_secs"].as_f64().unwrap() - 30.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_overdue_items() {
+        let (app, storage) = setup_test_app();
+        let now = Utc::now();
+        for (offset, message) in [(-10, "stalled"), (-1, "recent"), (60, "scheduled")] {
+            let item = QueueItem {
+                datetime: now + Duration::minutes(offset),
+                message: message.to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+
+        for (query, expected) in [("?by=5m", vec!["stalled"]), ("", vec!["stalled", "recent"])] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/queue/overdue{query}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let items: Vec<QueueItem> = serde_json::from_str(&body_string(response).await).unwrap();
+            let messages: Vec<&str> = items.iter().map(|item| item.message.as_str()).collect();
+            assert_eq!(messages, expected, "query {query:?}");
+        }
+
+        for by in ["soon", "10000000000000s"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/queue/overdue?by={by}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "by={by}");
+            assert!(body_string(response).await.contains("InvalidParameter"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peek_unknown_queue() {
+        for empty_list in [false, true] {
+            let (app, storage) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                empty_list_for_unknown_queues: empty_list,
+                ..Default::default()
+            });
+
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/unknown/peek")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            if empty_list {
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(body_string(response).await, "[]");
+            } else {
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            }
+            assert!(!storage.queue_exists("unknown"));
+
+            // An existing but empty queue is always an empty list
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/queue/peek")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_string(response).await, "[]");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_head_queue_handler() {
+        let (app, storage) = setup_test_app();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("HEAD")
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Queue-Depth"], "0");
+        assert!(!response.headers().contains_key(header::LAST_MODIFIED));
+
+        let now = Utc::now();
+        for offset in 0..2 {
+            let item = QueueItem {
+                datetime: now + Duration::seconds(offset),
+                message: "queued".to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("HEAD")
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["X-Queue-Depth"], "2");
+        let last_modified = response.headers()[header::LAST_MODIFIED].to_str().unwrap();
+        assert!(last_modified.ends_with(" GMT"));
+        assert_eq!(body_string(response).await, "");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("HEAD")
+                    .uri("/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body_string(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_hide_item_handler() {
+        let (app, storage) = setup_test_app();
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let item = QueueItem {
+            datetime: now,
+            message: "deferred".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", item.clone()).unwrap();
+
+        let until = (now + Duration::hours(1)).timestamp_millis();
+        let uri = format!("/queue/hide?at={}&until={until}", now.timestamp_millis());
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(storage.export_items("queue").unwrap(), vec![item]);
+
+        let uri = format!("/queue/hide?at=1&until={until}");
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/hide?at=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_put_stream_handler() {
+        let now = Utc::now();
+        let line = |offset: i64| {
+            let item = QueueItem {
+                datetime: now + Duration::seconds(offset),
+                message: format!("item {offset}"),
+                ..Default::default()
+            };
+            item.to_json_string().unwrap()
+        };
+        // The last line has no trailing newline
+        let body = format!("{}\n{}\n\n{{broken\n{}", line(0), line(1), line(2));
+
+        for abort in [false, true] {
+            let (app, storage) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                stream_abort_on_error: abort,
+                ..Default::default()
+            });
+            // Deliver the body in small chunks that split lines
+            let chunks: Vec<Result<Bytes, std::io::Error>> = body
+                .as_bytes()
+                .chunks(7)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect();
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue/stream")
+                        .header("Content-Type", "application/x-ndjson")
+                        .body(Body::from_stream(futures_util::stream::iter(chunks)))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            let expected_status = if abort {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::OK
+            };
+            assert_eq!(response.status(), expected_status);
+            let summary: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            assert_eq!(summary["failed"], 1);
+            assert_eq!(summary["errors"][0]["line"], 4);
+            let expected_inserted = if abort { 2 } else { 3 };
+            assert_eq!(summary["inserted"], expected_inserted);
+            assert_eq!(storage.count_items("queue").unwrap(), expected_inserted);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ready_after_startup() {
+        let startup = Startup::default();
+        let app = startup_router(startup.clone());
+        let send = |uri: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("GET")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        // A slow storage initialization, finishing in the background
+        let opening = startup.clone();
+        let init = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let (app, _) = setup_test_app();
+            opening.finish(app);
+        });
+
+        let response = send("/_ready").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_string(response).await.contains("starting"));
+        let response = send("/queue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_string(response).await.contains("NotReady"));
+
+        init.await.unwrap();
+        let response = send("/_ready").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_string(response).await.contains("ready"));
+        assert_eq!(
+            send("/queue").await.unwrap().status(),
+            StatusCode::NO_CONTENT
+        );
+    }
+
+    #[tokio::test]
+    async fn test_content_ids() {
+        let item = QueueItem {
+            datetime: DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap(),
+            message: "hello".to_string(),
+            ..Default::default()
+        };
+        let request = |method: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/queue")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            content_ids: true,
+            ..Default::default()
+        });
+        storage.put_item("queue", item.clone()).unwrap();
+        for method in ["GET", "DELETE"] {
+            let response = app.clone().oneshot(request(method)).await.unwrap();
+            assert_eq!(response.headers()["X-Item-Id"], item.content_id().as_str());
+            let body: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            assert_eq!(body["content_id"], item.content_id());
+            assert_eq!(body["message"], "hello");
+        }
+
+        let (app, storage) = setup_test_app();
+        storage.put_item("queue", item).unwrap();
+        let response = app.oneshot(request("GET")).await.unwrap();
+        assert!(!response.headers().contains_key("X-Item-Id"));
+        assert!(!body_string(response).await.contains("content_id"));
+    }
+
+    #[tokio::test]
+    async fn test_tenant_filter() {
+        let (app, storage) = setup_test_app();
+        let base = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let items: Vec<QueueItem> = [Some("acme"), Some("globex"), None, Some("acme")]
+            .into_iter()
+            .enumerate()
+            .map(|(i, tenant)| QueueItem {
+                datetime: base + Duration::seconds(i as i64),
+                message: format!("job {i}"),
+                tenant: tenant.map(str::to_string),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items.clone()).unwrap();
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/queue?tenant=globex"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            QueueItem::from_json_string(&body_string(response).await).unwrap(),
+            items[1]
+        );
+
+        // Each tenant claims only its own items, in datetime order
+        for expected in [&items[0], &items[3]] {
+            let response = app
+                .clone()
+                .oneshot(request("DELETE", "/queue?tenant=acme"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                QueueItem::from_json_string(&body_string(response).await).unwrap(),
+                *expected
+            );
+        }
+        let response = app
+            .clone()
+            .oneshot(request("DELETE", "/queue?tenant=acme"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            storage.export_items("queue").unwrap(),
+            vec![items[1].clone(), items[2].clone()]
+        );
+
+        // Without a filter the head is taken regardless of tenant
+        let response = app.oneshot(request("DELETE", "/queue")).await.unwrap();
+        assert_eq!(
+            QueueItem::from_json_string(&body_string(response).await).unwrap(),
+            items[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_subscribers_per_queue() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string(), "other".to_string()],
+            max_subscribers_per_queue: Some(1),
+            ..Default::default()
+        });
+        let stream_request = |queue: &str, body: Body| {
+            Request::builder()
+                .method("PUT")
+                .uri(format!("/{queue}/stream"))
+                .body(body)
+                .unwrap()
+        };
+
+        // A stream whose body stays open until the sender is dropped
+        let (sender, receiver) = mpsc::channel::<Bytes>(1);
+        let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            let chunk = receiver.recv().await?;
+            Some((Ok::<_, std::io::Error>(chunk), receiver))
+        });
+        let open = tokio::spawn(
+            app.clone()
+                .oneshot(stream_request("queue", Body::from_stream(chunks))),
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let response = app
+            .clone()
+            .oneshot(stream_request("queue", Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body_string(response).await.contains("TooManySubscribers"));
+        // Other queues have their own limit
+        let response = app
+            .clone()
+            .oneshot(stream_request("other", Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The open stream keeps working, and its slot is released when it ends
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "streamed".to_string(),
+            ..Default::default()
+        };
+        let line = format!("{}\n", item.to_json_string().unwrap());
+        sender.send(Bytes::from(line)).await.unwrap();
+        drop(sender);
+        let response = open.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+
+        let response = app
+            .oneshot(stream_request("queue", Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rank_of_key_handler() {
+        let (app, storage) = setup_test_app();
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        for offset in 0..3 {
+            let item = QueueItem {
+                datetime: now + Duration::seconds(offset),
+                datetime_secondary: Some(now),
+                message: "queued".to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+
+        let at = (now + Duration::seconds(2)).timestamp_millis();
+        let secondary = now.timestamp_millis();
+        let uri = format!("/queue/rank?at={at}&secondary={secondary}");
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, r#"{"rank":2}"#);
+
+        // Without the secondary datetime it names a different, absent key
+        let uri = format!("/queue/rank?at={at}");
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_put_status_with_rest_semantics() {
+        for rest_semantics in [false, true] {
+            let (app, _) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                rest_semantics,
+                ..Default::default()
+            });
+            let item = QueueItem {
+                datetime: Utc::now(),
+                message: "test message".to_string(),
+                ..Default::default()
+            };
+
+            let mut statuses = Vec::new();
+            for _ in 0..2 {
+                let response = app
+                    .clone()
+                    .oneshot(
+                        Request::builder()
+                            .method("PUT")
+                            .uri("/queue")
+                            .body(Body::from(item.to_json_string().unwrap()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                statuses.push(response.status());
+            }
+
+            let created = if rest_semantics {
+                StatusCode::CREATED
+            } else {
+                StatusCode::OK
+            };
+            assert_eq!(statuses, [created, StatusCode::OK]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raw_mode_uses_queue_content_type() {
+        let mut config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "queue".to_string(),
+            dtqueue::QueueOptions {
+                content_type: Some("application/xml".to_string()),
+                ..Default::default()
+            },
+        );
+        let (app, storage) = setup_test_app_with(config);
+        let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let item = QueueItem {
+            datetime: now,
+            message: "<order id=\"1\"/>".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", item.clone()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        assert_eq!(
+            response.headers()["X-Message-Content-Type"],
+            "application/xml"
+        );
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("DELETE")
+                    .uri("/queue?raw=true")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "application/xml");
+        let datetime = response.headers()["X-Item-Datetime"].to_str().unwrap();
+        assert_eq!(DateTime::parse_from_rfc3339(datetime).unwrap(), now);
+        assert_eq!(body_string(response).await, item.message);
+        assert_eq!(storage.get_item("queue").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_fires_on_put() {
+        // A local receiver forwarding every delivery to the test
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let receiver = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| async move {
+                let signature = headers["X-Dtqueue-Signature"].to_str().unwrap().to_string();
+                sender.send((signature, body)).unwrap();
+                StatusCode::OK
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let mut config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "queue".to_string(),
+            dtqueue::QueueOptions {
+                webhook: Some(dtqueue::WebhookConfig {
+                    url: format!("http://{address}/hook"),
+                    events: vec![
+                        dtqueue::WebhookEvent::Enqueue,
+                        dtqueue::WebhookEvent::DepthThreshold,
+                    ],
+                    secret: Some("secret".to_string()),
+                    depth_threshold: Some(1),
+                }),
+                ..Default::default()
+            },
+        );
+        let (app, _) = setup_test_app_with(config);
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "hooked".to_string(),
+            ..Default::default()
+        };
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let (signature, body) =
+                tokio::time::timeout(std::time::Duration::from_secs(5), received.recv())
+                    .await
+                    .expect("webhook was not delivered")
+                    .unwrap();
+            assert_eq!(
+                signature,
+                dtqueue::webhooks::signature("secret", body.as_bytes())
+            );
+            let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(payload["queue"], "queue");
+            events.push(payload);
+        }
+        // Deliveries are concurrent, so either may arrive first
+        events.sort_by_key(|payload| payload["event"].as_str().unwrap().to_string());
+        assert_eq!(events[0]["event"], "depth_threshold");
+        assert_eq!(events[0]["depth"], 1);
+        assert_eq!(events[1]["event"], "enqueue");
+        assert_eq!(events[1]["item"]["message"], "hooked");
+    }
+
+    #[tokio::test]
+    async fn test_ip_filter() {
+        let (app, _) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            allowed_ips: vec!["127.0.0.0/8".parse().unwrap()],
+            denied_ips: vec!["127.0.0.13/32".parse().unwrap()],
+            ip_filter_exempt_paths: vec!["/queue/peek".to_string()],
+            ..Default::default()
+        });
+        let get_from = |peer: &str, uri: &str| {
+            let peer: SocketAddr = peer.parse().unwrap();
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            MockConnectInfo(peer).layer(app.clone()).oneshot(request)
+        };
+
+        let response = get_from("127.0.0.1:40000", "/queue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = get_from("127.0.0.13:40000", "/queue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(body_string(response).await.contains("Forbidden"));
+
+        let response = get_from("192.0.2.1:40000", "/queue").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = get_from("192.0.2.1:40000", "/queue/peek").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_peek_prefix() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: [
+                "orders_eu",
+                "orders_us",
+                "orders_asia",
+                "ordersx",
+                "returns_eu",
+            ]
+            .map(String::from)
+            .to_vec(),
+            ..Default::default()
+        });
+        let base = Utc::now();
+        for (queue, offset) in [("orders_eu", 2), ("orders_eu", 1), ("orders_us", 5)] {
+            let item = QueueItem {
+                datetime: base + Duration::seconds(offset),
+                message: format!("{queue} {offset}"),
+                ..Default::default()
+            };
+            storage.put_item(queue, item).unwrap();
+        }
+        for queue in ["ordersx", "returns_eu"] {
+            let item = QueueItem {
+                datetime: base,
+                message: "other".to_string(),
+                ..Default::default()
+            };
+            storage.put_item(queue, item).unwrap();
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/_prefix/orders/peek")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let heads: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        let heads = heads.as_object().unwrap();
+        assert_eq!(
+            heads.keys().collect::<Vec<_>>(),
+            ["orders_asia", "orders_eu", "orders_us"]
+        );
+        assert!(heads["orders_asia"].is_null());
+        assert_eq!(heads["orders_eu"]["message"], "orders_eu 1");
+        assert_eq!(heads["orders_us"]["message"], "orders_us 5");
+    }
+
+    #[tokio::test]
+    async fn test_read_only_rejects_writes() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            read_only: true,
+            ..Default::default()
+        });
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "existing".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", item.clone()).unwrap();
+        let send = |method: &str, uri: &str, body: String| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::from(body))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let json = item.to_json_string().unwrap();
+        for (method, uri, body) in [
+            ("PUT", "/queue", json.clone()),
+            ("PUT", "/queue/stream", json),
+            ("DELETE", "/queue", String::new()),
+            ("POST", "/_restore", String::new()),
+        ] {
+            let response = send(method, uri, body).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{method} {uri}");
+            assert!(body_string(response).await.contains("ReadOnly"));
+        }
+
+        for (method, uri) in [
+            ("GET", "/queue"),
+            ("GET", "/queue/peek"),
+            ("HEAD", "/queue"),
+        ] {
+            let response = send(method, uri, String::new()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK, "{method} {uri}");
+        }
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_delete() {
+        let (app, storage) = setup_test_app();
+        let base = Utc::now();
+        let items: Vec<QueueItem> = (0..2)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: format!("item {i}"),
+                ..Default::default()
+            })
+            .collect();
+        for item in &items {
+            storage.put_item("queue", item.clone()).unwrap();
+        }
+        let cas_delete = |key: &QueueItem| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/queue/cas-delete")
+                .body(Body::from(serde_json::to_string(&key.key()).unwrap()))
+                .unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = cas_delete(&items[1]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(body_string(response).await.contains("HeadChanged"));
+        assert_eq!(storage.count_items("queue").unwrap(), 2);
+
+        let response = cas_delete(&items[0]).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let deleted = QueueItem::from_json_string(&body_string(response).await).unwrap();
+        assert_eq!(deleted.message, "item 0");
+        let head = storage.get_item("queue").unwrap().unwrap();
+        assert_eq!(head.message, "item 1");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_increments() {
+        let (app, storage) = setup_test_app();
+        let at = Utc::now().timestamp_millis();
+
+        let requests = (0..50).map(|i| {
+            let request = Request::builder()
+                .method("POST")
+                .uri(format!("/queue/incr?at={at}&by={i}"))
+                .body(Body::empty())
+                .unwrap();
+            tokio::spawn(app.clone().oneshot(request))
+        });
+        for request in requests.collect::<Vec<_>>() {
+            let response = request.await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let head = storage.get_item("queue").unwrap().unwrap();
+        assert_eq!(head.message, (0..50).sum::<i64>().to_string());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("/queue/incr?at={at}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(body_string(response).await, r#"{"value":1226}"#);
+    }
+
+    #[tokio::test]
+    async fn test_require_message() {
+        let datetime = Utc::now().to_rfc3339();
+        let body = format!(r#"{{"datetime": "{datetime}"}}"#);
+
+        for (require_message, status) in [(true, StatusCode::BAD_REQUEST), (false, StatusCode::OK)]
+        {
+            let (app, storage) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                require_message,
+                ..Default::default()
+            });
+            for uri in ["/queue", "/queue/stream"] {
+                let response = app
+                    .clone()
+                    .oneshot(
+                        Request::builder()
+                            .method("PUT")
+                            .uri(uri)
+                            .body(Body::from(body.clone()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                if uri == "/queue" {
+                    assert_eq!(response.status(), status);
+                }
+            }
+            let stored = storage.count_items("queue").unwrap();
+            assert_eq!(stored, if require_message { 0 } else { 1 });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_datetime_precision() {
+        let mut config = AppConfig {
+            queues: vec!["strict".to_string(), "queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "strict".to_string(),
+            QueueOptions {
+                strict_datetime_precision: true,
+                ..Default::default()
+            },
+        );
+        let (app, storage) = setup_test_app_with(config);
+        let put = |uri: &str, datetime: &str| {
+            let body = format!(r#"{{"datetime": "{datetime}", "message": "job"}}"#);
+            app.clone().oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let response = put("/strict", "2024-06-01T12:00:00.123456Z").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("milliseconds"));
+        // Millisecond precision and any offset are accepted
+        let response = put("/strict", "2024-06-01T14:00:00.123+02:00")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // Other queues truncate
+        let response = put("/queue", "2024-06-01T12:00:00.123456Z").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let expected = DateTime::parse_from_rfc3339("2024-06-01T12:00:00.123Z").unwrap();
+        for queue in ["strict", "queue"] {
+            let stored = storage.export_items(queue).unwrap();
+            assert_eq!(stored.len(), 1, "{queue}");
+            assert_eq!(stored[0].datetime, expected, "{queue}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_datetimes_beyond_four_digit_years_are_rejected() {
+        let (app, storage) = setup_test_app();
+        for (datetime, secondary, status) in [
+            ("-0001-12-31T23:59:59.999Z", None, StatusCode::BAD_REQUEST),
+            ("+10000-01-01T00:00:00Z", None, StatusCode::BAD_REQUEST),
+            (
+                "2024-06-01T12:00:00Z",
+                Some("+10000-01-01T00:00:00Z"),
+                StatusCode::BAD_REQUEST,
+            ),
+            ("0000-01-01T00:00:00Z", None, StatusCode::OK),
+            ("9999-12-31T23:59:59.999Z", None, StatusCode::OK),
+        ] {
+            let secondary = secondary
+                .map(|secondary| format!(r#", "datetime_secondary": "{secondary}""#))
+                .unwrap_or_default();
+            let body = format!(r#"{{"datetime": "{datetime}"{secondary}, "message": "job"}}"#);
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue")
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status, "{datetime}");
+        }
+        assert_eq!(storage.count_items("queue").unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_async_write_ack() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            async_write_ack: true,
+            ..Default::default()
+        });
+        let datetime = Utc::now().to_rfc3339();
+
+        for i in 0..20 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue")
+                        .body(Body::from(format!(
+                            r#"{{"datetime": "{datetime}", "datetime_secondary": "{}", "message": "{i}"}}"#,
+                            (Utc::now() + Duration::milliseconds(i)).to_rfc3339()
+                        )))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+            let receipt: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            assert!(receipt["datetime"].is_string());
+        }
+
+        // The writer stores the accepted items shortly afterwards
+        for _ in 0..100 {
+            if storage.count_items("queue").unwrap() == 20 {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("buffered items were not stored");
+    }
+
+    #[tokio::test]
+    async fn test_key_by_id() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            queue_options: std::collections::HashMap::from([(
+                "queue".to_string(),
+                dtqueue::QueueOptions {
+                    key_by: KeyBy::Id,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+        let now = Utc::now();
+        let put = |body: String| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        for (offset, message) in [(60, "first"), (30, "second")] {
+            let datetime = (now + Duration::seconds(offset)).to_rfc3339();
+            let body =
+                format!(r#"{{"datetime": "{datetime}", "id": "evt-1", "message": "{message}"}}"#);
+            assert_eq!(put(body).await.unwrap().status(), StatusCode::OK);
+        }
+        let stored = storage.export_items("queue").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].message, "second");
+        assert_eq!(stored[0].id.as_deref(), Some("evt-1"));
+
+        let response = put(format!(r#"{{"datetime": "{}"}}"#, now.to_rfc3339()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response).await.contains("MissingId"));
+    }
+
+    #[tokio::test]
+    async fn test_recurring_item_reappears() {
+        let (app, storage) = setup_test_app();
+        let datetime = "2024-06-01T12:00:00Z";
+        let send = |method: &str, body: String| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri("/queue")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+        };
+
+        let body = format!(
+            r#"{{"datetime": "{datetime}", "recurrence_interval_secs": 0, "message": "tick"}}"#
+        );
+        let response = send("PUT", body).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            body_string(response)
+                .await
+                .contains("recurrence_interval_secs")
+        );
+
+        let body = format!(
+            r#"{{"datetime": "{datetime}", "recurrence_interval_secs": 60, "recurrence_until": "2024-06-01T12:01:00Z", "message": "tick"}}"#
+        );
+        assert_eq!(send("PUT", body).await.unwrap().status(), StatusCode::OK);
+        let mut consumed = Vec::new();
+        loop {
+            let response = send("DELETE", String::new()).await.unwrap();
+            if response.status() == StatusCode::NO_CONTENT {
+                break;
+            }
+            let item: QueueItem = serde_json::from_str(&body_string(response).await).unwrap();
+            assert_eq!(item.recurrence_interval_secs, Some(60));
+            consumed.push(item.datetime.to_rfc3339());
+        }
+        assert_eq!(
+            consumed,
+            ["2024-06-01T12:00:00+00:00", "2024-06-01T12:01:00+00:00"]
+        );
+        assert_eq!(storage.count_items("queue").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_multibyte_message_length() {
+        use axum::body::HttpBody;
+
+        let (app, _storage) = setup_test_app();
+        let message = "héllo, 世界 🌍";
+        let body = format!(r#"{{"datetime": "2024-01-01T00:00:00Z", "message": "{message}"}}"#);
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for uri in ["/queue?raw=true", "/queue"] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            // The router derives the length from the body, which counts bytes
+            let length = response.headers()["Content-Length"]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(response.body().size_hint().exact(), length.parse().ok());
+            let body = body_string(response).await;
+            assert!(body.contains(message));
+            assert_eq!(length, body.len().to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_stream() {
+        let (app, storage) = setup_test_app();
+        storage
+            .put_item(
+                "queue",
+                QueueItem::from_json_string(r#"{"datetime": "2024-01-01T00:00:00Z"}"#).unwrap(),
+            )
+            .unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/_metrics/stream")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+        let mut frames = response.into_body().into_data_stream();
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(5), frames.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(frame.starts_with("event: metrics\n"), "{frame}");
+        let data = frame
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let metrics: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert!(metrics["t"].is_string());
+        assert_eq!(metrics["queues"]["queue"]["valid"], 1);
+        assert_eq!(metrics["queues"]["queue"]["invalid"], 0);
+
+        // Disconnecting drops the stream along with its handle on the storage
+        let references = Arc::strong_count(&storage);
+        drop(frames);
+        assert_eq!(Arc::strong_count(&storage), references - 1);
+    }
+
+    #[tokio::test]
+    async fn test_protobuf_items() {
+        let (app, _storage) = setup_test_app();
+        let item = QueueItem::from_json_string(
+            r#"{"datetime": "2024-01-01T00:00:00Z", "message": "proto"}"#,
+        )
+        .unwrap();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .header("Content-Type", PROTOBUF)
+                    .body(Body::from(item.to_protobuf()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // JSON stays the default
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["Content-Type"], "application/json");
+        for method in ["GET", "DELETE"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri("/queue")
+                        .header("Accept", PROTOBUF)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.headers()["Content-Type"], PROTOBUF);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            assert_eq!(QueueItem::from_protobuf(&body).unwrap(), item);
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .header("Content-Type", PROTOBUF)
+                    .body(Body::from(vec![0xff]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_queue_stats_report_ordering() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string(), "events".to_string()],
+            queue_options: std::collections::HashMap::from([(
+                "events".to_string(),
+                dtqueue::QueueOptions {
+                    key_by: KeyBy::Id,
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        });
+        storage
+            .put_item(
+                "queue",
+                QueueItem::from_json_string(r#"{"datetime": "2024-01-01T00:00:00Z"}"#).unwrap(),
+            )
+            .unwrap();
+        let stats = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap()
+            }
+        };
+
+        let queue = stats("/queue/stats").await;
+        assert_eq!(queue["valid"], 1);
+        assert!(queue["last_modified"].is_string());
+        assert_eq!(
+            queue["ordering"],
+            serde_json::json!({"direction": "asc", "secondary_nulls": "first", "key_by": "datetime"})
+        );
+        let events = stats("/events/stats").await;
+        assert_eq!(events["valid"], 0);
+        assert_eq!(events["ordering"]["key_by"], "id");
+    }
+
+    #[tokio::test]
+    async fn test_stale_queue() {
+        let config = AppConfig {
+            queues: vec!["queue".to_string(), "heartbeat".to_string()],
+            queue_options: std::collections::HashMap::from([(
+                "heartbeat".to_string(),
+                dtqueue::QueueOptions {
+                    stale_after_secs: Some(1),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        };
+        let (app, storage) = setup_test_app_with(config.clone());
+        let stats = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap()
+            }
+        };
+        let beat = || {
+            storage
+                .put_item(
+                    "heartbeat",
+                    QueueItem::from_json_string(r#"{"datetime": "2024-01-01T00:00:00Z"}"#).unwrap(),
+                )
+                .unwrap()
+        };
+
+        beat();
+        assert_eq!(stats("/heartbeat/stats").await["stale"], false);
+        assert!(stats("/queue/stats").await.get("stale").is_none());
+
+        // Silence past the interval is reported, and the next write clears it
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        assert_eq!(stats("/heartbeat/stats").await["stale"], true);
+        let activity = Activity::default();
+        let metrics = maintenance::collect_metrics(storage.as_ref(), &config, &activity);
+        assert_eq!(
+            metrics.stale,
+            BTreeMap::from([("heartbeat".to_string(), true)])
+        );
+        beat();
+        assert_eq!(stats("/heartbeat/stats").await["stale"], false);
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        for pause_blocks_reads in [false, true] {
+            let (app, _storage) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                pause_blocks_reads,
+                ..Default::default()
+            });
+            let send = |method: &'static str, uri: &'static str| {
+                let body = if method == "PUT" {
+                    Body::from(r#"{"datetime": "2024-01-01T00:00:00Z"}"#)
+                } else {
+                    Body::empty()
+                };
+                app.clone().oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .body(body)
+                        .unwrap(),
+                )
+            };
+            let health = |response: Response| async {
+                let health: serde_json::Value =
+                    serde_json::from_str(&body_string(response).await).unwrap();
+                health["paused"].as_bool().unwrap()
+            };
+
+            let response = send("POST", "/_pause").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            let response = send("PUT", "/queue").await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert!(body_string(response).await.contains("ServicePaused"));
+            let read = send("GET", "/queue").await.unwrap().status();
+            if pause_blocks_reads {
+                assert_eq!(read, StatusCode::SERVICE_UNAVAILABLE);
+            } else {
+                assert_eq!(read, StatusCode::NO_CONTENT);
+            }
+            assert!(health(send("GET", "/_health").await.unwrap()).await);
+
+            let response = send("POST", "/_resume").await.unwrap();
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            assert_eq!(
+                send("PUT", "/queue").await.unwrap().status(),
+                StatusCode::OK
+            );
+            assert_eq!(
+                send("GET", "/queue").await.unwrap().status(),
+                StatusCode::OK
+            );
+            assert!(!health(send("GET", "/_health").await.unwrap()).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_payload() {
+        let (app, _storage) = setup_test_app();
+        let body = r#"{"datetime": "2024-01-01T00:00:00Z", "message": "1",
+            "payloads": {"json": "{\"n\":1}", "text": "n is 1"}}"#;
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/queue")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let get = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/queue").await.unwrap();
+        let item: QueueItem = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(item.payloads.unwrap().len(), 2);
+
+        let response = get("/queue?payload=text").await.unwrap();
+        assert_eq!(response.headers()["X-Payload"], "text");
+        let item: QueueItem = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!((item.message.as_str(), item.payloads), ("n is 1", None));
+
+        let response = get("/queue?payload=json&raw=true").await.unwrap();
+        assert_eq!(body_string(response).await, r#"{"n":1}"#);
+
+        // An unknown payload falls back to the message
+        let response = get("/queue?payload=xml&raw=true").await.unwrap();
+        assert!(response.headers().get("X-Payload").is_none());
+        assert_eq!(body_string(response).await, "1");
+    }
+
+    #[tokio::test]
+    async fn test_secondary_policy() {
+        let datetime = Utc::now().to_rfc3339();
+        let without = format!(r#"{{"datetime": "{datetime}", "message": "a"}}"#);
+        let with = format!(
+            r#"{{"datetime": "{datetime}", "datetime_secondary": "{datetime}", "message": "b"}}"#
+        );
+
+        for (policy, accepts_without, accepts_with) in [
+            (dtqueue::SecondaryPolicy::Optional, true, true),
+            (dtqueue::SecondaryPolicy::Required, false, true),
+            (dtqueue::SecondaryPolicy::Forbidden, true, false),
+        ] {
+            let (app, storage) = setup_test_app_with(AppConfig {
+                queues: vec!["queue".to_string()],
+                queue_options: std::collections::HashMap::from([(
+                    "queue".to_string(),
+                    dtqueue::QueueOptions {
+                        datetime_secondary: policy,
+                        ..Default::default()
+                    },
+                )]),
+                ..Default::default()
+            });
+            for (body, accepted) in [(&without, accepts_without), (&with, accepts_with)] {
+                let response = app
+                    .clone()
+                    .oneshot(
+                        Request::builder()
+                            .method("PUT")
+                            .uri("/queue")
+                            .body(Body::from(body.clone()))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                if accepted {
+                    assert_eq!(response.status(), StatusCode::OK, "{policy:?}");
+                } else {
+                    assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{policy:?}");
+                    assert!(
+                        body_string(response)
+                            .await
+                            .contains("SecondaryPolicyViolation")
+                    );
+                }
+            }
+
+            // Streamed lines are held to the same policy, replacing the items put above
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method("PUT")
+                        .uri("/queue/stream")
+                        .body(Body::from(format!("{without}\n{with}\n")))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let summary: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            let rejected = [accepts_without, accepts_with]
+                .iter()
+                .filter(|accepted| !**accepted)
+                .count();
+            assert_eq!(summary["failed"], rejected, "{policy:?}");
+            assert_eq!(storage.count_items("queue").unwrap(), 2 - rejected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_claim_any_is_fair() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: ["a", "b", "c"].map(String::from).to_vec(),
+            ..Default::default()
+        });
+        // Every queue's items are due at the same instants, but a receives the most
+        let base = Utc::now() - Duration::hours(1);
+        for (queue, count) in [("a", 6), ("b", 3), ("c", 1)] {
+            for i in 0..count {
+                let item = QueueItem {
+                    datetime: base,
+                    datetime_secondary: Some(base + Duration::seconds(i)),
+                    message: format!("{queue}{i}"),
+                    ..Default::default()
+                };
+                storage.put_item(queue, item).unwrap();
+            }
+        }
+
+        let mut claimed = Vec::new();
+        loop {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/claim-any?queues=a,b,c")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            if response.status() == StatusCode::NO_CONTENT {
+                break;
+            }
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_str(&body_string(response).await).unwrap();
+            claimed.push(body["message"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            claimed,
+            ["a0", "b0", "c0", "a1", "b1", "a2", "b2", "a3", "a4", "a5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_order() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: ["a", "b", "c"].map(String::from).to_vec(),
+            ..Default::default()
+        });
+        let now = Utc::now();
+        for (queue, offset, message) in [
+            ("a", -30, "a late"),
+            ("a", 60, "a future"),
+            ("b", -60, "b early"),
+            ("b", -10, "b recent"),
+            ("c", -45, "c middle"),
+            ("c", 30, "c future"),
+        ] {
+            let item = QueueItem {
+                datetime: now + Duration::minutes(offset),
+                message: message.to_string(),
+                ..Default::default()
+            };
+            storage.put_item(queue, item).unwrap();
+        }
+        let dispatch = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                if response.status() == StatusCode::NO_CONTENT {
+                    return None;
+                }
+                assert_eq!(response.status(), StatusCode::OK);
+                let body: serde_json::Value =
+                    serde_json::from_str(&body_string(response).await).unwrap();
+                let queue = body["queue"].as_str().unwrap().to_string();
+                Some((queue, body["item"]["message"].as_str().unwrap().to_string()))
+            }
+        };
+
+        // The listed subset only
+        assert_eq!(
+            dispatch("/_dispatch?queues=a,c").await,
+            Some(("c".to_string(), "c middle".to_string()))
+        );
+        let mut dispatched = Vec::new();
+        while let Some((queue, message)) = dispatch("/_dispatch?due_only=true").await {
+            dispatched.push(format!("{queue}: {message}"));
+        }
+        assert_eq!(dispatched, ["b: b early", "a: a late", "b: b recent"]);
+
+        // Items not yet due are handed out only on request, still earliest first
+        assert_eq!(
+            dispatch("/_dispatch?due_only=false").await,
+            Some(("c".to_string(), "c future".to_string()))
+        );
+        assert_eq!(
+            dispatch("/_dispatch?due_only=false").await,
+            Some(("a".to_string(), "a future".to_string()))
+        );
+        assert_eq!(dispatch("/_dispatch?due_only=false").await, None);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/_dispatch?queues=a,..")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_pretty_responses() {
+        let (app, storage) = setup_test_app();
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "readable".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", item).unwrap();
+
+        for uri in ["/queue?pretty=true", "/queue/peek?pretty=true"] {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["Content-Type"], "application/json");
+            let body = body_string(response).await;
+            assert!(body.contains("\n  "), "{uri} is not indented: {body}");
+            let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert!(body.contains("readable"), "{parsed}");
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(!body_string(response).await.contains('\n'));
+    }
+
+    #[tokio::test]
+    async fn test_items_exist() {
+        let (app, storage) = setup_test_app();
+        let base = Utc::now();
+        let items: Vec<QueueItem> = (0..3)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: "scheduled".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items[..2].to_vec()).unwrap();
+        // The head is consumed, the last key never existed
+        storage.delete_item("queue").unwrap();
+
+        let keys: Vec<QueueKey> = items.iter().map(QueueItem::key).collect();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/exists")
+                    .body(Body::from(serde_json::to_string(&keys).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "[false,true,false]");
+    }
+
+    #[tokio::test]
+    async fn test_extend_hidden_handler() {
+        let (app, storage) = setup_test_app();
+        let base = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap()
+            - Duration::hours(1);
+        let items: Vec<QueueItem> = (0..3)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: "slow".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items.clone()).unwrap();
+        // A worker claims the first two with a short timeout; the third was never held
+        let timeout = Utc::now() + Duration::milliseconds(300);
+        for item in &items[..2] {
+            storage.hide_item("queue", &item.key(), timeout).unwrap();
+        }
+
+        let keys: Vec<QueueKey> = items.iter().map(QueueItem::key).collect();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/extend?secs=60")
+                    .body(Body::from(serde_json::to_string(&keys).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let result: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(result["held"], serde_json::to_value(&keys[..2]).unwrap());
+        assert_eq!(result["lost"], serde_json::to_value(&keys[2..]).unwrap());
+
+        // Past the original timeout the claimed items are still hidden
+        tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+        storage.delete_item("queue").unwrap();
+        assert_eq!(storage.export_items("queue").unwrap(), items[..2].to_vec());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/extend")
+                    .body(Body::from("[]"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_shift_items_handler() {
+        let (app, storage) = setup_test_app();
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let mut items: Vec<QueueItem> = (0..3)
+            .map(|i| QueueItem {
+                datetime: base + Duration::minutes(i),
+                datetime_secondary: Some(base),
+                message: format!("late {i}"),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items.clone()).unwrap();
+
+        let shift = |uri: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(shift("/queue/shift?by_secs=3600"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, r#"{"shifted":3}"#);
+        // Only the primary datetimes move unless asked otherwise
+        for item in &mut items {
+            item.datetime += Duration::hours(1);
+        }
+        assert_eq!(storage.export_items("queue").unwrap(), items);
+
+        for uri in ["/queue/shift", "/queue/shift?by_secs=99999999999999999"] {
+            let response = app.clone().oneshot(shift(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+        }
+        assert_eq!(storage.export_items("queue").unwrap(), items);
+    }
+
+    #[tokio::test]
+    async fn test_export_formats() {
+        let (app, storage) = setup_test_app();
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let items = vec![
+            QueueItem {
+                datetime: base,
+                message: r#"a, "quoted" message"#.to_string(),
+                ..Default::default()
+            },
+            QueueItem {
+                datetime: base + Duration::milliseconds(1500),
+                datetime_secondary: Some(base),
+                message: "two\nlines,\r\nand a comma".to_string(),
+                ..Default::default()
+            },
+        ];
+        storage.put_items("queue", items.clone()).unwrap();
+        let export = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(export("/queue/export?format=csv"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/csv");
+        let body = body_string(response).await;
+        let mut reader = csv::Reader::from_reader(body.as_bytes());
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec!["datetime", "datetime_secondary", "message"]
+        );
+        let parsed: Vec<QueueItem> = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let datetime = |cell: &str| cell.parse::<DateTime<Utc>>().unwrap();
+                QueueItem {
+                    datetime: datetime(&record[0]),
+                    datetime_secondary: (!record[1].is_empty()).then(|| datetime(&record[1])),
+                    message: record[2].to_string(),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        assert_eq!(parsed, items);
+
+        // NDJSON is the default
+        let response = app.clone().oneshot(export("/queue/export")).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let parsed: Vec<QueueItem> = body_string(response)
+            .await
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(parsed, items);
+
+        let response = app
+            .clone()
+            .oneshot(export("/queue/export?format=xml"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Without a byte limit, an export reads on past the first batch from storage
+        let more: Vec<QueueItem> = (0..2 * EXPORT_BATCH as i64)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(2 + i),
+                message: i.to_string(),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", more).unwrap();
+        let response = app.oneshot(export("/queue/export")).await.unwrap();
+        assert!(!response.headers().contains_key("X-Next-Cursor"));
+        assert_eq!(
+            body_string(response).await.lines().count(),
+            2 * EXPORT_BATCH + 2
+        );
+    }
+
+    #[test]
+    fn test_pretty_list_entries_size_the_list() {
+        let base = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let items: Vec<QueueItem> = (0..3)
+            .map(|i| QueueItem {
+                datetime: base + Duration::seconds(i),
+                message: "a\nmessage".to_string(),
+                payloads: Some(BTreeMap::from([("json".to_string(), format!("[{i}]"))])),
+                ..Default::default()
+            })
+            .collect();
+        let encoded = encode_within(&items, None, 2, 2, pretty_list_entry).unwrap();
+        let size = 2 + encoded.iter().map(|entry| entry.len() + 2).sum::<usize>();
+        assert_eq!(size, serde_json::to_string_pretty(&items).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_percent_encoded_queue_names() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["abc".to_string()],
+            ..Default::default()
+        });
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "decoded".to_string(),
+            ..Default::default()
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/%61b%63")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(storage.count_items("abc").unwrap(), 1);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/%2561bc")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body = body_string(response).await;
+        assert!(body.contains("InvalidQueueName"));
+        assert!(body.contains("%61bc is still percent-encoded"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_queue_names_ignore_case() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["MyQueue".to_string()],
+            auto_create_queues: true,
+            ..Default::default()
+        });
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "any case".to_string(),
+            ..Default::default()
+        };
+        assert!(storage.queue_exists("myqueue"));
 
-    match storage.put_item(&queue, item.clone()) {
-        Ok(_) => {
-            info!("append to queue {queue} successful, the item is {item:?}");
-            StatusCode::OK.into_response()
-        }
-        Err(e) => {
-            error!("Failed to append {item:?} to '{queue}': {e}");
-            utils::json_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "InternalError",
-                &format!("Failed to append item to queue {queue}: {e}"),
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/MYqueue")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
             )
-        }
-    }
-}
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(storage.queue_names(), ["myqueue"]);
 
-pub async fn get_item(
-    State(storage): State<Arc<dyn Storage>>,
-    Path(queue): Path<String>,
-) -> Response {
-    if !storage.queue_exists(&queue) {
-        warn!("Invalid queue name attempted: {queue}");
-        return utils::json_error(
-            StatusCode::FORBIDDEN,
-            "InvalidQueueName",
-            &format!("Invalid queue name attempted: {queue}"),
-        );
-    }
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/MyQueue")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let retrieved = QueueItem::from_json_string(&body_string(response).await).unwrap();
+        assert_eq!(retrieved.message, "any case");
 
-    match storage.get_item(&queue) {
-        Ok(Some(item)) => {
-            let body = item.to_json_string().unwrap();
-            info!("retrieve from queue {queue}, got {item:?}");
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .header("Content-Length", body.len().to_string())
-                .body(body.into())
-                .unwrap()
-        }
-        Ok(None) => {
-            info!("retrieve from queue {queue}, the queue is empty");
-            StatusCode::NO_CONTENT.into_response()
-        }
-        Err(e) => {
-            error!("Failed to get item from '{queue}': {e}");
-            utils::json_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "InternalError",
-                &format!("Failed to get item from queue {queue}: {e}"),
+        // An auto-created queue answers to every case as well
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri("/Fresh")
+                    .body(Body::from(item.to_json_string().unwrap()))
+                    .unwrap(),
             )
-        }
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/fRESH")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
-}
 
-pub async fn delete_item(
-    State(storage): State<Arc<dyn Storage>>,
-    Path(queue): Path<String>,
-) -> Response {
-    if !storage.queue_exists(&queue) {
-        warn!("Invalid queue name attempted: {queue}");
-        return utils::json_error(
-            StatusCode::FORBIDDEN,
-            "InvalidQueueName",
-            &format!("Invalid queue name attempted: {queue}"),
-        );
+    #[tokio::test]
+    async fn test_create_queue_handler() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["existing".to_string()],
+            ..Default::default()
+        });
+        let create = |uri: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = create("/_queues/Fresh").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(storage.queue_exists("fresh"));
+
+        let response = create("/_queues/fresh?if_not_exists=true").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = create("/_queues/existing?if_not_exists=true")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = create("/_queues/fresh").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(body_string(response).await.contains("QueueExists"));
+
+        let response = create("/_queues/bad!name").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
     }
 
-    match storage.delete_item(&queue) {
-        Ok(Some(item)) => {
-            let body = item.to_json_string().unwrap();
-            info!("pop from queue {queue}, got {item:?}");
-            Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .header("Content-Length", body.len().to_string())
-                .body(body.into())
-                .unwrap()
-        }
-        Ok(None) => {
-            info!("pop from queue {queue}, the queue is empty");
-            StatusCode::NO_CONTENT.into_response()
+    #[tokio::test]
+    async fn test_counts_handler() {
+        let (app, storage) = setup_test_app();
+        for i in 0..2 {
+            let item = QueueItem {
+                datetime: Utc::now() + Duration::milliseconds(i),
+                message: "job".to_string(),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
         }
-        Err(e) => {
-            error!("Failed to delete item from '{queue}': {e}");
-            utils::json_error(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "InternalError",
-                &format!("Failed to delete item from queue {queue}: {e}"),
+        storage.delete_item("queue").unwrap();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/queue/counts")
+                    .body(Body::empty())
+                    .unwrap(),
             )
-        }
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // The in-memory backend keeps no deleted items
+        assert_eq!(body_string(response).await, r#"{"valid":1,"invalid":0}"#);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::AppConfig;
-    use axum::Router;
-    use axum::body::Body;
-    use axum::http::{Request, StatusCode};
-    use axum::routing::get;
-    use chrono::Utc;
-    use tower::ServiceExt;
+    #[tokio::test]
+    async fn test_claim_if_due_handler() {
+        let (app, storage) = setup_test_app();
+        let claim = || {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/queue/claim-if-due")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
 
-    fn setup_test_app() -> (Router, Arc<dyn Storage>) {
-        let config = AppConfig {
-            bind_address: "127.0.0.1".to_string(),
-            port: 8080,
-            queues: vec!["queue".to_string()],
-            log_file: "test.log".to_string(),
-            log_level: "info".to_string(),
-            database_path: ":memory:".to_string(),
-            max_workers: Some(2),
+        // Empty: no item and no hint
+        let response = claim().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers().get("X-Next-Due-Ms").is_none());
+
+        // Future head: stays queued, with the wait until it is due
+        let future = QueueItem {
+            datetime: Utc::now() + Duration::seconds(60),
+            message: "later".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", future).unwrap();
+        let response = claim().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let wait: i64 = response.headers()["X-Next-Due-Ms"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((50_000..=60_000).contains(&wait), "{wait}");
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+
+        // Due head: returned and removed
+        let due = QueueItem {
+            datetime: Utc::now() - Duration::seconds(1),
+            message: "now".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", due).unwrap();
+        let response = claim().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body_string(response).await.contains(r#""message":"now""#));
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_rolls_back() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string(), "done".to_string()],
+            ..Default::default()
+        });
+        let transact = |body: serde_json::Value| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/_tx")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body.to_string()))
+                    .unwrap(),
+            )
+        };
+        let job = QueueItem {
+            datetime: DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            message: "job".to_string(),
+            ..Default::default()
         };
+        storage.put_item("queue", job.clone()).unwrap();
+        let done = serde_json::json!({"datetime": "2024-01-01T00:00:00Z", "message": "done"});
+
+        // The delete names no stored item, so the pop and the put are undone
+        let response = transact(serde_json::json!([
+            {"op": "pop", "queue": "queue"},
+            {"op": "put", "queue": "done", "item": done},
+            {"op": "delete", "queue": "queue", "key": {"datetime": "2024-01-01T00:00:00Z"}},
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = body_string(response).await;
+        assert!(error.contains("Operation 2 failed"), "{error}");
+        assert_eq!(storage.export_items("queue").unwrap(), vec![job.clone()]);
+        assert_eq!(storage.count_items("done").unwrap(), 0);
+
+        // Unknown queues are refused before anything is applied
+        let response = transact(serde_json::json!([
+            {"op": "pop", "queue": "queue"},
+            {"op": "pop", "queue": "nosuch"},
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
 
-        let storage = Arc::new(dtqueue::InMemoryStorage::new(&config));
+        let response = transact(serde_json::json!([
+            {"op": "pop", "queue": "queue"},
+            {"op": "put", "queue": "done", "item": done},
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let results: Vec<Option<QueueItem>> =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap().message, "job");
+        assert_eq!(results[1], None);
+        assert_eq!(storage.count_items("queue").unwrap(), 0);
+        assert_eq!(storage.get_item("done").unwrap().unwrap().message, "done");
+    }
 
-        let app = Router::new()
-            .route("/{*queue}", get(get_item).put(put_item).delete(delete_item))
-            .with_state(storage.clone() as Arc<dyn Storage>);
+    #[tokio::test]
+    async fn test_effective_config() {
+        let (app, _) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            api_keys: vec!["secret-key".to_string()],
+            database_key: Some("db-key".to_string()),
+            max_response_bytes: Some(4096),
+            ..Default::default()
+        });
+        let get = |key: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/_config")
+                    .header("Authorization", format!("Bearer {key}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            get("wrong").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
 
-        (app, storage)
+        let response = get("secret-key").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_string(response).await;
+        assert!(
+            !body.contains("secret-key") && !body.contains("db-key"),
+            "{body}"
+        );
+        let config: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(config["queues"], serde_json::json!(["queue"]));
+        assert_eq!(config["max_response_bytes"], 4096);
+        assert_eq!(config["sqlite_journal_mode"], "wal");
+        assert_eq!(config["api_keys"], serde_json::json!(["<redacted>"]));
+        assert_eq!(config["database_key"], "<redacted>");
     }
 
     #[tokio::test]
-    async fn test_put_item_handler() {
+    async fn test_purge_deleted() {
         let (app, _) = setup_test_app();
+        let purge = |uri: &'static str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let response = purge("/_purge-deleted?older_than=1h").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // The in-memory backend keeps no deleted items
+        assert_eq!(body_string(response).await, r#"{"queue":0}"#);
+        let response = purge("/_purge-deleted?older_than=soon").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
 
-        let now = Utc::now();
-        let item = QueueItem {
-            datetime: now,
-            datetime_secondary: None,
-            message: "test message".to_string(),
+    #[tokio::test]
+    async fn test_operation_stats() {
+        let (app, _) = setup_test_app();
+        let send = |method: &str, uri: &str, body: String| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
         };
+        let item = r#"{"datetime":"2024-01-01T00:00:00Z","message":"job"}"#;
+        for _ in 0..2 {
+            let response = send("PUT", "/queue", item.to_string()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send("GET", "/queue", String::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("DELETE", "/queue", String::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("PUT", "/queue", "{".to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Unknown queues count towards the totals only
+        let response = send("GET", "/nosuch", String::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
 
-        let json = item.to_json_string().unwrap();
+        let response = send("GET", "/_stats", String::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert!(stats["since"].is_string());
+        assert_eq!(
+            stats["errors"],
+            serde_json::json!({"BadRequest": 1, "InvalidQueueName": 1})
+        );
+        assert_eq!(
+            stats["queues"],
+            serde_json::json!({"queue": {
+                "puts": 2,
+                "gets": 1,
+                "deletes": 1,
+                "errors": {"BadRequest": 1},
+            }})
+        );
+
+        // A reset zeroes every count and moves `since` on
+        let response = send("POST", "/_stats/reset", String::new()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send("GET", "/_stats", String::new()).await.unwrap();
+        let reset: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(reset["errors"], serde_json::json!({}));
+        assert_eq!(reset["queues"], serde_json::json!({}));
+        assert!(reset["since"].as_str() >= stats["since"].as_str());
+        let response = send("PUT", "/queue", item.to_string()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send("GET", "/_stats", String::new()).await.unwrap();
+        let stats: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(stats["queues"]["queue"]["puts"], 1);
 
+        // Like reading them, resetting the counts takes an API key when keys are set
+        let (app, _) = setup_test_app_with(AppConfig {
+            queues: vec!["queue".to_string()],
+            api_keys: vec!["secret-key".to_string()],
+            ..Default::default()
+        });
         let response = app
             .oneshot(
                 Request::builder()
-                    .method("PUT")
-                    .uri("/queue")
-                    .header("Content-Type", "application/json")
-                    .header("Content-Length", json.len().to_string())
-                    .body(Body::from(json))
+                    .method("POST")
+                    .uri("/_stats/reset")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
-
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
     }
 
     #[tokio::test]
-    async fn test_put_invalid_queue() {
+    async fn test_error_catalog_covers_every_code() {
         let (app, _) = setup_test_app();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/_errors")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let catalog: Vec<serde_json::Value> =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        let listed: Vec<&str> = catalog
+            .iter()
+            .map(|entry| entry["code"].as_str().unwrap())
+            .collect();
+        assert!(catalog.iter().all(|entry| entry["status"].is_u64()));
 
-        let now = Utc::now();
-        let item = QueueItem {
-            datetime: now,
-            datetime_secondary: None,
-            message: "test message".to_string(),
-        };
+        // Every code the handlers reference must be listed
+        let sources = [include_str!("handlers.rs"), include_str!("utils.rs")];
+        let mut emitted = 0;
+        for source in sources {
+            for reference in source.split("ErrorCode::").skip(1) {
+                let code: String = reference
+                    .chars()
+                    .take_while(char::is_ascii_alphanumeric)
+                    .collect();
+                if !code.is_empty() {
+                    assert!(listed.contains(&code.as_str()), "{code} is not listed");
+                    emitted += 1;
+                }
+            }
+        }
+        assert!(emitted > 40);
+    }
 
-        let json = item.to_json_string().unwrap();
+    #[tokio::test]
+    async fn test_snapshot_handler() {
+        let (app, storage) = setup_test_app_with(AppConfig {
+            queues: vec!["a".to_string(), "b".to_string()],
+            ..Default::default()
+        });
+        let now = Utc::now();
+        storage
+            .put_item(
+                "a",
+                QueueItem {
+                    datetime: now - chrono::Duration::seconds(1),
+                    message: "head of a".to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
 
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
-                    .method("PUT")
-                    .uri("/queue/invalid_queue")
-                    .header("Content-Type", "application/json")
-                    .body(Body::from(json))
+                    .uri("/_snapshot?queues=a,B")
+                    .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let heads: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(heads["a"]["message"], "head of a");
+        assert!(heads["b"].is_null());
 
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/_snapshot?queues=a,nope")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::FORBIDDEN);
     }
+
+    #[tokio::test]
+    async fn test_restore_consumed_items() {
+        let (app, storage) = setup_test_app();
+        let now = Utc::now();
+        let lines: Vec<String> = [
+            ("pending", None),
+            ("consumed", Some(false)),
+            ("kept", Some(true)),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (message, valid))| {
+            let mut record = serde_json::json!({
+                "queue": "queue",
+                "datetime": (now + Duration::seconds(i as i64)).to_rfc3339(),
+                "message": message,
+            });
+            if let Some(valid) = valid {
+                record["valid"] = valid.into();
+            }
+            record.to_string()
+        })
+        .collect();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/_restore")
+                    .body(Body::from(lines.join("\n")))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let summary: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        assert_eq!(summary["restored"], 3);
+        assert_eq!(summary["consumed"], 1);
+
+        let delivered: Vec<String> = std::iter::from_fn(|| storage.delete_item("queue").unwrap())
+            .map(|item| item.message)
+            .collect();
+        assert_eq!(delivered, ["pending", "kept"]);
+    }
 }
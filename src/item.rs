@@ -1,30 +1,171 @@
 use chrono::{DateTime, Utc};
+use prost::Message;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 /// Represents an item in the queue with primary and optional secondary datetime,
 /// along with the message content.
 ///
 /// This struct is used to serialize and deserialize queue items
 ///
+/// The default is an empty item at the Unix epoch, for filling in the optional fields:
+/// `QueueItem { datetime, message, ..Default::default() }`.
 pub struct QueueItem {
     pub datetime: DateTime<Utc>, // Primary datetime
     #[serde(skip_serializing_if = "Option::is_none")]
     pub datetime_secondary: Option<DateTime<Utc>>, // Secondary datetime, optional
     #[serde(skip_serializing_if = "String::is_empty", default)]
     pub message: String, // Message content
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<String>, // Producer-assigned id, the replace key of `key_by = "id"` queues
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub payloads: Option<BTreeMap<String, String>>, // Named alternative encodings of the message
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tenant: Option<String>, // Partition key consumers can restrict delivery to
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub recurrence_interval_secs: Option<u32>, // Reinserted this much later once consumed
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub recurrence_until: Option<DateTime<Utc>>, // Latest datetime a recurrence is reinserted at
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+/// Identifies an item within a queue by its primary and optional secondary datetime.
+///
+/// Two items with the same key cannot coexist in a queue; a PUT with an existing
+/// key replaces the stored message.
+pub struct QueueKey {
+    pub datetime: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub datetime_secondary: Option<DateTime<Utc>>,
 }
 
 impl QueueItem {
+    /// Returns the key identifying this item within its queue.
+    pub fn key(&self) -> QueueKey {
+        QueueKey {
+            datetime: self.datetime,
+            datetime_secondary: self.datetime_secondary,
+        }
+    }
+
+    /// Returns a stable id derived from the key and message, the hex SHA-256 of the
+    /// datetimes in milliseconds followed by the message. Identical items always get the
+    /// same id, so consumers can recognize items they have already processed. Datetimes
+    /// are hashed at the precision they are stored with, so the id survives storage.
+    pub fn content_id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.datetime.timestamp_millis().to_be_bytes());
+        // The marker keeps a missing secondary apart from any present one
+        match self.datetime_secondary {
+            Some(secondary) => {
+                hasher.update([1]);
+                hasher.update(secondary.timestamp_millis().to_be_bytes());
+            }
+            None => hasher.update([0]),
+        }
+        hasher.update(self.message.as_bytes());
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    /// Returns the item a recurring item is replaced with once consumed: the same item
+    /// `recurrence_interval_secs` later, unless that is past `recurrence_until`. Items
+    /// without an interval, or with an interval of 0, do not recur.
+    pub fn next_occurrence(&self) -> Option<QueueItem> {
+        let interval = chrono::Duration::seconds(self.recurrence_interval_secs?.into());
+        let datetime = self.datetime.checked_add_signed(interval)?;
+        if interval.is_zero() || self.recurrence_until.is_some_and(|until| datetime > until) {
+            return None;
+        }
+        Some(QueueItem {
+            datetime,
+            ..self.clone()
+        })
+    }
+
     /// Serializes the QueueItem to a JSON string.
     pub fn to_json_string(&self) -> Result<String, serde_json::Error> {
         serde_json::to_string(self)
     }
 
+    /// Serializes a QueueItem to an indented JSON string, for reading by humans.
+    pub fn to_json_string_pretty(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
     /// Deserializes a QueueItem from a JSON string.
     pub fn from_json_string(s: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(s)
     }
+
+    /// Serializes the QueueItem as the `QueueItem` message of `proto/queue_item.proto`.
+    /// Datetimes are sent in milliseconds, so finer precision is lost.
+    pub fn to_protobuf(&self) -> Vec<u8> {
+        ProtoQueueItem {
+            datetime: self.datetime.timestamp_millis(),
+            datetime_secondary: self.datetime_secondary.map(|d| d.timestamp_millis()),
+            message: self.message.clone(),
+            id: self.id.clone(),
+            payloads: self.payloads.clone().unwrap_or_default(),
+            tenant: self.tenant.clone(),
+            recurrence_interval_secs: self.recurrence_interval_secs,
+            recurrence_until: self.recurrence_until.map(|d| d.timestamp_millis()),
+        }
+        .encode_to_vec()
+    }
+
+    /// Deserializes a QueueItem from the `QueueItem` message of `proto/queue_item.proto`.
+    /// An empty `payloads` map reads as no payloads.
+    pub fn from_protobuf(bytes: &[u8]) -> Result<Self, ProtobufError> {
+        let proto = ProtoQueueItem::decode(bytes)?;
+        let datetime = |millis: i64| {
+            DateTime::<Utc>::from_timestamp_millis(millis).ok_or(ProtobufError::DatetimeOutOfRange)
+        };
+        Ok(QueueItem {
+            datetime: datetime(proto.datetime)?,
+            datetime_secondary: proto.datetime_secondary.map(datetime).transpose()?,
+            message: proto.message,
+            id: proto.id,
+            payloads: (!proto.payloads.is_empty()).then_some(proto.payloads),
+            tenant: proto.tenant,
+            recurrence_interval_secs: proto.recurrence_interval_secs,
+            recurrence_until: proto.recurrence_until.map(datetime).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtobufError {
+    #[error("invalid protobuf: {0}")]
+    Decode(#[from] prost::DecodeError),
+    #[error("datetime out of range")]
+    DatetimeOutOfRange,
+}
+
+/// `QueueItem` of `proto/queue_item.proto`.
+#[derive(Clone, PartialEq, prost::Message)]
+struct ProtoQueueItem {
+    #[prost(int64, tag = "1")]
+    datetime: i64,
+    #[prost(int64, optional, tag = "2")]
+    datetime_secondary: Option<i64>,
+    #[prost(string, tag = "3")]
+    message: String,
+    #[prost(string, optional, tag = "4")]
+    id: Option<String>,
+    #[prost(btree_map = "string, string", tag = "5")]
+    payloads: BTreeMap<String, String>,
+    #[prost(string, optional, tag = "6")]
+    tenant: Option<String>,
+    #[prost(uint32, optional, tag = "7")]
+    recurrence_interval_secs: Option<u32>,
+    #[prost(int64, optional, tag = "8")]
+    recurrence_until: Option<i64>,
 }
 
 #[cfg(test)]
@@ -39,6 +180,7 @@ mod tests {
             datetime: now,
             datetime_secondary: None,
             message: "test message".to_string(),
+            ..Default::default()
         };
 
         let json = item.to_json_string().unwrap();
@@ -57,6 +199,7 @@ mod tests {
             datetime: now,
             datetime_secondary: Some(secondary),
             message: "test message".to_string(),
+            ..Default::default()
         };
 
         let json = item.to_json_string().unwrap();
@@ -74,12 +217,73 @@ mod tests {
             datetime: now,
             datetime_secondary: None,
             message: "".to_string(),
+            ..Default::default()
         };
 
         let json = item.to_json_string().unwrap();
         assert!(!json.contains("message"));
     }
 
+    #[test]
+    fn test_protobuf_round_trip() {
+        let now = DateTime::<Utc>::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let without_secondary = QueueItem {
+            datetime: now,
+            message: "test message".to_string(),
+            ..Default::default()
+        };
+        let with_everything = QueueItem {
+            datetime_secondary: Some(now + chrono::Duration::hours(1)),
+            id: Some("evt-1".to_string()),
+            payloads: Some(BTreeMap::from([("text".to_string(), "hi".to_string())])),
+            tenant: Some("acme".to_string()),
+            recurrence_interval_secs: Some(86_400),
+            recurrence_until: Some(now + chrono::Duration::days(30)),
+            ..without_secondary.clone()
+        };
+
+        for item in [without_secondary, with_everything] {
+            let decoded = QueueItem::from_protobuf(&item.to_protobuf()).unwrap();
+            assert_eq!(decoded, item);
+        }
+        // A secondary at the epoch is present, not absent
+        let at_epoch = QueueItem {
+            datetime_secondary: Some(DateTime::UNIX_EPOCH),
+            ..QueueItem::from_json_string(r#"{"datetime": "2024-01-01T00:00:00Z"}"#).unwrap()
+        };
+        let decoded = QueueItem::from_protobuf(&at_epoch.to_protobuf()).unwrap();
+        assert_eq!(decoded.datetime_secondary, Some(DateTime::UNIX_EPOCH));
+        assert!(QueueItem::from_protobuf(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_content_id() {
+        let item = QueueItem::from_json_string(
+            r#"{"datetime": "2024-01-01T00:00:00Z", "message": "hello"}"#,
+        )
+        .unwrap();
+        let id = item.content_id();
+        assert_eq!(id.len(), 64);
+        // Fields outside the key and message, and precision below milliseconds, do not count
+        let same = QueueItem {
+            datetime: item.datetime + chrono::Duration::microseconds(5),
+            id: Some("evt-1".to_string()),
+            ..item.clone()
+        };
+        assert_eq!(same.content_id(), id);
+
+        let other_message = QueueItem {
+            message: "hello!".to_string(),
+            ..item.clone()
+        };
+        let with_secondary = QueueItem {
+            datetime_secondary: Some(DateTime::UNIX_EPOCH),
+            ..item.clone()
+        };
+        assert_ne!(other_message.content_id(), id);
+        assert_ne!(with_secondary.content_id(), id);
+    }
+
     #[test]
     fn test_invalid_json_deserialization() {
         let result = QueueItem::from_json_string("{invalid_json}");
@@ -0,0 +1,685 @@
+use crate::storage::StorageResult;
+use crate::{
+    CasResult, CorruptRow, QueueItem, QueueKey, Storage, StorageError, TxOp, ValidityCounts,
+};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum JournalError {
+    #[error("Journal I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed journal line {line}: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Failed to replay journal line {line}: {source}")]
+    Storage { line: usize, source: StorageError },
+}
+
+/// A mutating storage operation as recorded in the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalOp {
+    Put {
+        item: QueueItem,
+    },
+    /// A put that carried a fencing token, which replay restores as the highest.
+    FencedPut {
+        item: QueueItem,
+        token: i64,
+    },
+    /// The deletion of the item under `key`; a pop is recorded with the key it removed.
+    Delete {
+        key: QueueKey,
+    },
+    /// The deletion of every item with a datetime before `before`.
+    Purge {
+        before: DateTime<Utc>,
+    },
+    Swap {
+        a: QueueKey,
+        b: QueueKey,
+    },
+    Hide {
+        key: QueueKey,
+        until: DateTime<Utc>,
+    },
+    Increment {
+        key: QueueKey,
+        by: i64,
+    },
+    /// A shift of every item by `by_ms` milliseconds.
+    Shift {
+        by_ms: i64,
+        secondary: bool,
+    },
+    CreateQueue,
+}
+
+/// One line of the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: DateTime<Utc>,
+    pub queue: String,
+    #[serde(flatten)]
+    pub op: JournalOp,
+}
+
+/// A storage that appends every successful mutation to a newline-delimited JSON journal
+/// before returning, so `replay` can rebuild the same state from scratch.
+///
+/// Mutations are serialized around the journal lock so the journal order is exactly the
+/// order in which they were applied. Reads go straight to the inner storage.
+pub struct JournaledStorage {
+    inner: Arc<dyn Storage>,
+    journal: Mutex<File>,
+}
+
+impl JournaledStorage {
+    /// Wraps `inner`, appending to the journal at `path`.
+    pub fn open(inner: Arc<dyn Storage>, path: &str) -> Result<Self, JournalError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JournaledStorage {
+            inner,
+            journal: Mutex::new(file),
+        })
+    }
+
+    /// Applies `apply` to the inner storage and journals the operations it reports.
+    fn record<T>(
+        &self,
+        queue: &str,
+        apply: impl FnOnce(&dyn Storage) -> StorageResult<(T, Vec<JournalOp>)>,
+    ) -> StorageResult<T> {
+        let mut journal = self.journal.lock().map_err(|_| StorageError::LockError)?;
+        let (result, ops) = apply(self.inner.as_ref())?;
+        let timestamp = Utc::now();
+        for op in ops {
+            append(&mut journal, timestamp, queue, op);
+        }
+        Ok(result)
+    }
+}
+
+/// The operations that replay the consumption of `item`: its deletion and, if it
+/// recurs, the put of its next occurrence, since replay deletes by key.
+fn consumed(item: &QueueItem) -> Vec<JournalOp> {
+    let mut ops = vec![JournalOp::Delete { key: item.key() }];
+    ops.extend(item.next_occurrence().map(|item| JournalOp::Put { item }));
+    ops
+}
+
+/// Writes one journal entry.
+fn append(journal: &mut File, timestamp: DateTime<Utc>, queue: &str, op: JournalOp) {
+    let entry = JournalEntry {
+        timestamp,
+        queue: queue.to_string(),
+        op,
+    };
+    let mut line = serde_json::to_string(&entry).expect("journal entries serialize");
+    line.push('\n');
+    // The mutation already happened, so a failed write only loses the record
+    if let Err(e) = journal.write_all(line.as_bytes()) {
+        error!("Failed to write journal entry {entry:?}: {e}");
+    }
+}
+
+impl Storage for JournaledStorage {
+    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let created = storage.put_item(queue, item.clone())?;
+            Ok((created, vec![JournalOp::Put { item }]))
+        })
+    }
+
+    fn put_item_fenced(&self, queue: &str, item: QueueItem, token: i64) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let created = storage.put_item_fenced(queue, item.clone(), token)?;
+            Ok((created, vec![JournalOp::FencedPut { item, token }]))
+        })
+    }
+
+    fn replace_if_message(
+        &self,
+        queue: &str,
+        item: QueueItem,
+        expected: &str,
+    ) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let replaced = storage.replace_if_message(queue, item.clone(), expected)?;
+            // A successful replacement has the effect of a put
+            let ops = match replaced {
+                true => vec![JournalOp::Put { item }],
+                false => Vec::new(),
+            };
+            Ok((replaced, ops))
+        })
+    }
+
+    fn put_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        self.record(queue, |storage| {
+            storage.put_items(queue, items.clone())?;
+            let ops = items.into_iter().map(|item| JournalOp::Put { item });
+            Ok(((), ops.collect()))
+        })
+    }
+
+    fn put_consumed_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        self.record(queue, |storage| {
+            storage.put_consumed_items(queue, items.clone())?;
+            // Replays as a put followed by its deletion, which leaves the same tombstone
+            let ops = items.into_iter().flat_map(|item| {
+                let key = item.key();
+                [JournalOp::Put { item }, JournalOp::Delete { key }]
+            });
+            Ok(((), ops.collect()))
+        })
+    }
+
+    fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        self.inner.get_item(queue)
+    }
+
+    fn tail_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        self.inner.tail_item(queue)
+    }
+
+    fn snapshot_heads(&self, queues: &[String]) -> StorageResult<Vec<Option<QueueItem>>> {
+        self.inner.snapshot_heads(queues)
+    }
+
+    fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        self.record(queue, |storage| {
+            let item = storage.delete_item(queue)?;
+            let ops = item.iter().flat_map(consumed);
+            Ok((item.clone(), ops.collect()))
+        })
+    }
+
+    fn get_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        self.inner.get_tenant_item(queue, tenant, due_by)
+    }
+
+    fn delete_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        self.record(queue, |storage| {
+            let item = storage.delete_tenant_item(queue, tenant, due_by)?;
+            let ops = item.iter().flat_map(consumed);
+            Ok((item.clone(), ops.collect()))
+        })
+    }
+
+    fn get_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        self.inner.get_newest_item(queue)
+    }
+
+    fn delete_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        self.record(queue, |storage| {
+            let item = storage.delete_newest_item(queue)?;
+            let ops = item.iter().flat_map(consumed);
+            Ok((item.clone(), ops.collect()))
+        })
+    }
+
+    fn remove_item(&self, queue: &str, key: &QueueKey) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let removed = storage.remove_item(queue, key)?;
+            let ops = removed.then_some(JournalOp::Delete { key: *key });
+            Ok((removed, ops.into_iter().collect()))
+        })
+    }
+
+    fn compare_and_delete(&self, queue: &str, expected: &QueueKey) -> StorageResult<CasResult> {
+        self.record(queue, |storage| {
+            let result = storage.compare_and_delete(queue, expected)?;
+            let ops = match &result {
+                CasResult::Deleted(item) => consumed(item),
+                CasResult::Mismatch(_) => Vec::new(),
+            };
+            Ok((result, ops))
+        })
+    }
+
+    fn claim_any(
+        &self,
+        queues: &[String],
+        now: DateTime<Utc>,
+        due_only: bool,
+        start: usize,
+    ) -> StorageResult<Option<(String, QueueItem)>> {
+        // The queue is only known afterwards, so this journals without `record`
+        let mut journal = self.journal.lock().map_err(|_| StorageError::LockError)?;
+        let claimed = self.inner.claim_any(queues, now, due_only, start)?;
+        if let Some((queue, item)) = &claimed {
+            let timestamp = Utc::now();
+            for op in consumed(item) {
+                append(&mut journal, timestamp, queue, op);
+            }
+        }
+        Ok(claimed)
+    }
+
+    fn transact(&self, ops: &[TxOp]) -> StorageResult<Vec<Option<QueueItem>>> {
+        // The operations span queues, so this journals without `record`. Replay applies
+        // them one by one, which ends in the same state.
+        let mut journal = self.journal.lock().map_err(|_| StorageError::LockError)?;
+        let results = self.inner.transact(ops)?;
+        let timestamp = Utc::now();
+        for (op, result) in ops.iter().zip(&results) {
+            let entries = match op {
+                TxOp::Put { item, .. } => vec![JournalOp::Put { item: item.clone() }],
+                TxOp::Pop { .. } => result.iter().flat_map(consumed).collect(),
+                TxOp::Delete { key, .. } => vec![JournalOp::Delete { key: *key }],
+            };
+            for entry in entries {
+                append(&mut journal, timestamp, op.queue(), entry);
+            }
+        }
+        Ok(results)
+    }
+
+    fn purge_before(&self, queue: &str, cutoff: DateTime<Utc>) -> StorageResult<usize> {
+        self.record(queue, |storage| {
+            let purged = storage.purge_before(queue, cutoff)?;
+            let ops = (purged > 0).then_some(JournalOp::Purge { before: cutoff });
+            Ok((purged, ops.into_iter().collect()))
+        })
+    }
+
+    fn swap_items(&self, queue: &str, key_a: &QueueKey, key_b: &QueueKey) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let swapped = storage.swap_items(queue, key_a, key_b)?;
+            let ops = swapped.then_some(JournalOp::Swap {
+                a: *key_a,
+                b: *key_b,
+            });
+            Ok((swapped, ops.into_iter().collect()))
+        })
+    }
+
+    fn hide_item(&self, queue: &str, key: &QueueKey, until: DateTime<Utc>) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let hidden = storage.hide_item(queue, key, until)?;
+            let ops = hidden.then_some(JournalOp::Hide { key: *key, until });
+            Ok((hidden, ops.into_iter().collect()))
+        })
+    }
+
+    fn extend_hidden(
+        &self,
+        queue: &str,
+        keys: &[QueueKey],
+        until: DateTime<Utc>,
+    ) -> StorageResult<Vec<bool>> {
+        self.record(queue, |storage| {
+            let held = storage.extend_hidden(queue, keys, until)?;
+            let ops = keys
+                .iter()
+                .zip(&held)
+                .filter(|(_, held)| **held)
+                .map(|(key, _)| JournalOp::Hide { key: *key, until });
+            Ok((held.clone(), ops.collect()))
+        })
+    }
+
+    fn requeue_hidden_since(
+        &self,
+        queue: &str,
+        hidden_before: DateTime<Utc>,
+    ) -> StorageResult<Vec<QueueKey>> {
+        self.record(queue, |storage| {
+            let requeued = storage.requeue_hidden_since(queue, hidden_before)?;
+            // Replay has its own clock, so record the outcome: hidden until the epoch
+            let ops = requeued.iter().map(|key| JournalOp::Hide {
+                key: *key,
+                until: DateTime::UNIX_EPOCH,
+            });
+            Ok((requeued.clone(), ops.collect()))
+        })
+    }
+
+    fn increment_item(&self, queue: &str, key: &QueueKey, by: i64) -> StorageResult<i64> {
+        self.record(queue, |storage| {
+            let value = storage.increment_item(queue, key, by)?;
+            Ok((value, vec![JournalOp::Increment { key: *key, by }]))
+        })
+    }
+
+    fn shift_items(
+        &self,
+        queue: &str,
+        by: chrono::Duration,
+        secondary: bool,
+    ) -> StorageResult<usize> {
+        self.record(queue, |storage| {
+            let shifted = storage.shift_items(queue, by, secondary)?;
+            let ops = (shifted > 0).then_some(JournalOp::Shift {
+                by_ms: by.num_milliseconds(),
+                secondary,
+            });
+            Ok((shifted, ops.into_iter().collect()))
+        })
+    }
+
+    fn peek_items(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        self.inner.peek_items(queue, after, limit)
+    }
+
+    fn export_items(&self, queue: &str) -> StorageResult<Vec<QueueItem>> {
+        self.inner.export_items(queue)
+    }
+
+    fn export_page(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        self.inner.export_page(queue, after, limit)
+    }
+
+    fn overdue_items(&self, queue: &str, before: DateTime<Utc>) -> StorageResult<Vec<QueueItem>> {
+        self.inner.overdue_items(queue, before)
+    }
+
+    fn export_rows(&self, queue: &str) -> StorageResult<Vec<Result<QueueItem, CorruptRow>>> {
+        self.inner.export_rows(queue)
+    }
+
+    fn rank_of_key(&self, queue: &str, key: &QueueKey) -> StorageResult<Option<usize>> {
+        self.inner.rank_of_key(queue, key)
+    }
+
+    fn items_exist(&self, queue: &str, keys: &[QueueKey]) -> StorageResult<Vec<bool>> {
+        self.inner.items_exist(queue, keys)
+    }
+
+    fn count_items(&self, queue: &str) -> StorageResult<usize> {
+        self.inner.count_items(queue)
+    }
+
+    fn count_by_validity(&self, queue: &str) -> StorageResult<ValidityCounts> {
+        self.inner.count_by_validity(queue)
+    }
+
+    fn last_modified(&self, queue: &str) -> StorageResult<Option<DateTime<Utc>>> {
+        self.inner.last_modified(queue)
+    }
+
+    fn create_queue(&self, queue: &str) -> StorageResult<bool> {
+        self.record(queue, |storage| {
+            let created = storage.create_queue(queue)?;
+            let ops = created.then_some(JournalOp::CreateQueue);
+            Ok((created, ops.into_iter().collect()))
+        })
+    }
+
+    fn queue_names(&self) -> Vec<String> {
+        self.inner.queue_names()
+    }
+
+    fn queue_exists(&self, queue: &str) -> bool {
+        self.inner.queue_exists(queue)
+    }
+
+    fn enforce_wal_limit(&self, max_bytes: u64) -> StorageResult<Option<(u64, u64)>> {
+        self.inner.enforce_wal_limit(max_bytes)
+    }
+
+    fn compact(&self, queue: &str, limit: usize) -> StorageResult<usize> {
+        // Removing deleted items changes nothing a replay could observe
+        self.inner.compact(queue, limit)
+    }
+
+    fn purge_deleted(&self, queue: &str, older_than: chrono::Duration) -> StorageResult<usize> {
+        self.inner.purge_deleted(queue, older_than)
+    }
+
+    fn vacuum(&self) -> StorageResult<Option<(u64, u64)>> {
+        self.inner.vacuum()
+    }
+}
+
+/// Rebuilds `inner`, which should be fresh, from the journal at `path` if there is one,
+/// then journals further mutations to the same file. Returns the storage and the number
+/// of operations replayed. An entry cut short by a crash is discarded first; it was
+/// never acknowledged, since mutations return only after their entry is written.
+pub fn recover(
+    inner: Arc<dyn Storage>,
+    path: &str,
+) -> Result<(JournaledStorage, usize), JournalError> {
+    let replayed = match std::fs::read(path) {
+        Ok(contents) => {
+            let complete = contents
+                .iter()
+                .rposition(|&byte| byte == b'\n')
+                .map_or(0, |end| end + 1);
+            if complete < contents.len() {
+                error!(
+                    "Discarding {} bytes of an incomplete entry at the end of {path}",
+                    contents.len() - complete
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(complete as u64)?;
+            }
+            replay(path, inner.as_ref())?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    Ok((JournaledStorage::open(inner, path)?, replayed))
+}
+
+/// Re-applies every operation in the journal at `path` to `storage`, in order,
+/// and returns the number of operations applied.
+pub fn replay(path: &str, storage: &dyn Storage) -> Result<usize, JournalError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut applied = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JournalEntry =
+            serde_json::from_str(&line).map_err(|source| JournalError::Parse {
+                line: index + 1,
+                source,
+            })?;
+        apply(storage, entry).map_err(|source| JournalError::Storage {
+            line: index + 1,
+            source,
+        })?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+fn apply(storage: &dyn Storage, entry: JournalEntry) -> StorageResult<()> {
+    let queue = entry.queue.as_str();
+    match entry.op {
+        JournalOp::Put { item } => storage.put_item(queue, item).map(|_| ()),
+        JournalOp::FencedPut { item, token } => {
+            storage.put_item_fenced(queue, item, token).map(|_| ())
+        }
+        JournalOp::Delete { key } => storage.remove_item(queue, &key).map(|_| ()),
+        JournalOp::Purge { before } => storage.purge_before(queue, before).map(|_| ()),
+        JournalOp::Swap { a, b } => storage.swap_items(queue, &a, &b).map(|_| ()),
+        JournalOp::Hide { key, until } => storage.hide_item(queue, &key, until).map(|_| ()),
+        JournalOp::Increment { key, by } => storage.increment_item(queue, &key, by).map(|_| ()),
+        JournalOp::Shift { by_ms, secondary } => storage
+            .shift_items(queue, chrono::Duration::milliseconds(by_ms), secondary)
+            .map(|_| ()),
+        JournalOp::CreateQueue => storage.create_queue(queue).map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppConfig, InMemoryStorage, SqliteStorage};
+    use chrono::Duration;
+
+    fn item(datetime: DateTime<Utc>, message: &str) -> QueueItem {
+        QueueItem {
+            datetime,
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_replay_reproduces_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_path = dir.path().join("journal.ndjson");
+        let journal_path = journal_path.to_str().unwrap();
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+
+        let journaled =
+            JournaledStorage::open(Arc::new(InMemoryStorage::new(&config)), journal_path).unwrap();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let mut items: Vec<QueueItem> = (0..4)
+            .map(|i| item(base + Duration::seconds(i), &format!("{i}")))
+            .collect();
+        // Consuming it puts its next occurrence, which the journal records as well
+        items[0].recurrence_interval_secs = Some(60);
+        journaled.put_item("queue", items[0].clone()).unwrap();
+        journaled.put_items("queue", items[1..].to_vec()).unwrap();
+        journaled.delete_item("queue").unwrap();
+        journaled
+            .swap_items("queue", &items[1].key(), &items[3].key())
+            .unwrap();
+        journaled
+            .hide_item("queue", &items[2].key(), Utc::now() + Duration::hours(1))
+            .unwrap();
+        journaled.create_queue("extra").unwrap();
+        journaled.put_item("extra", item(base, "extra")).unwrap();
+        let counter = item(base + Duration::seconds(10), "").key();
+        journaled.increment_item("extra", &counter, 2).unwrap();
+        journaled.increment_item("extra", &counter, 3).unwrap();
+        let moved = [
+            TxOp::Pop {
+                queue: "queue".to_string(),
+                due_only: false,
+            },
+            TxOp::Put {
+                queue: "extra".to_string(),
+                item: item(base + Duration::seconds(20), "moved"),
+            },
+        ];
+        journaled.transact(&moved).unwrap();
+        // A transaction that fails applies nothing, so nothing is journaled
+        let missing = TxOp::Delete {
+            queue: "queue".to_string(),
+            key: counter,
+        };
+        assert!(journaled.transact(&[moved[1].clone(), missing]).is_err());
+        // Operations that change nothing are not journaled
+        assert!(!journaled.remove_item("queue", &items[0].key()).unwrap());
+
+        let db_path = dir.path().join("replayed.sqlite");
+        let fresh_sqlite = SqliteStorage::new(&AppConfig {
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..config.clone()
+        })
+        .unwrap();
+        let fresh_memory = InMemoryStorage::new(&config);
+        let replayed: [&dyn Storage; 2] = [&fresh_sqlite, &fresh_memory];
+        for storage in replayed {
+            assert_eq!(replay(journal_path, storage).unwrap(), 14);
+            assert_eq!(storage.queue_names(), journaled.queue_names());
+            for queue in journaled.queue_names() {
+                assert_eq!(
+                    storage.export_items(&queue).unwrap(),
+                    journaled.export_items(&queue).unwrap()
+                );
+                assert_eq!(
+                    storage.get_item(&queue).unwrap(),
+                    journaled.get_item(&queue).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_recover_after_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal_path = dir.path().join("memory.wal");
+        let wal_path = wal_path.to_str().unwrap();
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        let (storage, replayed) =
+            recover(Arc::new(InMemoryStorage::new(&config)), wal_path).unwrap();
+        assert_eq!(replayed, 0);
+        for i in 0..3 {
+            storage
+                .put_item("queue", item(base + Duration::seconds(i), &format!("{i}")))
+                .unwrap();
+        }
+        storage.delete_item("queue").unwrap();
+        // The process dies without shutting down, halfway through writing an entry
+        drop(storage);
+        let mut wal = OpenOptions::new().append(true).open(wal_path).unwrap();
+        wal.write_all(b"{\"timestamp\":\"2024-").unwrap();
+        drop(wal);
+
+        let (storage, replayed) =
+            recover(Arc::new(InMemoryStorage::new(&config)), wal_path).unwrap();
+        assert_eq!(replayed, 4);
+        let messages: Vec<String> = storage
+            .export_items("queue")
+            .unwrap()
+            .into_iter()
+            .map(|item| item.message)
+            .collect();
+        assert_eq!(messages, ["1", "2"]);
+
+        // Later mutations are appended after the recovered ones
+        storage.delete_item("queue").unwrap();
+        drop(storage);
+        let (storage, replayed) =
+            recover(Arc::new(InMemoryStorage::new(&config)), wal_path).unwrap();
+        assert_eq!(replayed, 5);
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_replay_reports_malformed_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_path = dir.path().join("journal.ndjson");
+        std::fs::write(&journal_path, "{\"op\":\"create_queue\"}\n").unwrap();
+
+        let storage = InMemoryStorage::new(&AppConfig::default());
+        let result = replay(journal_path.to_str().unwrap(), &storage);
+        assert!(matches!(result, Err(JournalError::Parse { line: 1, .. })));
+    }
+}
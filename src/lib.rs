@@ -1,8 +1,20 @@
+#[cfg(feature = "amqp")]
+pub mod amqp;
 mod config;
+pub mod errors;
 mod item;
+pub mod journal;
+pub mod maintenance;
 mod storage;
 pub mod utils;
+pub mod webhooks;
 
-pub use config::AppConfig;
-pub use item::QueueItem;
-pub use storage::{InMemoryStorage, SqliteStorage, Storage};
+pub use config::{
+    AmqpBinding, AppConfig, DecodeErrorPolicy, JournalMode, KeyBy, QueueOptions, SecondaryPolicy,
+    StorageBackend, TempStore, WebhookConfig, WebhookEvent,
+};
+pub use item::{ProtobufError, QueueItem, QueueKey};
+pub use storage::{
+    CasResult, CorruptRow, InMemoryStorage, SqliteStorage, Storage, StorageError, TxOp,
+    ValidityCounts,
+};
@@ -1,17 +1,115 @@
-use axum::{Router, routing::get};
-use dtqueue::{AppConfig, InMemoryStorage, SqliteStorage, Storage};
-use log::info;
+use dtqueue::journal::{self, JournaledStorage};
+use dtqueue::maintenance;
+use dtqueue::webhooks::Webhooks;
+use dtqueue::{AppConfig, InMemoryStorage, SqliteStorage, Storage, StorageBackend};
+use log::{error, info, warn};
 use std::env;
-use std::fs::OpenOptions;
 use std::io::Write;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
+use std::time::Duration;
 mod handlers;
 
+/// Opens the storage backend selected by `storage_backend` or `database_path`.
+fn open_storage(app_config: &AppConfig) -> Arc<dyn Storage> {
+    match app_config.backend() {
+        StorageBackend::Memory => Arc::new(InMemoryStorage::new(app_config)),
+        StorageBackend::Sqlite => {
+            Arc::new(SqliteStorage::new(app_config).expect("Failed to initialize database"))
+        }
+    }
+}
+
+/// Opens the storage with the in-memory WAL replayed and the operations journal attached,
+/// as configured.
+fn open_journaled_storage(app_config: &AppConfig) -> Arc<dyn Storage> {
+    let mut storage = open_storage(app_config);
+    if let Some(wal_path) = &app_config.memory_wal_path
+        && app_config.backend() == StorageBackend::Memory
+    {
+        let (recovered, replayed) =
+            journal::recover(storage, wal_path).expect("Failed to recover the in-memory WAL");
+        info!("Recovered {replayed} operations from {wal_path}");
+        storage = Arc::new(recovered);
+    }
+    if let Some(journal_path) = &app_config.journal_path {
+        info!("Journaling mutations to {journal_path}");
+        storage = Arc::new(
+            JournaledStorage::open(storage, journal_path).expect("Failed to open journal"),
+        );
+    }
+    storage
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM. The handlers are installed
+/// right away, so a signal arriving before the future is awaited is not lost.
+#[cfg(unix)]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> impl Future<Output = ()> {
+    async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+    }
+}
+
+/// `dtqueue replay --journal <path> --config <path>`: re-applies a journal to the
+/// storage described by the configuration, which should be fresh.
+fn replay(args: &[String]) -> std::io::Result<()> {
+    let mut journal_path = None;
+    let mut config_path = "config.toml".to_string();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--journal" => journal_path = args.next().cloned(),
+            "--config" => config_path = args.next().cloned().unwrap_or(config_path),
+            other => {
+                eprintln!("Unknown argument: {other}");
+                std::process::exit(2);
+            }
+        }
+    }
+    let Some(journal_path) = journal_path else {
+        eprintln!("Usage: dtqueue replay --journal <path> [--config <path>]");
+        std::process::exit(2);
+    };
+
+    let app_config = AppConfig::from_file(&config_path).expect("Failed to load config");
+    // The replayed operations must not be journaled again
+    let storage = open_storage(&app_config);
+    match journal::replay(&journal_path, storage.as_ref()) {
+        Ok(applied) => {
+            println!("Replayed {applied} operations from {journal_path}");
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Replay failed: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let config_path = env::args()
-        .nth(1)
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("replay") {
+        return replay(&args[2..]);
+    }
+    let config_path = args
+        .get(1)
+        .cloned()
         .unwrap_or_else(|| "config.toml".to_string());
 
     let app_config = AppConfig::from_file(&config_path).expect("Failed to load config");
@@ -23,22 +121,29 @@ async fn main() -> std::io::Result<()> {
     };
 
     // Setup logging to file
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&app_config.log_file)
-        .expect("Failed to open log file");
-    let log_file = std::sync::Mutex::new(log_file);
+    let log_file = match app_config.open_log_file() {
+        Ok(log_file) => Some(log_file),
+        Err(e) if app_config.log_file_required => {
+            eprintln!("Cannot write the log file: {e}");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Cannot write the log file, logging to stderr only: {e}");
+            None
+        }
+    };
+    let log_file = log_file.map(std::sync::Mutex::new);
     let logger = env_logger::Builder::new()
         .format(move |buf, record| {
-            let mut log_file = log_file.lock().unwrap();
-            let log_line = format!(
-                "{} [{}] - {}\n",
-                chrono::Utc::now().to_rfc3339(),
-                record.level(),
-                record.args()
-            );
-            let _ = log_file.write_all(log_line.as_bytes());
+            if let Some(log_file) = &log_file {
+                let log_line = format!(
+                    "{} [{}] - {}\n",
+                    chrono::Utc::now().to_rfc3339(),
+                    record.level(),
+                    record.args()
+                );
+                let _ = log_file.lock().unwrap().write_all(log_line.as_bytes());
+            }
             writeln!(
                 buf,
                 "{} [{}] - {}",
@@ -57,22 +162,6 @@ async fn main() -> std::io::Result<()> {
         app_config.bind_address, app_config.port
     );
 
-    let storage: Arc<dyn Storage> = if app_config.database_path == ":memory:" {
-        Arc::new(InMemoryStorage::new(&app_config))
-    } else {
-        Arc::new(SqliteStorage::new(&app_config).expect("Failed to initialize database"))
-    };
-
-    // Define routes
-    let app = Router::new()
-        .route(
-            "/{*queue}",
-            get(handlers::get_item)
-                .put(handlers::put_item)
-                .delete(handlers::delete_item),
-        )
-        .with_state(storage);
-
     // Create socket address
     let addr = (app_config.bind_address.as_str(), app_config.port)
         .to_socket_addrs()
@@ -80,6 +169,99 @@ async fn main() -> std::io::Result<()> {
         .next()
         .unwrap();
 
+    // Listen right away, answering 503 until the storage is open, so load balancers
+    // can tell a starting instance from a dead one
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let startup = handlers::Startup::default();
+    // A signal during startup is acted on once the storage is open
+    let shutdown = shutdown_signal();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    // The peer address is needed by the allowed_ips / denied_ips filter
+    let mut server = tokio::spawn(
+        axum::serve(
+            listener,
+            handlers::startup_router(startup.clone())
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async {
+            let _ = stopped.await;
+        })
+        .into_future(),
+    );
+
+    // Opening runs migrations, prewarms connections and replays the WAL, which can take
+    // a while; keep it off the workers serving the startup router
+    let opening = app_config.clone();
+    let storage = tokio::task::spawn_blocking(move || open_journaled_storage(&opening))
+        .await
+        .expect("Failed to open storage");
+
+    let config = Arc::new(app_config.clone());
+    tokio::spawn(maintenance::run_sweeps(storage.clone(), config.clone()));
+    tokio::spawn(maintenance::run_wal_guard(storage.clone(), config.clone()));
+    tokio::spawn(maintenance::run_depth_logging(
+        storage.clone(),
+        config.clone(),
+    ));
+
+    #[cfg(feature = "amqp")]
+    tokio::spawn(dtqueue::amqp::run_bridges(storage.clone(), config.clone()));
+
+    let activity = Arc::new(maintenance::Activity::default());
+    tokio::spawn(maintenance::run_idle_compaction(
+        storage.clone(),
+        config.clone(),
+        activity.clone(),
+    ));
+
+    tokio::spawn(maintenance::run_deleted_purge(
+        storage.clone(),
+        config.clone(),
+    ));
+
+    let depth_history = Arc::new(maintenance::DepthHistory::new(config.depth_history_points));
+    tokio::spawn(maintenance::run_depth_history(
+        storage.clone(),
+        config.clone(),
+        depth_history.clone(),
+    ));
+
+    let webhooks = Webhooks::new(config.clone());
+    tokio::spawn(webhooks.clone().run_outbox());
+    tokio::spawn(maintenance::run_stale_checks(
+        storage.clone(),
+        config.clone(),
+        activity.clone(),
+        webhooks.clone(),
+    ));
+
+    let (write_buffer, write_receiver) = if config.async_write_ack {
+        let (sender, receiver) = tokio::sync::mpsc::channel(handlers::WRITE_BUFFER_CAPACITY);
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    // Define routes
+    let state = handlers::AppState {
+        storage,
+        webhooks,
+        config,
+        claim_rotation: Default::default(),
+        queue_creations: Default::default(),
+        activity,
+        depth_history,
+        write_buffer,
+        paused: Default::default(),
+        subscribers: Default::default(),
+        producers: Default::default(),
+        operations: Default::default(),
+        head_reads: Default::default(),
+    };
+    let write_buffer = write_receiver
+        .map(|receiver| tokio::spawn(handlers::run_write_buffer(state.clone(), receiver)));
+    let app = handlers::router(state);
+
     // Configure workers if available
     let concurrency_limit = app_config.max_workers.unwrap_or(1);
 
@@ -91,6 +273,40 @@ async fn main() -> std::io::Result<()> {
         .layer(axum::extract::DefaultBodyLimit::max(1024 * 1024 * 10))
         .layer(tower::limit::ConcurrencyLimitLayer::new(concurrency_limit));
 
-    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
-    axum::serve(listener, app).await
+    startup.finish(app);
+    // Only the server holds the router from here on, so the write buffer ends with it
+    drop(startup);
+    info!("Storage is open, the server is ready");
+    tokio::select! {
+        result = &mut server => return result.expect("Server task failed"),
+        () = shutdown => {}
+    }
+
+    let timeout = Duration::from_secs(app_config.shutdown_timeout_secs);
+    info!(
+        "Shutting down, waiting up to {}s for in-flight requests",
+        timeout.as_secs()
+    );
+    let _ = stop.send(());
+    let drained = tokio::time::timeout(timeout, async {
+        let served = server.await.expect("Server task failed");
+        if let Some(write_buffer) = write_buffer
+            && let Err(e) = write_buffer.await
+        {
+            error!("Buffered write failed: {e}");
+        }
+        served
+    })
+    .await;
+    match drained {
+        Ok(served) => served?,
+        Err(_) => warn!(
+            "Requests were still running after shutdown_timeout_secs = {}, exiting anyway",
+            timeout.as_secs()
+        ),
+    }
+    // The SQLite pool closes, checkpointing the WAL, as the runtime drops the
+    // maintenance tasks holding the last references to the storage
+    info!("Shut down");
+    Ok(())
 }
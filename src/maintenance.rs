@@ -0,0 +1,979 @@
+use crate::storage::StorageResult;
+use crate::webhooks::Webhooks;
+use crate::{AppConfig, Storage, ValidityCounts, WebhookEvent};
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// One depth sample of a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DepthSample {
+    pub t: DateTime<Utc>,
+    pub depth: usize,
+}
+
+/// The most recent depth samples of every queue, at most `capacity` per queue.
+pub struct DepthHistory {
+    capacity: usize,
+    samples: Mutex<HashMap<String, VecDeque<DepthSample>>>,
+}
+
+impl DepthHistory {
+    pub fn new(capacity: usize) -> Self {
+        DepthHistory {
+            capacity,
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Appends a sample for `queue`, dropping its oldest once `capacity` are kept.
+    pub fn record(&self, queue: &str, sample: DepthSample) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        let queue_samples = samples
+            .entry(queue.to_string())
+            .or_insert_with(|| VecDeque::with_capacity(self.capacity));
+        if queue_samples.len() == self.capacity {
+            queue_samples.pop_front();
+        }
+        queue_samples.push_back(sample);
+    }
+
+    /// Returns the latest `points` samples of `queue`, oldest first.
+    pub fn recent(&self, queue: &str, points: usize) -> Vec<DepthSample> {
+        let samples = self.samples.lock().unwrap();
+        let Some(queue_samples) = samples.get(queue) else {
+            return Vec::new();
+        };
+        let skip = queue_samples.len().saturating_sub(points);
+        queue_samples.iter().skip(skip).copied().collect()
+    }
+}
+
+/// Time-to-empty of a queue projected from its depth samples.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct DrainEstimate {
+    /// Items consumed per second, the negated least-squares slope of the samples;
+    /// negative while the queue grows. `None` with fewer than two distinct sample times.
+    pub rate_per_sec: Option<f64>,
+    /// Depth of the latest sample, the starting point of the projection.
+    pub depth: usize,
+    /// Seconds until the queue empties at `rate_per_sec`, 0 if it is already empty.
+    /// `None` when the queue is not draining.
+    pub eta_secs: Option<f64>,
+    /// Whether the queue is filling faster than it is consumed.
+    pub growing: bool,
+    pub samples: usize,
+}
+
+/// Fits a line through `samples` (oldest first) and projects when it reaches zero.
+pub fn estimate_drain(samples: &[DepthSample]) -> DrainEstimate {
+    let depth = samples.last().map_or(0, |sample| sample.depth);
+    let rate_per_sec = samples.first().and_then(|first| {
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .map(|sample| {
+                let x = (sample.t - first.t).num_milliseconds() as f64 / 1000.0;
+                (x, sample.depth as f64)
+            })
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let spread: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        if spread == 0.0 {
+            return None;
+        }
+        let covariance: f64 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        Some(-covariance / spread)
+    });
+
+    let eta_secs = match rate_per_sec {
+        _ if depth == 0 => Some(0.0),
+        Some(rate) if rate > 0.0 => Some(depth as f64 / rate),
+        _ => None,
+    };
+    DrainEstimate {
+        rate_per_sec,
+        depth,
+        eta_secs,
+        growing: rate_per_sec.is_some_and(|rate| rate < 0.0),
+        samples: samples.len(),
+    }
+}
+
+/// Deleted items removed per compaction step, so a step never holds the write lock long.
+const COMPACT_BATCH: usize = 500;
+
+/// When each queue last served a request, for finding idle queues.
+pub struct Activity {
+    started: Instant,
+    last_seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity {
+            started: Instant::now(),
+            last_seen: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Activity {
+    /// Records a request to `queue`.
+    pub fn touch(&self, queue: &str) {
+        let mut last_seen = self.last_seen.lock().unwrap();
+        match last_seen.get_mut(queue) {
+            Some(seen) => *seen = Instant::now(),
+            None => {
+                last_seen.insert(queue.to_string(), Instant::now());
+            }
+        }
+    }
+
+    /// When the server started, for measuring queues that were never used.
+    pub fn started_at(&self) -> DateTime<Utc> {
+        Utc::now() - Duration::from_std(self.started.elapsed()).unwrap_or_default()
+    }
+
+    /// How long `queue` has gone without a request; queues never used count from startup.
+    pub fn idle_for(&self, queue: &str) -> std::time::Duration {
+        let last_seen = self.last_seen.lock().unwrap();
+        last_seen
+            .get(queue)
+            .copied()
+            .unwrap_or(self.started)
+            .elapsed()
+    }
+}
+
+/// The datetime `secs` seconds before `now`, or None if that is out of chrono's range, in
+/// which case nothing can be older.
+fn secs_before(now: DateTime<Utc>, secs: u64) -> Option<DateTime<Utc>> {
+    let secs = Duration::try_seconds(i64::try_from(secs).ok()?)?;
+    now.checked_sub_signed(secs)
+}
+
+/// Deletes the items of every queue with `retention_secs` whose datetime is older than
+/// the retention window, and returns the number deleted per queue that had any.
+pub fn sweep_retention(storage: &dyn Storage, config: &AppConfig) -> Vec<(String, usize)> {
+    let now = Utc::now();
+    let mut purged = Vec::new();
+    for queue in storage.queue_names() {
+        let Some(retention_secs) = config.queue_options(&queue).retention_secs else {
+            continue;
+        };
+        let Some(cutoff) = secs_before(now, retention_secs) else {
+            continue;
+        };
+        match storage.purge_before(&queue, cutoff) {
+            Ok(0) => {}
+            Ok(count) => {
+                info!("retention purged {count} items older than {cutoff} from queue {queue}");
+                purged.push((queue, count));
+            }
+            Err(e) => error!("Failed to enforce retention on '{queue}': {e}"),
+        }
+    }
+    purged
+}
+
+/// Makes visible again the items of every queue with `max_in_flight_secs` that have
+/// stayed hidden longer than that, however often their hiding was extended, and returns
+/// the number requeued per queue that had any.
+pub fn sweep_in_flight(storage: &dyn Storage, config: &AppConfig) -> Vec<(String, usize)> {
+    let now = Utc::now();
+    let mut requeued = Vec::new();
+    for queue in storage.queue_names() {
+        let Some(max_in_flight_secs) = config.queue_options(&queue).max_in_flight_secs else {
+            continue;
+        };
+        let Some(hidden_before) = secs_before(now, max_in_flight_secs) else {
+            continue;
+        };
+        match storage.requeue_hidden_since(&queue, hidden_before) {
+            Ok(keys) if keys.is_empty() => {}
+            Ok(keys) => {
+                for key in &keys {
+                    warn!(
+                        "forced requeue of item {} from queue {queue}, hidden for over {max_in_flight_secs}s",
+                        key.datetime.to_rfc3339()
+                    );
+                }
+                requeued.push((queue, keys.len()));
+            }
+            Err(e) => error!("Failed to enforce max_in_flight_secs on '{queue}': {e}"),
+        }
+    }
+    requeued
+}
+
+/// Runs `sweep_retention` and `sweep_in_flight` every `retention_sweep_secs` until the
+/// task is dropped. Does nothing unless some queue has a retention period or an
+/// in-flight ceiling.
+pub async fn run_sweeps(storage: Arc<dyn Storage>, config: Arc<AppConfig>) {
+    // A read-only server must not change anything
+    if config.read_only
+        || config
+            .queue_options
+            .values()
+            .all(|options| options.retention_secs.is_none() && options.max_in_flight_secs.is_none())
+    {
+        return;
+    }
+    let period = std::time::Duration::from_secs(config.retention_sweep_secs.max(1));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let config = config.clone();
+        // Sweeps hold storage locks, so keep them off the async workers
+        let sweep = tokio::task::spawn_blocking(move || {
+            sweep_retention(storage.as_ref(), &config);
+            sweep_in_flight(storage.as_ref(), &config);
+        });
+        if let Err(e) = sweep.await {
+            error!("Sweep failed: {e}");
+        }
+    }
+}
+
+/// Logs the depth of every queue, one info line per queue.
+pub fn log_depths(storage: &dyn Storage) {
+    for queue in storage.queue_names() {
+        match storage.count_items(&queue) {
+            Ok(depth) => info!("queue depth {queue}: {depth}"),
+            Err(e) => error!("Failed to count items of '{queue}': {e}"),
+        }
+    }
+}
+
+/// Runs `log_depths` every `depth_log_interval_secs` until the task is dropped.
+/// Does nothing unless the interval is configured.
+pub async fn run_depth_logging(storage: Arc<dyn Storage>, config: Arc<AppConfig>) {
+    let Some(interval_secs) = config.depth_log_interval_secs else {
+        return;
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs.max(1)));
+    // A late tick is skipped rather than followed by a burst of catch-up reports
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        // Counting queries the database, so keep it off the async workers
+        if let Err(e) = tokio::task::spawn_blocking(move || log_depths(storage.as_ref())).await {
+            error!("Depth logging failed: {e}");
+        }
+    }
+}
+
+/// Records the current depth of every queue in `history`.
+pub fn sample_depths(storage: &dyn Storage, history: &DepthHistory) {
+    let t = Utc::now();
+    for queue in storage.queue_names() {
+        match storage.count_items(&queue) {
+            Ok(depth) => history.record(&queue, DepthSample { t, depth }),
+            Err(e) => error!("Failed to count items of '{queue}': {e}"),
+        }
+    }
+}
+
+/// One frame of `GET /_metrics/stream`: the item counts of every queue at `t`, and
+/// whether each queue with `stale_after_secs` is stale.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+    pub t: DateTime<Utc>,
+    pub queues: BTreeMap<String, ValidityCounts>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub stale: BTreeMap<String, bool>,
+}
+
+/// Counts the items of every queue. Queues that fail to count are left out.
+pub fn collect_metrics(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    activity: &Activity,
+) -> MetricsSnapshot {
+    let t = Utc::now();
+    let mut queues = BTreeMap::new();
+    let mut stale = BTreeMap::new();
+    for queue in storage.queue_names() {
+        match staleness(storage, config, activity, &queue) {
+            Ok(Some((is_stale, _))) => {
+                stale.insert(queue.clone(), is_stale);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to check whether '{queue}' is stale: {e}"),
+        }
+        match storage.count_by_validity(&queue) {
+            Ok(counts) => {
+                queues.insert(queue, counts);
+            }
+            Err(e) => error!("Failed to count items of '{queue}': {e}"),
+        }
+    }
+    MetricsSnapshot { t, queues, stale }
+}
+
+/// Whether `queue` has gone longer than its `stale_after_secs` without a write, together
+/// with when it was last written to, or None if it has no such limit. A queue never
+/// written to counts from startup, as for `Activity::idle_for`.
+pub fn staleness(
+    storage: &dyn Storage,
+    config: &AppConfig,
+    activity: &Activity,
+    queue: &str,
+) -> StorageResult<Option<(bool, Option<DateTime<Utc>>)>> {
+    let Some(stale_after_secs) = config.queue_options(queue).stale_after_secs else {
+        return Ok(None);
+    };
+    let last_modified = storage.last_modified(queue)?;
+    let since = last_modified.unwrap_or_else(|| activity.started_at());
+    let stale_after = Duration::seconds(stale_after_secs.min(i64::MAX as u64 / 1000) as i64);
+    Ok(Some((Utc::now() - since > stale_after, last_modified)))
+}
+
+/// Checks every queue with `stale_after_secs` for staleness until the task is dropped,
+/// notifying `webhooks` when one goes stale. Checks as often as the shortest interval,
+/// at least once a minute; does nothing unless a queue subscribes to `stale` events.
+pub async fn run_stale_checks(
+    storage: Arc<dyn Storage>,
+    config: Arc<AppConfig>,
+    activity: Arc<Activity>,
+    webhooks: Webhooks,
+) {
+    let Some(shortest) = config
+        .queue_options
+        .values()
+        .filter(|options| {
+            options
+                .webhook
+                .as_ref()
+                .is_some_and(|webhook| webhook.events.contains(&WebhookEvent::Stale))
+        })
+        .filter_map(|options| options.stale_after_secs)
+        .min()
+    else {
+        return;
+    };
+    let period = std::time::Duration::from_secs(shortest.clamp(1, 60));
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let config = config.clone();
+        let activity = activity.clone();
+        let webhooks = webhooks.clone();
+        // Reading when queues were last written queries the database
+        let checking = tokio::task::spawn_blocking(move || {
+            for queue in storage.queue_names() {
+                match staleness(storage.as_ref(), &config, &activity, &queue) {
+                    Ok(Some((stale, last_modified))) => {
+                        webhooks.staleness_checked(&queue, stale, last_modified)
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to check whether '{queue}' is stale: {e}"),
+                }
+            }
+        });
+        if let Err(e) = checking.await {
+            error!("Stale check failed: {e}");
+        }
+    }
+}
+
+/// Runs `sample_depths` every `depth_history_interval_secs` until the task is dropped.
+/// Does nothing unless the interval is configured.
+pub async fn run_depth_history(
+    storage: Arc<dyn Storage>,
+    config: Arc<AppConfig>,
+    history: Arc<DepthHistory>,
+) {
+    let Some(interval_secs) = config.depth_history_interval_secs else {
+        return;
+    };
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let history = history.clone();
+        // Counting queries the database, so keep it off the async workers
+        let sampling =
+            tokio::task::spawn_blocking(move || sample_depths(storage.as_ref(), &history));
+        if let Err(e) = sampling.await {
+            error!("Depth sampling failed: {e}");
+        }
+    }
+}
+
+/// Truncates the write-ahead log if it has grown beyond `max_wal_bytes`, returning its
+/// size before and after as `Storage::enforce_wal_limit` does.
+pub fn check_wal(storage: &dyn Storage, max_bytes: u64) -> Option<(u64, u64)> {
+    match storage.enforce_wal_limit(max_bytes) {
+        Ok(Some((before, after))) => {
+            warn!(
+                "write-ahead log reached {before} bytes, above max_wal_bytes = {max_bytes}; checkpointed it down to {after} bytes"
+            );
+            Some((before, after))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to check the write-ahead log: {e}");
+            None
+        }
+    }
+}
+
+/// Runs `check_wal` every `wal_check_secs` until the task is dropped.
+/// Does nothing unless `max_wal_bytes` is configured.
+pub async fn run_wal_guard(storage: Arc<dyn Storage>, config: Arc<AppConfig>) {
+    // A read-only server cannot checkpoint
+    let Some(max_bytes) = config.max_wal_bytes.filter(|_| !config.read_only) else {
+        return;
+    };
+    let mut interval =
+        tokio::time::interval(std::time::Duration::from_secs(config.wal_check_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        // Checkpointing writes back the whole log, so keep it off the async workers
+        let check = tokio::task::spawn_blocking(move || check_wal(storage.as_ref(), max_bytes));
+        if let Err(e) = check.await {
+            error!("Write-ahead log check failed: {e}");
+        }
+    }
+}
+
+/// Compacts every queue that has been idle for at least `idle`, batch by batch, and
+/// stops compacting a queue as soon as it serves a request again. Returns the number of
+/// deleted items removed per queue that had any.
+pub fn compact_idle_queues(
+    storage: &dyn Storage,
+    activity: &Activity,
+    idle: std::time::Duration,
+) -> Vec<(String, usize)> {
+    let mut compacted = Vec::new();
+    for queue in storage.queue_names() {
+        let mut removed = 0;
+        while activity.idle_for(&queue) >= idle {
+            match storage.compact(&queue, COMPACT_BATCH) {
+                Ok(0) => break,
+                Ok(count) => removed += count,
+                Err(e) => {
+                    error!("Failed to compact '{queue}': {e}");
+                    break;
+                }
+            }
+        }
+        if removed > 0 {
+            info!("compacted {removed} deleted items of idle queue {queue}");
+            compacted.push((queue, removed));
+        }
+    }
+    compacted
+}
+
+/// Runs `compact_idle_queues` until the task is dropped, checking for idle queues every
+/// `idle_compact_secs`. Does nothing unless that is configured.
+pub async fn run_idle_compaction(
+    storage: Arc<dyn Storage>,
+    config: Arc<AppConfig>,
+    activity: Arc<Activity>,
+) {
+    let Some(idle_secs) = config.idle_compact_secs.filter(|_| !config.read_only) else {
+        return;
+    };
+    let idle = std::time::Duration::from_secs(idle_secs.max(1));
+    let mut interval = tokio::time::interval(idle);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let activity = activity.clone();
+        // Compaction writes to the database, so keep it off the async workers
+        let compaction = tokio::task::spawn_blocking(move || {
+            compact_idle_queues(storage.as_ref(), &activity, idle)
+        });
+        if let Err(e) = compaction.await {
+            error!("Idle compaction failed: {e}");
+        }
+    }
+}
+
+/// Purges the deleted items of every queue last written more than `older_than` ago, see
+/// `Storage::purge_deleted`. Returns the number removed per queue that had any.
+pub fn purge_deleted_items(storage: &dyn Storage, older_than: Duration) -> Vec<(String, usize)> {
+    let mut purged = Vec::new();
+    for queue in storage.queue_names() {
+        match storage.purge_deleted(&queue, older_than) {
+            Ok(0) => {}
+            Ok(count) => {
+                info!("purged {count} deleted items of queue {queue}");
+                purged.push((queue, count));
+            }
+            Err(e) => error!("Failed to purge deleted items of '{queue}': {e}"),
+        }
+    }
+    purged
+}
+
+/// Vacuums the database once `purged` items were removed, if that reaches `threshold`,
+/// returning its size before and after as `Storage::vacuum` does.
+pub fn vacuum_after_purge(
+    storage: &dyn Storage,
+    purged: usize,
+    threshold: usize,
+) -> Option<(u64, u64)> {
+    if purged == 0 || purged < threshold {
+        return None;
+    }
+    match storage.vacuum() {
+        Ok(Some((before, after))) => {
+            info!(
+                "vacuumed the database after purging {purged} deleted items, from {before} to {after} bytes"
+            );
+            Some((before, after))
+        }
+        Ok(None) => None,
+        Err(e) => {
+            error!("Failed to vacuum the database: {e}");
+            None
+        }
+    }
+}
+
+/// Runs `purge_deleted_items` every `purge_interval_secs` until the task is dropped,
+/// followed by `vacuum_after_purge` if `vacuum_after_purged` is set. Does nothing unless
+/// `purge_interval_secs` is configured.
+pub async fn run_deleted_purge(storage: Arc<dyn Storage>, config: Arc<AppConfig>) {
+    let Some(interval_secs) = config.purge_interval_secs.filter(|_| !config.read_only) else {
+        return;
+    };
+    let older_than = i64::try_from(config.purge_older_than_secs)
+        .ok()
+        .and_then(Duration::try_seconds)
+        .unwrap_or(Duration::MAX);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        // Purging writes to the database, so keep it off the async workers
+        let vacuum_after = config.vacuum_after_purged;
+        let purge = tokio::task::spawn_blocking(move || {
+            let purged = purge_deleted_items(storage.as_ref(), older_than);
+            if let Some(threshold) = vacuum_after {
+                let total = purged.iter().map(|(_, count)| count).sum();
+                vacuum_after_purge(storage.as_ref(), total, threshold);
+            }
+        });
+        if let Err(e) = purge.await {
+            error!("Purging deleted items failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStorage, QueueItem, QueueOptions, SqliteStorage};
+
+    #[test]
+    fn test_sweep_purges_only_expired_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = AppConfig {
+            queues: vec!["expiring".to_string(), "kept".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "expiring".to_string(),
+            QueueOptions {
+                retention_secs: Some(3600),
+                ..Default::default()
+            },
+        );
+        let sqlite_config = AppConfig {
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..config.clone()
+        };
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&sqlite_config).unwrap()),
+            Box::new(InMemoryStorage::new(&config)),
+        ];
+
+        let now = Utc::now();
+        let old = QueueItem {
+            datetime: now - Duration::hours(2),
+            datetime_secondary: Some(now),
+            message: "old".to_string(),
+            ..Default::default()
+        };
+        let new = QueueItem {
+            datetime: now - Duration::minutes(30),
+            message: "new".to_string(),
+            ..Default::default()
+        };
+        for storage in backends {
+            for queue in ["expiring", "kept"] {
+                storage.put_item(queue, old.clone()).unwrap();
+                storage.put_item(queue, new.clone()).unwrap();
+            }
+
+            let purged = sweep_retention(storage.as_ref(), &config);
+            assert_eq!(purged, vec![("expiring".to_string(), 1)]);
+            let messages: Vec<String> = storage
+                .export_items("expiring")
+                .unwrap()
+                .into_iter()
+                .map(|item| item.message)
+                .collect();
+            assert_eq!(messages, ["new"]);
+            assert_eq!(storage.count_items("kept").unwrap(), 2);
+            assert!(sweep_retention(storage.as_ref(), &config).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sweeps_ignore_windows_beyond_datetime_range() {
+        let mut config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                retention_secs: Some(u64::MAX),
+                max_in_flight_secs: Some(u64::MAX),
+                ..Default::default()
+            },
+        );
+        let storage = InMemoryStorage::new(&config);
+        let item = QueueItem {
+            datetime: Utc::now() - Duration::days(365),
+            message: "ancient".to_string(),
+            ..Default::default()
+        };
+        storage.put_item("queue", item.clone()).unwrap();
+        storage
+            .hide_item("queue", &item.key(), Utc::now() + Duration::hours(1))
+            .unwrap();
+
+        assert!(sweep_retention(&storage, &config).is_empty());
+        assert!(sweep_in_flight(&storage, &config).is_empty());
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sweep_in_flight_ignores_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = AppConfig {
+            queues: vec!["queue".to_string()],
+            ..Default::default()
+        };
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                max_in_flight_secs: Some(1),
+                ..Default::default()
+            },
+        );
+        let sqlite_config = AppConfig {
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..config.clone()
+        };
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&sqlite_config).unwrap()),
+            Box::new(InMemoryStorage::new(&config)),
+        ];
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "hoarded".to_string(),
+            ..Default::default()
+        };
+        for storage in backends {
+            storage.put_item("queue", item.clone()).unwrap();
+            // The consumer keeps the item hidden for another 10 seconds every 300 ms
+            let started = std::time::Instant::now();
+            let requeued = loop {
+                let until = Utc::now() + Duration::seconds(10);
+                assert!(storage.hide_item("queue", &item.key(), until).unwrap());
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                let requeued = sweep_in_flight(storage.as_ref(), &config);
+                if !requeued.is_empty() {
+                    break requeued;
+                }
+                assert!(storage.get_item("queue").unwrap().is_none());
+            };
+            let elapsed = started.elapsed();
+            assert!(elapsed >= std::time::Duration::from_secs(1), "{elapsed:?}");
+            assert!(elapsed < std::time::Duration::from_secs(2), "{elapsed:?}");
+            assert_eq!(requeued, vec![("queue".to_string(), 1)]);
+            assert_eq!(
+                storage.get_item("queue").unwrap().unwrap().message,
+                "hoarded"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_wal_truncates_a_large_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let wal_path = dir.path().join("queue.sqlite-wal");
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage = SqliteStorage::new(&config).unwrap();
+        const LIMIT: u64 = 512 * 1024;
+        assert_eq!(check_wal(&storage, LIMIT), None);
+
+        // An open read transaction keeps checkpoints from rewinding the log, as a lagging
+        // reader would, so sustained writes make it grow
+        let reader = rusqlite::Connection::open(&db_path).unwrap();
+        reader.execute_batch("BEGIN").unwrap();
+        reader
+            .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            .unwrap();
+        let now = Utc::now();
+        for batch in 0..20 {
+            let items = (0..50)
+                .map(|i| QueueItem {
+                    datetime: now + Duration::milliseconds(batch * 50 + i),
+                    message: "x".repeat(1024),
+                    ..Default::default()
+                })
+                .collect();
+            storage.put_items("queue", items).unwrap();
+        }
+        let grown = std::fs::metadata(&wal_path).unwrap().len();
+        assert!(grown > LIMIT, "log is only {grown} bytes");
+        reader.execute_batch("COMMIT").unwrap();
+
+        let (before, after) = check_wal(&storage, LIMIT).unwrap();
+        assert_eq!(before, grown);
+        assert_eq!(after, 0);
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), 0);
+        assert_eq!(storage.count_items("queue").unwrap(), 1000);
+        assert_eq!(check_wal(&storage, LIMIT), None);
+    }
+
+    #[test]
+    fn test_depth_history_is_bounded() {
+        let history = DepthHistory::new(3);
+        let t = Utc::now();
+        for depth in 0..5 {
+            history.record("queue", DepthSample { t, depth });
+        }
+        let depths: Vec<usize> = history
+            .recent("queue", 10)
+            .iter()
+            .map(|sample| sample.depth)
+            .collect();
+        assert_eq!(depths, [2, 3, 4]);
+        assert_eq!(history.recent("queue", 1)[0].depth, 4);
+        assert!(history.recent("other", 10).is_empty());
+    }
+
+    #[test]
+    fn test_estimate_drain() {
+        let t = Utc::now();
+        let sample = |secs: i64, depth| DepthSample {
+            t: t + Duration::seconds(secs),
+            depth,
+        };
+
+        // 2 items per second consumed, 40 left: empty in 20 seconds
+        let draining: Vec<DepthSample> = (0..5)
+            .map(|i| sample(i * 10, 120 - 20 * i as usize))
+            .collect();
+        let estimate = estimate_drain(&draining);
+        assert_eq!(estimate.depth, 40);
+        assert!((estimate.rate_per_sec.unwrap() - 2.0).abs() < 1e-9);
+        assert!((estimate.eta_secs.unwrap() - 20.0).abs() < 1e-9);
+        assert!(!estimate.growing);
+        assert_eq!(estimate.samples, 5);
+
+        let growing = [sample(0, 5), sample(10, 8), sample(20, 12)];
+        let estimate = estimate_drain(&growing);
+        assert!(estimate.growing);
+        assert!(estimate.rate_per_sec.unwrap() < 0.0);
+        assert_eq!(estimate.eta_secs, None);
+
+        let steady = [sample(0, 7), sample(10, 7)];
+        let estimate = estimate_drain(&steady);
+        assert_eq!(estimate.rate_per_sec, Some(0.0));
+        assert_eq!((estimate.eta_secs, estimate.growing), (None, false));
+
+        let single = estimate_drain(&[sample(0, 3)]);
+        assert_eq!((single.rate_per_sec, single.eta_secs), (None, None));
+        assert_eq!(estimate_drain(&[]).eta_secs, Some(0.0));
+    }
+
+    #[test]
+    fn test_idle_queues_are_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = AppConfig {
+            queues: vec!["idle".to_string(), "busy".to_string()],
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage = SqliteStorage::new(&config).unwrap();
+        let activity = Activity::default();
+        let idle = std::time::Duration::from_millis(100);
+        let now = Utc::now();
+        for queue in ["idle", "busy"] {
+            for i in 0..3 {
+                let item = QueueItem {
+                    datetime: now - Duration::seconds(10) + Duration::milliseconds(i),
+                    message: "done".to_string(),
+                    ..Default::default()
+                };
+                storage.put_item(queue, item).unwrap();
+            }
+            for _ in 0..2 {
+                storage.delete_item(queue).unwrap();
+                activity.touch(queue);
+            }
+        }
+        let rows = |queue: &str| -> i64 {
+            rusqlite::Connection::open(&db_path)
+                .unwrap()
+                .query_row(&format!("SELECT COUNT(*) FROM queue_{queue}"), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        // Nothing is compacted while the queues have just been used
+        assert!(compact_idle_queues(&storage, &activity, idle).is_empty());
+        assert_eq!(rows("idle"), 3);
+
+        std::thread::sleep(idle);
+        activity.touch("busy");
+        assert_eq!(
+            compact_idle_queues(&storage, &activity, idle),
+            [("idle".to_string(), 2)]
+        );
+        assert_eq!(rows("idle"), 1);
+        assert_eq!(rows("busy"), 3);
+        // The remaining item is untouched and the queue keeps working
+        assert_eq!(storage.get_item("idle").unwrap().unwrap().message, "done");
+        assert!(compact_idle_queues(&storage, &activity, idle).is_empty());
+    }
+
+    #[test]
+    fn test_deleted_items_are_purged_once_old() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage = SqliteStorage::new(&config).unwrap();
+        let now = Utc::now();
+        for i in 0..3 {
+            let item = QueueItem {
+                datetime: now - Duration::seconds(10) + Duration::milliseconds(i),
+                message: format!("{i}"),
+                ..Default::default()
+            };
+            storage.put_item("queue", item).unwrap();
+        }
+        storage.delete_item("queue").unwrap();
+        storage.delete_item("queue").unwrap();
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        // Every item was written long ago; the first was also deleted long ago, while the
+        // second was consumed just now and must wait out the age in full
+        conn.execute(
+            "UPDATE queue_queue SET last_modified = '2000-01-01 00:00:00'",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE queue_queue SET deleted_at = '2000-01-01 00:00:00' WHERE message = '0'",
+            [],
+        )
+        .unwrap();
+        let rows = || -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM queue_queue", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        assert_eq!(
+            storage.purge_deleted("queue", Duration::hours(1)).unwrap(),
+            1
+        );
+        assert_eq!(rows(), 2);
+        assert_eq!(
+            purge_deleted_items(&storage, Duration::zero()),
+            [("queue".to_string(), 1)]
+        );
+        assert_eq!(rows(), 1);
+        // The pending item is kept, and an unbounded age purges nothing
+        assert_eq!(storage.get_item("queue").unwrap().unwrap().message, "2");
+        assert_eq!(storage.purge_deleted("queue", Duration::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_vacuum_after_purge_shrinks_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = AppConfig {
+            queues: vec!["queue".to_string()],
+            database_path: db_path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let storage = SqliteStorage::new(&config).unwrap();
+        let now = Utc::now();
+        let items: Vec<QueueItem> = (0..500)
+            .map(|i| QueueItem {
+                datetime: now - Duration::seconds(10) + Duration::milliseconds(i),
+                message: format!("{i:04}{}", "x".repeat(2000)),
+                ..Default::default()
+            })
+            .collect();
+        storage.put_items("queue", items.clone()).unwrap();
+        for _ in 0..499 {
+            storage.delete_item("queue").unwrap();
+        }
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute(
+            "UPDATE queue_queue SET deleted_at = '2000-01-01 00:00:00' WHERE valid = 0",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+        let purged = purge_deleted_items(&storage, Duration::hours(1));
+        assert_eq!(purged, [("queue".to_string(), 499)]);
+
+        // Below the threshold nothing is vacuumed
+        assert_eq!(vacuum_after_purge(&storage, 499, 500), None);
+        let (before, after) = vacuum_after_purge(&storage, 499, 100).unwrap();
+        assert!(after * 4 < before, "{before} -> {after}");
+        assert!(std::fs::metadata(&db_path).unwrap().len() <= after);
+        // The pending item is untouched
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+        assert_eq!(
+            storage.get_item("queue").unwrap().unwrap().message,
+            items[499].message
+        );
+    }
+}
@@ -1,35 +1,434 @@
-use crate::AppConfig;
-use crate::QueueItem;
-use crate::utils::sanitize_queue_name;
-use chrono::{DateTime, Utc};
-use rusqlite::{Connection, OptionalExtension, params};
+use crate::utils::{normalize_queue_name, sanitize_queue_name};
+use crate::{AppConfig, JournalMode, KeyBy, QueueOptions, TempStore};
+use crate::{QueueItem, QueueKey};
+use chrono::{DateTime, Datelike, Utc};
+use log::{info, warn};
+use rusqlite::{Connection, MAIN_DB, OpenFlags, OptionalExtension, TransactionBehavior, params};
+use serde::Serialize;
+use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::sync::RwLock;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum StorageError {
     #[error("Database error: {0}")]
-    Database(#[from] rusqlite::Error),
+    Database(rusqlite::Error),
+    /// A stored value that cannot be decoded, such as a datetime out of range.
+    #[error("Corrupt data in the database: {0}")]
+    CorruptData(String),
     #[error("Queue not found: {0}")]
     QueueNotFound(String),
+    #[error("Invalid queue name: {0}")]
+    InvalidQueueName(String),
+    /// A queue that cannot be created because it would share a SQLite table with
+    /// another, see `tables_collide`.
+    #[error("Queue {queue} would share a SQLite table with queue {other}")]
+    TableConflict { queue: String, other: String },
+    #[error("Queue limit of {0} reached")]
+    QueueLimitReached(usize),
+    #[error("Message is not an integer that can be incremented: {0}")]
+    NotAnInteger(String),
+    #[error("Fencing token {token} is lower than {highest}, the highest seen for the queue")]
+    StaleFenceToken { token: i64, highest: i64 },
+    /// A datetime that an operation such as a shift would move out of the range that
+    /// can be stored.
+    #[error("Datetime out of range: {0}")]
+    DatetimeOutOfRange(String),
+    /// An operation of `Storage::transact` that could not be applied; nothing was.
+    #[error("Operation {index} failed: {reason}")]
+    TransactionAborted { index: usize, reason: String },
     #[error("Lock error")]
     LockError,
     #[error("Pool error: {0}")]
     PoolError(#[from] r2d2::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(
+        "database_key is set but SQLite was built without SQLCipher; enable the sqlcipher feature"
+    )]
+    EncryptionUnavailable,
 }
 
 pub type StorageResult<T> = Result<T, StorageError>;
 
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            // Raised while decoding a column, by SQLite's conversions or by `row_to_item`
+            rusqlite::Error::FromSqlConversionFailure(column, _, cause) => {
+                StorageError::CorruptData(format!("column {column}: {cause}"))
+            }
+            e => StorageError::Database(e),
+        }
+    }
+}
+
+/// The outcome of `Storage::compare_and_delete`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CasResult {
+    /// The head matched the expected key and was deleted.
+    Deleted(QueueItem),
+    /// The head is a different item, or None if the queue is empty; nothing was deleted.
+    Mismatch(Option<QueueItem>),
+}
+
+/// One operation of `Storage::transact`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxOp {
+    /// Stores the item, as `put_item` does.
+    Put { queue: String, item: QueueItem },
+    /// Consumes the visible head, as `delete_item` does; with `due_only` only a due one.
+    /// Fails when there is no such head.
+    Pop { queue: String, due_only: bool },
+    /// Deletes the item under `key`, as `remove_item` does. Fails when there is none.
+    Delete { queue: String, key: QueueKey },
+}
+
+impl TxOp {
+    pub fn queue(&self) -> &str {
+        match self {
+            TxOp::Put { queue, .. } | TxOp::Pop { queue, .. } | TxOp::Delete { queue, .. } => queue,
+        }
+    }
+}
+
+/// A stored row that could not be decoded into an item, see `Storage::export_rows`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptRow {
+    /// The raw key columns in milliseconds since the epoch; an absent secondary datetime
+    /// is stored as `i64::MIN`.
+    pub datetime: i64,
+    pub datetime_secondary: i64,
+    /// Why the row could not be decoded.
+    pub error: String,
+}
+
+/// The items a queue stores, by state, as returned by `Storage::count_by_validity`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ValidityCounts {
+    /// Pending items, as counted by `count_items`.
+    pub valid: usize,
+    /// Deleted items kept until `compact` removes them.
+    pub invalid: usize,
+}
+
 pub trait Storage: Send + Sync {
-    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<()>;
+    /// Stores `item` under its key. An existing item with the same key is replaced
+    /// (a previously deleted one is revived): the put that completes last wins, on every
+    /// backend. Writes to one queue are serialized, so concurrent puts never interleave.
+    /// Returns true if the key was newly created and false if an item was replaced.
+    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<bool>;
+    /// Replaces the valid item stored under `item`'s key, as by `put_item`, only if its
+    /// message is `expected`. The check and the replacement are atomic. Returns false,
+    /// leaving the queue untouched, if the key is not present or holds another message.
+    fn replace_if_message(
+        &self,
+        queue: &str,
+        item: QueueItem,
+        expected: &str,
+    ) -> StorageResult<bool>;
+    /// Stores `item` as by `put_item` unless `token` is lower than the highest fencing
+    /// token a fenced put to the queue carried, failing with `StaleFenceToken` and storing
+    /// nothing in that case. Otherwise `token` becomes the highest, committed together
+    /// with the item. SQLite keeps the highest token of each queue across restarts.
+    fn put_item_fenced(&self, queue: &str, item: QueueItem, token: i64) -> StorageResult<bool>;
+    /// Stores several items at once, each as by `put_item`, in a single transaction.
+    fn put_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()>;
+    /// Stores items that were already consumed elsewhere, for migrations, in a single
+    /// transaction: they are never delivered. SQLite keeps them as soft-deleted rows for
+    /// audit; the in-memory backend only removes any live item under the same key.
+    fn put_consumed_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()>;
     fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>>;
+    /// Returns the valid item retrieved last, the one with the latest key, without
+    /// removing it. Hidden items count, since they keep their place.
+    fn tail_item(&self, queue: &str) -> StorageResult<Option<QueueItem>>;
+    /// Returns the head of each of `queues`, as by `get_item`, all read at one consistent
+    /// point: no write commits between the reads of two queues.
+    fn snapshot_heads(&self, queues: &[String]) -> StorageResult<Vec<Option<QueueItem>>>;
+    /// Deletes and returns the head. A recurring item is replaced by its next occurrence
+    /// in the same step, see `QueueItem::next_occurrence`, as are the items taken by
+    /// `delete_tenant_item`, `delete_newest_item`, `compare_and_delete` and `claim_any`.
+    /// `remove_item` and `purge_before` delete without reinserting.
     fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>>;
+    /// Returns the head of the items tagged with `tenant`, as `get_item` does for the
+    /// whole queue. Other tenants' items and untagged items are passed over. With
+    /// `due_by`, so are the items whose datetime is after it, compared in milliseconds.
+    fn get_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>>;
+    /// Deletes and returns the head of the items tagged with `tenant`, as by
+    /// `get_tenant_item`.
+    fn delete_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>>;
+    /// Returns the visible item with the latest key, the head if the queue were retrieved
+    /// newest first. Unlike `tail_item`, hidden items are passed over.
+    fn get_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>>;
+    /// Deletes and returns the item `get_newest_item` returns.
+    fn delete_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>>;
+    /// Deletes the item stored under `key`, wherever it is in the queue.
+    /// Returns false if the key is not present.
+    fn remove_item(&self, queue: &str, key: &QueueKey) -> StorageResult<bool>;
+    /// Deletes the head of a queue, as by `delete_item`, only if it is stored under
+    /// `expected`. The check and the deletion are atomic.
+    fn compare_and_delete(&self, queue: &str, expected: &QueueKey) -> StorageResult<CasResult>;
+    /// Atomically deletes and returns the earliest visible head among `queues` whose
+    /// datetime is not after `now`, compared in milliseconds, together with its queue.
+    /// Without `due_only` the earliest visible head is taken whatever its datetime.
+    /// Heads with equal keys tie, and a tie goes to the first tied queue at or after
+    /// index `start` of `queues`, wrapping around, so rotating `start` shares ties fairly.
+    fn claim_any(
+        &self,
+        queues: &[String],
+        now: DateTime<Utc>,
+        due_only: bool,
+        start: usize,
+    ) -> StorageResult<Option<(String, QueueItem)>>;
+    /// Applies `ops` in order as one atomic step, across any number of queues: either
+    /// all of them are applied or, if one fails, none. Returns the item each `Pop`
+    /// consumed, and None for the other operations.
+    fn transact(&self, ops: &[TxOp]) -> StorageResult<Vec<Option<QueueItem>>>;
+    /// Deletes every valid item whose primary datetime is before `cutoff`, hidden or not,
+    /// and returns how many were deleted.
+    fn purge_before(&self, queue: &str, cutoff: DateTime<Utc>) -> StorageResult<usize>;
+    /// Atomically exchanges the positions of the items stored under `key_a` and `key_b`:
+    /// the message at `key_a` moves to `key_b` and vice versa.
+    /// Returns false, leaving the queue untouched, if either key is not present.
+    fn swap_items(&self, queue: &str, key_a: &QueueKey, key_b: &QueueKey) -> StorageResult<bool>;
+    /// Hides the item stored under `key` from get, delete and peek until `until`,
+    /// without removing it. A later put of the same key makes it visible again.
+    /// Returns false if the key is not present.
+    fn hide_item(&self, queue: &str, key: &QueueKey, until: DateTime<Utc>) -> StorageResult<bool>;
+    /// Moves the end of the hiding of each of `keys` that is still hidden to `until`, all
+    /// in one transaction, and returns for each key in order whether it was. Items whose
+    /// hiding expired, or that are gone, are left alone. An extension keeps when the
+    /// hiding started, as by `hide_item`.
+    fn extend_hidden(
+        &self,
+        queue: &str,
+        keys: &[QueueKey],
+        until: DateTime<Utc>,
+    ) -> StorageResult<Vec<bool>>;
+    /// Makes visible again every item hidden without a break since before `hidden_before`,
+    /// however often its hiding was extended, and returns the keys of those items.
+    fn requeue_hidden_since(
+        &self,
+        queue: &str,
+        hidden_before: DateTime<Utc>,
+    ) -> StorageResult<Vec<QueueKey>>;
+    /// Atomically adds `by` to the integer stored as the message under `key` and returns
+    /// the new value. A missing key is created with the value `by`, as if it held 0.
+    /// Fails with `NotAnInteger` if the message is not an integer or the sum overflows.
+    fn increment_item(&self, queue: &str, key: &QueueKey, by: i64) -> StorageResult<i64>;
+    /// Adds `by` to the datetime of every valid item, hidden or not, and to its secondary
+    /// datetime too if `secondary` is set, all in one transaction, and returns how many
+    /// items moved. Items keep their content, hiding and relative order. Fails with
+    /// `DatetimeOutOfRange`, leaving the queue untouched, if a datetime would leave the
+    /// representable range.
+    fn shift_items(
+        &self,
+        queue: &str,
+        by: chrono::Duration,
+        secondary: bool,
+    ) -> StorageResult<usize>;
+    /// Returns up to `limit` visible items from the head of a queue in retrieval order,
+    /// without removing them. With `after`, only items ordering after that key are
+    /// considered, so a listing can continue where an earlier one stopped.
+    fn peek_items(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>>;
+    /// Returns all valid items of a queue in retrieval order.
+    fn export_items(&self, queue: &str) -> StorageResult<Vec<QueueItem>>;
+    /// Returns up to `limit` valid items of a queue in retrieval order, hidden ones
+    /// included. With `after`, only items ordering after that key are considered, so an
+    /// export can be read a page at a time.
+    fn export_page(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>>;
+    /// Returns every valid item whose primary datetime is before `before`, hidden or not,
+    /// in retrieval order.
+    fn overdue_items(&self, queue: &str, before: DateTime<Utc>) -> StorageResult<Vec<QueueItem>>;
+    /// Like `export_items`, but a row that cannot be decoded is returned in its place as a
+    /// `CorruptRow` instead of failing the whole export. The in-memory backend only holds
+    /// decoded items, so all of its rows are items.
+    fn export_rows(&self, queue: &str) -> StorageResult<Vec<Result<QueueItem, CorruptRow>>>;
+    /// Returns how many valid items order before the item under `key`, i.e. its
+    /// zero-based position in line, or None if the key is not present.
+    /// Hidden items count, since they keep their place.
+    fn rank_of_key(&self, queue: &str, key: &QueueKey) -> StorageResult<Option<usize>>;
+    /// Returns, for each of `keys` in order, whether a valid item is stored under it.
+    /// Hidden items count as present.
+    fn items_exist(&self, queue: &str, keys: &[QueueKey]) -> StorageResult<Vec<bool>>;
+    /// Returns the number of valid items in a queue.
+    fn count_items(&self, queue: &str) -> StorageResult<usize>;
+    /// Returns the number of valid items and of the deleted items still stored.
+    /// Backends that do not keep deleted items always report 0 of them.
+    fn count_by_validity(&self, queue: &str) -> StorageResult<ValidityCounts>;
+    /// Returns when a queue's content last changed by a put, swap, increment or shift, or
+    /// None if it never did. Consuming or hiding items does not count. SQLite records this
+    /// with one-second precision.
+    fn last_modified(&self, queue: &str) -> StorageResult<Option<DateTime<Utc>>>;
+    /// Registers a queue that was not listed in the configuration.
+    /// Returns false if the queue already exists, and fails once `max_queues` queues
+    /// are registered. Queues created at runtime are forgotten on restart unless
+    /// they are added to the configuration.
+    fn create_queue(&self, queue: &str) -> StorageResult<bool>;
+    /// Returns the names of all registered queues, sorted.
+    fn queue_names(&self) -> Vec<String>;
     fn queue_exists(&self, queue: &str) -> bool;
+    /// Truncates the SQLite write-ahead log with a checkpoint if its file has grown
+    /// beyond `max_bytes`, returning its size before and after, or None if it was within
+    /// the limit. The log only shrinks once no reader still needs it. Backends without a
+    /// log do nothing.
+    fn enforce_wal_limit(&self, max_bytes: u64) -> StorageResult<Option<(u64, u64)>>;
+    /// Physically deletes up to `limit` of the deleted items a queue keeps, and returns
+    /// how many were removed. Backends that do not keep deleted items do nothing.
+    fn compact(&self, queue: &str, limit: usize) -> StorageResult<usize>;
+    /// Physically deletes every deleted item a queue keeps that was consumed or deleted
+    /// more than `older_than` ago, and returns how many were removed. Backends that do
+    /// not keep deleted items do nothing.
+    fn purge_deleted(&self, queue: &str, older_than: chrono::Duration) -> StorageResult<usize>;
+    /// Rebuilds the SQLite database with `VACUUM`, returning the pages freed by purged
+    /// rows to the filesystem, and returns its size in bytes before and after. Every
+    /// other write waits until it is done. Backends without a database file do nothing.
+    fn vacuum(&self) -> StorageResult<Option<(u64, u64)>>;
+}
+
+/// The ordering key shared by every backend: primary datetime, then secondary datetime,
+/// both as milliseconds since the epoch, with a missing secondary stored as `i64::MIN`
+/// so it sorts before any present one.
+///
+/// SQLite stores exactly these two values as its primary key columns and the in-memory
+/// backend uses this type as its map key, so both select heads identically. Keys that
+/// only differ below a millisecond are the same key in both backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct SortKey {
+    datetime: i64,
+    datetime_secondary: i64,
+}
+
+impl SortKey {
+    const NO_SECONDARY: i64 = i64::MIN;
+
+    fn new(key: &QueueKey) -> Self {
+        SortKey {
+            datetime: key.datetime.timestamp_millis(),
+            datetime_secondary: key
+                .datetime_secondary
+                .map(|d| d.timestamp_millis())
+                .unwrap_or(Self::NO_SECONDARY),
+        }
+    }
+
+    /// Whether an item under this key is due at `now`, in milliseconds. The boundary is
+    /// inclusive: an item scheduled at the current millisecond is due. Both backends
+    /// decide with this, so they agree at the boundary.
+    fn is_due(self, now: i64) -> bool {
+        self.datetime <= now
+    }
+
+    /// Decodes a key read from storage. A secondary of `NO_SECONDARY` is no secondary
+    /// datetime; any other value, like the primary, must be a representable datetime.
+    /// Fails with the index of the offending column, 0 or 1, and why.
+    fn try_to_key(self) -> Result<QueueKey, (usize, String)> {
+        let decode = |column, name, millis| {
+            DateTime::<Utc>::from_timestamp_millis(millis)
+                .ok_or_else(|| (column, format!("{name} {millis} is out of range")))
+        };
+        let datetime_secondary = match self.datetime_secondary {
+            Self::NO_SECONDARY => None,
+            millis => Some(decode(1, "datetime_secondary", millis)?),
+        };
+        Ok(QueueKey {
+            datetime: decode(0, "datetime", self.datetime)?,
+            datetime_secondary,
+        })
+    }
+
+    /// Like `try_to_key` for the keys of the in-memory backend, which are built from
+    /// datetimes and so always decode.
+    fn to_key(self) -> QueueKey {
+        self.try_to_key()
+            .expect("in-memory keys are built from valid datetimes")
+    }
+
+    fn to_item(
+        self,
+        message: String,
+        id: Option<String>,
+        payloads: Option<BTreeMap<String, String>>,
+        tenant: Option<String>,
+        recurrence: Recurrence,
+    ) -> QueueItem {
+        item_at(self.to_key(), message, id, payloads, tenant, recurrence)
+    }
 }
 
 struct SqliteConnectionManager {
     path: String,
+    /// The SQLCipher key, which must be given before anything else reads the file.
+    key: Option<String>,
+    read_only: bool,
+    statement_cache_capacity: usize,
+    temp_store: Option<TempStore>,
+    journal_size_limit: Option<u64>,
+    journal_mode: JournalMode,
+}
+
+impl SqliteConnectionManager {
+    fn apply_key(&self, conn: &Connection) -> rusqlite::Result<()> {
+        match &self.key {
+            Some(key) => conn.pragma_update(None, "key", key),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Logs the journal mode, temp store and journal size limit a connection ended up with,
+/// which SQLite may differ from the configuration on, e.g. when built with a fixed temp
+/// store.
+fn log_tuning(conn: &Connection) -> rusqlite::Result<()> {
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    let temp_store: i64 = conn.query_row("PRAGMA temp_store", [], |row| row.get(0))?;
+    let journal_size_limit: i64 =
+        conn.query_row("PRAGMA journal_size_limit", [], |row| row.get(0))?;
+    let temp_store = match temp_store {
+        1 => "file",
+        2 => "memory",
+        _ => "default",
+    };
+    let journal_size_limit = if journal_size_limit < 0 {
+        "unlimited".to_string()
+    } else {
+        format!("{journal_size_limit} bytes")
+    };
+    info!(
+        "SQLite journal_mode: {journal_mode}, temp_store: {temp_store}, journal_size_limit: {journal_size_limit}"
+    );
+    Ok(())
+}
+
+/// Whether the linked SQLite is SQLCipher, which reports its version and plain SQLite
+/// does not.
+fn has_sqlcipher(conn: &Connection) -> rusqlite::Result<bool> {
+    let version: Option<String> = conn
+        .query_row("PRAGMA cipher_version", [], |row| row.get(0))
+        .optional()?;
+    Ok(version.is_some())
 }
 
 impl r2d2::ManageConnection for SqliteConnectionManager {
@@ -37,10 +436,37 @@ impl r2d2::ManageConnection for SqliteConnectionManager {
     type Error = rusqlite::Error;
 
     fn connect(&self) -> Result<Connection, rusqlite::Error> {
-        let conn = Connection::open(&self.path)?;
-        conn.pragma_update(None, "journal_mode", "WAL")?;
-        conn.pragma_update(None, "synchronous", "FULL")?;
+        let conn = if self.read_only {
+            // The journal mode is a property of the file and cannot be changed read-only
+            let conn = Connection::open_with_flags(
+                &self.path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            self.apply_key(&conn)?;
+            conn
+        } else {
+            let conn = Connection::open(&self.path)?;
+            self.apply_key(&conn)?;
+            conn.pragma_update(None, "journal_mode", self.journal_mode.pragma_value())?;
+            conn.pragma_update(None, "synchronous", "FULL")?;
+            conn
+        };
         conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.set_prepared_statement_cache_capacity(self.statement_cache_capacity);
+        if let Some(temp_store) = self.temp_store {
+            let value = match temp_store {
+                TempStore::File => "FILE",
+                TempStore::Memory => "MEMORY",
+            };
+            conn.pragma_update(None, "temp_store", value)?;
+        }
+        if let Some(limit) = self.journal_size_limit {
+            conn.pragma_update(
+                None,
+                "journal_size_limit",
+                limit.min(i64::MAX as u64) as i64,
+            )?;
+        }
         Ok(conn)
     }
 
@@ -53,261 +479,4891 @@ impl r2d2::ManageConnection for SqliteConnectionManager {
     }
 }
 
-pub struct SqliteStorage {
-    pool: r2d2::Pool<SqliteConnectionManager>,
-    queues: HashSet<String>,
-    get_item_sqls: HashMap<String, String>,
-    put_item_sqls: HashMap<String, String>,
-    delete_item_sqls: HashMap<String, String>,
+/// Prepared SQL for the table holding one shard of a queue.
+struct ShardSql {
+    table: String,
+    put_item: String,
+    put_consumed_item: String,
+    replace_item: String,
+    delete_item: String,
+    delete_tenant_item: String,
+    delete_newest_item: String,
+    find_message: String,
+    update_message: String,
+    remove_item: String,
+    purge_before: String,
+    hide_item: String,
+    extend_hidden: String,
+    requeue_hidden: String,
+    compact: String,
+    purge_deleted: String,
+    supersede_id: String,
+    valid_rows: String,
+    invalidate_all: String,
+    put_moved: String,
 }
 
-impl SqliteStorage {
-    pub fn new(config: &AppConfig) -> StorageResult<Self> {
-        let manager = SqliteConnectionManager {
-            path: config.database_path.clone(),
+/// A registered queue: its shard tables and the SQL used to access them.
+struct SqliteQueue {
+    /// For a queue partitioned by day, the days (as `YYYYMMDD`) that have a partition,
+    /// sorted; `shards[i + 1]` is the partition of `days[i]`, after the base table.
+    partition_days: Option<Vec<u32>>,
+    /// Whether an item replaces the item carrying the same id, see `KeyBy::Id`.
+    key_by_id: bool,
+    get_item: String,
+    get_tenant_item: String,
+    get_newest_item: String,
+    tail_item: String,
+    peek_items: String,
+    export_items: String,
+    export_page: String,
+    overdue_items: String,
+    count_items: String,
+    count_by_validity: String,
+    count_before: String,
+    last_modified: String,
+    shards: Vec<ShardSql>,
+}
+
+impl SqliteQueue {
+    /// Creates the tables backing `queue` if needed and prepares its SQL.
+    fn open(conn: &Connection, queue: &str, options: &QueueOptions) -> StorageResult<Self> {
+        let table = sanitize_queue_name(queue)
+            .ok_or_else(|| StorageError::InvalidQueueName(queue.to_string()))?;
+        let shard_count = options.shards.max(1);
+        let mut partition_days = None;
+        let tables: Vec<String> = if options.partition_by_day {
+            // The base table stays in every read for items stored before partitioning
+            let days = partition_days_of(conn, &table)?;
+            let mut tables = vec![table.clone()];
+            tables.extend(days.iter().map(|day| partition_table(&table, *day)));
+            partition_days = Some(days);
+            tables
+        } else {
+            shard_tables(&table, shard_count)
         };
-        let pool = r2d2::Pool::new(manager)?;
-        let conn = pool.get().map_err(StorageError::PoolError)?;
 
-        let mut queues = HashSet::new();
-        let mut get_item_sqls = HashMap::new();
-        let mut put_item_sqls = HashMap::new();
-        let mut delete_item_sqls = HashMap::new();
+        let head = head_sql(&tables, "datetime, datetime_secondary", VISIBLE);
+        let tenant_head = head_sql(&tables, "datetime, datetime_secondary", TENANT_VISIBLE);
+        let newest = first_sql(&tables, "datetime, datetime_secondary", VISIBLE, "DESC");
+        let key_by_id = options.key_by == KeyBy::Id;
+        let mut shards = Vec::with_capacity(tables.len());
+        for table in &tables {
+            create_table(conn, table)?;
+            shards.push(ShardSql {
+                table: table.clone(),
+                put_item: format!(
+                    "INSERT INTO {table} (datetime, datetime_secondary, message, id, payloads, tenant, recurrence_interval_secs, recurrence_until)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                    ON CONFLICT (datetime, datetime_secondary)
+                    DO UPDATE SET message = excluded.message, id = excluded.id, payloads = excluded.payloads, tenant = excluded.tenant, {RECURRENCE_EXCLUDED}, valid = 1, hide_until = 0"
+                ),
+                put_consumed_item: format!(
+                    "INSERT INTO {table} (datetime, datetime_secondary, message, id, payloads, tenant, recurrence_interval_secs, recurrence_until, valid, deleted_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0, {DELETED_NOW})
+                    ON CONFLICT (datetime, datetime_secondary)
+                    DO UPDATE SET message = excluded.message, id = excluded.id, payloads = excluded.payloads, tenant = excluded.tenant, {RECURRENCE_EXCLUDED}, valid = 0, hide_until = 0, deleted_at = {DELETED_NOW}"
+                ),
+                replace_item: format!(
+                    "UPDATE {table} SET message = ?3, id = ?4, payloads = ?5, tenant = ?6, recurrence_interval_secs = ?7, recurrence_until = ?8, hide_until = 0 WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2"
+                ),
+                delete_item: format!(
+                    "UPDATE {table} SET valid = 0 WHERE (datetime, datetime_secondary) = ({head}) RETURNING {ITEM_COLUMNS}"
+                ),
+                delete_tenant_item: format!(
+                    "UPDATE {table} SET valid = 0 WHERE (datetime, datetime_secondary) = ({tenant_head}) RETURNING {ITEM_COLUMNS}"
+                ),
+                delete_newest_item: format!(
+                    "UPDATE {table} SET valid = 0 WHERE (datetime, datetime_secondary) = ({newest}) RETURNING {ITEM_COLUMNS}"
+                ),
+                find_message: format!(
+                    "SELECT message, id, payloads, tenant, recurrence_interval_secs, recurrence_until FROM {table} WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2"
+                ),
+                update_message: format!(
+                    "UPDATE {table} SET message = ?3, id = ?4, payloads = ?5, tenant = ?6, recurrence_interval_secs = ?7, recurrence_until = ?8 WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2"
+                ),
+                remove_item: format!(
+                    "UPDATE {table} SET valid = 0 WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2"
+                ),
+                purge_before: format!(
+                    "UPDATE {table} SET valid = 0 WHERE valid = 1 AND datetime < ?1"
+                ),
+                // Extending a hiding that has not expired keeps when it started
+                hide_item: format!(
+                    "UPDATE {table} SET hide_until = ?3,
+                    hidden_since = CASE WHEN hide_until > ?4 AND hidden_since > 0 THEN hidden_since ELSE ?4 END
+                    WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2"
+                ),
+                extend_hidden: format!(
+                    "UPDATE {table} SET hide_until = ?3
+                    WHERE valid = 1 AND datetime = ?1 AND datetime_secondary = ?2 AND hide_until > ?4"
+                ),
+                requeue_hidden: format!(
+                    "UPDATE {table} SET hide_until = 0, hidden_since = 0
+                    WHERE valid = 1 AND hide_until > ?1 AND hidden_since > 0 AND hidden_since < ?2
+                    RETURNING datetime, datetime_secondary"
+                ),
+                compact: format!(
+                    "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE valid = 0 LIMIT ?1)"
+                ),
+                purge_deleted: format!(
+                    // Rows deleted before `deleted_at` existed fall back to their last write
+                    "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE valid = 0 AND COALESCE(deleted_at, last_modified) <= ?1 LIMIT ?2)"
+                ),
+                supersede_id: format!(
+                    "UPDATE {table} SET valid = 0 WHERE valid = 1 AND id = ?1 AND (datetime, datetime_secondary) != (?2, ?3)"
+                ),
+                valid_rows: format!(
+                    "SELECT {ITEM_COLUMNS}, hide_until, hidden_since FROM {table} WHERE valid = 1"
+                ),
+                invalidate_all: format!("UPDATE {table} SET valid = 0 WHERE valid = 1"),
+                // A moved item can only land on a deleted row, which it takes over
+                put_moved: format!(
+                    "INSERT INTO {table} (datetime, datetime_secondary, message, id, payloads, tenant, recurrence_interval_secs, recurrence_until, hide_until, hidden_since)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                    ON CONFLICT (datetime, datetime_secondary)
+                    DO UPDATE SET message = excluded.message, id = excluded.id, payloads = excluded.payloads, tenant = excluded.tenant, {RECURRENCE_EXCLUDED}, valid = 1, hide_until = excluded.hide_until, hidden_since = excluded.hidden_since"
+                ),
+            });
+        }
+
+        Ok(SqliteQueue {
+            partition_days,
+            key_by_id,
+            get_item: head_sql(&tables, ITEM_COLUMNS, VISIBLE),
+            get_tenant_item: head_sql(&tables, ITEM_COLUMNS, TENANT_VISIBLE),
+            get_newest_item: first_sql(&tables, ITEM_COLUMNS, VISIBLE, "DESC"),
+            tail_item: first_sql(
+                &tables,
+                ITEM_COLUMNS,
+                VALID,
+                "DESC",
+            ),
+            peek_items: format!(
+                "{} LIMIT ?4",
+                list_sql(
+                    &tables,
+                    ITEM_COLUMNS,
+                    "valid = 1 AND hide_until <= ?1 AND (datetime, datetime_secondary) > (?2, ?3)"
+                )
+            ),
+            export_items: list_sql(&tables, ITEM_COLUMNS, VALID),
+            export_page: format!(
+                "{} LIMIT ?3",
+                list_sql(
+                    &tables,
+                    ITEM_COLUMNS,
+                    "valid = 1 AND (datetime, datetime_secondary) > (?1, ?2)"
+                )
+            ),
+            overdue_items: list_sql(&tables, ITEM_COLUMNS, "valid = 1 AND datetime < ?1"),
+            count_items: format!(
+                "SELECT {}",
+                tables
+                    .iter()
+                    .map(|table| format!("(SELECT COUNT(*) FROM {table} WHERE valid = 1)"))
+                    .collect::<Vec<_>>()
+                    .join(" + ")
+            ),
+            count_by_validity: format!(
+                "SELECT valid, COUNT(*) FROM ({}) GROUP BY valid",
+                tables
+                    .iter()
+                    .map(|table| format!("SELECT valid FROM {table}"))
+                    .collect::<Vec<_>>()
+                    .join(" UNION ALL ")
+            ),
+            count_before: format!(
+                "SELECT {}",
+                tables
+                    .iter()
+                    .map(|table| format!(
+                        "(SELECT COUNT(*) FROM {table} WHERE valid = 1 AND (datetime, datetime_secondary) < (?1, ?2))"
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(" + ")
+            ),
+            // Deleted rows are kept, so their timestamps still count
+            last_modified: format!(
+                "SELECT MAX(last_modified) FROM ({})",
+                tables
+                    .iter()
+                    .map(|table| format!("SELECT last_modified FROM {table}"))
+                    .collect::<Vec<_>>()
+                    .join(" UNION ALL ")
+            ),
+            shards,
+        })
+    }
 
-        for queue in &config.queues {
-            let table = sanitize_queue_name(queue)
-                .ok_or_else(|| StorageError::QueueNotFound(queue.clone()))?;
-            conn.execute(
-                &format!(
-                    "CREATE TABLE IF NOT EXISTS {table} (
+    /// In a queue keyed by id, deletes the item carrying `item`'s id under another key,
+    /// so that `item` replaces it. Returns whether there was one.
+    fn supersede_id(&self, conn: &Connection, item: &QueueItem) -> StorageResult<bool> {
+        let (true, Some(id)) = (self.key_by_id, &item.id) else {
+            return Ok(false);
+        };
+        let key = SortKey::new(&item.key());
+        let mut superseded = 0;
+        // The old item may be in any shard, since shards are chosen by datetime
+        for shard in &self.shards {
+            superseded += conn.prepare_cached(&shard.supersede_id)?.execute(params![
+                id,
+                key.datetime,
+                key.datetime_secondary
+            ])?;
+        }
+        Ok(superseded > 0)
+    }
+
+    /// Stores `item` as `Storage::put_item` does, on a connection inside a transaction,
+    /// and returns whether its key is new.
+    fn put(&self, conn: &Connection, item: &QueueItem) -> StorageResult<bool> {
+        let key = item.key();
+        let sort_key = SortKey::new(&key);
+        let shard = self.shard(&key);
+        // Replacing first tells an update from an insert
+        let superseded = self.supersede_id(conn, item)?;
+        let item_params = params![
+            sort_key.datetime,
+            sort_key.datetime_secondary,
+            item.message,
+            item.id,
+            payloads_json(&item.payloads),
+            item.tenant,
+            item.recurrence_interval_secs,
+            Recurrence::of(item).until_millis()
+        ];
+        let replaced = conn
+            .prepare_cached(&shard.replace_item)?
+            .execute(item_params)?
+            > 0;
+        if !replaced {
+            conn.prepare_cached(&shard.put_item)?.execute(item_params)?;
+        }
+        Ok(!replaced && !superseded)
+    }
+
+    /// Returns the SQL for the shard that stores `key`.
+    fn shard(&self, key: &QueueKey) -> &ShardSql {
+        match &self.partition_days {
+            None => &self.shards[shard_index(key, self.shards.len())],
+            // A day keeps its items in the base table until it gets a partition
+            Some(days) => match partition_day(key.datetime).map(|day| days.binary_search(&day)) {
+                Some(Ok(index)) => &self.shards[index + 1],
+                _ => &self.shards[0],
+            },
+        }
+    }
+
+    /// Returns the days of `keys` that lack a partition, if the queue is partitioned.
+    fn missing_partitions(&self, keys: impl IntoIterator<Item = QueueKey>) -> Vec<u32> {
+        let Some(days) = &self.partition_days else {
+            return Vec::new();
+        };
+        let mut missing: Vec<u32> = keys
+            .into_iter()
+            .filter_map(|key| partition_day(key.datetime))
+            .filter(|day| days.binary_search(day).is_err())
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+}
+
+/// Returns the UTC day of `datetime` as `YYYYMMDD`, the partition its items are stored in,
+/// or None outside the years 0000 through 9999, whose items stay in the base table.
+fn partition_day(datetime: DateTime<Utc>) -> Option<u32> {
+    let date = datetime.date_naive();
+    let year = u32::try_from(date.year())
+        .ok()
+        .filter(|year| *year <= 9999)?;
+    Some(year * 10_000 + date.month() * 100 + date.day())
+}
+
+/// Returns the name of the partition of `table` holding the items of `day`.
+fn partition_table(table: &str, day: u32) -> String {
+    format!("{table}_{day:08}")
+}
+
+/// Returns the day of the partition of `table` named `name`, if it is one.
+fn partition_day_of(name: &str, table: &str) -> Option<u32> {
+    let suffix = name.strip_prefix(table)?.strip_prefix('_')?;
+    if suffix.len() != 8 || chrono::NaiveDate::parse_from_str(suffix, "%Y%m%d").is_err() {
+        return None;
+    }
+    suffix.parse().ok()
+}
+
+/// Returns the days that have a partition of `table` in the database, sorted.
+fn partition_days_of(conn: &Connection, table: &str) -> StorageResult<Vec<u32>> {
+    let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut days: Vec<u32> = names
+        .iter()
+        .filter_map(|name| partition_day_of(name, table))
+        .collect();
+    days.sort_unstable();
+    Ok(days)
+}
+
+/// Creates the partition of `table` for `day`, on a connection inside a transaction,
+/// and moves into it the valid items of that day still in the base table: those stored
+/// before partitioning, or while the day had no partition. A key the partition already
+/// holds a valid item under keeps it.
+fn create_partition(conn: &Connection, table: &str, day: u32) -> StorageResult<()> {
+    let partition = partition_table(table, day);
+    create_table(conn, &partition)?;
+    let start = chrono::NaiveDate::from_ymd_opt(day as i32 / 10_000, day / 100 % 100, day % 100)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .ok_or_else(|| StorageError::CorruptData(format!("partition day {day}")))?;
+    let range = params![
+        start.timestamp_millis(),
+        (start + chrono::Duration::days(1)).timestamp_millis()
+    ];
+    const COLUMNS: &str = "datetime, datetime_secondary, message, id, payloads, tenant, recurrence_interval_secs, recurrence_until, valid, last_modified, hide_until, hidden_since";
+    conn.execute(
+        &format!(
+            "INSERT INTO {partition} ({COLUMNS})
+            SELECT {COLUMNS} FROM {table} WHERE valid = 1 AND datetime >= ?1 AND datetime < ?2
+            ON CONFLICT (datetime, datetime_secondary)
+            DO UPDATE SET message = excluded.message, id = excluded.id, payloads = excluded.payloads, tenant = excluded.tenant, {RECURRENCE_EXCLUDED}, valid = 1, last_modified = excluded.last_modified, hide_until = excluded.hide_until, hidden_since = excluded.hidden_since
+            WHERE valid = 0"
+        ),
+        range,
+    )?;
+    conn.execute(
+        &format!("DELETE FROM {table} WHERE valid = 1 AND datetime >= ?1 AND datetime < ?2"),
+        range,
+    )?;
+    Ok(())
+}
+
+/// Returns the tables of a queue with base table `table` spread over `shards` shards.
+fn shard_tables(table: &str, shards: usize) -> Vec<String> {
+    if shards <= 1 {
+        vec![table.to_string()]
+    } else {
+        (0..shards).map(|i| format!("{table}_{i}")).collect()
+    }
+}
+
+/// Whether the SQLite tables of `a` and `b`, two distinct valid queue names, could be
+/// the same table. A sharded queue's tables and a partitioned queue's day tables are
+/// named after the queue, so e.g. the second shard of `orders` is the table of a queue
+/// named `orders_1`.
+pub(crate) fn tables_collide(
+    (a, options_a): (&str, &QueueOptions),
+    (b, options_b): (&str, &QueueOptions),
+) -> bool {
+    let (Some(table_a), Some(table_b)) = (sanitize_queue_name(a), sanitize_queue_name(b)) else {
+        return false;
+    };
+    // A partitioned queue has its base table now and day tables later
+    let fixed = |table: &str, options: &QueueOptions| {
+        if options.partition_by_day {
+            vec![table.to_string()]
+        } else {
+            shard_tables(table, options.shards)
+        }
+    };
+    let claims = |table: &str, options: &QueueOptions, name: &str| {
+        fixed(table, options).iter().any(|owned| owned == name)
+            || (options.partition_by_day && partition_day_of(name, table).is_some())
+    };
+    fixed(&table_b, options_b)
+        .iter()
+        .any(|name| claims(&table_a, options_a, name))
+        || fixed(&table_a, options_a)
+            .iter()
+            .any(|name| claims(&table_b, options_b, name))
+}
+
+/// Picks the shard for a key with a stable hash (a splitmix64 finalizer over the stored
+/// column values), so an item is always routed to the same table across restarts.
+fn shard_index(key: &QueueKey, shards: usize) -> usize {
+    if shards <= 1 {
+        return 0;
+    }
+    let key = SortKey::new(key);
+    let mut x = (key.datetime as u64) ^ (key.datetime_secondary as u64).rotate_left(32);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    (x % shards as u64) as usize
+}
+
+/// Row filter for items that have not been deleted.
+const VALID: &str = "valid = 1";
+/// Row filter for items that can be retrieved now: not deleted and not hidden past `?1`,
+/// the current time in milliseconds.
+const VISIBLE: &str = "valid = 1 AND hide_until <= ?1";
+/// Like `VISIBLE`, for the items of the tenant `?2` only whose datetime is not after `?3`.
+const TENANT_VISIBLE: &str = "valid = 1 AND hide_until <= ?1 AND tenant = ?2 AND datetime <= ?3";
+
+/// Builds a query selecting `columns` of the head item matching `filter` over all `tables`.
+/// With several shards, each contributes its own head and the earliest of those wins.
+fn head_sql(tables: &[String], columns: &str, filter: &str) -> String {
+    first_sql(tables, columns, filter, "ASC")
+}
+
+/// Like `head_sql`, but with the keys sorted in `direction`, `ASC` or `DESC`.
+fn first_sql(tables: &[String], columns: &str, filter: &str, direction: &str) -> String {
+    let order = format!("ORDER BY datetime {direction}, datetime_secondary {direction} LIMIT 1");
+    if let [table] = tables {
+        return format!("SELECT {columns} FROM {table} WHERE {filter} {order}");
+    }
+    let heads = tables
+        .iter()
+        .map(|table| {
+            format!("SELECT * FROM (SELECT {columns} FROM {table} WHERE {filter} {order})")
+        })
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    format!("SELECT {columns} FROM ({heads}) {order}")
+}
+
+/// Builds a query selecting `columns` of every item matching `filter` over all `tables`
+/// in retrieval order.
+fn list_sql(tables: &[String], columns: &str, filter: &str) -> String {
+    const ORDER: &str = "ORDER BY datetime ASC, datetime_secondary ASC";
+    if let [table] = tables {
+        return format!("SELECT {columns} FROM {table} WHERE {filter} {ORDER}");
+    }
+    let items = tables
+        .iter()
+        .map(|table| format!("SELECT {columns} FROM {table} WHERE {filter}"))
+        .collect::<Vec<_>>()
+        .join(" UNION ALL ");
+    format!("SELECT {columns} FROM ({items}) {ORDER}")
+}
+
+/// The highest fencing token of each queue. Queue tables are all named `queue_*`, so
+/// this name cannot collide with one.
+const CREATE_FENCES: &str =
+    "CREATE TABLE IF NOT EXISTS dtqueue_fences (queue TEXT PRIMARY KEY, token BIGINT NOT NULL)";
+const GET_FENCE: &str = "SELECT token FROM dtqueue_fences WHERE queue = ?1";
+const SET_FENCE: &str = "INSERT INTO dtqueue_fences (queue, token) VALUES (?1, ?2)
+    ON CONFLICT (queue) DO UPDATE SET token = excluded.token";
+/// Statements prepared once per connection rather than per queue, `GET_FENCE` and `SET_FENCE`.
+const SHARED_STATEMENTS: usize = 2;
+
+/// Creates a queue table with its timestamp trigger and head-selection index.
+fn create_table(conn: &Connection, table: &str) -> StorageResult<()> {
+    // A read-only database is used as it is
+    if conn.is_readonly(MAIN_DB)? {
+        return Ok(());
+    }
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
                     datetime BIGINT NOT NULL,
                     datetime_secondary BIGINT NOT NULL DEFAULT -9223372036854775808,
                     message TEXT NOT NULL DEFAULT '',
+                    id TEXT,
+                    payloads TEXT,
+                    tenant TEXT,
+                    recurrence_interval_secs INTEGER,
+                    recurrence_until BIGINT,
                     valid INT2 NOT NULL DEFAULT 1,
                     last_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                    hide_until BIGINT NOT NULL DEFAULT 0,
+                    hidden_since BIGINT NOT NULL DEFAULT 0,
+                    deleted_at TIMESTAMP,
                     PRIMARY KEY (datetime, datetime_secondary)
                 )"
-                ),
-                params![],
-            )?;
-            queues.insert(queue.clone());
-            let sql = format!(
-                "CREATE TRIGGER IF NOT EXISTS update_{table}_timestamp
-                 AFTER UPDATE ON {table}
+        ),
+        params![],
+    )?;
+    // Tables created by earlier versions lack the newer columns
+    ensure_column(conn, table, "hide_until", "BIGINT NOT NULL DEFAULT 0")?;
+    ensure_column(conn, table, "hidden_since", "BIGINT NOT NULL DEFAULT 0")?;
+    ensure_column(conn, table, "id", "TEXT")?;
+    ensure_column(conn, table, "payloads", "TEXT")?;
+    ensure_column(conn, table, "tenant", "TEXT")?;
+    ensure_column(conn, table, "recurrence_interval_secs", "INTEGER")?;
+    ensure_column(conn, table, "recurrence_until", "BIGINT")?;
+    ensure_column(conn, table, "deleted_at", "TIMESTAMP")?;
+    // Only content changes count as modifications: consuming or hiding an item updates
+    // `valid` or `hide_until` alone and leaves the timestamp be. Earlier versions fired
+    // on every update, so the trigger is recreated rather than kept if it exists
+    conn.execute(
+        &format!("DROP TRIGGER IF EXISTS update_{table}_timestamp"),
+        [],
+    )?;
+    let sql = format!(
+        "CREATE TRIGGER update_{table}_timestamp
+                 AFTER UPDATE OF message, id, payloads, tenant, recurrence_interval_secs, recurrence_until ON {table}
                  BEGIN UPDATE {table} SET last_modified = CURRENT_TIMESTAMP WHERE datetime = NEW.datetime AND datetime_secondary = NEW.datetime_secondary; END;",
-            );
-            conn.execute(&sql, [])?;
+    );
+    conn.execute(&sql, [])?;
+    // Records when an item was consumed or deleted, which purging deleted items goes by;
+    // `put_consumed_item` stores a consumed row directly and sets it itself
+    conn.execute(
+        &format!(
+            "CREATE TRIGGER IF NOT EXISTS delete_{table}_timestamp
+                 AFTER UPDATE OF valid ON {table} WHEN OLD.valid = 1 AND NEW.valid = 0
+                 BEGIN UPDATE {table} SET deleted_at = {DELETED_NOW} WHERE datetime = NEW.datetime AND datetime_secondary = NEW.datetime_secondary; END;"
+        ),
+        [],
+    )?;
 
-            let index_sql = format!(
-                "CREATE INDEX IF NOT EXISTS idx_{table}_isvalid ON {table} (valid, datetime, datetime_secondary)"
-            );
-            conn.execute(&index_sql, [])?;
+    let index_sql = format!(
+        "CREATE INDEX IF NOT EXISTS idx_{table}_isvalid ON {table} (valid, datetime, datetime_secondary)"
+    );
+    conn.execute(&index_sql, [])?;
+    // Not unique: deleted items keep their id, and uniqueness has to span every shard
+    // of a queue anyway, which `SqliteQueue::supersede_id` enforces on each put
+    conn.execute(
+        &format!("CREATE INDEX IF NOT EXISTS idx_{table}_id ON {table} (id) WHERE id IS NOT NULL"),
+        [],
+    )?;
+    // Selects the head of one tenant without scanning the other tenants' items
+    conn.execute(
+        &format!(
+            "CREATE INDEX IF NOT EXISTS idx_{table}_tenant ON {table} (tenant, valid, datetime, datetime_secondary) WHERE tenant IS NOT NULL"
+        ),
+        [],
+    )?;
+    Ok(())
+}
 
-            get_item_sqls.insert(
-                queue.clone(),
-                format!(
-                    "SELECT datetime, datetime_secondary, message FROM {table} WHERE valid = 1 ORDER BY datetime ASC, datetime_secondary ASC LIMIT 1"
-                ),
-            );
+/// Adds `column` to `table` unless it already exists.
+fn ensure_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> StorageResult<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table})"))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    if !columns.iter().any(|name| name == column) {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+    Ok(())
+}
 
-            put_item_sqls.insert(
-                queue.clone(),
-                format!(
-                    "INSERT OR REPLACE INTO {table} (datetime, datetime_secondary, message)
-                    VALUES (?1, ?2, ?3)"
-                ),
-            );
+/// The bound of a `due_by` in milliseconds, where no bound admits every datetime.
+fn due_by_millis(due_by: Option<DateTime<Utc>>) -> i64 {
+    due_by.map_or(i64::MAX, |due_by| due_by.timestamp_millis())
+}
 
-            delete_item_sqls.insert(
-                queue.clone(),
-                format!(
-                    "UPDATE {table} SET valid = 0 WHERE datetime = (SELECT datetime FROM {table} WHERE valid = 1 ORDER BY datetime ASC, datetime_secondary ASC LIMIT 1) AND datetime_secondary = (SELECT datetime_secondary FROM {table} WHERE valid = 1 ORDER BY datetime ASC, datetime_secondary ASC LIMIT 1) RETURNING datetime, datetime_secondary, message"
-                ),
-            );
-        }
+/// Iterates over the indices `0..len` starting at `start`, wrapping around.
+fn rotated(len: usize, start: usize) -> impl Iterator<Item = usize> {
+    let start = start.checked_rem(len).unwrap_or(0);
+    (start..len).chain(0..start)
+}
 
-        Ok(SqliteStorage {
-            pool,
-            queues,
-            get_item_sqls,
-            put_item_sqls,
-            delete_item_sqls,
-        })
-    }
+/// Adds `by` to the counter stored in `message`, a missing message counting as 0.
+fn increment_message(message: Option<&str>, by: i64) -> StorageResult<i64> {
+    let value = match message {
+        Some(message) => message
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| StorageError::NotAnInteger(message.to_string()))?,
+        None => 0,
+    };
+    value
+        .checked_add(by)
+        .ok_or_else(|| StorageError::NotAnInteger(format!("{value} + {by} overflows")))
 }
 
-impl Storage for SqliteStorage {
-    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<()> {
-        if !self.queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
-        }
+/// Returns `key` moved by `by`, its secondary datetime too if `secondary` is set.
+fn shift_key(key: &QueueKey, by: chrono::Duration, secondary: bool) -> StorageResult<QueueKey> {
+    let shift = |datetime: DateTime<Utc>| {
+        datetime
+            .checked_add_signed(by)
+            .ok_or_else(|| StorageError::DatetimeOutOfRange(format!("{datetime} shifted by {by}")))
+    };
+    Ok(QueueKey {
+        datetime: shift(key.datetime)?,
+        datetime_secondary: match key.datetime_secondary {
+            Some(datetime) if secondary => Some(shift(datetime)?),
+            datetime_secondary => datetime_secondary,
+        },
+    })
+}
 
-        let datetime_val = item.datetime.timestamp_millis();
-        let datetime_secondary_val = item
-            .datetime_secondary
-            .map(|d| d.timestamp_millis())
-            .unwrap_or(i64::MIN);
+/// The current time as `deleted_at` records it: like `CURRENT_TIMESTAMP`, which
+/// `last_modified` holds, with milliseconds, so the two still compare as text.
+const DELETED_NOW: &str = "strftime('%Y-%m-%d %H:%M:%f', 'now')";
 
-        let conn = self.pool.get().map_err(StorageError::PoolError)?;
-        let put_sql = self
-            .put_item_sqls
-            .get(queue)
-            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+/// The columns `row_to_item` reads, in order.
+const ITEM_COLUMNS: &str = "datetime, datetime_secondary, message, id, payloads, tenant, recurrence_interval_secs, recurrence_until";
 
-        let mut stmt = conn.prepare_cached(put_sql)?;
-        stmt.execute(params![datetime_val, datetime_secondary_val, item.message])?;
+/// Sets the recurrence columns from the row being inserted, in an upsert.
+const RECURRENCE_EXCLUDED: &str = "recurrence_interval_secs = excluded.recurrence_interval_secs, recurrence_until = excluded.recurrence_until";
 
-        Ok(())
+fn row_to_item(row: &rusqlite::Row) -> rusqlite::Result<QueueItem> {
+    let key = SortKey {
+        datetime: row.get(0)?,
+        datetime_secondary: row.get(1)?,
+    };
+    let key = key.try_to_key().map_err(|(column, cause)| {
+        rusqlite::Error::FromSqlConversionFailure(
+            column,
+            rusqlite::types::Type::Integer,
+            cause.into(),
+        )
+    })?;
+    let payloads: Option<String> = row.get(4)?;
+    Ok(item_at(
+        key,
+        row.get(2)?,
+        row.get(3)?,
+        parse_payloads(payloads, 4)?,
+        row.get(5)?,
+        Recurrence::read(row, 6)?,
+    ))
+}
+
+/// Builds the item stored under `key`.
+fn item_at(
+    key: QueueKey,
+    message: String,
+    id: Option<String>,
+    payloads: Option<BTreeMap<String, String>>,
+    tenant: Option<String>,
+    recurrence: Recurrence,
+) -> QueueItem {
+    QueueItem {
+        datetime: key.datetime,
+        datetime_secondary: key.datetime_secondary,
+        message,
+        id,
+        payloads,
+        tenant,
+        recurrence_interval_secs: recurrence.interval_secs,
+        recurrence_until: recurrence.until,
     }
+}
 
-    fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
-        if !self.queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
+/// An item's `recurrence_interval_secs` and `recurrence_until`, which move along with
+/// its content. SQLite keeps them in columns of the same names, `until` in milliseconds.
+#[derive(Clone, Copy, Default)]
+struct Recurrence {
+    interval_secs: Option<u32>,
+    until: Option<DateTime<Utc>>,
+}
+
+impl Recurrence {
+    fn of(item: &QueueItem) -> Self {
+        Recurrence {
+            interval_secs: item.recurrence_interval_secs,
+            until: item.recurrence_until,
         }
+    }
 
-        let conn = self.pool.get().map_err(StorageError::PoolError)?;
-        let sql = self
-            .get_item_sqls
-            .get(queue)
-            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
-        let mut stmt = conn.prepare_cached(sql)?;
+    /// The value of the `recurrence_until` column.
+    fn until_millis(self) -> Option<i64> {
+        self.until.map(|until| until.timestamp_millis())
+    }
 
-        let item = stmt
-            .query_row(params![], |row| {
-                let datetime: i64 = row.get(0)?;
-                let datetime_secondary: i64 = row.get(1)?;
-                let message: String = row.get(2)?;
-                Ok(QueueItem {
-                    datetime: DateTime::<Utc>::from_timestamp_millis(datetime)
-                        .expect("Invalid datetime from DB"),
-                    datetime_secondary: if datetime_secondary == i64::MIN {
-                        None
-                    } else {
-                        Some(
-                            DateTime::<Utc>::from_timestamp_millis(datetime_secondary)
-                                .expect("Invalid datetime_secondary from DB"),
-                        )
-                    },
-                    message,
+    /// Reads the two recurrence columns starting at `column`.
+    fn read(row: &rusqlite::Row, column: usize) -> rusqlite::Result<Self> {
+        let until = row
+            .get::<_, Option<i64>>(column + 1)?
+            .map(|millis| {
+                DateTime::from_timestamp_millis(millis).ok_or_else(|| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        column + 1,
+                        rusqlite::types::Type::Integer,
+                        format!("recurrence_until {millis} is out of range").into(),
+                    )
                 })
             })
-            .optional()?;
-
-        Ok(item)
+            .transpose()?;
+        Ok(Recurrence {
+            interval_secs: row.get(column)?,
+            until,
+        })
     }
+}
 
-    fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
-        if !self.queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
-        }
+/// Encodes an item's payloads for the `payloads` column, as a JSON object.
+fn payloads_json(payloads: &Option<BTreeMap<String, String>>) -> Option<String> {
+    payloads
+        .as_ref()
+        .map(|payloads| serde_json::to_string(payloads).expect("string maps serialize"))
+}
 
-        let conn = self.pool.get().map_err(StorageError::PoolError)?;
-        let delete_sql = self
-            .delete_item_sqls
-            .get(queue)
-            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
-        let mut stmt = conn.prepare_cached(delete_sql)?;
+/// Decodes the `payloads` column read at `column`.
+fn parse_payloads(
+    json: Option<String>,
+    column: usize,
+) -> rusqlite::Result<Option<BTreeMap<String, String>>> {
+    json.map(|json| {
+        serde_json::from_str(&json).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, e.into())
+        })
+    })
+    .transpose()
+}
 
-        let item = stmt
-            .query_row(params![], |row| {
-                let datetime: i64 = row.get(0)?;
-                let datetime_secondary: i64 = row.get(1)?;
-                let message: String = row.get(2)?;
-                Ok(QueueItem {
-                    datetime: DateTime::<Utc>::from_timestamp_millis(datetime)
-                        .expect("Invalid datetime from DB"),
-                    datetime_secondary: if datetime_secondary == i64::MIN {
-                        None
-                    } else {
-                        Some(
-                            DateTime::<Utc>::from_timestamp_millis(datetime_secondary)
-                                .expect("Invalid datetime_secondary from DB"),
-                        )
-                    },
-                    message,
-                })
-            })
-            .optional()?;
+/// Capacity of rusqlite's statement cache when no queue needs more.
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 16;
 
-        Ok(item)
-    }
+/// Deleted items removed per statement of `purge_deleted`, so writers wait at most for one.
+const PURGE_BATCH: usize = 500;
 
-    fn queue_exists(&self, queue: &str) -> bool {
-        self.queues.contains(queue)
-    }
+/// Distinct statements a queue with `shards` tables can prepare: those in `ShardSql`
+/// for every shard and those in `SqliteQueue` once.
+fn statements_per_queue(shards: usize) -> usize {
+    19 * shards.max(1) + 12
 }
 
-type InMemoryQueue = BTreeMap<(DateTime<Utc>, Option<DateTime<Utc>>), String>;
+/// Returns `statement_cache_capacity`, or enough cache entries per connection for every
+/// configured queue plus the queues `max_queues` still allows to be created.
+fn statement_cache_capacity(config: &AppConfig) -> usize {
+    if let Some(capacity) = config.statement_cache_capacity {
+        return capacity;
+    }
+    let configured: usize = config
+        .queues
+        .iter()
+        .map(|queue| statements_per_queue(config.queue_options(queue).shards))
+        .sum();
+    let creatable = config
+        .max_queues
+        .unwrap_or(0)
+        .saturating_sub(config.queues.len());
+    (configured + creatable * statements_per_queue(1) + SHARED_STATEMENTS)
+        .max(DEFAULT_STATEMENT_CACHE_CAPACITY)
+}
 
-pub struct InMemoryStorage {
-    // Map queue_name -> BTreeMap<(datetime, datetime_secondary), message>
-    queues: RwLock<HashMap<String, InMemoryQueue>>,
-    allowed_queues: HashSet<String>,
+/// Checks out `size` connections at once so each is opened, with its pragmas applied,
+/// before the first request arrives; they return to the pool idle.
+fn prewarm(pool: &r2d2::Pool<SqliteConnectionManager>, size: u32) -> StorageResult<()> {
+    let start = std::time::Instant::now();
+    let conns = (0..size)
+        .map(|_| pool.get())
+        .collect::<Result<Vec<_>, _>>()?;
+    drop(conns);
+    info!(
+        "prewarmed {size} database connections in {:?}",
+        start.elapsed()
+    );
+    Ok(())
 }
 
-impl InMemoryStorage {
-    pub fn new(config: &AppConfig) -> Self {
-        let mut queues_map = HashMap::new();
-        let mut allowed_queues = HashSet::new();
+pub struct SqliteStorage {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    wal_path: String,
+    queues: RwLock<HashMap<String, Arc<SqliteQueue>>>,
+    queue_options: HashMap<String, QueueOptions>,
+    max_queues: Option<usize>,
+}
 
-        for queue in &config.queues {
-            queues_map.insert(queue.clone(), BTreeMap::new());
-            allowed_queues.insert(queue.clone());
+impl SqliteStorage {
+    pub fn new(config: &AppConfig) -> StorageResult<Self> {
+        if config.database_key.is_some() && !has_sqlcipher(&Connection::open_in_memory()?)? {
+            // Plain SQLite ignores the key and would write the database unencrypted
+            return Err(StorageError::EncryptionUnavailable);
+        }
+        let manager = SqliteConnectionManager {
+            path: config.database_path.clone(),
+            key: config.database_key.clone(),
+            read_only: config.read_only,
+            statement_cache_capacity: statement_cache_capacity(config),
+            temp_store: config.sqlite_temp_store,
+            journal_size_limit: config.sqlite_journal_size_limit,
+            journal_mode: config.sqlite_journal_mode,
+        };
+        if config.sqlite_journal_mode == JournalMode::Delete {
+            warn!(
+                "SQLite runs with the rollback journal: reads wait for writes and writes for reads, so throughput under concurrency is lower than with WAL"
+            );
+        }
+        // Connect once directly, so that a file that cannot be opened, such as one
+        // encrypted with another key, is reported as such rather than as the pool timing
+        // out while it retries
+        let probe = r2d2::ManageConnection::connect(&manager)?;
+        log_tuning(&probe)?;
+        drop(probe);
+        // Connections are opened on demand unless prewarming is requested
+        let pool = r2d2::Pool::builder()
+            .max_size(config.db_pool_size)
+            .min_idle(Some(0))
+            .build(manager)?;
+        if config.db_prewarm {
+            prewarm(&pool, config.db_pool_size)?;
+        }
+        let conn = pool.get().map_err(StorageError::PoolError)?;
+        if !conn.is_readonly(MAIN_DB)? {
+            conn.execute(CREATE_FENCES, [])?;
         }
 
-        InMemoryStorage {
-            queues: RwLock::new(queues_map),
-            allowed_queues,
+        let mut queues = HashMap::new();
+        for queue in config
+            .queues
+            .iter()
+            .map(|queue| normalize_queue_name(queue))
+        {
+            let sqls = SqliteQueue::open(&conn, &queue, &config.queue_options(&queue))?;
+            queues.insert(queue, Arc::new(sqls));
         }
+
+        Ok(SqliteStorage {
+            pool,
+            wal_path: format!("{}-wal", config.database_path),
+            queues: RwLock::new(queues),
+            queue_options: config.queue_options.clone(),
+            max_queues: config.max_queues,
+        })
     }
-}
 
-impl Storage for InMemoryStorage {
-    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<()> {
-        if !self.allowed_queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
-        }
+    fn queue(&self, queue: &str) -> StorageResult<Arc<SqliteQueue>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        queues
+            .get(queue)
+            .cloned()
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))
+    }
 
+    /// Like `queue`, but first creates the partitions that items under `keys` will be
+    /// stored in, for a queue partitioned by day.
+    fn queue_for_keys(
+        &self,
+        queue: &str,
+        keys: impl IntoIterator<Item = QueueKey>,
+    ) -> StorageResult<Arc<SqliteQueue>> {
+        let sqls = self.queue(queue)?;
+        let missing = sqls.missing_partitions(keys);
+        if missing.is_empty() {
+            return Ok(sqls);
+        }
+        // The connection is checked out before the registry is locked, as everywhere, so a
+        // caller holding the last connection can still register a queue
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
         let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
-        if let Some(queue_map) = queues.get_mut(queue) {
-            queue_map.insert((item.datetime, item.datetime_secondary), item.message);
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for day in missing {
+            create_partition(&tx, &sqls.shards[0].table, day)?;
         }
+        tx.commit()?;
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        let sqls = Arc::new(SqliteQueue::open(&conn, queue, &options)?);
+        queues.insert(queue.to_string(), sqls.clone());
+        Ok(sqls)
+    }
+
+    /// Reopens the SQL of `queue` over the tables it has in the database now, on the
+    /// connection the caller already holds.
+    fn reload_queue(&self, conn: &Connection, queue: &str) -> StorageResult<()> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        queues.insert(
+            queue.to_string(),
+            Arc::new(SqliteQueue::open(conn, queue, &options)?),
+        );
         Ok(())
     }
 
-    fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
-        if !self.allowed_queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
+    /// Stores the next occurrence of `item`, consumed in the same transaction on `conn`,
+    /// if it recurs; see `QueueItem::next_occurrence`. An occurrence on a day of a
+    /// partitioned queue that has no partition yet creates it in the transaction, and the
+    /// SQL of `queue` reopened over it is returned; once the transaction commits, the
+    /// caller registers it with `reload_queue`.
+    fn recur(
+        &self,
+        conn: &Connection,
+        queue: &str,
+        sqls: &SqliteQueue,
+        item: &QueueItem,
+    ) -> StorageResult<Option<Arc<SqliteQueue>>> {
+        let Some(next) = item.next_occurrence() else {
+            return Ok(None);
+        };
+        let missing = sqls.missing_partitions([next.key()]);
+        if missing.is_empty() {
+            sqls.put(conn, &next)?;
+            return Ok(None);
+        }
+        for day in missing {
+            create_partition(conn, &sqls.shards[0].table, day)?;
         }
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        let reopened = Arc::new(SqliteQueue::open(conn, queue, &options)?);
+        reopened.put(conn, &next)?;
+        Ok(Some(reopened))
+    }
 
-        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
-        if let Some((key, message)) = queues.get(queue).and_then(|q| q.first_key_value()) {
-            return Ok(Some(QueueItem {
-                datetime: key.0,
-                datetime_secondary: key.1,
-                message: message.clone(),
-            }));
+    /// Drops the partitions of `queue` for days before `cutoff`'s and returns how many
+    /// valid items they held.
+    fn drop_partitions_before(&self, queue: &str, cutoff: DateTime<Utc>) -> StorageResult<usize> {
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let sqls = queues
+            .get(queue)
+            .cloned()
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let Some(days) = &sqls.partition_days else {
+            return Ok(0);
+        };
+        // Every day is before a cutoff past the year 9999, and none before one before 0000
+        let cutoff_day =
+            partition_day(cutoff).unwrap_or(if cutoff.year() < 0 { 0 } else { u32::MAX });
+        let expired: Vec<&ShardSql> = days
+            .iter()
+            .zip(&sqls.shards[1..])
+            .filter(|(day, _)| **day < cutoff_day)
+            .map(|(_, shard)| shard)
+            .collect();
+        if expired.is_empty() {
+            return Ok(0);
         }
-        Ok(None)
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut dropped = 0;
+        for shard in expired {
+            let count: i64 = tx.query_row(
+                &format!("SELECT COUNT(*) FROM {} WHERE valid = 1", shard.table),
+                [],
+                |row| row.get(0),
+            )?;
+            tx.execute(&format!("DROP TABLE {}", shard.table), [])?;
+            info!("dropped partition {} of queue {queue}", shard.table);
+            dropped += count as usize;
+        }
+        tx.commit()?;
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        queues.insert(
+            queue.to_string(),
+            Arc::new(SqliteQueue::open(&conn, queue, &options)?),
+        );
+        Ok(dropped)
     }
+}
 
-    fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
-        if !self.allowed_queues.contains(queue) {
-            return Err(StorageError::QueueNotFound(queue.to_string()));
+impl Storage for SqliteStorage {
+    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<bool> {
+        let sqls = self.queue_for_keys(queue, [item.key()])?;
+
+        // The transaction keeps the statements from interleaving with another writer
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let created = sqls.put(&tx, &item)?;
+        tx.commit()?;
+
+        Ok(created)
+    }
+
+    fn put_item_fenced(&self, queue: &str, item: QueueItem, token: i64) -> StorageResult<bool> {
+        let sqls = self.queue_for_keys(queue, [item.key()])?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // The immediate transaction keeps another fenced writer from passing the check too
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let highest: Option<i64> = tx
+            .prepare_cached(GET_FENCE)?
+            .query_row(params![queue], |row| row.get(0))
+            .optional()?;
+        if let Some(highest) = highest.filter(|highest| token < *highest) {
+            return Err(StorageError::StaleFenceToken { token, highest });
         }
+        tx.prepare_cached(SET_FENCE)?
+            .execute(params![queue, token])?;
+        let created = sqls.put(&tx, &item)?;
+        tx.commit()?;
 
-        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
-        if let Some((key, message)) = queues.get_mut(queue).and_then(|q| q.pop_first()) {
-            return Ok(Some(QueueItem {
-                datetime: key.0,
-                datetime_secondary: key.1,
-                message,
-            }));
+        Ok(created)
+    }
+
+    fn replace_if_message(
+        &self,
+        queue: &str,
+        item: QueueItem,
+        expected: &str,
+    ) -> StorageResult<bool> {
+        let key = item.key();
+        let sqls = self.queue_for_keys(queue, [key])?;
+        let sort_key = SortKey::new(&key);
+        let shard = sqls.shard(&key);
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // The immediate transaction keeps another writer from changing the message in between
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current: Option<String> = tx
+            .prepare_cached(&shard.find_message)?
+            .query_row(
+                params![sort_key.datetime, sort_key.datetime_secondary],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if current.as_deref() != Some(expected) {
+            return Ok(false);
         }
-        Ok(None)
+        sqls.supersede_id(&tx, &item)?;
+        tx.prepare_cached(&shard.replace_item)?.execute(params![
+            sort_key.datetime,
+            sort_key.datetime_secondary,
+            item.message,
+            item.id,
+            payloads_json(&item.payloads),
+            item.tenant,
+            item.recurrence_interval_secs,
+            Recurrence::of(&item).until_millis()
+        ])?;
+        tx.commit()?;
+
+        Ok(true)
     }
 
-    fn queue_exists(&self, queue: &str) -> bool {
-        self.allowed_queues.contains(queue)
+    fn put_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        let sqls = self.queue_for_keys(queue, items.iter().map(QueueItem::key))?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for item in items {
+            sqls.supersede_id(&tx, &item)?;
+            let key = item.key();
+            let sort_key = SortKey::new(&key);
+            tx.prepare_cached(&sqls.shard(&key).put_item)?
+                .execute(params![
+                    sort_key.datetime,
+                    sort_key.datetime_secondary,
+                    item.message,
+                    item.id,
+                    payloads_json(&item.payloads),
+                    item.tenant,
+                    item.recurrence_interval_secs,
+                    Recurrence::of(&item).until_millis()
+                ])?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn put_consumed_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        let sqls = self.queue_for_keys(queue, items.iter().map(QueueItem::key))?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for item in items {
+            let key = item.key();
+            let sort_key = SortKey::new(&key);
+            tx.prepare_cached(&sqls.shard(&key).put_consumed_item)?
+                .execute(params![
+                    sort_key.datetime,
+                    sort_key.datetime_secondary,
+                    item.message,
+                    item.id,
+                    payloads_json(&item.payloads),
+                    item.tenant,
+                    item.recurrence_interval_secs,
+                    Recurrence::of(&item).until_millis()
+                ])?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.get_item)?;
+        let item = stmt
+            .query_row(params![Utc::now().timestamp_millis()], row_to_item)
+            .optional()?;
+
+        Ok(item)
+    }
+
+    fn tail_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.tail_item)?;
+        let item = stmt.query_row(params![], row_to_item).optional()?;
+
+        Ok(item)
+    }
+
+    fn snapshot_heads(&self, queues: &[String]) -> StorageResult<Vec<Option<QueueItem>>> {
+        let sqls = queues
+            .iter()
+            .map(|queue| self.queue(queue))
+            .collect::<StorageResult<Vec<_>>>()?;
+        let now = Utc::now().timestamp_millis();
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // Every read of one transaction sees the database as of its first read
+        let tx = conn.transaction()?;
+        let mut heads = Vec::with_capacity(sqls.len());
+        for sqls in &sqls {
+            let head = tx
+                .prepare_cached(&sqls.get_item)?
+                .query_row(params![now], row_to_item)
+                .optional()?;
+            heads.push(head);
+        }
+        tx.commit()?;
+
+        Ok(heads)
+    }
+
+    fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = Utc::now().timestamp_millis();
+        let mut item = None;
+        // Only the shard holding the merged head has a row matching it
+        for shard in &sqls.shards {
+            let mut stmt = tx.prepare_cached(&shard.delete_item)?;
+            item = stmt.query_row(params![now], row_to_item).optional()?;
+            if item.is_some() {
+                break;
+            }
+        }
+        let reopened = match &item {
+            Some(item) => self.recur(&tx, queue, &sqls, item)?,
+            None => None,
+        };
+        tx.commit()?;
+        if reopened.is_some() {
+            self.reload_queue(&conn, queue)?;
+        }
+
+        Ok(item)
+    }
+
+    fn get_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.get_tenant_item)?;
+        let now = Utc::now().timestamp_millis();
+        let item = stmt
+            .query_row(params![now, tenant, due_by_millis(due_by)], row_to_item)
+            .optional()?;
+
+        Ok(item)
+    }
+
+    fn delete_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = Utc::now().timestamp_millis();
+        let mut item = None;
+        for shard in &sqls.shards {
+            let mut stmt = tx.prepare_cached(&shard.delete_tenant_item)?;
+            item = stmt
+                .query_row(params![now, tenant, due_by_millis(due_by)], row_to_item)
+                .optional()?;
+            if item.is_some() {
+                break;
+            }
+        }
+        let reopened = match &item {
+            Some(item) => self.recur(&tx, queue, &sqls, item)?,
+            None => None,
+        };
+        tx.commit()?;
+        if reopened.is_some() {
+            self.reload_queue(&conn, queue)?;
+        }
+
+        Ok(item)
+    }
+
+    fn get_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.get_newest_item)?;
+        let item = stmt
+            .query_row(params![Utc::now().timestamp_millis()], row_to_item)
+            .optional()?;
+
+        Ok(item)
+    }
+
+    fn delete_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let now = Utc::now().timestamp_millis();
+        let mut item = None;
+        for shard in &sqls.shards {
+            let mut stmt = tx.prepare_cached(&shard.delete_newest_item)?;
+            item = stmt.query_row(params![now], row_to_item).optional()?;
+            if item.is_some() {
+                break;
+            }
+        }
+        let reopened = match &item {
+            Some(item) => self.recur(&tx, queue, &sqls, item)?,
+            None => None,
+        };
+        tx.commit()?;
+        if reopened.is_some() {
+            self.reload_queue(&conn, queue)?;
+        }
+
+        Ok(item)
+    }
+
+    fn remove_item(&self, queue: &str, key: &QueueKey) -> StorageResult<bool> {
+        let sqls = self.queue(queue)?;
+        let sort_key = SortKey::new(key);
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.shard(key).remove_item)?;
+        let changed = stmt.execute(params![sort_key.datetime, sort_key.datetime_secondary])?;
+
+        Ok(changed > 0)
+    }
+
+    fn compare_and_delete(&self, queue: &str, expected: &QueueKey) -> StorageResult<CasResult> {
+        let sqls = self.queue(queue)?;
+        let sort_key = SortKey::new(expected);
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // The immediate transaction keeps another writer from changing the head in between
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let head = tx
+            .prepare_cached(&sqls.get_item)?
+            .query_row(params![Utc::now().timestamp_millis()], row_to_item)
+            .optional()?;
+        let mut reopened = None;
+        let result = match head {
+            Some(head) if SortKey::new(&head.key()) == sort_key => {
+                tx.prepare_cached(&sqls.shard(expected).remove_item)?
+                    .execute(params![sort_key.datetime, sort_key.datetime_secondary])?;
+                reopened = self.recur(&tx, queue, &sqls, &head)?;
+                CasResult::Deleted(head)
+            }
+            head => CasResult::Mismatch(head),
+        };
+        tx.commit()?;
+        if reopened.is_some() {
+            self.reload_queue(&conn, queue)?;
+        }
+
+        Ok(result)
+    }
+
+    fn claim_any(
+        &self,
+        queues: &[String],
+        now: DateTime<Utc>,
+        due_only: bool,
+        start: usize,
+    ) -> StorageResult<Option<(String, QueueItem)>> {
+        let sqls = queues
+            .iter()
+            .map(|queue| self.queue(queue))
+            .collect::<StorageResult<Vec<_>>>()?;
+        let now = now.timestamp_millis();
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // All queues live in one database, so one transaction covers every head
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut best: Option<(usize, SortKey, QueueItem)> = None;
+        for index in rotated(queues.len(), start) {
+            let head = tx
+                .prepare_cached(&sqls[index].get_item)?
+                .query_row(params![now], row_to_item)
+                .optional()?;
+            let Some(head) = head else {
+                continue;
+            };
+            let key = SortKey::new(&head.key());
+            if (!due_only || key.is_due(now))
+                && best.as_ref().is_none_or(|(_, best, _)| key < *best)
+            {
+                best = Some((index, key, head));
+            }
+        }
+        let Some((index, key, head)) = best else {
+            return Ok(None);
+        };
+        tx.prepare_cached(&sqls[index].shard(&head.key()).remove_item)?
+            .execute(params![key.datetime, key.datetime_secondary])?;
+        let reopened = self.recur(&tx, &queues[index], &sqls[index], &head)?;
+        tx.commit()?;
+        if reopened.is_some() {
+            self.reload_queue(&conn, &queues[index])?;
+        }
+
+        Ok(Some((queues[index].clone(), head)))
+    }
+
+    fn transact(&self, ops: &[TxOp]) -> StorageResult<Vec<Option<QueueItem>>> {
+        // Partitions the puts need are created first, outside the transaction
+        let mut sqls = ops
+            .iter()
+            .map(|op| match op {
+                TxOp::Put { queue, item } => self.queue_for_keys(queue, [item.key()]),
+                TxOp::Pop { queue, .. } | TxOp::Delete { queue, .. } => self.queue(queue),
+            })
+            .collect::<StorageResult<Vec<_>>>()?;
+        let now = Utc::now().timestamp_millis();
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // All queues live in one database; returning early drops the transaction, which
+        // rolls back what was applied so far
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut results = Vec::with_capacity(ops.len());
+        let mut reopened_queues = Vec::new();
+        for (index, op) in ops.iter().enumerate() {
+            let queue_sqls = sqls[index].clone();
+            let result = match op {
+                TxOp::Put { item, .. } => {
+                    queue_sqls.put(&tx, item)?;
+                    None
+                }
+                TxOp::Pop { queue, due_only } => {
+                    let head = tx
+                        .prepare_cached(&queue_sqls.get_item)?
+                        .query_row(params![now], row_to_item)
+                        .optional()?
+                        .filter(|head| !due_only || SortKey::new(&head.key()).is_due(now))
+                        .ok_or_else(|| StorageError::TransactionAborted {
+                            index,
+                            reason: format!("queue {queue} has no item to pop"),
+                        })?;
+                    let key = SortKey::new(&head.key());
+                    tx.prepare_cached(&queue_sqls.shard(&head.key()).remove_item)?
+                        .execute(params![key.datetime, key.datetime_secondary])?;
+                    // Later operations on the queue see the partition the occurrence made
+                    if let Some(reopened) = self.recur(&tx, queue, &queue_sqls, &head)? {
+                        for (later, op) in ops.iter().enumerate().skip(index + 1) {
+                            if op.queue() == queue {
+                                sqls[later] = reopened.clone();
+                            }
+                        }
+                        reopened_queues.push(queue);
+                    }
+                    Some(head)
+                }
+                TxOp::Delete { queue, key } => {
+                    let sort_key = SortKey::new(key);
+                    let changed = tx
+                        .prepare_cached(&queue_sqls.shard(key).remove_item)?
+                        .execute(params![sort_key.datetime, sort_key.datetime_secondary])?;
+                    if changed == 0 {
+                        return Err(StorageError::TransactionAborted {
+                            index,
+                            reason: format!("queue {queue} has no item under {key:?}"),
+                        });
+                    }
+                    None
+                }
+            };
+            results.push(result);
+        }
+        tx.commit()?;
+        for queue in reopened_queues {
+            self.reload_queue(&conn, queue)?;
+        }
+
+        Ok(results)
+    }
+
+    fn purge_before(&self, queue: &str, cutoff: DateTime<Utc>) -> StorageResult<usize> {
+        // Whole days before the cutoff are dropped instead of deleted item by item
+        let mut purged = self.drop_partitions_before(queue, cutoff)?;
+        let sqls = self.queue(queue)?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for shard in &sqls.shards {
+            purged += tx
+                .prepare_cached(&shard.purge_before)?
+                .execute(params![cutoff.timestamp_millis()])?;
+        }
+        tx.commit()?;
+
+        Ok(purged)
+    }
+
+    fn swap_items(&self, queue: &str, key_a: &QueueKey, key_b: &QueueKey) -> StorageResult<bool> {
+        let sqls = self.queue(queue)?;
+        let (shard_a, shard_b) = (sqls.shard(key_a), sqls.shard(key_b));
+        let (a, b) = (SortKey::new(key_a), SortKey::new(key_b));
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // An item's id, payloads, tenant and recurrence move along with its message;
+        // payloads stay encoded
+        type Content = (
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Recurrence,
+        );
+        let content_of = |row: &rusqlite::Row| -> rusqlite::Result<Content> {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                Recurrence::read(row, 4)?,
+            ))
+        };
+        let content_a = tx
+            .prepare_cached(&shard_a.find_message)?
+            .query_row(params![a.datetime, a.datetime_secondary], content_of)
+            .optional()?;
+        let content_b = tx
+            .prepare_cached(&shard_b.find_message)?
+            .query_row(params![b.datetime, b.datetime_secondary], content_of)
+            .optional()?;
+        let (Some(content_a), Some(content_b)) = (content_a, content_b) else {
+            return Ok(false);
+        };
+        tx.prepare_cached(&shard_a.update_message)?
+            .execute(params![
+                a.datetime,
+                a.datetime_secondary,
+                content_b.0,
+                content_b.1,
+                content_b.2,
+                content_b.3,
+                content_b.4.interval_secs,
+                content_b.4.until_millis()
+            ])?;
+        tx.prepare_cached(&shard_b.update_message)?
+            .execute(params![
+                b.datetime,
+                b.datetime_secondary,
+                content_a.0,
+                content_a.1,
+                content_a.2,
+                content_a.3,
+                content_a.4.interval_secs,
+                content_a.4.until_millis()
+            ])?;
+        tx.commit()?;
+
+        Ok(true)
+    }
+
+    fn hide_item(&self, queue: &str, key: &QueueKey, until: DateTime<Utc>) -> StorageResult<bool> {
+        let sqls = self.queue(queue)?;
+        let sort_key = SortKey::new(key);
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.shard(key).hide_item)?;
+        let changed = stmt.execute(params![
+            sort_key.datetime,
+            sort_key.datetime_secondary,
+            until.timestamp_millis(),
+            Utc::now().timestamp_millis()
+        ])?;
+
+        Ok(changed > 0)
+    }
+
+    fn extend_hidden(
+        &self,
+        queue: &str,
+        keys: &[QueueKey],
+        until: DateTime<Utc>,
+    ) -> StorageResult<Vec<bool>> {
+        let sqls = self.queue(queue)?;
+        let now = Utc::now().timestamp_millis();
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut held = Vec::with_capacity(keys.len());
+        for key in keys {
+            let sort_key = SortKey::new(key);
+            let changed = tx
+                .prepare_cached(&sqls.shard(key).extend_hidden)?
+                .execute(params![
+                    sort_key.datetime,
+                    sort_key.datetime_secondary,
+                    until.timestamp_millis(),
+                    now
+                ])?;
+            held.push(changed > 0);
+        }
+        tx.commit()?;
+
+        Ok(held)
+    }
+
+    fn requeue_hidden_since(
+        &self,
+        queue: &str,
+        hidden_before: DateTime<Utc>,
+    ) -> StorageResult<Vec<QueueKey>> {
+        let sqls = self.queue(queue)?;
+        let now = Utc::now().timestamp_millis();
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut requeued = Vec::new();
+        for shard in &sqls.shards {
+            let mut stmt = tx.prepare_cached(&shard.requeue_hidden)?;
+            let keys = stmt.query_map(params![now, hidden_before.timestamp_millis()], |row| {
+                Ok(SortKey {
+                    datetime: row.get(0)?,
+                    datetime_secondary: row.get(1)?,
+                })
+            })?;
+            for key in keys {
+                let key = key?
+                    .try_to_key()
+                    .map_err(|(_, cause)| StorageError::CorruptData(cause))?;
+                requeued.push(key);
+            }
+        }
+        tx.commit()?;
+
+        Ok(requeued)
+    }
+
+    fn increment_item(&self, queue: &str, key: &QueueKey, by: i64) -> StorageResult<i64> {
+        let sqls = self.queue_for_keys(queue, [*key])?;
+        let shard = sqls.shard(key);
+        let sort_key = SortKey::new(key);
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        type Content = (
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Recurrence,
+        );
+        let found: Option<Content> = tx
+            .prepare_cached(&shard.find_message)?
+            .query_row(
+                params![sort_key.datetime, sort_key.datetime_secondary],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        Recurrence::read(row, 4)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let value = increment_message(found.as_ref().map(|(message, ..)| message.as_str()), by)?;
+        // Updating in place keeps an existing item's hidden state, id, payloads, tenant
+        // and recurrence
+        let (sql, id, payloads, tenant, recurrence) = match found {
+            Some((_, id, payloads, tenant, recurrence)) => {
+                (&shard.update_message, id, payloads, tenant, recurrence)
+            }
+            None => (&shard.put_item, None, None, None, Recurrence::default()),
+        };
+        tx.prepare_cached(sql)?.execute(params![
+            sort_key.datetime,
+            sort_key.datetime_secondary,
+            value.to_string(),
+            id,
+            payloads,
+            tenant,
+            recurrence.interval_secs,
+            recurrence.until_millis()
+        ])?;
+        tx.commit()?;
+
+        Ok(value)
+    }
+
+    fn shift_items(
+        &self,
+        queue: &str,
+        by: chrono::Duration,
+        secondary: bool,
+    ) -> StorageResult<usize> {
+        // Partitions for the shifted days are created up front; an item put meanwhile
+        // whose new day has none moves to the base table, which every read includes,
+        // until that day gets a partition
+        let planned = self
+            .export_items(queue)?
+            .iter()
+            .map(|item| shift_key(&item.key(), by, secondary))
+            .collect::<StorageResult<Vec<_>>>()?;
+        let sqls = self.queue_for_keys(queue, planned)?;
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Every item leaves its row before any is stored under its new key, so no moved
+        // item can land on one that has yet to move; shifted keys stay distinct
+        let mut moved = Vec::new();
+        for shard in &sqls.shards {
+            let mut stmt = tx.prepare_cached(&shard.valid_rows)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row_to_item(row)?,
+                    row.get::<_, i64>(8)?,
+                    row.get::<_, i64>(9)?,
+                ))
+            })?;
+            for row in rows {
+                let (item, hide_until, hidden_since) = row?;
+                let key = shift_key(&item.key(), by, secondary)?;
+                moved.push((key, item, hide_until, hidden_since));
+            }
+            drop(stmt);
+            tx.prepare_cached(&shard.invalidate_all)?.execute([])?;
+        }
+        for (key, item, hide_until, hidden_since) in &moved {
+            let sort_key = SortKey::new(key);
+            tx.prepare_cached(&sqls.shard(key).put_moved)?
+                .execute(params![
+                    sort_key.datetime,
+                    sort_key.datetime_secondary,
+                    item.message,
+                    item.id,
+                    payloads_json(&item.payloads),
+                    item.tenant,
+                    item.recurrence_interval_secs,
+                    Recurrence::of(item).until_millis(),
+                    hide_until,
+                    hidden_since
+                ])?;
+        }
+        tx.commit()?;
+
+        Ok(moved.len())
+    }
+
+    fn peek_items(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        let sqls = self.queue(queue)?;
+        // No stored key orders before this one, since datetimes cannot reach i64::MIN
+        let after = after.map_or(
+            SortKey {
+                datetime: i64::MIN,
+                datetime_secondary: SortKey::NO_SECONDARY,
+            },
+            SortKey::new,
+        );
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.peek_items)?;
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let items = stmt
+            .query_map(
+                params![
+                    Utc::now().timestamp_millis(),
+                    after.datetime,
+                    after.datetime_secondary,
+                    limit
+                ],
+                row_to_item,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    fn export_items(&self, queue: &str) -> StorageResult<Vec<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.export_items)?;
+        let items = stmt
+            .query_map(params![], row_to_item)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    fn export_page(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        let sqls = self.queue(queue)?;
+        // As in `peek_items`, no stored key orders before this one
+        let after = after.map_or(
+            SortKey {
+                datetime: i64::MIN,
+                datetime_secondary: SortKey::NO_SECONDARY,
+            },
+            SortKey::new,
+        );
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.export_page)?;
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let items = stmt
+            .query_map(
+                params![after.datetime, after.datetime_secondary, limit],
+                row_to_item,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    fn overdue_items(&self, queue: &str, before: DateTime<Utc>) -> StorageResult<Vec<QueueItem>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.overdue_items)?;
+        let items = stmt
+            .query_map(params![before.timestamp_millis()], row_to_item)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(items)
+    }
+
+    fn export_rows(&self, queue: &str) -> StorageResult<Vec<Result<QueueItem, CorruptRow>>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.export_items)?;
+        // Only rows whose key columns hold integers can be reported; any other fails
+        let rows = stmt
+            .query_map(params![], |row| {
+                let (datetime, datetime_secondary) = (row.get(0)?, row.get(1)?);
+                Ok(row_to_item(row).map_err(|e| CorruptRow {
+                    datetime,
+                    datetime_secondary,
+                    error: e.to_string(),
+                }))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(rows)
+    }
+
+    fn items_exist(&self, queue: &str, keys: &[QueueKey]) -> StorageResult<Vec<bool>> {
+        let sqls = self.queue(queue)?;
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sort_keys: Vec<SortKey> = keys.iter().map(SortKey::new).collect();
+
+        // One query over every shard, each matching the whole key list
+        let values = (0..sort_keys.len())
+            .map(|i| format!("(?{}, ?{})", 2 * i + 1, 2 * i + 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = sqls
+            .shards
+            .iter()
+            .map(|shard| {
+                format!(
+                    "SELECT datetime, datetime_secondary FROM {} WHERE valid = 1 AND (datetime, datetime_secondary) IN (VALUES {values})",
+                    shard.table
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" UNION ALL ");
+        let params: Vec<i64> = sort_keys
+            .iter()
+            .flat_map(|key| [key.datetime, key.datetime_secondary])
+            .collect();
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // The statement depends on the number of keys, so it is not worth caching
+        let mut stmt = conn.prepare(&sql)?;
+        let found = stmt
+            .query_map(rusqlite::params_from_iter(params), |row| {
+                Ok(SortKey {
+                    datetime: row.get(0)?,
+                    datetime_secondary: row.get(1)?,
+                })
+            })?
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        Ok(sort_keys.iter().map(|key| found.contains(key)).collect())
+    }
+
+    fn count_items(&self, queue: &str) -> StorageResult<usize> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.count_items)?;
+        let count: i64 = stmt.query_row(params![], |row| row.get(0))?;
+
+        Ok(count as usize)
+    }
+
+    fn count_by_validity(&self, queue: &str) -> StorageResult<ValidityCounts> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.count_by_validity)?;
+        let mut rows = stmt.query(params![])?;
+        let mut counts = ValidityCounts::default();
+        while let Some(row) = rows.next()? {
+            let valid: bool = row.get(0)?;
+            let count: i64 = row.get(1)?;
+            if valid {
+                counts.valid = count as usize;
+            } else {
+                counts.invalid = count as usize;
+            }
+        }
+
+        Ok(counts)
+    }
+
+    fn rank_of_key(&self, queue: &str, key: &QueueKey) -> StorageResult<Option<usize>> {
+        let sqls = self.queue(queue)?;
+        let sort_key = SortKey::new(key);
+        let key_params = params![sort_key.datetime, sort_key.datetime_secondary];
+
+        let mut conn = self.pool.get().map_err(StorageError::PoolError)?;
+        // Read both in one snapshot so a concurrent delete cannot skew the count
+        let tx = conn.transaction()?;
+        let present = tx
+            .prepare_cached(&sqls.shard(key).find_message)?
+            .query_row(key_params, |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !present {
+            return Ok(None);
+        }
+        let count: i64 = tx
+            .prepare_cached(&sqls.count_before)?
+            .query_row(key_params, |row| row.get(0))?;
+        tx.commit()?;
+
+        Ok(Some(count as usize))
+    }
+
+    fn last_modified(&self, queue: &str) -> StorageResult<Option<DateTime<Utc>>> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut stmt = conn.prepare_cached(&sqls.last_modified)?;
+        let last_modified = stmt.query_row(params![], |row| row.get(0))?;
+
+        Ok(last_modified)
+    }
+
+    fn create_queue(&self, queue: &str) -> StorageResult<bool> {
+        if sanitize_queue_name(queue).is_none() {
+            return Err(StorageError::InvalidQueueName(queue.to_string()));
+        }
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        if queues.contains_key(queue) {
+            return Ok(false);
+        }
+        if let Some(max_queues) = self.max_queues
+            && queues.len() >= max_queues
+        {
+            return Err(StorageError::QueueLimitReached(max_queues));
+        }
+
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        // Configured queues are checked against each other by `AppConfig::validate`
+        let options_of = |name: &str| self.queue_options.get(name).cloned().unwrap_or_default();
+        if let Some(other) = queues
+            .keys()
+            .find(|other| tables_collide((queue, &options), (other, &options_of(other))))
+        {
+            return Err(StorageError::TableConflict {
+                queue: queue.to_string(),
+                other: other.clone(),
+            });
+        }
+
+        let sqls = SqliteQueue::open(&conn, queue, &options)?;
+        queues.insert(queue.to_string(), Arc::new(sqls));
+        Ok(true)
+    }
+
+    fn queue_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .queues
+            .read()
+            .map(|queues| queues.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    fn queue_exists(&self, queue: &str) -> bool {
+        self.queues
+            .read()
+            .map(|queues| queues.contains_key(queue))
+            .unwrap_or(false)
+    }
+
+    fn enforce_wal_limit(&self, max_bytes: u64) -> StorageResult<Option<(u64, u64)>> {
+        // The frame count reported by a checkpoint restarts when a writer rewinds the log,
+        // but the file keeps its size, so the file itself is measured
+        let before = wal_size(&self.wal_path)?;
+        if before <= max_bytes {
+            return Ok(None);
+        }
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(Some((before, wal_size(&self.wal_path)?)))
+    }
+
+    fn compact(&self, queue: &str, limit: usize) -> StorageResult<usize> {
+        let sqls = self.queue(queue)?;
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut compacted = 0;
+        // Each shard is its own short write, so foreground writers wait at most for one
+        for shard in &sqls.shards {
+            if compacted >= limit {
+                break;
+            }
+            compacted += conn
+                .prepare_cached(&shard.compact)?
+                .execute(params![(limit - compacted) as i64])?;
+        }
+        Ok(compacted)
+    }
+
+    fn purge_deleted(&self, queue: &str, older_than: chrono::Duration) -> StorageResult<usize> {
+        let sqls = self.queue(queue)?;
+        let Some(cutoff) = Utc::now().checked_sub_signed(older_than) else {
+            return Ok(0);
+        };
+        // In the format `DELETED_NOW` writes, so the text columns compare in order
+        let cutoff = cutoff.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let mut purged = 0;
+        // Each batch is its own short write; with WAL, readers are never blocked at all
+        for shard in &sqls.shards {
+            loop {
+                let removed = conn
+                    .prepare_cached(&shard.purge_deleted)?
+                    .execute(params![cutoff, PURGE_BATCH as i64])?;
+                purged += removed;
+                if removed < PURGE_BATCH {
+                    break;
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    fn vacuum(&self) -> StorageResult<Option<(u64, u64)>> {
+        let conn = self.pool.get().map_err(StorageError::PoolError)?;
+        let size = || -> rusqlite::Result<u64> {
+            conn.query_row(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+                [],
+                |row| row.get::<_, i64>(0).map(|bytes| bytes as u64),
+            )
+        };
+        let before = size()?;
+        conn.execute_batch("VACUUM")?;
+        // With WAL, the rebuilt pages sit in the log until a checkpoint writes them back
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        Ok(Some((before, size()?)))
+    }
+}
+
+/// Returns the size of the write-ahead log at `path`, which is absent until the first write.
+fn wal_size(path: &str) -> std::io::Result<u64> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone)]
+struct InMemoryItem {
+    message: String,
+    id: Option<String>,
+    payloads: Option<BTreeMap<String, String>>,
+    tenant: Option<String>,
+    recurrence: Recurrence,
+    // Milliseconds since the epoch before which the item is hidden, as stored by SQLite
+    hide_until: i64,
+    /// When the current hiding began, kept while it is extended; 0 when not hidden.
+    hidden_since: i64,
+}
+
+impl InMemoryItem {
+    fn is_visible(&self, now: i64) -> bool {
+        self.hide_until <= now
+    }
+
+    fn of_tenant(&self, tenant: &str) -> bool {
+        self.tenant.as_deref() == Some(tenant)
+    }
+
+    fn to_item(&self, key: SortKey) -> QueueItem {
+        key.to_item(
+            self.message.clone(),
+            self.id.clone(),
+            self.payloads.clone(),
+            self.tenant.clone(),
+            self.recurrence,
+        )
+    }
+
+    fn into_item(self, key: SortKey) -> QueueItem {
+        key.to_item(
+            self.message,
+            self.id,
+            self.payloads,
+            self.tenant,
+            self.recurrence,
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+struct InMemoryQueue {
+    items: BTreeMap<SortKey, InMemoryItem>,
+    // Time of the last content change, see `Storage::last_modified`
+    last_modified: Option<DateTime<Utc>>,
+    // Whether an item replaces the item carrying the same id, see `KeyBy::Id`
+    key_by_id: bool,
+    // The highest fencing token a put carried, see `Storage::put_item_fenced`
+    fence: Option<i64>,
+}
+
+impl InMemoryQueue {
+    fn new(options: &QueueOptions) -> Self {
+        InMemoryQueue {
+            key_by_id: options.key_by == KeyBy::Id,
+            ..Default::default()
+        }
+    }
+
+    /// Stores `item` under its key, first removing the item carrying its id under
+    /// another key in a queue keyed by id. Returns whether an item was replaced.
+    fn insert(&mut self, item: QueueItem) -> bool {
+        let key = SortKey::new(&item.key());
+        let mut superseded = false;
+        if self.key_by_id
+            && let Some(id) = &item.id
+        {
+            // Ids are not indexed, so this scans the queue
+            let before = self.items.len();
+            self.items
+                .retain(|other, entry| *other == key || entry.id.as_ref() != Some(id));
+            superseded = self.items.len() < before;
+        }
+        let entry = InMemoryItem {
+            recurrence: Recurrence::of(&item),
+            message: item.message,
+            id: item.id,
+            payloads: item.payloads,
+            tenant: item.tenant,
+            hide_until: 0,
+            hidden_since: 0,
+        };
+        self.items.insert(key, entry).is_some() || superseded
+    }
+
+    /// The items in key order, up to the last one whose datetime is not after `due_by`,
+    /// compared in milliseconds, or all of them without `due_by`.
+    fn due_items(
+        &self,
+        due_by: Option<DateTime<Utc>>,
+    ) -> impl Iterator<Item = (&SortKey, &InMemoryItem)> {
+        let due = SortKey {
+            datetime: due_by_millis(due_by),
+            datetime_secondary: i64::MAX,
+        };
+        self.items.range(..=due)
+    }
+
+    /// Removes the item under `key` as it is consumed. A recurring item is replaced by
+    /// its next occurrence, see `QueueItem::next_occurrence`.
+    fn consume(&mut self, key: SortKey) -> QueueItem {
+        let entry = self.items.remove(&key).expect("consumed key is present");
+        let item = entry.into_item(key);
+        if let Some(next) = item.next_occurrence() {
+            self.insert(next);
+            self.last_modified = Some(Utc::now());
+        }
+        item
+    }
+}
+
+pub struct InMemoryStorage {
+    // Map queue_name -> items ordered by SortKey
+    queues: RwLock<HashMap<String, InMemoryQueue>>,
+    queue_options: HashMap<String, QueueOptions>,
+    max_queues: Option<usize>,
+}
+
+impl InMemoryStorage {
+    pub fn new(config: &AppConfig) -> Self {
+        let mut queues_map = HashMap::new();
+
+        for queue in &config.queues {
+            let queue = normalize_queue_name(queue);
+            let options = config.queue_options(&queue);
+            queues_map.insert(queue, InMemoryQueue::new(&options));
+        }
+
+        InMemoryStorage {
+            queues: RwLock::new(queues_map),
+            queue_options: config.queue_options.clone(),
+            max_queues: config.max_queues,
+        }
+    }
+}
+
+impl Storage for InMemoryStorage {
+    fn put_item(&self, queue: &str, item: QueueItem) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let replaced = queue_map.insert(item);
+        queue_map.last_modified = Some(Utc::now());
+        Ok(!replaced)
+    }
+
+    fn put_item_fenced(&self, queue: &str, item: QueueItem, token: i64) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        if let Some(highest) = queue_map.fence.filter(|highest| token < *highest) {
+            return Err(StorageError::StaleFenceToken { token, highest });
+        }
+        queue_map.fence = Some(token);
+        let replaced = queue_map.insert(item);
+        queue_map.last_modified = Some(Utc::now());
+        Ok(!replaced)
+    }
+
+    fn replace_if_message(
+        &self,
+        queue: &str,
+        item: QueueItem,
+        expected: &str,
+    ) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let current = queue_map.items.get(&SortKey::new(&item.key()));
+        if current.map(|entry| entry.message.as_str()) != Some(expected) {
+            return Ok(false);
+        }
+        queue_map.insert(item);
+        queue_map.last_modified = Some(Utc::now());
+        Ok(true)
+    }
+
+    fn put_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        for item in items {
+            queue_map.insert(item);
+        }
+        queue_map.last_modified = Some(Utc::now());
+        Ok(())
+    }
+
+    fn put_consumed_items(&self, queue: &str, items: Vec<QueueItem>) -> StorageResult<()> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        // Consumed items leave no trace in memory
+        for item in items {
+            queue_map.items.remove(&SortKey::new(&item.key()));
+        }
+        queue_map.last_modified = Some(Utc::now());
+        Ok(())
+    }
+
+    fn get_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        Ok(queue_map
+            .items
+            .iter()
+            .find(|(_, entry)| entry.is_visible(now))
+            .map(|(key, entry)| entry.to_item(*key)))
+    }
+
+    fn tail_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        Ok(queue_map
+            .items
+            .last_key_value()
+            .map(|(key, entry)| entry.to_item(*key)))
+    }
+
+    fn snapshot_heads(&self, queues: &[String]) -> StorageResult<Vec<Option<QueueItem>>> {
+        // One read lock keeps every writer out until all heads are read
+        let queue_maps = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let now = Utc::now().timestamp_millis();
+        queues
+            .iter()
+            .map(|queue| {
+                let queue_map = queue_maps
+                    .get(queue)
+                    .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+                Ok(queue_map
+                    .items
+                    .iter()
+                    .find(|(_, entry)| entry.is_visible(now))
+                    .map(|(key, entry)| entry.to_item(*key)))
+            })
+            .collect()
+    }
+
+    fn delete_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let Some(key) = queue_map
+            .items
+            .iter()
+            .find(|(_, entry)| entry.is_visible(now))
+            .map(|(key, _)| *key)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(queue_map.consume(key)))
+    }
+
+    fn get_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        Ok(queue_map
+            .due_items(due_by)
+            .find(|(_, entry)| entry.is_visible(now) && entry.of_tenant(tenant))
+            .map(|(key, entry)| entry.to_item(*key)))
+    }
+
+    fn delete_tenant_item(
+        &self,
+        queue: &str,
+        tenant: &str,
+        due_by: Option<DateTime<Utc>>,
+    ) -> StorageResult<Option<QueueItem>> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let Some(key) = queue_map
+            .due_items(due_by)
+            .find(|(_, entry)| entry.is_visible(now) && entry.of_tenant(tenant))
+            .map(|(key, _)| *key)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(queue_map.consume(key)))
+    }
+
+    fn get_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        Ok(queue_map
+            .items
+            .iter()
+            .rev()
+            .find(|(_, entry)| entry.is_visible(now))
+            .map(|(key, entry)| entry.to_item(*key)))
+    }
+
+    fn delete_newest_item(&self, queue: &str) -> StorageResult<Option<QueueItem>> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let Some(key) = queue_map
+            .items
+            .iter()
+            .rev()
+            .find(|(_, entry)| entry.is_visible(now))
+            .map(|(key, _)| *key)
+        else {
+            return Ok(None);
+        };
+        Ok(Some(queue_map.consume(key)))
+    }
+
+    fn remove_item(&self, queue: &str, key: &QueueKey) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        if queue_map.items.remove(&SortKey::new(key)).is_none() {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    fn compare_and_delete(&self, queue: &str, expected: &QueueKey) -> StorageResult<CasResult> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let Some((key, entry)) = queue_map
+            .items
+            .iter()
+            .find(|(_, entry)| entry.is_visible(now))
+        else {
+            return Ok(CasResult::Mismatch(None));
+        };
+        if *key != SortKey::new(expected) {
+            return Ok(CasResult::Mismatch(Some(entry.to_item(*key))));
+        }
+        let key = *key;
+        Ok(CasResult::Deleted(queue_map.consume(key)))
+    }
+
+    fn claim_any(
+        &self,
+        queues: &[String],
+        now: DateTime<Utc>,
+        due_only: bool,
+        start: usize,
+    ) -> StorageResult<Option<(String, QueueItem)>> {
+        let mut queue_maps = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let now = now.timestamp_millis();
+        let mut best: Option<(usize, SortKey)> = None;
+        for index in rotated(queues.len(), start) {
+            let queue_map = queue_maps
+                .get(&queues[index])
+                .ok_or_else(|| StorageError::QueueNotFound(queues[index].clone()))?;
+            let head = queue_map
+                .items
+                .iter()
+                .find(|(_, entry)| entry.is_visible(now))
+                .map(|(key, _)| *key);
+            if let Some(key) = head
+                && (!due_only || key.is_due(now))
+                && best.is_none_or(|(_, best)| key < best)
+            {
+                best = Some((index, key));
+            }
+        }
+        let Some((index, key)) = best else {
+            return Ok(None);
+        };
+        let queue_map = queue_maps
+            .get_mut(&queues[index])
+            .expect("claimed queue is present");
+        Ok(Some((queues[index].clone(), queue_map.consume(key))))
+    }
+
+    fn transact(&self, ops: &[TxOp]) -> StorageResult<Vec<Option<QueueItem>>> {
+        let mut queue_maps = self.queues.write().map_err(|_| StorageError::LockError)?;
+        // The operations apply to copies of the queues they touch, which replace the
+        // originals only once every operation succeeded
+        let mut touched: HashMap<&str, InMemoryQueue> = HashMap::new();
+        let now = Utc::now().timestamp_millis();
+        let mut results = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            let queue = op.queue();
+            let queue_map = match touched.entry(queue) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let original = queue_maps
+                        .get(queue)
+                        .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+                    entry.insert(original.clone())
+                }
+            };
+            let result = match op {
+                TxOp::Put { item, .. } => {
+                    queue_map.insert(item.clone());
+                    None
+                }
+                TxOp::Pop { due_only, .. } => {
+                    let key = queue_map
+                        .items
+                        .iter()
+                        .find(|(_, entry)| entry.is_visible(now))
+                        .map(|(key, _)| *key)
+                        .filter(|key| !due_only || key.is_due(now))
+                        .ok_or_else(|| StorageError::TransactionAborted {
+                            index,
+                            reason: format!("queue {queue} has no item to pop"),
+                        })?;
+                    Some(queue_map.consume(key))
+                }
+                TxOp::Delete { key, .. } => {
+                    if queue_map.items.remove(&SortKey::new(key)).is_none() {
+                        return Err(StorageError::TransactionAborted {
+                            index,
+                            reason: format!("queue {queue} has no item under {key:?}"),
+                        });
+                    }
+                    None
+                }
+            };
+            results.push(result);
+        }
+        let modified = Some(Utc::now());
+        for (queue, mut queue_map) in touched {
+            queue_map.last_modified = modified;
+            queue_maps.insert(queue.to_string(), queue_map);
+        }
+        Ok(results)
+    }
+
+    fn purge_before(&self, queue: &str, cutoff: DateTime<Utc>) -> StorageResult<usize> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let cutoff = SortKey {
+            datetime: cutoff.timestamp_millis(),
+            datetime_secondary: SortKey::NO_SECONDARY,
+        };
+        // Everything ordering before the cutoff's smallest key has an earlier datetime
+        let kept = queue_map.items.split_off(&cutoff);
+        let purged = std::mem::replace(&mut queue_map.items, kept).len();
+        Ok(purged)
+    }
+
+    fn swap_items(&self, queue: &str, key_a: &QueueKey, key_b: &QueueKey) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let items = &mut queue_map.items;
+        let (key_a, key_b) = (SortKey::new(key_a), SortKey::new(key_b));
+        if !items.contains_key(&key_a) || !items.contains_key(&key_b) {
+            return Ok(false);
+        }
+        if key_a != key_b {
+            // The contents trade places; as in SQLite, hiding belongs to the key
+            let mut entry_a = items.remove(&key_a).expect("checked above");
+            let mut entry_b = items.remove(&key_b).expect("checked above");
+            std::mem::swap(&mut entry_a.hide_until, &mut entry_b.hide_until);
+            std::mem::swap(&mut entry_a.hidden_since, &mut entry_b.hidden_since);
+            items.insert(key_a, entry_b);
+            items.insert(key_b, entry_a);
+            queue_map.last_modified = Some(Utc::now());
+        }
+        Ok(true)
+    }
+
+    fn hide_item(&self, queue: &str, key: &QueueKey, until: DateTime<Utc>) -> StorageResult<bool> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let Some(entry) = queue_map.items.get_mut(&SortKey::new(key)) else {
+            return Ok(false);
+        };
+        let now = Utc::now().timestamp_millis();
+        // Extending a hiding that has not expired keeps when it started
+        if entry.is_visible(now) || entry.hidden_since == 0 {
+            entry.hidden_since = now;
+        }
+        entry.hide_until = until.timestamp_millis();
+        Ok(true)
+    }
+
+    fn extend_hidden(
+        &self,
+        queue: &str,
+        keys: &[QueueKey],
+        until: DateTime<Utc>,
+    ) -> StorageResult<Vec<bool>> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let held: Vec<bool> = keys
+            .iter()
+            .map(|key| match queue_map.items.get_mut(&SortKey::new(key)) {
+                Some(entry) if !entry.is_visible(now) => {
+                    entry.hide_until = until.timestamp_millis();
+                    true
+                }
+                _ => false,
+            })
+            .collect();
+        Ok(held)
+    }
+
+    fn requeue_hidden_since(
+        &self,
+        queue: &str,
+        hidden_before: DateTime<Utc>,
+    ) -> StorageResult<Vec<QueueKey>> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let hidden_before = hidden_before.timestamp_millis();
+        let mut requeued = Vec::new();
+        for (key, entry) in queue_map.items.iter_mut() {
+            if !entry.is_visible(now)
+                && entry.hidden_since > 0
+                && entry.hidden_since < hidden_before
+            {
+                entry.hide_until = 0;
+                entry.hidden_since = 0;
+                requeued.push(key.to_key());
+            }
+        }
+        Ok(requeued)
+    }
+
+    fn increment_item(&self, queue: &str, key: &QueueKey, by: i64) -> StorageResult<i64> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let entry = queue_map.items.get_mut(&SortKey::new(key));
+        let value = increment_message(entry.as_ref().map(|entry| entry.message.as_str()), by)?;
+        match entry {
+            Some(entry) => entry.message = value.to_string(),
+            None => {
+                let entry = InMemoryItem {
+                    message: value.to_string(),
+                    id: None,
+                    payloads: None,
+                    tenant: None,
+                    recurrence: Recurrence::default(),
+                    hide_until: 0,
+                    hidden_since: 0,
+                };
+                queue_map.items.insert(SortKey::new(key), entry);
+            }
+        }
+        queue_map.last_modified = Some(Utc::now());
+        Ok(value)
+    }
+
+    fn shift_items(
+        &self,
+        queue: &str,
+        by: chrono::Duration,
+        secondary: bool,
+    ) -> StorageResult<usize> {
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get_mut(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        // Every key is checked before any item moves, so a failure changes nothing
+        let shifted = queue_map
+            .items
+            .keys()
+            .map(|key| shift_key(&key.to_key(), by, secondary).map(|key| SortKey::new(&key)))
+            .collect::<StorageResult<Vec<_>>>()?;
+        let items = std::mem::take(&mut queue_map.items);
+        queue_map.items = shifted.into_iter().zip(items.into_values()).collect();
+        if !queue_map.items.is_empty() {
+            queue_map.last_modified = Some(Utc::now());
+        }
+        Ok(queue_map.items.len())
+    }
+
+    fn peek_items(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let now = Utc::now().timestamp_millis();
+        let start = match after {
+            Some(after) => Bound::Excluded(SortKey::new(after)),
+            None => Bound::Unbounded,
+        };
+        Ok(queue_map
+            .items
+            .range((start, Bound::Unbounded))
+            .filter(|(_, entry)| entry.is_visible(now))
+            .take(limit)
+            .map(|(key, entry)| entry.to_item(*key))
+            .collect())
+    }
+
+    fn export_items(&self, queue: &str) -> StorageResult<Vec<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        Ok(queue_map
+            .items
+            .iter()
+            .map(|(key, entry)| entry.to_item(*key))
+            .collect())
+    }
+
+    fn export_page(
+        &self,
+        queue: &str,
+        after: Option<&QueueKey>,
+        limit: usize,
+    ) -> StorageResult<Vec<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let start = match after {
+            Some(after) => Bound::Excluded(SortKey::new(after)),
+            None => Bound::Unbounded,
+        };
+        Ok(queue_map
+            .items
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, entry)| entry.to_item(*key))
+            .collect())
+    }
+
+    fn overdue_items(&self, queue: &str, before: DateTime<Utc>) -> StorageResult<Vec<QueueItem>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        // Every key with an earlier primary datetime sorts before this one
+        let end = SortKey {
+            datetime: before.timestamp_millis(),
+            datetime_secondary: SortKey::NO_SECONDARY,
+        };
+        Ok(queue_map
+            .items
+            .range(..end)
+            .map(|(key, entry)| entry.to_item(*key))
+            .collect())
+    }
+
+    fn export_rows(&self, queue: &str) -> StorageResult<Vec<Result<QueueItem, CorruptRow>>> {
+        Ok(self.export_items(queue)?.into_iter().map(Ok).collect())
+    }
+
+    fn items_exist(&self, queue: &str, keys: &[QueueKey]) -> StorageResult<Vec<bool>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        Ok(keys
+            .iter()
+            .map(|key| queue_map.items.contains_key(&SortKey::new(key)))
+            .collect())
+    }
+
+    fn count_items(&self, queue: &str) -> StorageResult<usize> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        Ok(queue_map.items.len())
+    }
+
+    fn count_by_validity(&self, queue: &str) -> StorageResult<ValidityCounts> {
+        Ok(ValidityCounts {
+            valid: self.count_items(queue)?,
+            invalid: 0,
+        })
+    }
+
+    fn rank_of_key(&self, queue: &str, key: &QueueKey) -> StorageResult<Option<usize>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        let key = SortKey::new(key);
+        if !queue_map.items.contains_key(&key) {
+            return Ok(None);
+        }
+        Ok(Some(queue_map.items.range(..key).count()))
+    }
+
+    fn last_modified(&self, queue: &str) -> StorageResult<Option<DateTime<Utc>>> {
+        let queues = self.queues.read().map_err(|_| StorageError::LockError)?;
+        let queue_map = queues
+            .get(queue)
+            .ok_or_else(|| StorageError::QueueNotFound(queue.to_string()))?;
+        Ok(queue_map.last_modified)
+    }
+
+    fn create_queue(&self, queue: &str) -> StorageResult<bool> {
+        if sanitize_queue_name(queue).is_none() {
+            return Err(StorageError::InvalidQueueName(queue.to_string()));
+        }
+        let mut queues = self.queues.write().map_err(|_| StorageError::LockError)?;
+        if queues.contains_key(queue) {
+            return Ok(false);
+        }
+        if let Some(max_queues) = self.max_queues
+            && queues.len() >= max_queues
+        {
+            return Err(StorageError::QueueLimitReached(max_queues));
+        }
+        let options = self.queue_options.get(queue).cloned().unwrap_or_default();
+        queues.insert(queue.to_string(), InMemoryQueue::new(&options));
+        Ok(true)
+    }
+
+    fn queue_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .queues
+            .read()
+            .map(|queues| queues.keys().cloned().collect())
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+
+    fn queue_exists(&self, queue: &str) -> bool {
+        self.queues
+            .read()
+            .map(|queues| queues.contains_key(queue))
+            .unwrap_or(false)
+    }
+
+    fn enforce_wal_limit(&self, _max_bytes: u64) -> StorageResult<Option<(u64, u64)>> {
+        Ok(None)
+    }
+
+    fn compact(&self, _queue: &str, _limit: usize) -> StorageResult<usize> {
+        // Deleted items are removed right away
+        Ok(0)
+    }
+
+    fn purge_deleted(&self, _queue: &str, _older_than: chrono::Duration) -> StorageResult<usize> {
+        Ok(0)
+    }
+
+    fn vacuum(&self) -> StorageResult<Option<(u64, u64)>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    fn test_config(database_path: &str) -> AppConfig {
+        AppConfig {
+            queues: vec!["queue".to_string()],
+            database_path: database_path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Builds one storage of each backend so tests can assert identical behavior
+    fn backends() -> (TempDir, Vec<Box<dyn Storage>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let sqlite = SqliteStorage::new(&test_config(db_path.to_str().unwrap())).unwrap();
+        let memory = InMemoryStorage::new(&test_config(":memory:"));
+        (dir, vec![Box::new(sqlite), Box::new(memory)])
+    }
+
+    fn item(datetime: DateTime<Utc>, message: &str) -> QueueItem {
+        QueueItem {
+            datetime,
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_swap_items_flips_order() {
+        let (_dir, backends) = backends();
+        let now = DateTime::<Utc>::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+
+        for storage in backends {
+            let first = item(now, "first");
+            let second = item(now + Duration::seconds(10), "second");
+            storage.put_item("queue", first.clone()).unwrap();
+            storage.put_item("queue", second.clone()).unwrap();
+
+            assert!(
+                storage
+                    .swap_items("queue", &first.key(), &second.key())
+                    .unwrap()
+            );
+
+            let head = storage.delete_item("queue").unwrap().unwrap();
+            assert_eq!(head.datetime, first.datetime);
+            assert_eq!(head.message, "second");
+            let next = storage.delete_item("queue").unwrap().unwrap();
+            assert_eq!(next.datetime, second.datetime);
+            assert_eq!(next.message, "first");
+        }
+    }
+
+    #[test]
+    fn test_swap_items_missing_key() {
+        let (_dir, backends) = backends();
+        let now = DateTime::<Utc>::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+
+        for storage in backends {
+            let present = item(now, "present");
+            let absent = item(now + Duration::seconds(10), "absent");
+            storage.put_item("queue", present.clone()).unwrap();
+
+            assert!(
+                !storage
+                    .swap_items("queue", &present.key(), &absent.key())
+                    .unwrap()
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), Some(present));
+            assert!(matches!(
+                storage.swap_items("missing", &absent.key(), &absent.key()),
+                Err(StorageError::QueueNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_overdue_items() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let mut with_secondary = item(base - Duration::seconds(1), "secondary");
+            with_secondary.datetime_secondary = Some(base);
+            for item in [
+                item(base, "at the cutoff"),
+                item(base - Duration::seconds(2), "overdue"),
+                with_secondary.clone(),
+                item(base + Duration::seconds(1), "later"),
+            ] {
+                storage.put_item("queue", item).unwrap();
+            }
+            storage.delete_item("queue").unwrap();
+            storage
+                .put_item("queue", item(base - Duration::seconds(3), "hidden"))
+                .unwrap();
+            storage
+                .hide_item(
+                    "queue",
+                    &item(base - Duration::seconds(3), "").key(),
+                    Utc::now() + Duration::minutes(1),
+                )
+                .unwrap();
+
+            // The deleted one is gone, the hidden one is still overdue
+            let overdue: Vec<String> = storage
+                .overdue_items("queue", base)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.message)
+                .collect();
+            assert_eq!(overdue, ["hidden", "secondary"]);
+        }
+    }
+
+    #[test]
+    fn test_peek_items_in_order() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            for i in (0..5).rev() {
+                storage
+                    .put_item("queue", item(base + Duration::seconds(i), &format!("{i}")))
+                    .unwrap();
+            }
+            let peeked = storage.peek_items("queue", None, 3).unwrap();
+            let messages: Vec<&str> = peeked.iter().map(|item| item.message.as_str()).collect();
+            assert_eq!(messages, ["0", "1", "2"]);
+            assert_eq!(storage.peek_items("queue", None, 10).unwrap().len(), 5);
+            assert_eq!(storage.peek_items("queue", None, 0).unwrap(), vec![]);
+
+            // Continuing after the last item peeked
+            let rest = storage
+                .peek_items("queue", Some(&peeked[2].key()), 10)
+                .unwrap();
+            let messages: Vec<&str> = rest.iter().map(|item| item.message.as_str()).collect();
+            assert_eq!(messages, ["3", "4"]);
+            let last = rest[1].key();
+            assert_eq!(
+                storage.peek_items("queue", Some(&last), 10).unwrap(),
+                vec![]
+            );
+        }
+    }
+
+    #[test]
+    fn test_export_page_includes_hidden_items() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            for i in (0..5).rev() {
+                storage
+                    .put_item("queue", item(base + Duration::seconds(i), &format!("{i}")))
+                    .unwrap();
+            }
+            let hidden = item(base + Duration::seconds(1), "1").key();
+            let until = Utc::now() + Duration::seconds(60);
+            assert!(storage.hide_item("queue", &hidden, until).unwrap());
+            storage
+                .remove_item("queue", &item(base + Duration::seconds(3), "").key())
+                .unwrap();
+
+            let page = storage.export_page("queue", None, 2).unwrap();
+            let messages: Vec<&str> = page.iter().map(|item| item.message.as_str()).collect();
+            assert_eq!(messages, ["0", "1"]);
+            let rest = storage
+                .export_page("queue", Some(&page[1].key()), 10)
+                .unwrap();
+            let messages: Vec<&str> = rest.iter().map(|item| item.message.as_str()).collect();
+            assert_eq!(messages, ["2", "4"]);
+            assert_eq!(
+                storage
+                    .export_page("queue", Some(&rest[1].key()), 10)
+                    .unwrap(),
+                vec![]
+            );
+        }
+    }
+
+    // Counts the statements currently prepared on `conn`, which are those held by its cache
+    fn prepared_statements(conn: &Connection) -> usize {
+        let mut count = 0;
+        // SAFETY: the handle stays valid while `conn` is borrowed and the statements are only
+        // walked, not used
+        unsafe {
+            let db = conn.handle();
+            let mut stmt = rusqlite::ffi::sqlite3_next_stmt(db, std::ptr::null_mut());
+            while !stmt.is_null() {
+                count += 1;
+                stmt = rusqlite::ffi::sqlite3_next_stmt(db, stmt);
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_statement_cache_holds_every_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let queues: Vec<String> = (0..20).map(|i| format!("queue{i}")).collect();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        // get, replace, insert, delete and count on every queue
+        let used_statements = 5 * queues.len();
+
+        for (capacity, expected) in [(None, used_statements), (Some(16), 16)] {
+            let db_path = dir.path().join(format!("queue_{expected}.sqlite"));
+            let storage = SqliteStorage::new(&AppConfig {
+                queues: queues.clone(),
+                database_path: db_path.to_str().unwrap().to_string(),
+                db_pool_size: 1,
+                statement_cache_capacity: capacity,
+                ..Default::default()
+            })
+            .unwrap();
+            for _ in 0..2 {
+                for queue in &queues {
+                    storage.put_item(queue, item(base, "message")).unwrap();
+                    storage.get_item(queue).unwrap();
+                    storage.delete_item(queue).unwrap();
+                    storage.count_items(queue).unwrap();
+                }
+            }
+            let conn = storage.pool.get().unwrap();
+            assert_eq!(prepared_statements(&conn), expected);
+        }
+    }
+
+    #[test]
+    fn test_prewarm_fills_pool() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.db_pool_size = 4;
+
+        let lazy = SqliteStorage::new(&config).unwrap();
+        assert_eq!(lazy.pool.state().connections, 1);
+
+        config.db_prewarm = true;
+        let prewarmed = SqliteStorage::new(&config).unwrap();
+        let state = prewarmed.pool.state();
+        assert_eq!(state.connections, 4);
+        assert_eq!(state.idle_connections, 4);
+    }
+
+    #[test]
+    fn test_count_and_last_modified() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let before = Utc::now() - Duration::seconds(1);
+            assert_eq!(storage.count_items("queue").unwrap(), 0);
+            assert_eq!(storage.last_modified("queue").unwrap(), None);
+
+            storage.put_item("queue", item(base, "first")).unwrap();
+            storage
+                .put_item("queue", item(base + Duration::seconds(1), "second"))
+                .unwrap();
+            storage.delete_item("queue").unwrap();
+
+            assert_eq!(storage.count_items("queue").unwrap(), 1);
+            let last_modified = storage.last_modified("queue").unwrap().unwrap();
+            assert!(last_modified >= before && last_modified <= Utc::now());
+            assert!(matches!(
+                storage.count_items("missing"),
+                Err(StorageError::QueueNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_hidden_item_skipped_until_expiry() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let first = item(base, "first");
+            let second = item(base + Duration::seconds(1), "second");
+            storage.put_item("queue", first.clone()).unwrap();
+            storage.put_item("queue", second.clone()).unwrap();
+
+            let until = Utc::now() + Duration::milliseconds(300);
+            assert!(storage.hide_item("queue", &first.key(), until).unwrap());
+            assert!(
+                !storage
+                    .hide_item("queue", &item(base - Duration::seconds(1), "").key(), until)
+                    .unwrap()
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), Some(second.clone()));
+            assert_eq!(
+                storage.peek_items("queue", None, 10).unwrap(),
+                vec![second.clone()]
+            );
+            // Hidden items still count towards the depth
+            assert_eq!(storage.count_items("queue").unwrap(), 2);
+            assert_eq!(storage.delete_item("queue").unwrap(), Some(second));
+            assert_eq!(storage.delete_item("queue").unwrap(), None);
+
+            std::thread::sleep(std::time::Duration::from_millis(350));
+            assert_eq!(storage.delete_item("queue").unwrap(), Some(first));
+        }
+    }
+
+    #[test]
+    fn test_extend_hidden() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let keys: Vec<QueueKey> = (0..3)
+                .map(|i| item(base + Duration::seconds(i), "job").key())
+                .collect();
+            for i in 0..3 {
+                storage
+                    .put_item("queue", item(base + Duration::seconds(i), "job"))
+                    .unwrap();
+            }
+            let soon = Utc::now() + Duration::milliseconds(200);
+            storage.hide_item("queue", &keys[0], soon).unwrap();
+            storage
+                .hide_item("queue", &keys[1], Utc::now() - Duration::seconds(1))
+                .unwrap();
+            storage.remove_item("queue", &keys[2]).unwrap();
+
+            // Only the item whose hiding is still running is held
+            let later = Utc::now() + Duration::seconds(60);
+            assert_eq!(
+                storage.extend_hidden("queue", &keys, later).unwrap(),
+                [true, false, false]
+            );
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            assert_eq!(
+                storage.get_item("queue").unwrap().map(|item| item.key()),
+                Some(keys[1])
+            );
+        }
+    }
+
+    #[test]
+    fn test_shift_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let mut sharded = test_config(&path("sharded.sqlite"));
+        sharded.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 3,
+                ..Default::default()
+            },
+        );
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&test_config(&path("plain.sqlite"))).unwrap()),
+            Box::new(SqliteStorage::new(&sharded).unwrap()),
+            Box::new(SqliteStorage::new(&partitioned_config(&path("days.sqlite"))).unwrap()),
+            Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let by = Duration::days(1);
+
+        for storage in backends {
+            let mut items: Vec<QueueItem> = (0..4)
+                .map(|i| item(base + Duration::hours(i), &format!("job {i}")))
+                .collect();
+            items[1].datetime_secondary = Some(base);
+            items[2].tenant = Some("acme".to_string());
+            storage.put_items("queue", items.clone()).unwrap();
+            // A deleted item sits where the first one lands
+            let deleted = item(base + by, "deleted");
+            storage.put_item("queue", deleted.clone()).unwrap();
+            storage.remove_item("queue", &deleted.key()).unwrap();
+            let until = Utc::now() + Duration::hours(1);
+            storage.hide_item("queue", &items[3].key(), until).unwrap();
+
+            assert_eq!(storage.shift_items("queue", by, false).unwrap(), 4);
+            for item in &mut items {
+                item.datetime += by;
+            }
+            assert_eq!(storage.export_items("queue").unwrap(), items);
+            // The hidden item moved hidden
+            assert_eq!(storage.peek_items("queue", None, 10).unwrap(), items[..3]);
+
+            assert_eq!(storage.shift_items("queue", -by, true).unwrap(), 4);
+            for item in &mut items {
+                item.datetime -= by;
+                item.datetime_secondary = item.datetime_secondary.map(|d| d - by);
+            }
+            assert_eq!(storage.export_items("queue").unwrap(), items);
+
+            // A shift out of range moves nothing
+            assert!(matches!(
+                storage.shift_items("queue", Duration::days(365 * 300_000), false),
+                Err(StorageError::DatetimeOutOfRange(_))
+            ));
+            assert_eq!(storage.export_items("queue").unwrap(), items);
+        }
+    }
+
+    #[test]
+    fn test_existing_table_gains_hide_until() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE queue_queue (
+                datetime BIGINT NOT NULL,
+                datetime_secondary BIGINT NOT NULL DEFAULT -9223372036854775808,
+                message TEXT NOT NULL DEFAULT '',
+                valid INT2 NOT NULL DEFAULT 1,
+                last_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (datetime, datetime_secondary)
+            );
+            INSERT INTO queue_queue (datetime, message) VALUES (1700000000000, 'old');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let storage = SqliteStorage::new(&test_config(db_path.to_str().unwrap())).unwrap();
+        let head = storage.delete_item("queue").unwrap().unwrap();
+        assert_eq!(head.message, "old");
+    }
+
+    #[test]
+    fn test_soft_delete_keeps_last_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let old = DateTime::parse_from_rfc3339("2020-01-01T00:00:00Z").unwrap();
+        // A table from an earlier version, whose trigger fired on every update
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE queue_queue (
+                datetime BIGINT NOT NULL,
+                datetime_secondary BIGINT NOT NULL DEFAULT -9223372036854775808,
+                message TEXT NOT NULL DEFAULT '',
+                valid INT2 NOT NULL DEFAULT 1,
+                last_modified TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (datetime, datetime_secondary)
+            );
+            CREATE TRIGGER update_queue_queue_timestamp AFTER UPDATE ON queue_queue
+                BEGIN UPDATE queue_queue SET last_modified = CURRENT_TIMESTAMP WHERE datetime = NEW.datetime AND datetime_secondary = NEW.datetime_secondary; END;
+            INSERT INTO queue_queue (datetime, message, last_modified)
+                VALUES (1700000000000, 'first', '2020-01-01 00:00:00'),
+                       (1700000001000, 'second', '2020-01-01 00:00:00');",
+        )
+        .unwrap();
+        drop(conn);
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&test_config(db_path.to_str().unwrap())).unwrap()),
+            Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for (index, storage) in backends.into_iter().enumerate() {
+            if index == 1 {
+                storage.put_item("queue", item(base, "first")).unwrap();
+                storage
+                    .put_item("queue", item(base + Duration::seconds(1), "second"))
+                    .unwrap();
+            }
+            let written = storage.last_modified("queue").unwrap().unwrap();
+            if index == 0 {
+                assert_eq!(written, old);
+            }
+
+            let first = storage.delete_item("queue").unwrap().unwrap();
+            let second = storage.get_item("queue").unwrap().unwrap();
+            storage
+                .hide_item("queue", &second.key(), Utc::now() + Duration::hours(1))
+                .unwrap();
+            storage.remove_item("queue", &second.key()).unwrap();
+            assert_eq!(storage.last_modified("queue").unwrap(), Some(written));
+
+            // Putting the consumed item back changes the content again
+            storage
+                .put_item("queue", item(first.datetime, "first again"))
+                .unwrap();
+            assert!(storage.last_modified("queue").unwrap().unwrap() > written);
+        }
+    }
+
+    #[test]
+    fn test_read_only_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = test_config(db_path.to_str().unwrap());
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        SqliteStorage::new(&config)
+            .unwrap()
+            .put_item("queue", item(base, "kept"))
+            .unwrap();
+
+        let storage = SqliteStorage::new(&AppConfig {
+            read_only: true,
+            ..config
+        })
+        .unwrap();
+        assert_eq!(storage.get_item("queue").unwrap().unwrap().message, "kept");
+        assert!(storage.put_item("queue", item(base, "new")).is_err());
+        assert!(storage.delete_item("queue").is_err());
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    fn partitioned_config(database_path: &str) -> AppConfig {
+        let mut config = test_config(database_path);
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                partition_by_day: true,
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    // Lists the tables backing `queue`, sorted
+    fn queue_tables(db_path: &std::path::Path) -> Vec<String> {
+        let conn = Connection::open(db_path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'queue_queue%' ORDER BY name")
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_partitioned_queue_orders_across_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = partitioned_config(db_path.to_str().unwrap());
+        let storage = SqliteStorage::new(&config).unwrap();
+        let day = DateTime::parse_from_rfc3339("2024-03-10T23:59:59.999Z")
+            .unwrap()
+            .to_utc();
+
+        // Written out of order, across a month boundary and both sides of midnight
+        for (offset, message) in [
+            (Duration::days(21), "april"),
+            (Duration::milliseconds(1), "next day"),
+            (Duration::zero(), "late"),
+            (Duration::hours(-23), "early"),
+        ] {
+            storage
+                .put_item("queue", item(day + offset, message))
+                .unwrap();
+        }
+        storage
+            .put_items(
+                "queue",
+                vec![
+                    item(day + Duration::days(1), "batch"),
+                    item(day, "replaced"),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(
+            queue_tables(&db_path),
+            [
+                "queue_queue",
+                "queue_queue_20240310",
+                "queue_queue_20240311",
+                "queue_queue_20240331"
+            ]
+        );
+        let messages = |storage: &dyn Storage| -> Vec<String> {
+            storage
+                .peek_items("queue", None, 10)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.message)
+                .collect()
+        };
+        assert_eq!(
+            messages(&storage),
+            ["early", "replaced", "next day", "batch", "april"]
+        );
+        assert_eq!(storage.count_items("queue").unwrap(), 5);
+        let key = item(day + Duration::days(1), "").key();
+        assert_eq!(storage.rank_of_key("queue", &key).unwrap(), Some(3));
+        assert_eq!(
+            storage
+                .items_exist("queue", &[key, item(day + Duration::days(2), "").key()])
+                .unwrap(),
+            [true, false]
+        );
+
+        // Partitions are found again after a restart
+        drop(storage);
+        let storage = SqliteStorage::new(&config).unwrap();
+        assert_eq!(
+            messages(&storage),
+            ["early", "replaced", "next day", "batch", "april"]
+        );
+        assert_eq!(
+            storage.delete_item("queue").unwrap().unwrap().message,
+            "early"
+        );
+        assert_eq!(
+            storage.delete_item("queue").unwrap().unwrap().message,
+            "replaced"
+        );
+        assert_eq!(
+            storage.get_item("queue").unwrap().unwrap().message,
+            "next day"
+        );
+    }
+
+    #[test]
+    fn test_partition_retention_drops_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let now = Utc::now();
+
+        // Stored before the queue was partitioned, so it stays in the base table
+        SqliteStorage::new(&test_config(db_path.to_str().unwrap()))
+            .unwrap()
+            .put_item("queue", item(now - Duration::days(5), "legacy"))
+            .unwrap();
+        let storage = SqliteStorage::new(&partitioned_config(db_path.to_str().unwrap())).unwrap();
+        for days in [3, 2] {
+            for i in 0..3 {
+                let datetime = now - Duration::days(days) + Duration::milliseconds(i);
+                storage.put_item("queue", item(datetime, "old")).unwrap();
+            }
+        }
+        storage.put_item("queue", item(now, "current")).unwrap();
+        assert_eq!(queue_tables(&db_path).len(), 4);
+
+        // The cutoff's own day is kept and purged item by item
+        let cutoff = now - Duration::days(2) + Duration::milliseconds(1);
+        assert_eq!(storage.purge_before("queue", cutoff).unwrap(), 5);
+        let tables = queue_tables(&db_path);
+        assert_eq!(tables.len(), 3);
+        assert!(!tables.contains(&partition_table(
+            "queue_queue",
+            partition_day(now - Duration::days(3)).unwrap()
+        )));
+        let messages: Vec<String> = storage
+            .export_items("queue")
+            .unwrap()
+            .into_iter()
+            .map(|item| item.message)
+            .collect();
+        assert_eq!(messages, ["old", "old", "current"]);
+
+        // Writes to a dropped day recreate its partition
+        storage
+            .put_item("queue", item(now - Duration::days(3), "late arrival"))
+            .unwrap();
+        assert_eq!(
+            storage.get_item("queue").unwrap().unwrap().message,
+            "late arrival"
+        );
+        assert_eq!(queue_tables(&db_path).len(), 4);
+    }
+
+    #[test]
+    fn test_partitions_take_over_base_table_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let day = DateTime::<Utc>::from_timestamp_millis(1_767_268_800_000).unwrap();
+        let base_rows = || {
+            Connection::open(&db_path)
+                .unwrap()
+                .query_row(
+                    "SELECT COUNT(*) FROM queue_queue WHERE valid = 1",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+        };
+
+        // Stored before the queue was partitioned, so its day has no partition yet
+        let legacy = item(day, "legacy");
+        SqliteStorage::new(&test_config(db_path.to_str().unwrap()))
+            .unwrap()
+            .put_item("queue", legacy.clone())
+            .unwrap();
+        let storage = SqliteStorage::new(&partitioned_config(db_path.to_str().unwrap())).unwrap();
+        assert!(!storage.put_item("queue", item(day, "replaced")).unwrap());
+        assert_eq!(base_rows(), 0);
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+        assert_eq!(
+            storage.rank_of_key("queue", &legacy.key()).unwrap(),
+            Some(0)
+        );
+        assert!(storage.remove_item("queue", &legacy.key()).unwrap());
+        assert_eq!(storage.count_items("queue").unwrap(), 0);
+
+        // The next occurrence lands on a day with no partition, which it creates
+        let recurring = QueueItem {
+            recurrence_interval_secs: Some(86_400),
+            ..item(day + Duration::hours(1), "daily")
+        };
+        storage.put_item("queue", recurring.clone()).unwrap();
+        assert_eq!(
+            storage.delete_item("queue").unwrap(),
+            Some(recurring.clone())
+        );
+        let next = recurring.next_occurrence().unwrap();
+        assert!(queue_tables(&db_path).contains(&partition_table(
+            "queue_queue",
+            partition_day(next.datetime).unwrap()
+        )));
+        assert_eq!(base_rows(), 0);
+        let until = Utc::now() + Duration::seconds(60);
+        assert!(storage.hide_item("queue", &next.key(), until).unwrap());
+        assert_eq!(storage.get_item("queue").unwrap(), None);
+    }
+
+    #[test]
+    fn test_recurrence_creates_partition_on_a_single_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = partitioned_config(db_path.to_str().unwrap());
+        config.db_pool_size = 1;
+        let storage = SqliteStorage::new(&config).unwrap();
+        let day = DateTime::<Utc>::from_timestamp_millis(1_767_268_800_000).unwrap();
+        let recurring = QueueItem {
+            recurrence_interval_secs: Some(86_400),
+            ..item(day, "daily")
+        };
+        storage.put_item("queue", recurring.clone()).unwrap();
+
+        // Registering the new partition must not wait for a second connection
+        let started = std::time::Instant::now();
+        assert_eq!(
+            storage.delete_item("queue").unwrap(),
+            Some(recurring.clone())
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+        assert_eq!(
+            storage.get_item("queue").unwrap(),
+            recurring.next_occurrence()
+        );
+    }
+
+    #[test]
+    fn test_partitioned_queue_keeps_datetimes_beyond_four_digit_years() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = partitioned_config(db_path.to_str().unwrap());
+        let edges: Vec<QueueItem> = ["-0001-12-31T00:00:00Z", "+10000-01-01T00:00:00Z"]
+            .iter()
+            .map(|datetime| item(datetime.parse().unwrap(), datetime))
+            .collect();
+        let storage = SqliteStorage::new(&config).unwrap();
+        for edge in &edges {
+            storage.put_item("queue", edge.clone()).unwrap();
+        }
+        // They stay in the base table, so they are still read after a restart
+        assert_eq!(queue_tables(&db_path), ["queue_queue"]);
+        let storage = SqliteStorage::new(&config).unwrap();
+        assert_eq!(storage.export_items("queue").unwrap(), edges);
+        assert!(storage.remove_item("queue", &edges[1].key()).unwrap());
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_database_key_requires_sqlcipher() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = AppConfig {
+            database_key: Some("secret".to_string()),
+            ..test_config(db_path.to_str().unwrap())
+        };
+        assert!(matches!(
+            SqliteStorage::new(&config),
+            Err(StorageError::EncryptionUnavailable)
+        ));
+        // Nothing was written unencrypted
+        assert!(!db_path.exists());
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypted_database_needs_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let path = db_path.to_str().unwrap();
+        let keyed = |key: Option<&str>| AppConfig {
+            database_key: key.map(str::to_string),
+            ..test_config(path)
+        };
+        let storage = SqliteStorage::new(&keyed(Some("secret"))).unwrap();
+        storage
+            .put_item("queue", item(Utc::now(), "hidden"))
+            .unwrap();
+        drop(storage);
+
+        let contents = std::fs::read(&db_path).unwrap();
+        assert!(!contents.windows(6).any(|window| window == b"hidden"));
+        assert!(SqliteStorage::new(&keyed(None)).is_err());
+        assert!(matches!(
+            SqliteStorage::new(&keyed(Some("wrong"))),
+            Err(StorageError::Database(_))
+        ));
+        let storage = SqliteStorage::new(&keyed(Some("secret"))).unwrap();
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sqlite_tuning_pragmas() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = AppConfig {
+            sqlite_temp_store: Some(TempStore::Memory),
+            sqlite_journal_size_limit: Some(1 << 20),
+            db_pool_size: 2,
+            ..test_config(db_path.to_str().unwrap())
+        };
+        let storage = SqliteStorage::new(&config).unwrap();
+        // Every pooled connection gets the settings, not only the first
+        let connections = [storage.pool.get().unwrap(), storage.pool.get().unwrap()];
+        for conn in &connections {
+            let pragma = |name: &str| -> i64 {
+                conn.query_row(&format!("PRAGMA {name}"), [], |row| row.get(0))
+                    .unwrap()
+            };
+            assert_eq!(pragma("temp_store"), 2);
+            assert_eq!(pragma("journal_size_limit"), 1 << 20);
+        }
+    }
+
+    #[test]
+    fn test_sqlite_journal_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        for (mode, expected) in [(JournalMode::Wal, "wal"), (JournalMode::Delete, "delete")] {
+            let db_path = dir.path().join(format!("{expected}.sqlite"));
+            let config = AppConfig {
+                sqlite_journal_mode: mode,
+                db_pool_size: 2,
+                ..test_config(db_path.to_str().unwrap())
+            };
+            let storage = SqliteStorage::new(&config).unwrap();
+            let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+            storage.put_item("queue", item(base, "journaled")).unwrap();
+            let connections = [storage.pool.get().unwrap(), storage.pool.get().unwrap()];
+            for conn in &connections {
+                let journal_mode: String = conn
+                    .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(journal_mode, expected);
+            }
+            let wal_path = dir.path().join(format!("{expected}.sqlite-wal"));
+            assert_eq!(wal_path.exists(), mode == JournalMode::Wal);
+        }
+    }
+
+    #[test]
+    fn test_configured_queue_names_are_normalized() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = |database_path: &str| AppConfig {
+            queues: vec!["MyQueue".to_string()],
+            database_path: database_path.to_string(),
+            ..Default::default()
+        };
+        let sqlite = SqliteStorage::new(&config(db_path.to_str().unwrap())).unwrap();
+        let memory = InMemoryStorage::new(&config(":memory:"));
+        let backends: Vec<Box<dyn Storage>> = vec![Box::new(sqlite), Box::new(memory)];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            assert!(storage.queue_exists("myqueue"));
+            assert_eq!(storage.queue_names(), ["myqueue"]);
+            storage.put_item("myqueue", item(base, "stored")).unwrap();
+            assert_eq!(storage.count_items("myqueue").unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_put_items_batch() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            storage.put_item("queue", item(base, "replaced")).unwrap();
+            let batch = (0..3)
+                .map(|i| item(base + Duration::seconds(i), &format!("{i}")))
+                .collect();
+            storage.put_items("queue", batch).unwrap();
+
+            let messages: Vec<String> = storage
+                .export_items("queue")
+                .unwrap()
+                .into_iter()
+                .map(|item| item.message)
+                .collect();
+            assert_eq!(messages, ["0", "1", "2"]);
+            assert!(matches!(
+                storage.put_items("missing", vec![item(base, "")]),
+                Err(StorageError::QueueNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_remove_item_by_key() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let first = item(base, "first");
+            let second = item(base + Duration::seconds(1), "second");
+            storage.put_item("queue", first.clone()).unwrap();
+            storage.put_item("queue", second.clone()).unwrap();
+
+            assert!(storage.remove_item("queue", &second.key()).unwrap());
+            assert!(!storage.remove_item("queue", &second.key()).unwrap());
+            assert_eq!(storage.export_items("queue").unwrap(), vec![first]);
+        }
+    }
+
+    #[test]
+    fn test_compare_and_delete() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let first = item(base, "first");
+            let second = item(base + Duration::seconds(1), "second");
+            assert_eq!(
+                storage.compare_and_delete("queue", &first.key()).unwrap(),
+                CasResult::Mismatch(None)
+            );
+            storage.put_item("queue", first.clone()).unwrap();
+            storage.put_item("queue", second.clone()).unwrap();
+
+            assert_eq!(
+                storage.compare_and_delete("queue", &second.key()).unwrap(),
+                CasResult::Mismatch(Some(first.clone()))
+            );
+            assert_eq!(storage.count_items("queue").unwrap(), 2);
+            assert_eq!(
+                storage.compare_and_delete("queue", &first.key()).unwrap(),
+                CasResult::Deleted(first)
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), Some(second));
+        }
+    }
+
+    #[test]
+    fn test_corrupt_secondary_datetime() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let storage = SqliteStorage::new(&test_config(db_path.to_str().unwrap())).unwrap();
+        // Written past the API: not the no-secondary sentinel, yet not a datetime either
+        storage
+            .pool
+            .get()
+            .unwrap()
+            .execute(
+                "INSERT INTO queue_queue (datetime, datetime_secondary, message)
+                 VALUES (1700000000000, 9000000000000000000, 'corrupt')",
+                [],
+            )
+            .unwrap();
+
+        assert!(matches!(
+            storage.get_item("queue"),
+            Err(StorageError::CorruptData(message)) if message.contains("datetime_secondary")
+        ));
+        assert!(matches!(
+            storage.delete_item("queue"),
+            Err(StorageError::CorruptData(_))
+        ));
+        // The failed delete was rolled back
+        assert_eq!(storage.count_items("queue").unwrap(), 1);
+
+        // The sentinel and in-range values still decode
+        let base = DateTime::<Utc>::from_timestamp_millis(1_600_000_000_000).unwrap();
+        let mut with_secondary = item(base + Duration::seconds(1), "secondary");
+        with_secondary.datetime_secondary = Some(base);
+        storage.put_item("queue", item(base, "none")).unwrap();
+        storage.put_item("queue", with_secondary.clone()).unwrap();
+        assert_eq!(
+            storage.delete_item("queue").unwrap(),
+            Some(item(base, "none"))
+        );
+        assert_eq!(storage.delete_item("queue").unwrap(), Some(with_secondary));
+    }
+
+    #[test]
+    fn test_replace_if_message() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            // A missing key never matches
+            assert!(
+                !storage
+                    .replace_if_message("queue", item(base, "v2"), "v1")
+                    .unwrap()
+            );
+            assert_eq!(storage.count_items("queue").unwrap(), 0);
+
+            storage.put_item("queue", item(base, "v1")).unwrap();
+            assert!(
+                !storage
+                    .replace_if_message("queue", item(base, "v2"), "v0")
+                    .unwrap()
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), Some(item(base, "v1")));
+            assert!(
+                storage
+                    .replace_if_message("queue", item(base, "v2"), "v1")
+                    .unwrap()
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), Some(item(base, "v2")));
+            assert_eq!(storage.count_items("queue").unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_fence_tokens() {
+        let (dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            assert!(
+                storage
+                    .put_item_fenced("queue", item(base, "first"), 5)
+                    .unwrap()
+            );
+            // An equal token is the same writer, a higher one its successor
+            storage
+                .put_item_fenced("queue", item(base, "again"), 5)
+                .unwrap();
+            storage
+                .put_item_fenced("queue", item(base, "successor"), 7)
+                .unwrap();
+            assert!(matches!(
+                storage.put_item_fenced("queue", item(base + Duration::seconds(1), "stale"), 6),
+                Err(StorageError::StaleFenceToken {
+                    token: 6,
+                    highest: 7
+                })
+            ));
+            assert_eq!(storage.count_items("queue").unwrap(), 1);
+            assert_eq!(
+                storage.get_item("queue").unwrap().unwrap().message,
+                "successor"
+            );
+            // Unfenced puts neither check nor move the token
+            storage
+                .put_item("queue", item(base + Duration::seconds(2), "unfenced"))
+                .unwrap();
+            assert_eq!(storage.count_items("queue").unwrap(), 2);
+        }
+
+        // SQLite remembers the highest token across restarts
+        let db_path = dir.path().join("queue.sqlite");
+        let reopened = SqliteStorage::new(&test_config(db_path.to_str().unwrap())).unwrap();
+        assert!(matches!(
+            reopened.put_item_fenced("queue", item(base, "stale"), 6),
+            Err(StorageError::StaleFenceToken { highest: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_increments() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let key = item(base, "").key();
+
+        for storage in backends {
+            std::thread::scope(|scope| {
+                for by in 1..=8 {
+                    let storage = &storage;
+                    scope.spawn(move || {
+                        for _ in 0..25 {
+                            storage.increment_item("queue", &key, by).unwrap();
+                        }
+                    });
+                }
+            });
+            // 25 increments of each of 1..=8
+            assert_eq!(storage.get_item("queue").unwrap().unwrap().message, "900");
+            assert_eq!(storage.increment_item("queue", &key, -900).unwrap(), 0);
+
+            storage.put_item("queue", item(base, "text")).unwrap();
+            assert!(matches!(
+                storage.increment_item("queue", &key, 1),
+                Err(StorageError::NotAnInteger(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_recurring_items() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let recurring = QueueItem {
+            id: Some("report".to_string()),
+            recurrence_interval_secs: Some(3600),
+            // Inclusive: the occurrence at exactly this datetime is still reinserted
+            recurrence_until: Some(base + Duration::hours(2)),
+            ..item(base, "daily report")
+        };
+
+        for storage in backends {
+            storage.put_item("queue", recurring.clone()).unwrap();
+            storage
+                .put_item("queue", item(base + Duration::minutes(90), "other"))
+                .unwrap();
+            let mut consumed = Vec::new();
+            while let Some(item) = storage.delete_item("queue").unwrap() {
+                consumed.push((item.datetime - base, item.message));
+            }
+            assert_eq!(
+                consumed,
+                [
+                    (Duration::zero(), "daily report".to_string()),
+                    (Duration::hours(1), "daily report".to_string()),
+                    (Duration::minutes(90), "other".to_string()),
+                    (Duration::hours(2), "daily report".to_string()),
+                ]
+            );
+
+            // Claims recur too, and the occurrence keeps everything but its datetime
+            storage.put_item("queue", recurring.clone()).unwrap();
+            let (_, claimed) = storage
+                .claim_any(&["queue".to_string()], Utc::now(), true, 0)
+                .unwrap()
+                .unwrap();
+            assert_eq!(claimed, recurring);
+            let next = storage.get_item("queue").unwrap().unwrap();
+            assert_eq!(
+                next,
+                QueueItem {
+                    datetime: base + Duration::hours(1),
+                    ..recurring.clone()
+                }
+            );
+            // Removing an item by key is not consuming it
+            assert!(storage.remove_item("queue", &next.key()).unwrap());
+            assert_eq!(storage.get_item("queue").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_transact_is_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = |database_path: &str| AppConfig {
+            queues: vec!["queue".to_string(), "done".to_string()],
+            database_path: database_path.to_string(),
+            ..Default::default()
+        };
+        let sqlite = SqliteStorage::new(&config(db_path.to_str().unwrap())).unwrap();
+        let memory = InMemoryStorage::new(&config(":memory:"));
+        let backends: Vec<Box<dyn Storage>> = vec![Box::new(sqlite), Box::new(memory)];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let job = item(base, "job");
+        let pop = TxOp::Pop {
+            queue: "queue".to_string(),
+            due_only: true,
+        };
+        let put = TxOp::Put {
+            queue: "done".to_string(),
+            item: item(base, "job done"),
+        };
+
+        for storage in backends {
+            storage.put_item("queue", job.clone()).unwrap();
+            // The third operation fails, so the pop and the put are rolled back
+            let missing = TxOp::Delete {
+                queue: "queue".to_string(),
+                key: item(base + Duration::seconds(1), "").key(),
+            };
+            let aborted = storage.transact(&[pop.clone(), put.clone(), missing]);
+            assert!(matches!(
+                aborted,
+                Err(StorageError::TransactionAborted { index: 2, .. })
+            ));
+            assert_eq!(storage.export_items("queue").unwrap(), vec![job.clone()]);
+            assert_eq!(storage.count_items("done").unwrap(), 0);
+
+            let results = storage.transact(&[pop.clone(), put.clone()]).unwrap();
+            assert_eq!(results, [Some(job.clone()), None]);
+            assert_eq!(storage.count_items("queue").unwrap(), 0);
+            assert_eq!(
+                storage.export_items("done").unwrap(),
+                [item(base, "job done")]
+            );
+
+            // Popping an empty queue fails the transaction too
+            let empty = storage.transact(&[put.clone(), pop.clone()]);
+            assert!(matches!(
+                empty,
+                Err(StorageError::TransactionAborted { index: 1, .. })
+            ));
+            assert_eq!(storage.count_items("done").unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_deletes_consume_once() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let seeded: Vec<QueueItem> = (0..200)
+            .map(|i| item(base + Duration::seconds(i), &format!("job {i}")))
+            .collect();
+
+        for storage in backends {
+            storage.put_items("queue", seeded.clone()).unwrap();
+            let mut consumed: Vec<QueueItem> = std::thread::scope(|scope| {
+                let consumers: Vec<_> = (0..2)
+                    .map(|_| {
+                        let storage = &storage;
+                        scope.spawn(move || {
+                            let mut consumed = Vec::new();
+                            while let Some(item) = storage.delete_item("queue").unwrap() {
+                                consumed.push(item);
+                            }
+                            consumed
+                        })
+                    })
+                    .collect();
+                consumers
+                    .into_iter()
+                    .flat_map(|consumer| consumer.join().unwrap())
+                    .collect()
+            });
+            // Every item was handed to exactly one consumer
+            consumed.sort_by_key(QueueItem::key);
+            assert_eq!(consumed, seeded);
+            assert_eq!(storage.count_items("queue").unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_tail_item_is_latest() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 3,
+                ..Default::default()
+            },
+        );
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            assert_eq!(storage.tail_item("queue").unwrap(), None);
+            for (offset, message) in [(30, "middle"), (365, "latest"), (-5, "past"), (1, "soon")] {
+                storage
+                    .put_item("queue", item(base + Duration::days(offset), message))
+                    .unwrap();
+            }
+            let mut tied = item(base + Duration::days(365), "latest secondary");
+            tied.datetime_secondary = Some(base);
+            storage.put_item("queue", tied).unwrap();
+
+            assert_eq!(
+                storage.tail_item("queue").unwrap().unwrap().message,
+                "latest secondary"
+            );
+            // Hidden items keep their place at the end
+            let tail = storage.tail_item("queue").unwrap().unwrap();
+            storage
+                .hide_item("queue", &tail.key(), Utc::now() + Duration::hours(1))
+                .unwrap();
+            assert_eq!(storage.tail_item("queue").unwrap(), Some(tail.clone()));
+            storage.remove_item("queue", &tail.key()).unwrap();
+            assert_eq!(
+                storage.tail_item("queue").unwrap().unwrap().message,
+                "latest"
+            );
+            assert_eq!(storage.get_item("queue").unwrap().unwrap().message, "past");
+        }
+    }
+
+    #[test]
+    fn test_key_by_id_replaces_across_datetimes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queues.push("plain".to_string());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 3,
+                key_by: KeyBy::Id,
+                ..Default::default()
+            },
+        );
+        let mut memory_config = config.clone();
+        memory_config.database_path = ":memory:".to_string();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&memory_config)),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let event = |seconds: i64, id: &str, message: &str| QueueItem {
+            id: Some(id.to_string()),
+            ..item(base + Duration::seconds(seconds), message)
+        };
+        let contents = |storage: &dyn Storage, queue: &str| -> Vec<(i64, String, String)> {
+            storage
+                .export_items(queue)
+                .unwrap()
+                .into_iter()
+                .map(|item| {
+                    let seconds = (item.datetime - base).num_seconds();
+                    (seconds, item.id.unwrap(), item.message)
+                })
+                .collect()
+        };
+        let expected = |entries: &[(i64, &str, &str)]| -> Vec<(i64, String, String)> {
+            entries
+                .iter()
+                .map(|(seconds, id, message)| (*seconds, id.to_string(), message.to_string()))
+                .collect()
+        };
+
+        for storage in backends {
+            assert!(storage.put_item("queue", event(10, "e1", "v1")).unwrap());
+            assert!(storage.put_item("queue", event(5, "e2", "v1")).unwrap());
+            // Same id, new datetime: the old item is replaced and the new one is ordered
+            // by its own datetime
+            assert!(!storage.put_item("queue", event(1, "e1", "v2")).unwrap());
+            assert_eq!(
+                contents(storage.as_ref(), "queue"),
+                expected(&[(1, "e1", "v2"), (5, "e2", "v1")])
+            );
+
+            storage
+                .put_items("queue", vec![event(20, "e2", "v2"), event(0, "e3", "v1")])
+                .unwrap();
+            assert_eq!(
+                contents(storage.as_ref(), "queue"),
+                expected(&[(0, "e3", "v1"), (1, "e1", "v2"), (20, "e2", "v2")])
+            );
+
+            // Ids travel with their messages
+            let (first, second) = (event(0, "", "").key(), event(1, "", "").key());
+            storage.swap_items("queue", &first, &second).unwrap();
+            let head = storage.delete_item("queue").unwrap().unwrap();
+            assert_eq!(
+                (head.id.as_deref(), head.message.as_str()),
+                (Some("e1"), "v2")
+            );
+            assert_eq!(storage.count_items("queue").unwrap(), 2);
+
+            // Queues keyed by datetime keep items with the same id apart
+            storage.put_item("plain", event(0, "e1", "a")).unwrap();
+            storage.put_item("plain", event(1, "e1", "b")).unwrap();
+            assert_eq!(storage.count_items("plain").unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_payloads_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let config = test_config(db_path.to_str().unwrap());
+        let mut memory_config = config.clone();
+        memory_config.database_path = ":memory:".to_string();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&memory_config)),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let payloads = BTreeMap::from([
+            ("json".to_string(), r#"{"n":1}"#.to_string()),
+            ("text".to_string(), "n is 1".to_string()),
+        ]);
+        let with_payloads = QueueItem {
+            payloads: Some(payloads.clone()),
+            ..item(base, "1")
+        };
+
+        for storage in backends {
+            storage.put_item("queue", with_payloads.clone()).unwrap();
+            storage
+                .put_item("queue", item(base + Duration::seconds(1), "plain"))
+                .unwrap();
+            let stored = storage.export_items("queue").unwrap();
+            assert_eq!(stored[0], with_payloads);
+            assert_eq!(stored[1].payloads, None);
+
+            // Payloads move with their message and survive an increment
+            let (first, second) = (with_payloads.key(), stored[1].key());
+            storage.swap_items("queue", &first, &second).unwrap();
+            storage.swap_items("queue", &first, &second).unwrap();
+            storage.increment_item("queue", &first, 1).unwrap();
+            let head = storage.delete_item("queue").unwrap().unwrap();
+            assert_eq!(head.message, "2");
+            assert_eq!(head.payloads.as_ref(), Some(&payloads));
+        }
+    }
+
+    #[test]
+    fn test_newest_item() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 2,
+                ..Default::default()
+            },
+        );
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            let items: Vec<QueueItem> = (0..4)
+                .map(|i| item(base + Duration::seconds(i), &format!("job {i}")))
+                .collect();
+            storage.put_items("queue", items.clone()).unwrap();
+            // The newest item is hidden, so the one before it is served
+            storage
+                .hide_item("queue", &items[3].key(), Utc::now() + Duration::hours(1))
+                .unwrap();
+
+            assert_eq!(
+                storage.get_newest_item("queue").unwrap(),
+                Some(items[2].clone())
+            );
+            assert_eq!(
+                storage.delete_newest_item("queue").unwrap(),
+                Some(items[2].clone())
+            );
+            assert_eq!(
+                storage.delete_item("queue").unwrap(),
+                Some(items[0].clone())
+            );
+            assert_eq!(
+                storage.delete_newest_item("queue").unwrap(),
+                Some(items[1].clone())
+            );
+            assert_eq!(storage.delete_newest_item("queue").unwrap(), None);
+            assert_eq!(storage.tail_item("queue").unwrap(), Some(items[3].clone()));
+        }
+    }
+
+    #[test]
+    fn test_tenant_heads() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 2,
+                ..Default::default()
+            },
+        );
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let tagged = |seconds: i64, tenant: Option<&str>| QueueItem {
+            tenant: tenant.map(str::to_string),
+            ..item(
+                base + Duration::seconds(seconds),
+                &format!("{tenant:?} {seconds}"),
+            )
+        };
+
+        for storage in backends {
+            let items = [
+                tagged(0, None),
+                tagged(1, Some("acme")),
+                tagged(2, Some("globex")),
+                tagged(3, Some("acme")),
+                tagged(4, Some("globex")),
+            ];
+            storage.put_items("queue", items.to_vec()).unwrap();
+            // A hidden item of the tenant is passed over like any hidden item
+            storage
+                .hide_item("queue", &items[1].key(), Utc::now() + Duration::hours(1))
+                .unwrap();
+
+            assert_eq!(
+                storage.get_tenant_item("queue", "acme", None).unwrap(),
+                Some(items[3].clone())
+            );
+            // A bound passes over the tenant's items after it, inclusive to the millisecond
+            let due_by = |millis: i64| Some(base + Duration::milliseconds(millis));
+            for before in [due_by(2000), due_by(2999)] {
+                assert_eq!(
+                    storage.get_tenant_item("queue", "acme", before).unwrap(),
+                    None
+                );
+                assert_eq!(
+                    storage.delete_tenant_item("queue", "acme", before).unwrap(),
+                    None
+                );
+            }
+            assert_eq!(
+                storage
+                    .get_tenant_item("queue", "acme", due_by(3000))
+                    .unwrap(),
+                Some(items[3].clone())
+            );
+            assert_eq!(
+                storage
+                    .delete_tenant_item("queue", "globex", due_by(2000))
+                    .unwrap(),
+                Some(items[2].clone())
+            );
+            assert_eq!(
+                storage.delete_tenant_item("queue", "globex", None).unwrap(),
+                Some(items[4].clone())
+            );
+            assert_eq!(
+                storage.delete_tenant_item("queue", "globex", None).unwrap(),
+                None
+            );
+            assert_eq!(
+                storage.get_tenant_item("queue", "initech", None).unwrap(),
+                None
+            );
+            // The untagged head and the other tenant's items are untouched
+            assert_eq!(storage.get_item("queue").unwrap(), Some(items[0].clone()));
+            assert_eq!(
+                storage.delete_tenant_item("queue", "acme", None).unwrap(),
+                Some(items[3].clone())
+            );
+            assert_eq!(storage.count_items("queue").unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_count_by_validity() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 2,
+                ..Default::default()
+            },
+        );
+        let backends: Vec<(Box<dyn Storage>, bool)> = vec![
+            (Box::new(SqliteStorage::new(&config).unwrap()), true),
+            (
+                Box::new(InMemoryStorage::new(&test_config(":memory:"))),
+                false,
+            ),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for (storage, keeps_deleted) in backends {
+            for i in 0..4 {
+                storage
+                    .put_item("queue", item(base + Duration::seconds(i), "job"))
+                    .unwrap();
+            }
+            let mut invalid = 0;
+            for popped in 1..=3 {
+                storage.delete_item("queue").unwrap();
+                if keeps_deleted {
+                    invalid = popped;
+                }
+                let counts = storage.count_by_validity("queue").unwrap();
+                assert_eq!(
+                    counts,
+                    ValidityCounts {
+                        valid: 4 - popped,
+                        invalid
+                    }
+                );
+            }
+
+            storage.compact("queue", 100).unwrap();
+            assert_eq!(
+                storage.count_by_validity("queue").unwrap(),
+                ValidityCounts {
+                    valid: 1,
+                    invalid: 0
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_heads_is_consistent() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let queues = ["a", "b"].map(String::from);
+
+        for storage in backends {
+            for queue in &queues {
+                storage.create_queue(queue).unwrap();
+                storage.put_item(queue, item(base, "0")).unwrap();
+            }
+            let done = std::sync::atomic::AtomicBool::new(false);
+            std::thread::scope(|scope| {
+                let storage = &storage;
+                let done = &done;
+                // `a` is always written first, so at any instant it is equal to `b` or
+                // one ahead; reading the queues at different instants can see `b` ahead
+                scope.spawn(move || {
+                    for n in 1..=2000 {
+                        for queue in ["a", "b"] {
+                            storage.put_item(queue, item(base, &n.to_string())).unwrap();
+                        }
+                    }
+                    done.store(true, std::sync::atomic::Ordering::Relaxed);
+                });
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let heads = storage.snapshot_heads(&queues).unwrap();
+                    let [a, b] = [&heads[0], &heads[1]]
+                        .map(|head| head.as_ref().unwrap().message.parse::<i64>().unwrap());
+                    assert!(a == b || a == b + 1, "a = {a}, b = {b}");
+                }
+            });
+            let heads = storage.snapshot_heads(&queues).unwrap();
+            assert!(
+                heads
+                    .iter()
+                    .all(|head| head.as_ref().unwrap().message == "2000")
+            );
+            assert!(matches!(
+                storage.snapshot_heads(&["a".to_string(), "missing".to_string()]),
+                Err(StorageError::QueueNotFound(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_claim_any_due_boundary_is_inclusive() {
+        let (_dir, backends) = backends();
+        let now = DateTime::<Utc>::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+        let queues = ["queue".to_string()];
+
+        for storage in backends {
+            storage
+                .put_item("queue", item(now + Duration::milliseconds(1), "later"))
+                .unwrap();
+            storage.put_item("queue", item(now, "at now")).unwrap();
+            let (_, claimed) = storage.claim_any(&queues, now, true, 0).unwrap().unwrap();
+            assert_eq!(claimed.message, "at now");
+            // A clock reading within the same millisecond sees the same due items
+            let early = now + Duration::microseconds(999);
+            assert_eq!(storage.claim_any(&queues, early, true, 0).unwrap(), None);
+            assert!(
+                storage
+                    .claim_any(&queues, early + Duration::microseconds(1), true, 0)
+                    .unwrap()
+                    .is_some()
+            );
+        }
+    }
+
+    #[test]
+    fn test_claim_any_shares_ties() {
+        let (_dir, backends) = backends();
+        let now = Utc::now();
+        let base = now - Duration::hours(1);
+        let queues = ["a", "b", "c"].map(String::from);
+
+        for storage in backends {
+            for queue in &queues {
+                storage.create_queue(queue).unwrap();
+            }
+            // Queue a receives an item every second, b every other second, c just one
+            for i in 0..6 {
+                storage
+                    .put_item("a", item(base + Duration::seconds(i), "a"))
+                    .unwrap();
+            }
+            for i in [0, 2, 4] {
+                storage
+                    .put_item("b", item(base + Duration::seconds(i), "b"))
+                    .unwrap();
+            }
+            storage.put_item("c", item(base, "c")).unwrap();
+            storage
+                .put_item("c", item(now + Duration::hours(1), "not due"))
+                .unwrap();
+
+            let mut claimed = Vec::new();
+            for start in 0.. {
+                match storage.claim_any(&queues, now, true, start).unwrap() {
+                    Some((queue, item)) => {
+                        assert_eq!(item.message, queue);
+                        claimed.push((queue, item.datetime));
+                    }
+                    None => break,
+                }
+            }
+            // Claims follow datetime order, and tied heads go round-robin
+            let order: Vec<&str> = claimed.iter().map(|(queue, _)| queue.as_str()).collect();
+            assert_eq!(order, ["a", "b", "c", "a", "b", "a", "a", "b", "a", "a"]);
+            assert!(claimed.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            assert_eq!(storage.count_items("c").unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn test_items_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path().join("queue.sqlite").to_str().unwrap());
+        let options = QueueOptions {
+            shards: 3,
+            ..Default::default()
+        };
+        config.queue_options.insert("queue".to_string(), options);
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&config).unwrap()),
+            Box::new(InMemoryStorage::new(&config)),
+        ];
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let items: Vec<QueueItem> = (0..6)
+            .map(|i| item(base + Duration::seconds(i), "pending"))
+            .collect();
+
+        for storage in backends {
+            storage.put_items("queue", items[..4].to_vec()).unwrap();
+            assert!(storage.remove_item("queue", &items[1].key()).unwrap());
+            storage
+                .hide_item("queue", &items[3].key(), Utc::now() + Duration::hours(1))
+                .unwrap();
+
+            let keys: Vec<QueueKey> = [5, 0, 1, 3, 2, 4].map(|i| items[i].key()).to_vec();
+            assert_eq!(
+                storage.items_exist("queue", &keys).unwrap(),
+                [false, true, false, true, true, false]
+            );
+            assert!(storage.items_exist("queue", &[]).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_put_consumed_items() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let items: Vec<QueueItem> = (0..4)
+            .map(|i| item(base + Duration::seconds(i), &format!("{i}")))
+            .collect();
+
+        for storage in backends {
+            storage.put_items("queue", items[..2].to_vec()).unwrap();
+            // One consumed item replaces a pending one, the others are new
+            storage
+                .put_consumed_items("queue", vec![items[1].clone(), items[3].clone()])
+                .unwrap();
+            storage.put_items("queue", vec![items[2].clone()]).unwrap();
+
+            assert_eq!(
+                storage.export_items("queue").unwrap(),
+                [items[0].clone(), items[2].clone()]
+            );
+            assert_eq!(
+                storage.items_exist("queue", &[items[3].key()]).unwrap(),
+                [false]
+            );
+        }
+
+        // SQLite keeps the consumed items as tombstones
+        let dir = tempfile::tempdir().unwrap();
+        let storage =
+            SqliteStorage::new(&test_config(dir.path().join("q.sqlite").to_str().unwrap()))
+                .unwrap();
+        storage.put_consumed_items("queue", items.clone()).unwrap();
+        let conn = storage.pool.get().unwrap();
+        let tombstones: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM queue_queue WHERE valid = 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tombstones, 4);
+        assert_eq!(storage.count_items("queue").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rank_of_key() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let keys: Vec<QueueKey> = (0..5)
+            .map(|i| item(base + Duration::seconds(i), "").key())
+            .collect();
+
+        for storage in backends {
+            for key in keys.iter().rev() {
+                storage
+                    .put_item("queue", item(key.datetime, "queued"))
+                    .unwrap();
+            }
+            assert_eq!(storage.rank_of_key("queue", &keys[0]).unwrap(), Some(0));
+            assert_eq!(storage.rank_of_key("queue", &keys[3]).unwrap(), Some(3));
+
+            storage.delete_item("queue").unwrap();
+            assert_eq!(storage.rank_of_key("queue", &keys[0]).unwrap(), None);
+            assert_eq!(storage.rank_of_key("queue", &keys[3]).unwrap(), Some(2));
+            let absent = item(base - Duration::seconds(1), "").key();
+            assert_eq!(storage.rank_of_key("queue", &absent).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_put_item_reports_creation() {
+        let (_dir, backends) = backends();
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            assert!(storage.put_item("queue", item(base, "created")).unwrap());
+            assert!(!storage.put_item("queue", item(base, "replaced")).unwrap());
+            storage.delete_item("queue").unwrap();
+            // A deleted key is created anew
+            assert!(storage.put_item("queue", item(base, "revived")).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_sharded_queue_distributes_and_merges() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 4,
+                ..Default::default()
+            },
+        );
+        let storage = SqliteStorage::new(&config).unwrap();
+
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        // Insert in descending order so the merged head must come from the reads
+        for i in (0..40).rev() {
+            storage
+                .put_item("queue", item(base + Duration::seconds(i), &format!("{i}")))
+                .unwrap();
+        }
+
+        let conn = storage.pool.get().unwrap();
+        for shard in 0..4 {
+            let count: i64 = conn
+                .query_row(
+                    &format!("SELECT COUNT(*) FROM queue_queue_{shard}"),
+                    [],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert!(count > 0, "shard {shard} received no items");
+        }
+        drop(conn);
+        assert_eq!(storage.count_items("queue").unwrap(), 40);
+        let key = item(base + Duration::seconds(25), "").key();
+        assert_eq!(storage.rank_of_key("queue", &key).unwrap(), Some(25));
+
+        for i in 0..40 {
+            let head = storage.get_item("queue").unwrap().unwrap();
+            assert_eq!(head.message, format!("{i}"));
+            let deleted = storage.delete_item("queue").unwrap().unwrap();
+            assert_eq!(deleted, head);
+        }
+        assert_eq!(storage.get_item("queue").unwrap(), None);
+        assert_eq!(storage.delete_item("queue").unwrap(), None);
+    }
+
+    #[test]
+    fn test_sharded_queue_replace_and_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = test_config(db_path.to_str().unwrap());
+        config.queue_options.insert(
+            "queue".to_string(),
+            QueueOptions {
+                shards: 3,
+                ..Default::default()
+            },
+        );
+        let storage = SqliteStorage::new(&config).unwrap();
+
+        let base = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+        let first = item(base, "first");
+        let second = item(base + Duration::seconds(1), "second");
+        storage.put_item("queue", first.clone()).unwrap();
+        storage.put_item("queue", second.clone()).unwrap();
+        storage.put_item("queue", item(base, "replaced")).unwrap();
+
+        assert!(
+            storage
+                .swap_items("queue", &first.key(), &second.key())
+                .unwrap()
+        );
+        assert_eq!(
+            storage.delete_item("queue").unwrap().unwrap().message,
+            "second"
+        );
+        assert_eq!(
+            storage.delete_item("queue").unwrap().unwrap().message,
+            "replaced"
+        );
+        assert_eq!(storage.delete_item("queue").unwrap(), None);
+    }
+
+    #[test]
+    fn test_create_queue_respects_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut sqlite_config = test_config(db_path.to_str().unwrap());
+        sqlite_config.max_queues = Some(2);
+        let mut memory_config = test_config(":memory:");
+        memory_config.max_queues = Some(2);
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(SqliteStorage::new(&sqlite_config).unwrap()),
+            Box::new(InMemoryStorage::new(&memory_config)),
+        ];
+        let now = DateTime::<Utc>::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+
+        for storage in backends {
+            assert!(!storage.queue_exists("extra"));
+            assert!(storage.create_queue("extra").unwrap());
+            assert!(!storage.create_queue("extra").unwrap());
+            storage.put_item("extra", item(now, "created")).unwrap();
+            assert_eq!(
+                storage.get_item("extra").unwrap().unwrap().message,
+                "created"
+            );
+            assert_eq!(storage.queue_names(), vec!["extra", "queue"]);
+
+            assert!(matches!(
+                storage.create_queue("another"),
+                Err(StorageError::QueueLimitReached(2))
+            ));
+            assert!(matches!(
+                storage.create_queue("not-valid"),
+                Err(StorageError::InvalidQueueName(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create_queue_refuses_shared_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("queue.sqlite");
+        let mut config = partitioned_config(db_path.to_str().unwrap());
+        config.queues.push("orders".to_string());
+        for (queue, shards) in [("orders", 2), ("jobs", 2)] {
+            config.queue_options.insert(
+                queue.to_string(),
+                QueueOptions {
+                    shards,
+                    ..Default::default()
+                },
+            );
+        }
+        let storage = SqliteStorage::new(&config).unwrap();
+
+        // A shard of a configured queue, and a day table of a partitioned one
+        for queue in ["orders_1", "queue_20260101"] {
+            assert!(
+                matches!(
+                    storage.create_queue(queue),
+                    Err(StorageError::TableConflict { .. })
+                ),
+                "{queue}"
+            );
+            assert!(!storage.queue_exists(queue));
+        }
+        // A queue created at runtime is checked against the ones created before it
+        assert!(storage.create_queue("jobs_0").unwrap());
+        assert!(matches!(
+            storage.create_queue("jobs"),
+            Err(StorageError::TableConflict { other, .. }) if other == "jobs_0"
+        ));
+        assert!(storage.create_queue("orders_2").unwrap());
+    }
+
+    #[test]
+    fn test_backends_agree_on_retrieval_order() {
+        let (_dir, backends) = backends();
+        let base = Utc::now();
+
+        // A small LCG keeps the sequence reproducible without extra dependencies
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |bound: i64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) as i64) % bound
+        };
+        let mut puts = Vec::new();
+        for i in 0..300 {
+            // Few distinct datetimes force ties, replacements and sub-millisecond collisions
+            let datetime = base + Duration::microseconds(next(20) * 700);
+            let datetime_secondary = match next(3) {
+                0 => None,
+                _ => Some(base + Duration::microseconds(next(5) * 400)),
+            };
+            puts.push(QueueItem {
+                datetime,
+                datetime_secondary,
+                message: format!("{i}"),
+                ..Default::default()
+            });
+        }
+
+        let mut drained = Vec::new();
+        for storage in backends {
+            for item in &puts {
+                storage.put_item("queue", item.clone()).unwrap();
+            }
+            let mut order = Vec::new();
+            while let Some(head) = storage.get_item("queue").unwrap() {
+                assert_eq!(storage.delete_item("queue").unwrap(), Some(head.clone()));
+                order.push(head);
+            }
+            drained.push(order);
+        }
+
+        assert!(!drained[0].is_empty());
+        assert_eq!(drained[0], drained[1]);
+    }
+
+    #[test]
+    fn test_backends_agree_on_last_writer() {
+        let (_dir, backends) = backends();
+        let key = DateTime::<Utc>::from_timestamp_millis(1_700_000_000_000).unwrap();
+
+        for storage in backends {
+            // Deleted rows must be revived by a replacing put, not shadowed by them
+            storage.put_item("queue", item(key, "deleted")).unwrap();
+            storage.delete_item("queue").unwrap();
+
+            // The mutex orders each put with the record of who wrote last, so the
+            // stored message must match the writer that completed last
+            let last_writer = std::sync::Mutex::new(String::new());
+            std::thread::scope(|scope| {
+                for writer in 0..8 {
+                    let (storage, last_writer) = (&storage, &last_writer);
+                    scope.spawn(move || {
+                        for round in 0..25 {
+                            let message = format!("{writer}-{round}");
+                            let mut last = last_writer.lock().unwrap();
+                            storage.put_item("queue", item(key, &message)).unwrap();
+                            *last = message;
+                        }
+                    });
+                }
+            });
+
+            let last = last_writer.into_inner().unwrap();
+            assert_eq!(
+                storage.export_items("queue").unwrap(),
+                vec![item(key, &last)]
+            );
+            assert_eq!(
+                storage.delete_item("queue").unwrap(),
+                Some(item(key, &last))
+            );
+            assert_eq!(storage.get_item("queue").unwrap(), None);
+        }
     }
 }
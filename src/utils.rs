@@ -1,23 +1,49 @@
+use crate::errors::ErrorCode;
 use axum::{
     Json,
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
 
 #[derive(Serialize)]
 pub struct JsonError {
-    pub code: String,
+    pub code: ErrorCode,
     pub message: String,
 }
 
 /// Build a JSON error Response with status code.
-pub fn json_error(status: StatusCode, code: &str, message: &str) -> Response {
+pub fn json_error(status: StatusCode, code: ErrorCode, message: &str) -> Response {
     let error = JsonError {
-        code: code.to_string(),
+        code,
         message: message.to_string(),
     };
-    (status, Json(error)).into_response()
+    let mut response = (status, Json(error)).into_response();
+    // Lets middleware tell the code without parsing the body
+    response.extensions_mut().insert(code);
+    response
+}
+
+/// Build a JSON Response with status code, indented if `pretty` is set.
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T, pretty: bool) -> Response {
+    if !pretty {
+        return (status, Json(value)).into_response();
+    }
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => (status, [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => json_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            &format!("Failed to serialize the response: {e}"),
+        ),
+    }
+}
+
+/// Returns the canonical form of a queue name, in which it is configured, stored and
+/// looked up. SQLite table names are case-insensitive, so names differing only in case
+/// must address the same queue.
+pub fn normalize_queue_name(queue: &str) -> String {
+    queue.to_ascii_lowercase()
 }
 
 /// Sanitize queue name to be a valid SQLite table name.
@@ -30,22 +56,69 @@ pub fn sanitize_queue_name(queue: &str) -> Option<String> {
     Some(format!("queue_{queue}"))
 }
 
+/// Parses a duration such as `90s`, `5m`, `2h` or `1d`; a bare number is in seconds.
+/// Returns None for anything else, including negative and overflowing amounts.
+pub fn parse_duration(text: &str) -> Option<chrono::Duration> {
+    let (amount, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => text.split_at(split),
+        None => (text, "s"),
+    };
+    let seconds_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let seconds = amount.parse::<i64>().ok()?.checked_mul(seconds_per_unit)?;
+    chrono::Duration::try_seconds(seconds)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_json_error_response() {
-        let response = json_error(StatusCode::BAD_REQUEST, "TestError", "Test error message");
+        let response = json_error(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::BadRequest,
+            "Test error message",
+        );
         let (status, _) = response.into_parts();
         assert_eq!(status.status, StatusCode::BAD_REQUEST);
 
         // Test more status codes
-        let response = json_error(StatusCode::NOT_FOUND, "NotFound", "Resource not found");
+        let response = json_error(
+            StatusCode::NOT_FOUND,
+            ErrorCode::ItemNotFound,
+            "Resource not found",
+        );
         let (status, _) = response.into_parts();
         assert_eq!(status.status, StatusCode::NOT_FOUND);
     }
 
+    #[test]
+    fn test_parse_duration() {
+        let seconds = |text| parse_duration(text).map(|duration| duration.num_seconds());
+        assert_eq!(seconds("45"), Some(45));
+        assert_eq!(seconds("90s"), Some(90));
+        assert_eq!(seconds("5m"), Some(300));
+        assert_eq!(seconds("2h"), Some(7200));
+        assert_eq!(seconds("1d"), Some(86400));
+        for invalid in ["", "m", "-5m", "5 m", "5w", "1.5h", "99999999999999999d"] {
+            assert_eq!(seconds(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_normalize_queue_name() {
+        assert_eq!(normalize_queue_name("MyQueue"), "myqueue");
+        assert_eq!(normalize_queue_name("my_queue_2"), "my_queue_2");
+        // Only ASCII is folded; other names stay invalid rather than aliasing a valid one
+        assert_eq!(normalize_queue_name("Ünter"), "Ünter");
+    }
+
     #[test]
     fn test_valid_queue_names() {
         // Test valid queue names
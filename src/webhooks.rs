@@ -0,0 +1,515 @@
+use crate::{AppConfig, JournalMode, QueueItem, WebhookConfig, WebhookEvent};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Delay before the first retry, doubled for each further one.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Longest delay between two retries of a persisted delivery.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+/// Time allowed for a single delivery attempt.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the outbox is checked for due retries when nothing new arrives.
+const OUTBOX_POLL: Duration = Duration::from_secs(1);
+/// Deliveries read from the outbox per pass.
+const OUTBOX_BATCH: i64 = 100;
+
+/// The JSON body POSTed to a webhook.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookPayload {
+    Enqueue {
+        queue: String,
+        timestamp: DateTime<Utc>,
+        item: QueueItem,
+    },
+    DepthThreshold {
+        queue: String,
+        timestamp: DateTime<Utc>,
+        depth: usize,
+        threshold: usize,
+    },
+    Stale {
+        queue: String,
+        timestamp: DateTime<Utc>,
+        last_modified: Option<DateTime<Utc>>,
+        stale_after_secs: u64,
+    },
+}
+
+/// Sends the per-queue webhooks configured in `queue_options`.
+///
+/// Deliveries run on background tasks and never delay the request that triggered them.
+/// Failed deliveries are retried up to `webhook_max_attempts` times and then logged and
+/// dropped; with `webhook_outbox_path` they are kept in SQLite until then, so pending
+/// deliveries survive a restart and exhausted ones stay behind as dead letters.
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    config: Arc<AppConfig>,
+    // Queues whose depth is at or above their threshold, so it fires once per crossing
+    above_threshold: Arc<Mutex<HashSet<String>>>,
+    // Queues last found stale, so it fires once per silence
+    stale: Arc<Mutex<HashSet<String>>>,
+    outbox: Option<Arc<Outbox>>,
+}
+
+/// Pending deliveries persisted in the `webhook_outbox` table.
+struct Outbox {
+    conn: Mutex<Connection>,
+    // Wakes `run_outbox` when a delivery is added
+    added: Notify,
+}
+
+/// A persisted delivery that is due.
+struct PendingDelivery {
+    id: i64,
+    queue: String,
+    url: String,
+    body: Vec<u8>,
+    attempts: u32,
+}
+
+impl Outbox {
+    /// Opens the outbox at `path` in `journal_mode`, which must be that of the database
+    /// when the outbox shares its file, or switching modes would undo the other's.
+    fn open(path: &str, journal_mode: JournalMode) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", journal_mode.pragma_value())?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS webhook_outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                queue TEXT NOT NULL,
+                url TEXT NOT NULL,
+                body BLOB NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt BIGINT NOT NULL,
+                dead INT2 NOT NULL DEFAULT 0,
+                last_error TEXT
+            )",
+            [],
+        )?;
+        Ok(Outbox {
+            conn: Mutex::new(conn),
+            added: Notify::new(),
+        })
+    }
+
+    fn add(&self, queue: &str, url: &str, body: &[u8]) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO webhook_outbox (queue, url, body, next_attempt) VALUES (?1, ?2, ?3, ?4)",
+            params![queue, url, body, Utc::now().timestamp_millis()],
+        )?;
+        drop(conn);
+        self.added.notify_one();
+        Ok(())
+    }
+
+    fn due(&self) -> rusqlite::Result<Vec<PendingDelivery>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT id, queue, url, body, attempts FROM webhook_outbox
+            WHERE dead = 0 AND next_attempt <= ?1 ORDER BY id LIMIT ?2",
+        )?;
+        stmt.query_map(
+            params![Utc::now().timestamp_millis(), OUTBOX_BATCH],
+            |row| {
+                Ok(PendingDelivery {
+                    id: row.get(0)?,
+                    queue: row.get(1)?,
+                    url: row.get(2)?,
+                    body: row.get(3)?,
+                    attempts: row.get(4)?,
+                })
+            },
+        )?
+        .collect()
+    }
+
+    fn delivered(&self, id: i64) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM webhook_outbox WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Records a failed attempt, scheduling the next one or giving up after `max_attempts`.
+    fn failed(
+        &self,
+        delivery: &PendingDelivery,
+        max_attempts: u32,
+        error: &str,
+    ) -> rusqlite::Result<bool> {
+        let attempts = delivery.attempts + 1;
+        let dead = attempts >= max_attempts;
+        let delay = retry_delay(attempts).min(MAX_RETRY_DELAY);
+        let next_attempt = Utc::now().timestamp_millis() + delay.as_millis() as i64;
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE webhook_outbox SET attempts = ?2, next_attempt = ?3, dead = ?4, last_error = ?5 WHERE id = ?1",
+            params![delivery.id, attempts, next_attempt, dead, error],
+        )?;
+        Ok(dead)
+    }
+}
+
+/// Runs an outbox call on the blocking pool, since its SQLite writes block the thread,
+/// describing why it failed if it did.
+async fn blocking<T: Send + 'static>(
+    outbox: &Arc<Outbox>,
+    call: impl FnOnce(&Outbox) -> rusqlite::Result<T> + Send + 'static,
+) -> Result<T, String> {
+    let outbox = outbox.clone();
+    match tokio::task::spawn_blocking(move || call(&outbox)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// The delay after the `attempts`-th failed attempt.
+fn retry_delay(attempts: u32) -> Duration {
+    RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+}
+
+impl Webhooks {
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build webhook client");
+        let outbox = config.webhook_outbox_path.as_deref().map(|path| {
+            Arc::new(
+                Outbox::open(path, config.sqlite_journal_mode)
+                    .expect("Failed to open the webhook outbox"),
+            )
+        });
+        Webhooks {
+            client,
+            config,
+            above_threshold: Arc::new(Mutex::new(HashSet::new())),
+            stale: Arc::new(Mutex::new(HashSet::new())),
+            outbox,
+        }
+    }
+
+    /// Returns the webhook of `queue` if it subscribes to `event`.
+    fn subscription(&self, queue: &str, event: WebhookEvent) -> Option<WebhookConfig> {
+        let webhook = self.config.queue_options.get(queue)?.webhook.clone()?;
+        webhook.events.contains(&event).then_some(webhook)
+    }
+
+    /// Whether anything is subscribed to depth changes of `queue`, so callers can skip
+    /// counting items otherwise.
+    pub fn wants_depth(&self, queue: &str) -> bool {
+        self.subscription(queue, WebhookEvent::DepthThreshold)
+            .is_some_and(|webhook| webhook.depth_threshold.is_some())
+    }
+
+    /// Notifies the queue's webhook that `item` was enqueued.
+    pub fn enqueued(&self, queue: &str, item: &QueueItem) {
+        if let Some(webhook) = self.subscription(queue, WebhookEvent::Enqueue) {
+            let payload = WebhookPayload::Enqueue {
+                queue: queue.to_string(),
+                timestamp: Utc::now(),
+                item: item.clone(),
+            };
+            self.send(queue, webhook, payload);
+        }
+    }
+
+    /// Records the depth of `queue` after a write, notifying the webhook when the depth
+    /// reaches its threshold from below.
+    pub fn depth_changed(&self, queue: &str, depth: usize) {
+        let Some(webhook) = self.subscription(queue, WebhookEvent::DepthThreshold) else {
+            return;
+        };
+        let Some(threshold) = webhook.depth_threshold else {
+            return;
+        };
+        let crossed = {
+            let mut above = self.above_threshold.lock().unwrap();
+            if depth >= threshold {
+                above.insert(queue.to_string())
+            } else {
+                above.remove(queue);
+                false
+            }
+        };
+        if crossed {
+            let payload = WebhookPayload::DepthThreshold {
+                queue: queue.to_string(),
+                timestamp: Utc::now(),
+                depth,
+                threshold,
+            };
+            self.send(queue, webhook, payload);
+        }
+    }
+
+    /// Records whether `queue` is stale, notifying the webhook when it becomes stale.
+    pub fn staleness_checked(
+        &self,
+        queue: &str,
+        stale: bool,
+        last_modified: Option<DateTime<Utc>>,
+    ) {
+        let Some(webhook) = self.subscription(queue, WebhookEvent::Stale) else {
+            return;
+        };
+        let Some(stale_after_secs) = self.config.queue_options(queue).stale_after_secs else {
+            return;
+        };
+        let became_stale = {
+            let mut stale_queues = self.stale.lock().unwrap();
+            if stale {
+                stale_queues.insert(queue.to_string())
+            } else {
+                stale_queues.remove(queue);
+                false
+            }
+        };
+        if became_stale {
+            warn!("queue {queue} went stale, last written at {last_modified:?}");
+            let payload = WebhookPayload::Stale {
+                queue: queue.to_string(),
+                timestamp: Utc::now(),
+                last_modified,
+                stale_after_secs,
+            };
+            self.send(queue, webhook, payload);
+        }
+    }
+
+    fn send(&self, queue: &str, webhook: WebhookConfig, payload: WebhookPayload) {
+        let body = serde_json::to_vec(&payload).expect("webhook payloads serialize");
+        if let Some(outbox) = &self.outbox {
+            // Delivered by `run_outbox`; persisted off the runtime so the request never
+            // waits on the outbox's SQLite write
+            let outbox = outbox.clone();
+            let queue = queue.to_string();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = outbox.add(&queue, &webhook.url, &body) {
+                    error!("Failed to persist webhook event for {}: {e}", webhook.url);
+                }
+            });
+            return;
+        }
+        let client = self.client.clone();
+        let max_attempts = self.config.webhook_max_attempts.max(1);
+        tokio::spawn(async move {
+            let mut delay = RETRY_DELAY;
+            for attempt in 1..=max_attempts {
+                match deliver(&client, &webhook.url, webhook.secret.as_deref(), &body).await {
+                    Ok(()) => return,
+                    Err(e) => warn!("{e} (attempt {attempt}/{max_attempts})"),
+                }
+                if attempt < max_attempts {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+            warn!(
+                "Dropping webhook event for {} after {max_attempts} attempts",
+                webhook.url
+            );
+        });
+    }
+
+    /// Delivers the persisted outbox until the task is dropped, retrying failures with
+    /// backoff. Does nothing without `webhook_outbox_path`.
+    pub async fn run_outbox(self) {
+        let Some(outbox) = self.outbox.clone() else {
+            return;
+        };
+        let max_attempts = self.config.webhook_max_attempts.max(1);
+        loop {
+            let due = match blocking(&outbox, Outbox::due).await {
+                Ok(due) => due,
+                Err(e) => {
+                    error!("Failed to read the webhook outbox: {e}");
+                    Vec::new()
+                }
+            };
+            let batch_len = due.len();
+            for delivery in due {
+                // The secret is looked up at delivery time, so it is never persisted
+                let secret = self
+                    .config
+                    .queue_options
+                    .get(&delivery.queue)
+                    .and_then(|options| options.webhook.as_ref())
+                    .and_then(|webhook| webhook.secret.as_deref());
+                let result = deliver(&self.client, &delivery.url, secret, &delivery.body).await;
+                let (id, url, attempts) = (delivery.id, delivery.url.clone(), delivery.attempts);
+                let recorded = match result {
+                    Ok(()) => blocking(&outbox, move |outbox| outbox.delivered(id)).await,
+                    Err(e) => {
+                        let error = e.clone();
+                        blocking(&outbox, move |outbox| {
+                            outbox.failed(&delivery, max_attempts, &error)
+                        })
+                        .await
+                        .map(|dead| match dead {
+                            true => warn!(
+                                "Giving up on webhook delivery {id} to {url} after {max_attempts} attempts: {e}"
+                            ),
+                            false => warn!("{e} (attempt {})", attempts + 1),
+                        })
+                    }
+                };
+                if let Err(e) = recorded {
+                    error!("Failed to update the webhook outbox: {e}");
+                }
+            }
+            if batch_len < OUTBOX_BATCH as usize {
+                let _ = tokio::time::timeout(OUTBOX_POLL, outbox.added.notified()).await;
+            }
+        }
+    }
+}
+
+/// Returns the `X-Dtqueue-Signature` value for `body`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("sha256={hex}")
+}
+
+/// Makes one delivery attempt, describing the failure if it does not succeed.
+async fn deliver(
+    client: &reqwest::Client,
+    url: &str,
+    secret: Option<&str>,
+    body: &[u8],
+) -> Result<(), String> {
+    let mut request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_vec());
+    if let Some(secret) = secret {
+        request = request.header("X-Dtqueue-Signature", signature(secret, body));
+    }
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            info!("webhook delivered to {url}");
+            Ok(())
+        }
+        Ok(response) => Err(format!("Webhook {url} answered {}", response.status())),
+        Err(e) => Err(format!("Webhook {url} failed: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_is_hex_hmac() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outbox_survives_restart() {
+        let receiver = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = receiver.local_addr().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = AppConfig {
+            webhook_outbox_path: Some(dir.path().join("outbox.db").to_str().unwrap().to_string()),
+            ..AppConfig::default()
+        };
+        config.queue_options.insert(
+            "orders".to_string(),
+            crate::QueueOptions {
+                webhook: Some(WebhookConfig {
+                    url: format!("http://{addr}/hook"),
+                    events: vec![WebhookEvent::Enqueue],
+                    secret: Some("s3cret".to_string()),
+                    depth_threshold: None,
+                }),
+                ..Default::default()
+            },
+        );
+        let config = Arc::new(config);
+
+        // Enqueued by an instance that stops before delivering anything
+        let item = QueueItem {
+            datetime: Utc::now(),
+            message: "hello".to_string(),
+            ..Default::default()
+        };
+        Webhooks::new(config.clone()).enqueued("orders", &item);
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |headers: axum::http::HeaderMap, body: String| {
+                let tx = tx.clone();
+                async move {
+                    let signature = headers["X-Dtqueue-Signature"].to_str().unwrap().to_string();
+                    tx.send((signature, body)).unwrap();
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(receiver, app).await.unwrap() });
+
+        let restarted = Webhooks::new(config);
+        let outbox = restarted.outbox.clone().unwrap();
+        let worker = tokio::spawn(restarted.run_outbox());
+        let (sig, body) = tokio::time::timeout(Duration::from_secs(10), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sig, signature("s3cret", body.as_bytes()));
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["queue"], "orders");
+        assert_eq!(payload["item"]["message"], "hello");
+
+        // The row is removed once the delivery succeeded
+        for _ in 0..50 {
+            let remaining: i64 = outbox
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("SELECT COUNT(*) FROM webhook_outbox", [], |row| row.get(0))
+                .unwrap();
+            if remaining == 0 {
+                worker.abort();
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("delivered webhook was not removed from the outbox");
+    }
+
+    #[test]
+    fn test_outbox_keeps_the_configured_journal_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.db");
+        let path = path.to_str().unwrap();
+        for (mode, expected) in [(JournalMode::Delete, "delete"), (JournalMode::Wal, "wal")] {
+            let outbox = Outbox::open(path, mode).unwrap();
+            let journal_mode: String = outbox
+                .conn
+                .lock()
+                .unwrap()
+                .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(journal_mode, expected);
+        }
+    }
+}
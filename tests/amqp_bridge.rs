@@ -0,0 +1,75 @@
+//! Needs a broker at `DTQUEUE_TEST_AMQP_URL`, by default a local RabbitMQ:
+//! `cargo test --features amqp --test amqp_bridge`
+#![cfg(feature = "amqp")]
+
+use chrono::{Duration, Utc};
+use dtqueue::{AmqpBinding, AppConfig, InMemoryStorage, QueueItem, Storage};
+use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_round_trip_through_broker() {
+    let amqp_url = std::env::var("DTQUEUE_TEST_AMQP_URL")
+        .unwrap_or_else(|_| "amqp://127.0.0.1:5672/%2f".to_string());
+    // A fresh AMQP queue, so leftovers of earlier runs cannot interfere
+    let amqp_queue = format!("dtqueue_test_{}", Uuid::new_v4().simple());
+    // outbound publishes through the default exchange straight into the AMQP queue,
+    // which inbound consumes
+    let config = AppConfig {
+        queues: vec!["outbound".to_string(), "inbound".to_string()],
+        amqp_url,
+        amqp_bindings: vec![
+            AmqpBinding {
+                queue: "inbound".to_string(),
+                publish_exchange: None,
+                routing_key: None,
+                consume_queue: Some(amqp_queue.clone()),
+            },
+            AmqpBinding {
+                queue: "outbound".to_string(),
+                publish_exchange: Some(String::new()),
+                routing_key: Some(amqp_queue),
+                consume_queue: None,
+            },
+        ],
+        ..Default::default()
+    };
+    let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new(&config));
+    let base = Utc::now() - Duration::minutes(1);
+    let items: Vec<QueueItem> = (0..3)
+        .map(|i| QueueItem {
+            datetime: base + Duration::seconds(i),
+            datetime_secondary: None,
+            message: format!("job {i}"),
+            id: Some(format!("id-{i}")),
+            ..Default::default()
+        })
+        .collect();
+    let future = QueueItem {
+        datetime: Utc::now() + Duration::hours(1),
+        datetime_secondary: None,
+        message: "not yet".to_string(),
+        ..Default::default()
+    };
+    storage.put_items("outbound", items.clone()).unwrap();
+    storage.put_item("outbound", future.clone()).unwrap();
+
+    let bridges = tokio::spawn(dtqueue::amqp::run_bridges(
+        storage.clone(),
+        Arc::new(config),
+    ));
+    let start = Instant::now();
+    while storage.count_items("inbound").unwrap() < items.len() {
+        assert!(
+            start.elapsed() < StdDuration::from_secs(10),
+            "items did not arrive through the broker"
+        );
+        tokio::time::sleep(StdDuration::from_millis(100)).await;
+    }
+    bridges.abort();
+
+    // Due items made the trip intact and in order; the future one stayed behind
+    assert_eq!(storage.export_items("inbound").unwrap(), items);
+    assert_eq!(storage.export_items("outbound").unwrap(), vec![future]);
+}
@@ -1,5 +1,5 @@
 use chrono::{Duration, Utc};
-use dtqueue::QueueItem;
+use dtqueue::{AppConfig, QueueItem, SqliteStorage, Storage};
 use serde_json::Value;
 use std::fs::{self, File};
 use std::io::Write;
@@ -27,6 +27,11 @@ struct TestServer {
 impl TestServer {
     // Create a new test server with unique queue, config, and database
     fn new(test_name: &str) -> Self {
+        Self::with_config(test_name, "")
+    }
+
+    // Like `new`, appending `extra_config` to the generated configuration
+    fn with_config(test_name: &str, extra_config: &str) -> Self {
         // Generate unique identifiers for this test
         let mut buffer = Uuid::encode_buffer();
         let test_id = Uuid::new_v4().simple().encode_lower(&mut buffer);
@@ -45,10 +50,17 @@ impl TestServer {
         let log_path = test_dir.join(format!("server_{}.log", test_id));
 
         // Create test configuration
-        create_test_config(&config_path, port, &queue_name, &db_path, &log_path);
+        create_test_config(
+            &config_path,
+            port,
+            &queue_name,
+            &db_path,
+            &log_path,
+            extra_config,
+        );
 
         // Start server process
-        let server_process = start_test_server(&config_path, port, &queue_name);
+        let server_process = start_test_server(&config_path, port);
 
         println!(
             "Started test server for '{}' on port {} with queue '{}'",
@@ -145,8 +157,9 @@ fn create_test_config(
     queue_name: &str,
     db_path: &Path,
     log_path: &Path,
+    extra_config: &str,
 ) {
-    let config_content = format!(
+    let mut config_content = format!(
         r#"bind_address = "127.0.0.1"
 port = {}
 queues = ["{}"]
@@ -160,6 +173,7 @@ max_workers = 1
         log_path.to_string_lossy(),
         db_path.to_string_lossy()
     );
+    config_content.push_str(extra_config);
 
     let mut file = File::create(config_path).expect("Failed to create config file");
     file.write_all(config_content.as_bytes())
@@ -167,7 +181,7 @@ max_workers = 1
 }
 
 // Start a test server with the given configuration
-fn start_test_server(config_path: &Path, port: u16, queue_name: &str) -> Child {
+fn start_test_server(config_path: &Path, port: u16) -> Child {
     // Start the server process
     let mut child = Command::new("cargo")
         .arg("run")
@@ -185,13 +199,13 @@ fn start_test_server(config_path: &Path, port: u16, queue_name: &str) -> Child {
     while start_time.elapsed() < timeout {
         thread::sleep(StdDuration::from_millis(100));
 
-        // Try to connect to verify the server is up
+        // The server listens before its storage is open, so wait for /_ready to pass
         match client
-            .get(format!("http://127.0.0.1:{}/{}", port, queue_name))
+            .get(format!("http://127.0.0.1:{}/_ready", port))
             .send()
         {
-            Ok(_) => return child, // Server is ready
-            Err(_) => continue,    // Keep waiting
+            Ok(response) if response.status().is_success() => return child, // Server is ready
+            _ => continue,                                                  // Keep waiting
         }
     }
 
@@ -269,6 +283,7 @@ fn create_queue_item(offset_seconds: i64, message: &str) -> String {
         datetime: dt,
         datetime_secondary: None,
         message: message.to_string(),
+        ..Default::default()
     };
     serde_json::to_string(&item).unwrap()
 }
@@ -303,6 +318,33 @@ fn test_put_get_delete_operations() {
     );
 }
 
+#[test]
+fn test_storage_backends() {
+    for backend in ["sqlite", "memory"] {
+        let server = TestServer::with_config(
+            &format!("storage_backend_{backend}"),
+            &format!("storage_backend = \"{backend}\"\n"),
+        );
+
+        for (offset, message) in [(20, "second"), (10, "first")] {
+            let item = create_queue_item(offset, message);
+            let (status, _) = server.request("PUT", "/", Some(&item)).unwrap();
+            assert_eq!(status, 200, "{backend}");
+        }
+        for expected in ["first", "second"] {
+            let (status, body) = server.request("DELETE", "/", None).unwrap();
+            assert_eq!(status, 200, "{backend}");
+            let deleted: QueueItem = serde_json::from_str(&body).unwrap();
+            assert_eq!(deleted.message, expected, "{backend}");
+        }
+        let (status, _) = server.request("GET", "/", None).unwrap();
+        assert_eq!(status, 204, "{backend}");
+
+        // Only SQLite writes the configured database file
+        assert_eq!(server.db_path.exists(), backend == "sqlite", "{backend}");
+    }
+}
+
 #[test]
 fn test_priority_ordering() {
     let server = TestServer::new("priority_ordering");
@@ -343,6 +385,47 @@ fn test_priority_ordering() {
     );
 }
 
+// DELETE is the atomic pop: concurrent consumers never receive the same item
+#[test]
+fn test_concurrent_deletes_consume_once() {
+    let server = TestServer::new("concurrent_deletes");
+
+    let mut expected: Vec<String> = (0..50).map(|i| format!("job {i:02}")).collect();
+    for (i, message) in expected.iter().enumerate() {
+        let item = create_queue_item(i as i64 - 60, message);
+        let (status, _) = server.request("PUT", "/", Some(&item)).unwrap();
+        assert_eq!(status, 200);
+    }
+
+    let path = format!("/{}", server.queue_name);
+    let mut consumed: Vec<String> = thread::scope(|scope| {
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut consumed = Vec::new();
+                    loop {
+                        let (status, body) =
+                            make_request("DELETE", &path, None, server.port).unwrap();
+                        if status == 204 {
+                            return consumed;
+                        }
+                        assert_eq!(status, 200);
+                        let item: QueueItem = serde_json::from_str(&body).unwrap();
+                        consumed.push(item.message);
+                    }
+                })
+            })
+            .collect();
+        consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().unwrap())
+            .collect()
+    });
+    consumed.sort();
+    expected.sort();
+    assert_eq!(consumed, expected);
+}
+
 #[test]
 fn test_idempotent_puts() {
     let server = TestServer::new("idempotent_puts");
@@ -353,6 +436,7 @@ fn test_idempotent_puts() {
         datetime: now,
         datetime_secondary: None,
         message: "original message".to_string(),
+        ..Default::default()
     };
     let item_json = serde_json::to_string(&item).unwrap();
 
@@ -365,6 +449,7 @@ fn test_idempotent_puts() {
         datetime: now,
         datetime_secondary: None,
         message: "updated message".to_string(),
+        ..Default::default()
     };
     let updated_json = serde_json::to_string(&updated_item).unwrap();
 
@@ -435,18 +520,21 @@ fn test_secondary_datetime_ordering() {
         datetime: now,
         datetime_secondary: Some(now + Duration::seconds(10)),
         message: "secondary 1".to_string(),
+        ..Default::default()
     };
 
     let item2 = QueueItem {
         datetime: now,
         datetime_secondary: Some(now + Duration::seconds(5)),
         message: "secondary 2".to_string(),
+        ..Default::default()
     };
 
     let item3 = QueueItem {
         datetime: now,
         datetime_secondary: None, // None should come first in ordering
         message: "secondary 3".to_string(),
+        ..Default::default()
     };
 
     // Add items in reverse order
@@ -532,3 +620,99 @@ fn test_invalid_queue_name() {
         }
     }
 }
+
+#[test]
+fn test_depth_is_logged_periodically() {
+    let server = TestServer::with_config("depth_logging", "depth_log_interval_secs = 1\n");
+
+    let item = create_queue_item(0, "counted");
+    let (status, _) = server.request("PUT", "/", Some(&item)).unwrap();
+    assert_eq!(status, 200);
+
+    let expected = format!("queue depth {}: 1", server.queue_name);
+    let start = Instant::now();
+    while start.elapsed() < StdDuration::from_secs(5) {
+        let log = fs::read_to_string(&server.log_path).unwrap_or_default();
+        if log.contains(&expected) {
+            return;
+        }
+        thread::sleep(StdDuration::from_millis(200));
+    }
+    panic!("No depth line for {} in the log", server.queue_name);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigterm_drains_buffered_writes() {
+    let mut server = TestServer::with_config("graceful_shutdown", "async_write_ack = true\n");
+
+    let item = create_queue_item(0, "acknowledged");
+    let (status, _) = server.request("PUT", "/", Some(&item)).unwrap();
+    assert_eq!(status, 202);
+    let signalled = Command::new("kill")
+        .args(["-TERM", &server.server_process.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(signalled.success());
+    let exit = server.server_process.wait().unwrap();
+    assert!(exit.success(), "{exit}");
+
+    let log = fs::read_to_string(&server.log_path).unwrap();
+    assert!(log.contains("Shutting down"), "{log}");
+    // The item acknowledged before the signal was stored before the server exited
+    let config = AppConfig::from_file(&server.config_path.to_string_lossy()).unwrap();
+    let storage = SqliteStorage::new(&config).unwrap();
+    assert_eq!(storage.count_items(&server.queue_name).unwrap(), 1);
+}
+
+#[test]
+fn test_unwritable_log_file() {
+    let mut buffer = Uuid::encode_buffer();
+    let test_id = Uuid::new_v4()
+        .simple()
+        .encode_lower(&mut buffer)
+        .to_string();
+    let test_dir = PathBuf::from("tests/tmp");
+    fs::create_dir_all(&test_dir).expect("Failed to create test directory");
+    // A log path below a regular file cannot be written, not even by root
+    let blocker = test_dir.join(format!("blocker_{test_id}"));
+    File::create(&blocker).unwrap();
+    let config_path = test_dir.join(format!("config_{test_id}.toml"));
+    let port = find_available_port();
+    let write_config = |required: bool| {
+        let config_content = format!(
+            r#"bind_address = "127.0.0.1"
+port = {port}
+queues = ["logless"]
+log_file = "{}"
+log_file_required = {required}
+log_level = "info"
+database_path = ":memory:"
+"#,
+            blocker.join("server.log").to_string_lossy()
+        );
+        fs::write(&config_path, config_content).unwrap();
+    };
+
+    // Required: a clean exit with a message instead of a panic
+    write_config(true);
+    let output = Command::new("cargo")
+        .args(["run", "--release", "--"])
+        .arg(&config_path)
+        .output()
+        .expect("Failed to run the server");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("Cannot write the log file"), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+
+    // Not required: the server comes up logging to stderr only
+    write_config(false);
+    let mut server = start_test_server(&config_path, port);
+    let (status, _) = make_request("GET", "/logless", None, port).unwrap();
+    assert_eq!(status, 204);
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = fs::remove_file(&config_path);
+    let _ = fs::remove_file(&blocker);
+}